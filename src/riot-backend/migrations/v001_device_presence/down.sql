@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS `presence_event`;
+ALTER TABLE `device`
+    DROP COLUMN `status`,
+    DROP COLUMN `heartbeat_timeout`;
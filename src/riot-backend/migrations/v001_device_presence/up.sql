@@ -0,0 +1,17 @@
+ALTER TABLE `device`
+    ADD COLUMN `status` VARCHAR(16) DEFAULT 'never_seen' NOT NULL, -- never_seen / online / offline
+    ADD COLUMN `heartbeat_timeout` INT UNSIGNED DEFAULT 600 NOT NULL; -- seconds without data before going offline
+
+-- Devices which have sent data before start as offline, their next record brings them online
+UPDATE `device` SET `status` = 'offline', `last_update` = `last_update`
+    WHERE `id` IN (SELECT DISTINCT `did` FROM `record`);
+
+CREATE TABLE IF NOT EXISTS `presence_event` (
+    `id` SERIAL PRIMARY KEY,
+    `did` BIGINT UNSIGNED NOT NULL,
+    `status` VARCHAR(16) NOT NULL, -- status after the transition
+    `cause` VARCHAR(16) NOT NULL, -- heartbeat / timeout / last_will
+    `timestamp` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    INDEX presence_device_index (`did`, `timestamp`),
+    FOREIGN KEY (`did`) REFERENCES `device`(id) ON DELETE RESTRICT
+);
@@ -0,0 +1,6 @@
+DROP TABLE IF EXISTS `geofence_event`;
+DROP TABLE IF EXISTS `geofence`;
+DROP TABLE IF EXISTS `location`;
+ALTER TABLE `device`
+    DROP INDEX coordinate_index,
+    DROP COLUMN `locate_from_payload`;
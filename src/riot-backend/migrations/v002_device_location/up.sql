@@ -0,0 +1,36 @@
+ALTER TABLE `device`
+    ADD COLUMN `locate_from_payload` BOOLEAN DEFAULT FALSE NOT NULL, -- track `lat`/`lng` fields of JSON payloads
+    ADD INDEX coordinate_index (`latitude`, `longitude`);
+
+CREATE TABLE IF NOT EXISTS `location` (
+    `id` SERIAL PRIMARY KEY,
+    `did` BIGINT UNSIGNED NOT NULL,
+    `latitude` DOUBLE NOT NULL,
+    `longitude` DOUBLE NOT NULL,
+    `source` VARCHAR(16) NOT NULL, -- api / payload
+    `timestamp` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    INDEX location_device_index (`did`, `timestamp`),
+    FOREIGN KEY (`did`) REFERENCES `device`(id) ON DELETE RESTRICT
+);
+
+CREATE TABLE IF NOT EXISTS `geofence` (
+    `id` SERIAL PRIMARY KEY,
+    `uid` BIGINT UNSIGNED NOT NULL,
+    `name` VARCHAR(256) NOT NULL,
+    `latitude` DOUBLE NOT NULL,
+    `longitude` DOUBLE NOT NULL,
+    `radius` DOUBLE NOT NULL, -- meters
+    `activated` BOOLEAN DEFAULT TRUE NOT NULL,
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
+
+CREATE TABLE IF NOT EXISTS `geofence_event` (
+    `id` SERIAL PRIMARY KEY,
+    `gid` BIGINT UNSIGNED NOT NULL,
+    `did` BIGINT UNSIGNED NOT NULL,
+    `transition` VARCHAR(16) NOT NULL, -- enter / exit
+    `timestamp` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    INDEX geofence_event_index (`gid`, `timestamp`),
+    FOREIGN KEY (`gid`) REFERENCES `geofence`(id) ON DELETE RESTRICT,
+    FOREIGN KEY (`did`) REFERENCES `device`(id) ON DELETE RESTRICT
+);
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS `alert` (
+    `id` SERIAL PRIMARY KEY,
+    `did` BIGINT UNSIGNED NOT NULL,
+    `severity` VARCHAR(16) NOT NULL, -- info / warning / critical
+    `message` VARCHAR(1024) NOT NULL,
+    `count` INT UNSIGNED DEFAULT 1 NOT NULL, -- times raised while unresolved (deduplicated)
+    `raised_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `last_raised_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `acknowledged_at` DATETIME(3) DEFAULT NULL,
+    `acknowledged_by` BIGINT UNSIGNED DEFAULT NULL,
+    `resolved_at` DATETIME(3) DEFAULT NULL,
+    INDEX alert_device_index (`did`, `resolved_at`),
+    FOREIGN KEY (`did`) REFERENCES `device`(id) ON DELETE RESTRICT,
+    FOREIGN KEY (`acknowledged_by`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
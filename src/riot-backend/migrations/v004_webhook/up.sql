@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS `webhook` (
+    `id` SERIAL PRIMARY KEY,
+    `uid` BIGINT UNSIGNED NOT NULL,
+    `url` VARCHAR(1024) NOT NULL,
+    `secret` VARCHAR(64) NOT NULL, -- HMAC-SHA256 key for the payload signature
+    `events` VARCHAR(512) NOT NULL, -- comma separated event names, e.g. "record.created,device.offline"
+    `activated` BOOLEAN DEFAULT TRUE NOT NULL,
+    `created_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    INDEX webhook_user_index (`uid`),
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
+
+CREATE TABLE IF NOT EXISTS `webhook_delivery` (
+    `id` SERIAL PRIMARY KEY,
+    `wid` BIGINT UNSIGNED NOT NULL,
+    `event` VARCHAR(32) NOT NULL,
+    `payload` MEDIUMTEXT NOT NULL, -- JSON body
+    `status` VARCHAR(16) NOT NULL DEFAULT 'pending', -- pending / delivered / dead
+    `attempts` INT UNSIGNED DEFAULT 0 NOT NULL,
+    `next_attempt_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `response_status` SMALLINT UNSIGNED DEFAULT NULL, -- HTTP status of the last attempt
+    `last_error` VARCHAR(1024) DEFAULT NULL,
+    `created_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `delivered_at` DATETIME(3) DEFAULT NULL,
+    INDEX delivery_due_index (`status`, `next_attempt_at`),
+    INDEX delivery_webhook_index (`wid`, `created_at`),
+    FOREIGN KEY (`wid`) REFERENCES `webhook`(id) ON DELETE RESTRICT
+);
@@ -0,0 +1,3 @@
+ALTER TABLE `tag`
+    ADD COLUMN `parent` BIGINT UNSIGNED NULL, -- e.g. building > floor > room
+    ADD CONSTRAINT `tag_parent_fk` FOREIGN KEY (`parent`) REFERENCES `tag`(id) ON DELETE SET NULL;
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS `device_label`;
+ALTER TABLE `device`
+    DROP COLUMN `metadata`;
@@ -0,0 +1,11 @@
+ALTER TABLE `device`
+    ADD COLUMN `metadata` JSON NULL; -- arbitrary user data, e.g. firmware version, serial number
+
+CREATE TABLE IF NOT EXISTS `device_label` (
+    `did` BIGINT UNSIGNED NOT NULL,
+    `key` VARCHAR(64) NOT NULL,
+    `value` VARCHAR(256) NOT NULL,
+    PRIMARY KEY (`did`, `key`),
+    INDEX label_index (`key`, `value`), -- equality filters
+    FOREIGN KEY (`did`) REFERENCES `device`(id) ON DELETE RESTRICT
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS `tag_share`;
+DROP TABLE IF EXISTS `device_share`;
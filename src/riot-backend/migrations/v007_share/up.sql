@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS `device_share` (
+    `did` BIGINT UNSIGNED NOT NULL,
+    `uid` BIGINT UNSIGNED NOT NULL, -- grantee
+    `permission` VARCHAR(16) NOT NULL, -- read / write / admin
+    `granted_by` BIGINT UNSIGNED NOT NULL,
+    `created_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    PRIMARY KEY (`did`, `uid`),
+    INDEX device_share_user_index (`uid`),
+    FOREIGN KEY (`did`) REFERENCES `device`(id) ON DELETE RESTRICT,
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT,
+    FOREIGN KEY (`granted_by`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
+
+-- Grants the permission on the tag and on the tag owner's devices under it
+CREATE TABLE IF NOT EXISTS `tag_share` (
+    `tid` BIGINT UNSIGNED NOT NULL,
+    `uid` BIGINT UNSIGNED NOT NULL, -- grantee
+    `permission` VARCHAR(16) NOT NULL, -- read / write / admin
+    `granted_by` BIGINT UNSIGNED NOT NULL,
+    `created_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    PRIMARY KEY (`tid`, `uid`),
+    INDEX tag_share_user_index (`uid`),
+    FOREIGN KEY (`tid`) REFERENCES `tag`(id) ON DELETE RESTRICT,
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT,
+    FOREIGN KEY (`granted_by`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
@@ -0,0 +1,10 @@
+ALTER TABLE `tag`
+    DROP FOREIGN KEY tag_org_fk,
+    DROP INDEX tag_org_index,
+    DROP COLUMN `oid`;
+ALTER TABLE `device`
+    DROP FOREIGN KEY device_org_fk,
+    DROP INDEX device_org_index,
+    DROP COLUMN `oid`;
+DROP TABLE IF EXISTS `membership`;
+DROP TABLE IF EXISTS `organization`;
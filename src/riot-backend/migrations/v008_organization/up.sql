@@ -0,0 +1,44 @@
+CREATE TABLE IF NOT EXISTS `organization` (
+    `id` SERIAL PRIMARY KEY,
+    `name` VARCHAR(256) NOT NULL,
+    `personal_of` BIGINT UNSIGNED DEFAULT NULL UNIQUE, -- the user of a personal organization
+    `api_key` VARCHAR(64) DEFAULT NULL UNIQUE, -- MQTT topic prefix of the organization's devices
+    `since` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `activated` BOOLEAN DEFAULT TRUE NOT NULL,
+    FOREIGN KEY (`personal_of`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
+
+CREATE TABLE IF NOT EXISTS `membership` (
+    `oid` BIGINT UNSIGNED NOT NULL,
+    `uid` BIGINT UNSIGNED NOT NULL,
+    `role` VARCHAR(16) NOT NULL, -- owner / admin / member / viewer
+    `since` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    PRIMARY KEY (`oid`, `uid`),
+    INDEX membership_user_index (`uid`),
+    FOREIGN KEY (`oid`) REFERENCES `organization`(id) ON DELETE RESTRICT,
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
+
+-- Every existing user gets a personal organization owning the user's devices and tags
+INSERT INTO `organization` (`name`, `personal_of`, `since`)
+    SELECT `username`, `id`, `since` FROM `user`;
+INSERT INTO `membership` (`oid`, `uid`, `role`, `since`)
+    SELECT `id`, `personal_of`, 'owner', `since` FROM `organization` WHERE `personal_of` IS NOT NULL;
+
+ALTER TABLE `device`
+    ADD COLUMN `oid` BIGINT UNSIGNED NULL;
+UPDATE `device` JOIN `organization` ON `organization`.`personal_of` = `device`.`uid`
+    SET `device`.`oid` = `organization`.`id`, `device`.`last_update` = `device`.`last_update`;
+ALTER TABLE `device`
+    MODIFY `oid` BIGINT UNSIGNED NOT NULL,
+    ADD INDEX device_org_index (`oid`),
+    ADD CONSTRAINT device_org_fk FOREIGN KEY (`oid`) REFERENCES `organization`(id) ON DELETE RESTRICT;
+
+ALTER TABLE `tag`
+    ADD COLUMN `oid` BIGINT UNSIGNED NULL;
+UPDATE `tag` JOIN `organization` ON `organization`.`personal_of` = `tag`.`uid`
+    SET `tag`.`oid` = `organization`.`id`;
+ALTER TABLE `tag`
+    MODIFY `oid` BIGINT UNSIGNED NOT NULL,
+    ADD INDEX tag_org_index (`oid`),
+    ADD CONSTRAINT tag_org_fk FOREIGN KEY (`oid`) REFERENCES `organization`(id) ON DELETE RESTRICT;
@@ -0,0 +1,17 @@
+-- Transfers of device ownership between users, kept as the audit trail once resolved
+CREATE TABLE IF NOT EXISTS `device_transfer` (
+    `id` SERIAL PRIMARY KEY,
+    `did` BIGINT UNSIGNED NOT NULL,
+    `from_uid` BIGINT UNSIGNED NOT NULL,
+    `to_uid` BIGINT UNSIGNED NOT NULL,
+    `with_records` BOOLEAN NOT NULL, -- hand over the history, or purge it on acceptance
+    `status` VARCHAR(16) NOT NULL DEFAULT 'pending', -- pending / accepted / cancelled
+    `created_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `resolved_at` DATETIME(3) DEFAULT NULL,
+    INDEX device_transfer_device_index (`did`, `status`),
+    INDEX device_transfer_to_index (`to_uid`),
+    INDEX device_transfer_from_index (`from_uid`),
+    FOREIGN KEY (`did`) REFERENCES `device`(id) ON DELETE RESTRICT,
+    FOREIGN KEY (`from_uid`) REFERENCES `user`(id) ON DELETE RESTRICT,
+    FOREIGN KEY (`to_uid`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
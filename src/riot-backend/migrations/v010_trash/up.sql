@@ -0,0 +1,2 @@
+-- Soft-deleted devices get a random topic to free the original one, which is kept for restoring
+ALTER TABLE `device` ADD COLUMN `original_topic` VARCHAR(512) DEFAULT NULL;
@@ -0,0 +1,23 @@
+-- Logged-in sessions, each holding a rotating refresh token
+CREATE TABLE IF NOT EXISTS `session` (
+    `id` SERIAL PRIMARY KEY,
+    `uid` BIGINT UNSIGNED NOT NULL,
+    `refresh_hash` CHAR(64) NOT NULL UNIQUE, -- SHA-256 (hex) of the current refresh token
+    `jti` VARCHAR(64) NOT NULL, -- ID shared by the access tokens of the session
+    `org` BIGINT UNSIGNED DEFAULT NULL, -- active organization
+    `user_agent` VARCHAR(512) DEFAULT NULL,
+    `ip` VARCHAR(64) DEFAULT NULL,
+    `created_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `last_seen` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3), -- last refresh
+    `expires_at` DATETIME(3) NOT NULL,
+    `revoked_at` DATETIME(3) DEFAULT NULL,
+    INDEX session_user_index (`uid`, `revoked_at`),
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
+
+-- Access tokens revoked before their expiry
+CREATE TABLE IF NOT EXISTS `revoked_token` (
+    `jti` VARCHAR(64) PRIMARY KEY,
+    `expires_at` DATETIME(3) NOT NULL, -- dropped from the list after this
+    INDEX revoked_token_expiry_index (`expires_at`)
+);
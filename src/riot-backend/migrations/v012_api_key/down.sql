@@ -0,0 +1,5 @@
+-- The plaintext keys cannot be recovered, users have to create new ones
+ALTER TABLE `user`
+    ADD COLUMN `api_key` VARCHAR(64) DEFAULT NULL,
+    ADD INDEX api_index (`api_key`);
+DROP TABLE IF EXISTS `api_key`;
@@ -0,0 +1,23 @@
+-- Named API keys of users, only the hash of a key is stored
+CREATE TABLE IF NOT EXISTS `api_key` (
+    `id` SERIAL PRIMARY KEY,
+    `uid` BIGINT UNSIGNED NOT NULL,
+    `name` VARCHAR(256) NOT NULL,
+    `prefix` VARCHAR(16) NOT NULL, -- leading characters of the key, shown to tell keys apart
+    `key_hash` CHAR(64) NOT NULL UNIQUE, -- SHA-256 (hex) of the key
+    `scopes` VARCHAR(256) NOT NULL, -- comma separated, e.g. "read_records,write_records"
+    `allowed_ips` VARCHAR(1024) DEFAULT NULL, -- comma separated, any IP if NULL
+    `expires_at` DATETIME(3) DEFAULT NULL, -- never expires if NULL
+    `last_used` DATETIME(3) DEFAULT NULL,
+    `created_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `revoked_at` DATETIME(3) DEFAULT NULL,
+    INDEX api_key_user_index (`uid`, `revoked_at`),
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
+
+-- Existing keys keep working (devices publish under them), but are no longer stored in plaintext
+INSERT INTO `api_key` (`uid`, `name`, `prefix`, `key_hash`, `scopes`)
+    SELECT `id`, 'default', LEFT(`api_key`, 8), SHA2(`api_key`, 256),
+        'read_records,write_records,manage_devices'
+    FROM `user` WHERE `api_key` IS NOT NULL;
+ALTER TABLE `user` DROP COLUMN `api_key`;
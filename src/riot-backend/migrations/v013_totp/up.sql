@@ -0,0 +1,19 @@
+-- TOTP (RFC 6238) second factor of users
+CREATE TABLE IF NOT EXISTS `totp` (
+    `uid` BIGINT UNSIGNED PRIMARY KEY,
+    `secret` VARCHAR(64) NOT NULL, -- base32, as shown to authenticator apps
+    `last_step` BIGINT UNSIGNED NOT NULL DEFAULT 0, -- last accepted time step, so that a code works once
+    `created_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `confirmed_at` DATETIME(3) DEFAULT NULL, -- 2FA is enabled once the enrollment is confirmed
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
+
+-- Single-use codes to pass 2FA without the authenticator
+CREATE TABLE IF NOT EXISTS `recovery_code` (
+    `id` SERIAL PRIMARY KEY,
+    `uid` BIGINT UNSIGNED NOT NULL,
+    `code_hash` CHAR(64) NOT NULL, -- SHA-256 (hex) of the normalized code
+    `used_at` DATETIME(3) DEFAULT NULL,
+    INDEX recovery_code_user_index (`uid`, `code_hash`),
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
@@ -0,0 +1,11 @@
+-- External identities (OIDC issuer + subject) linked to users, for single sign-on
+CREATE TABLE IF NOT EXISTS `identity` (
+    `id` SERIAL PRIMARY KEY,
+    `uid` BIGINT UNSIGNED NOT NULL,
+    `issuer` VARCHAR(256) NOT NULL,
+    `subject` VARCHAR(256) NOT NULL, -- `sub` claim, stable per issuer
+    `created_at` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    UNIQUE INDEX identity_subject_index (`issuer`, `subject`),
+    INDEX identity_user_index (`uid`),
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
@@ -0,0 +1,9 @@
+-- Single-use tokens of emailed links (verification, login, password reset, transfer, unlock)
+CREATE TABLE IF NOT EXISTS `one_time_token` (
+    `token_hash` CHAR(64) PRIMARY KEY, -- HMAC-SHA256 (hex) of the purpose and the token, the token itself is never stored
+    `purpose` VARCHAR(32) NOT NULL,
+    `uid` BIGINT UNSIGNED NOT NULL,
+    `expires_at` DATETIME(3) NOT NULL,
+    INDEX one_time_token_expiry_index (`expires_at`),
+    FOREIGN KEY (`uid`) REFERENCES `user`(id) ON DELETE RESTRICT
+);
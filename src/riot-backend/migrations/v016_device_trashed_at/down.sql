@@ -0,0 +1 @@
+ALTER TABLE `device` DROP COLUMN `trashed_at`;
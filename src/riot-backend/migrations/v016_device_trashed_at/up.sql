@@ -0,0 +1,4 @@
+-- Deleted devices are told apart from the deactivated ones by the time they were moved to the trash
+ALTER TABLE `device` ADD COLUMN `trashed_at` DATETIME(3) DEFAULT NULL;
+-- Until now, every deactivated device was in the trash
+UPDATE `device` SET `trashed_at` = `last_update` WHERE `activated` = FALSE;
@@ -0,0 +1,2 @@
+-- The keys can not be recovered from their hashes, they have to be generated again
+UPDATE `organization` SET `api_key` = NULL;
@@ -0,0 +1,2 @@
+-- Organization API keys are stored as their SHA-256 (hex), like the user ones
+UPDATE `organization` SET `api_key` = SHA2(`api_key`, 256) WHERE `api_key` IS NOT NULL;
@@ -0,0 +1 @@
+ALTER TABLE `device` DROP INDEX device_text_index;
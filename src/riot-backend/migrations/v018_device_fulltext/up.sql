@@ -0,0 +1,3 @@
+-- Device search matches words of the name and the description, ngram so that CJK text and
+-- parts of words are found too
+ALTER TABLE `device` ADD FULLTEXT INDEX device_text_index (`name`, `desc`) WITH PARSER ngram;
@@ -0,0 +1,2 @@
+-- Set once a purge of the trashed device has started, so that it can no longer be restored
+ALTER TABLE `device` ADD COLUMN `purging` BOOLEAN DEFAULT FALSE NOT NULL;
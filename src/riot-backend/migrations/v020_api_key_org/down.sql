@@ -0,0 +1,3 @@
+ALTER TABLE `api_key`
+    DROP FOREIGN KEY api_key_org_fk,
+    DROP COLUMN `oid`;
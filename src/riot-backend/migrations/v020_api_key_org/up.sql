@@ -0,0 +1,8 @@
+-- Organization a key works in, like the `org` claim of a session. Existing keys work in the personal organization
+ALTER TABLE `api_key`
+    ADD COLUMN `oid` BIGINT UNSIGNED NULL;
+UPDATE `api_key` JOIN `organization` ON `organization`.`personal_of` = `api_key`.`uid`
+    SET `api_key`.`oid` = `organization`.`id`;
+ALTER TABLE `api_key`
+    MODIFY `oid` BIGINT UNSIGNED NOT NULL,
+    ADD CONSTRAINT api_key_org_fk FOREIGN KEY (`oid`) REFERENCES `organization`(id) ON DELETE RESTRICT;
@@ -1,67 +1,369 @@
 use crate::config::Config;
-use crate::db::DBClient;
-use crate::utils::email::send_email_smtp;
-use crate::utils::jwt::generate_token;
+use crate::db::{DBClient, RevokeScope};
+use crate::models::{Device, NewSession};
+use crate::utils::email::{send_email_smtp, smtp_mailer};
+use crate::utils::hub::RecordHub;
+use crate::utils::jwt::{generate_token, hash_token, new_refresh_token};
+use crate::utils::login_guard::LoginGuard;
+use crate::utils::one_time_token::{expires_in, OneTimeTokens, TokenPurpose};
+use crate::utils::request_limit::RequestLimiter;
 use actix_web::cookie::{self, Cookie};
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use chrono::{Duration, Utc};
+use diesel::result::Error as DieselErr;
+use std::net::{IpAddr, SocketAddr};
+use uuid::Uuid;
 
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::transport::smtp::PoolConfig;
-use lettre::{AsyncSmtpTransport, Tokio1Executor};
 use log::info;
 use moka::future::Cache;
 
+pub const REFRESH_COOKIE: &str = "refresh";
+/// The refresh cookie is scoped to the refresh endpoint
+const REFRESH_PATH: &str = "/api/accounts/refresh";
+
+/// IP of the client: the peer address, unless it is one of the `trusted` proxies.
+/// Then it is the last address of `X-Forwarded-For` before the trusted ones, since the earlier
+/// ones are sent by the client, who can put anything there
+pub fn client_ip(req: &HttpRequest, trusted: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted.contains(&peer) {
+        return Some(peer);
+    }
+    let forwarded = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|ip| {
+            let ip = ip.trim();
+            ip.parse::<IpAddr>()
+                .or_else(|_| ip.parse::<SocketAddr>().map(|addr| addr.ip()))
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    for ip in forwarded.into_iter().rev() {
+        match ip {
+            Some(ip) if trusted.contains(&ip) => continue,
+            Some(ip) => return Some(ip),
+            // malformed, the proxy before did not set it
+            None => break,
+        }
+    }
+    Some(peer)
+}
+
+/// User agent and IP of the client, truncated to fit the session columns
+pub fn client_of(req: &HttpRequest, trusted: &[IpAddr]) -> (Option<String>, Option<String>) {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+        .map(|ua| ua.chars().take(512).collect());
+    let ip = client_ip(req, trusted).map(|ip| ip.to_string());
+    (user_agent, ip)
+}
+
 #[derive(Clone)]
 pub struct AppState {
     pub env: &'static Config,
     pub db: DBClient,
     /// Access control. k: IP/Email
     pub rate_limit: Cache<String, ()>,
-    /// For email verification
-    pub one_time_code: Cache<String, u64>, // TODO: This should be moved to somewhere like Redis
+    /// Failed password logins per account and IP
+    pub login_guard: LoginGuard,
+    /// Token buckets of API requests and MQTT messages
+    pub request_limit: RequestLimiter,
+    /// Tokens of emailed links: verification, login, password reset, transfer, unlock
+    pub tokens: OneTimeTokens,
+    /// Logins waiting for the TOTP code. k: challenge token, v: (UID, failed attempts)
+    pub totp_challenge: Cache<String, (u64, u8)>,
+    /// Live records pushed to SSE/WebSocket subscribers
+    pub hub: RecordHub,
 }
 
 // User ops
 impl AppState {
-    pub fn get_jwt_cookie(&self, uid: u64) -> Cookie {
+    /// Short-lived access token cookie of session `sid`,
+    /// `org` is the active organization (the personal one if `None`)
+    pub fn get_jwt_cookie(
+        &self,
+        uid: u64,
+        org: Option<u64>,
+        sid: u64,
+        jti: &str,
+    ) -> Cookie<'static> {
         let jwt_token = generate_token(
             &uid.to_string(),
+            org,
+            sid,
+            jti,
             self.env.jwt.secret.as_bytes(),
-            self.env.jwt.maxage,
+            self.env.jwt.access_maxage,
         )
         .unwrap();
         Cookie::build("token", jwt_token)
             .path("/")
+            .max_age(cookie::time::Duration::new(self.env.jwt.access_maxage, 0))
+            .http_only(true)
+            .finish()
+    }
+    /// Refresh token cookie, only sent to the refresh endpoint
+    pub fn get_refresh_cookie(&self, refresh_token: String) -> Cookie<'static> {
+        Cookie::build(REFRESH_COOKIE, refresh_token)
+            .path(REFRESH_PATH)
             .max_age(cookie::time::Duration::new(self.env.jwt.maxage, 0))
             .http_only(true)
             .finish()
     }
+    /// Expired cookies to clear both tokens on the client
+    pub fn clear_session_cookies() -> [Cookie<'static>; 2] {
+        [
+            Cookie::build("token", "")
+                .path("/")
+                .max_age(cookie::time::Duration::new(0, 0))
+                .http_only(true)
+                .finish(),
+            Cookie::build(REFRESH_COOKIE, "")
+                .path(REFRESH_PATH)
+                .max_age(cookie::time::Duration::new(0, 0))
+                .http_only(true)
+                .finish(),
+        ]
+    }
+    /// Start a login session of the user, return: access and refresh token cookies
+    pub async fn start_session(
+        &self,
+        uid: u64,
+        org: Option<u64>,
+        req: &HttpRequest,
+    ) -> Result<[Cookie<'static>; 2], DieselErr> {
+        let refresh_token = new_refresh_token();
+        let jti = Uuid::new_v4().to_string();
+        let (user_agent, ip) = client_of(req, &self.env.riot.trusted_proxies);
+        let expires_at = Utc::now().naive_utc() + Duration::seconds(self.env.jwt.maxage);
+        let sid = self
+            .db
+            .create_session(&NewSession {
+                uid,
+                refresh_hash: &hash_token(&refresh_token),
+                jti: &jti,
+                org,
+                user_agent: user_agent.as_deref(),
+                ip: ip.as_deref(),
+                expires_at: &expires_at,
+            })
+            .await?;
+        Ok([
+            self.get_jwt_cookie(uid, org, sid, &jti),
+            self.get_refresh_cookie(refresh_token),
+        ])
+    }
+    /// If the user has 2FA enabled, start a login challenge to be answered with a code,
+    /// return: the challenge token
+    pub async fn totp_challenge_of(&self, uid: u64) -> Result<Option<String>, DieselErr> {
+        match self.db.get_totp(uid).await {
+            Ok(totp) if totp.confirmed_at.is_some() => {
+                let token = Uuid::new_v4().to_string();
+                self.totp_challenge.insert(token.clone(), (uid, 0)).await;
+                Ok(Some(token))
+            }
+            Ok(_) | Err(DieselErr::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    /// Revoke sessions of the user, their access tokens stop working at once
+    pub async fn revoke_sessions(&self, uid: u64, scope: RevokeScope) -> Result<usize, DieselErr> {
+        let now = Utc::now().naive_utc();
+        self.db
+            .revoke_sessions(
+                uid,
+                scope,
+                &now,
+                &(now + Duration::seconds(self.env.jwt.access_maxage)),
+            )
+            .await
+    }
+    /// `purpose`: email verification or login, for the lifetime of the link
     pub async fn send_verify_mail(
         &self,
         user_email: &str,
         link: &str,
+        purpose: TokenPurpose<'_>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         info!("Sending verification email to {}", user_email);
-        let smtp_credentials = Credentials::new(
-            self.env.email.smtp_username.to_string(),
-            self.env.email.smtp_password.to_string(),
-        );
-
-        let mailer = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(
-            &self.env.email.smtp_relay_server,
-        )?
-        // Add credentials for authentication
-        .credentials(smtp_credentials)
-        // Connection pool settings
-        .pool_config(PoolConfig::new().max_size(20))
-        .build();
+        let mailer = smtp_mailer(&self.env.email)?;
 
         send_email_smtp(
             &mailer,
             &format!("RIoT <{}>", self.env.email.addr),
             &format!("<{}>", user_email),
             "RIoT Verification",
-            format!(include_str!("email.tplt"), link = link),
+            format!(
+                include_str!("email.tplt"),
+                link = link,
+                expires = expires_in(self.tokens.ttl(purpose))
+            ),
         )
         .await
     }
+    pub async fn send_reset_mail(
+        &self,
+        user_email: &str,
+        link: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Sending password reset email to {}", user_email);
+        let mailer = smtp_mailer(&self.env.email)?;
+
+        send_email_smtp(
+            &mailer,
+            &format!("RIoT <{}>", self.env.email.addr),
+            &format!("<{}>", user_email),
+            "RIoT Password Reset",
+            format!(
+                include_str!("reset.tplt"),
+                link = link,
+                expires = expires_in(self.env.one_time_token.reset_password)
+            ),
+        )
+        .await
+    }
+    /// Confirmation link, to the new address
+    pub async fn send_email_change_mail(
+        &self,
+        user_email: &str,
+        link: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Sending email change confirmation to {}", user_email);
+        let mailer = smtp_mailer(&self.env.email)?;
+
+        send_email_smtp(
+            &mailer,
+            &format!("RIoT <{}>", self.env.email.addr),
+            &format!("<{}>", user_email),
+            "RIoT Email Change",
+            format!(
+                include_str!("email_change.tplt"),
+                link = link,
+                expires = expires_in(self.env.one_time_token.verify_email)
+            ),
+        )
+        .await
+    }
+    /// Notice of a requested change, to the current address
+    pub async fn send_email_notice_mail(
+        &self,
+        user_email: &str,
+        new_email: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Sending email change notice to {}", user_email);
+        let mailer = smtp_mailer(&self.env.email)?;
+
+        send_email_smtp(
+            &mailer,
+            &format!("RIoT <{}>", self.env.email.addr),
+            &format!("<{}>", user_email),
+            "RIoT Email Change",
+            format!(include_str!("email_notice.tplt"), email = new_email),
+        )
+        .await
+    }
+    pub async fn send_unlock_mail(
+        &self,
+        user_email: &str,
+        link: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Sending account unlock email to {}", user_email);
+        let mailer = smtp_mailer(&self.env.email)?;
+
+        send_email_smtp(
+            &mailer,
+            &format!("RIoT <{}>", self.env.email.addr),
+            &format!("<{}>", user_email),
+            "RIoT Account Locked",
+            format!(
+                include_str!("unlock.tplt"),
+                link = link,
+                expires = expires_in(self.env.one_time_token.unlock)
+            ),
+        )
+        .await
+    }
+    pub async fn send_transfer_mail(
+        &self,
+        user_email: &str,
+        sender: &str,
+        device: &Device,
+        link: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Sending transfer email to {}", user_email);
+        let mailer = smtp_mailer(&self.env.email)?;
+
+        send_email_smtp(
+            &mailer,
+            &format!("RIoT <{}>", self.env.email.addr),
+            &format!("<{}>", user_email),
+            "RIoT Device Transfer",
+            format!(
+                include_str!("transfer.tplt"),
+                sender = sender,
+                device = device.name,
+                did = device.id,
+                link = link,
+                expires = expires_in(self.env.one_time_token.transfer)
+            ),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn forwarded_ip() {
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let trusted = [proxy];
+        let req = |peer: &str, forwarded: Option<&str>| {
+            let mut req = TestRequest::default().peer_addr(peer.parse().unwrap());
+            if let Some(forwarded) = forwarded {
+                req = req.insert_header(("X-Forwarded-For", forwarded));
+            }
+            req.to_http_request()
+        };
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+        // not through a trusted proxy: the header is ignored
+        assert_eq!(
+            client_ip(&req("1.2.3.4:5000", Some("5.6.7.8")), &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(&req("1.2.3.4:5000", Some("5.6.7.8")), &[]),
+            ip("1.2.3.4")
+        );
+        // the address the proxy appended, not the ones sent by the client
+        assert_eq!(
+            client_ip(&req("10.0.0.2:5000", Some("9.9.9.9, 1.2.3.4")), &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(&req("10.0.0.2:5000", Some("1.2.3.4, 10.0.0.2")), &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(&req("10.0.0.2:5000", Some("[::1]:80")), &trusted),
+            ip("::1")
+        );
+        assert_eq!(
+            client_ip(&req("10.0.0.2:5000", None), &trusted),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            client_ip(&req("10.0.0.2:5000", Some("1.2.3.4, garbage")), &trusted),
+            ip("10.0.0.2")
+        );
+    }
 }
@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use config::Config as ConfigUtil;
 use log::warn;
 use once_cell::sync::Lazy;
@@ -14,9 +16,15 @@ fn no_debug() -> bool {
 pub struct SiteConfig {
     /// Your site host, e.g. "http://myriot.com"
     pub host: String,
-    pub password_salt: String,
+    /// Unused: passwords are hashed with a random salt each, kept so that older configs still load
+    #[serde(default)]
+    pub password_salt: Option<String>,
     #[serde(default = "no_debug")]
     pub debug: bool,
+    /// Reverse proxies whose `X-Forwarded-For` is trusted for the client IP (login and rate
+    /// limits, API key allowlists), otherwise the peer address is the client
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -53,8 +61,424 @@ pub struct MqttConfig {
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct JwtConfig {
+    /// Seconds a session (i.e. its refresh token) lasts without being refreshed
     pub maxage: i64,
     pub secret: String,
+    /// Seconds an access token lasts, renewed with the refresh token
+    #[serde(default = "jwt_access_maxage")]
+    pub access_maxage: i64,
+}
+
+fn jwt_access_maxage() -> i64 {
+    15 * 60
+}
+
+fn yes() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Also accept API keys in the `api_key` query parameter, besides the `X-API-Key` header.
+    /// Off by default: URLs end up in access logs and browser history
+    #[serde(default)]
+    pub query: bool,
+}
+
+fn password_mem_cost() -> u32 {
+    19 * 1024
+}
+
+fn password_time_cost() -> u32 {
+    2
+}
+
+fn password_lanes() -> u32 {
+    1
+}
+
+/// Argon2id cost parameters of new password hashes, the defaults follow the OWASP recommendation.
+///
+/// Hashes made with other parameters are replaced on the next successful login
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordConfig {
+    /// Unit: KiB
+    #[serde(default = "password_mem_cost")]
+    pub mem_cost: u32,
+    /// Iterations
+    #[serde(default = "password_time_cost")]
+    pub time_cost: u32,
+    /// Degree of parallelism
+    #[serde(default = "password_lanes")]
+    pub lanes: u32,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            mem_cost: password_mem_cost(),
+            time_cost: password_time_cost(),
+            lanes: password_lanes(),
+        }
+    }
+}
+
+fn login_max_failures() -> u32 {
+    10
+}
+
+fn login_ip_max_failures() -> u32 {
+    50
+}
+
+fn login_lockout() -> u64 {
+    15 * 60
+}
+
+fn login_delay_after() -> u32 {
+    3
+}
+
+fn login_max_delay() -> u64 {
+    60
+}
+
+/// Brute-force protection of logins, wrong passwords and wrong second factors alike
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LoginConfig {
+    /// Failed logins of an account before it is locked, an unlock link is emailed to the user
+    #[serde(default = "login_max_failures")]
+    pub max_failures: u32,
+    /// Failed logins from an IP before it is blocked
+    #[serde(default = "login_ip_max_failures")]
+    pub ip_max_failures: u32,
+    /// Unit: seconds. Failures are forgotten this long after the last one, ending the lockout
+    #[serde(default = "login_lockout")]
+    pub lockout: u64,
+    /// Failures (of an account or an IP) before each further attempt must wait 1, 2, 4... seconds
+    #[serde(default = "login_delay_after")]
+    pub delay_after: u32,
+    /// Unit: seconds. Cap of the delay
+    #[serde(default = "login_max_delay")]
+    pub max_delay: u64,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: login_max_failures(),
+            ip_max_failures: login_ip_max_failures(),
+            lockout: login_lockout(),
+            delay_after: login_delay_after(),
+            max_delay: login_max_delay(),
+        }
+    }
+}
+
+/// Requests of a route group, per minute and per client: each is also the burst allowed at once.
+/// 0 means unlimited
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitGroup {
+    pub name: String,
+    /// Path prefixes under `/api`, a `*` segment matches any one segment, e.g. "/devices/*/records"
+    pub paths: Vec<String>,
+    /// Per IP, for requests without valid credentials
+    pub anonymous: u32,
+    /// Per user (login session) or API key, below the Admin privilege
+    pub normal: u32,
+    /// Per user or API key, Admin privilege and above
+    pub admin: u32,
+}
+
+impl RateLimitGroup {
+    fn new(name: &str, paths: &[&str], anonymous: u32, normal: u32, admin: u32) -> Self {
+        RateLimitGroup {
+            name: name.to_string(),
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+            anonymous,
+            normal,
+            admin,
+        }
+    }
+}
+
+fn rate_limit_groups() -> Vec<RateLimitGroup> {
+    vec![
+        RateLimitGroup::new(
+            "accounts",
+            &[
+                "/accounts/register",
+                "/accounts/login",
+                "/accounts/send_verification",
+                "/accounts/verify",
+                "/accounts/password",
+                "/accounts/unlock",
+            ],
+            20,
+            60,
+            60,
+        ),
+        RateLimitGroup::new(
+            "records",
+            &["/devices/*/records", "/tags/*/records"],
+            60,
+            1200,
+            6000,
+        ),
+        RateLimitGroup::new("default", &["/"], 120, 600, 3000),
+    ]
+}
+
+fn mqtt_per_minute() -> u32 {
+    600
+}
+
+/// Token-bucket limits of API requests and MQTT ingestion
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    #[serde(default = "yes")]
+    pub enabled: bool,
+    /// The first group matching the path applies, requests matching none are not limited.
+    /// Setting any group replaces all the default ones
+    #[serde(default = "rate_limit_groups", rename = "group")]
+    pub groups: Vec<RateLimitGroup>,
+    /// MQTT messages stored per minute, per user (or organization) publishing. 0 means unlimited
+    #[serde(default = "mqtt_per_minute")]
+    pub mqtt_per_minute: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: yes(),
+            groups: rate_limit_groups(),
+            mqtt_per_minute: mqtt_per_minute(),
+        }
+    }
+}
+
+/// Where one-time tokens are kept
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenStoreKind {
+    /// Lost on restart, and not shared between instances
+    Memory,
+    /// The `one_time_token` table
+    Db,
+}
+
+fn token_store() -> TokenStoreKind {
+    TokenStoreKind::Db
+}
+
+fn hour() -> i64 {
+    60 * 60
+}
+
+fn day() -> i64 {
+    24 * 60 * 60
+}
+
+/// One-time tokens of emailed links. TTLs unit: seconds
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OneTimeTokenConfig {
+    #[serde(default = "token_store")]
+    pub store: TokenStoreKind,
+    /// Email verification link, sent to users not activated yet, or to confirm a new address
+    #[serde(default = "day")]
+    pub verify_email: i64,
+    /// Login-by-email link
+    #[serde(default = "hour")]
+    pub login: i64,
+    #[serde(default = "hour")]
+    pub reset_password: i64,
+    /// Link to accept a device transfer
+    #[serde(default = "day")]
+    pub transfer: i64,
+    /// Link to unlock an account locked after failed logins
+    #[serde(default = "day")]
+    pub unlock: i64,
+}
+
+impl Default for OneTimeTokenConfig {
+    fn default() -> Self {
+        Self {
+            store: token_store(),
+            verify_email: day(),
+            login: hour(),
+            reset_password: hour(),
+            transfer: day(),
+            unlock: day(),
+        }
+    }
+}
+
+fn oidc_scopes() -> String {
+    "openid email profile".to_string()
+}
+
+fn oidc_default_privilege() -> u32 {
+    4 // UserPrivilege::Normal
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Shown on the login page, e.g. "Company SSO"
+    pub name: String,
+    /// Issuer URL, exactly as the provider reports it.
+    /// The provider is discovered at `{issuer}/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    /// Omit for a public client, protected by PKCE only
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// Space separated, `openid` is required
+    #[serde(default = "oidc_scopes")]
+    pub scopes: String,
+    /// Privilege of the users created on their first login
+    #[serde(default = "oidc_default_privilege")]
+    pub default_privilege: u32,
+    /// Link a new identity to the existing user with the same email, if the provider verified it
+    #[serde(default = "yes")]
+    pub link_by_email: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    /// Email the admins of the organization owning the device when a new alert is raised
+    #[serde(default = "yes")]
+    pub email: bool,
+    /// URLs to POST each new alert to (JSON), signed like the user webhooks
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    /// Key of the `X-RIoT-Signature` of the deliveries to `webhooks`
+    #[serde(default)]
+    pub secret: String,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        AlertConfig {
+            email: yes(),
+            webhooks: vec![],
+            secret: String::new(),
+        }
+    }
+}
+
+fn webhook_max_attempts() -> u32 {
+    8
+}
+
+fn webhook_retry_base() -> u64 {
+    30
+}
+
+fn webhook_timeout() -> u64 {
+    10
+}
+
+fn webhook_log_retention() -> u64 {
+    7
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Attempts before a delivery becomes a dead letter
+    #[serde(default = "webhook_max_attempts")]
+    pub max_attempts: u32,
+    /// Unit: seconds. Delay before the first retry, doubled after each failed attempt
+    #[serde(default = "webhook_retry_base")]
+    pub retry_base: u64,
+    /// Unit: seconds. Timeout of each attempt
+    #[serde(default = "webhook_timeout")]
+    pub timeout: u64,
+    /// Unit: days. Delivered entries of the delivery log are removed afterwards
+    #[serde(default = "webhook_log_retention")]
+    pub log_retention: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            max_attempts: webhook_max_attempts(),
+            retry_base: webhook_retry_base(),
+            timeout: webhook_timeout(),
+            log_retention: webhook_log_retention(),
+        }
+    }
+}
+
+fn sink_buffer() -> usize {
+    1024
+}
+
+fn sink_topic() -> String {
+    "{topic}".to_string()
+}
+
+fn sink_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn sink_max_files() -> usize {
+    8
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SinkKind {
+    /// Republish record payloads to another MQTT broker
+    Mqtt {
+        host: String,
+        port: u16,
+        /// Topic template, `{topic}` (the device topic), `{did}` and `{uid}` are replaced
+        #[serde(default = "sink_topic")]
+        topic: String,
+    },
+    /// Append records as JSON lines to `path`, rotated to `path.1`, `path.2`...
+    File {
+        path: String,
+        /// Unit: bytes. Rotate the file when it would grow beyond this size
+        #[serde(default = "sink_max_bytes")]
+        max_bytes: u64,
+        /// Rotated files kept
+        #[serde(default = "sink_max_files")]
+        max_files: usize,
+    },
+    /// Produce records (JSON, keyed by device id) to a Kafka cluster
+    Kafka {
+        /// Bootstrap brokers, e.g. "10.0.0.1:9092,10.0.0.2:9092"
+        brokers: String,
+        topic: String,
+        /// Partition of all records, by default they are spread by device id
+        #[serde(default)]
+        partition: Option<i32>,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SinkConfig {
+    pub name: String,
+    /// Only records of these devices and of devices under these tags. All records if both are empty
+    #[serde(default)]
+    pub devices: Vec<u64>,
+    #[serde(default)]
+    pub tags: Vec<u64>,
+    /// Records buffered while the sink is slow or down, newer records are dropped when it is full
+    #[serde(default = "sink_buffer")]
+    pub buffer: usize,
+    #[serde(flatten)]
+    pub kind: SinkKind,
 }
 
 #[derive(Deserialize, Debug)]
@@ -63,8 +487,28 @@ pub struct Config {
     pub riot: SiteConfig,
     pub email: EmailConfig,
     pub jwt: JwtConfig,
+    #[serde(default)]
+    pub password: PasswordConfig,
+    #[serde(default)]
+    pub login: LoginConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub one_time_token: OneTimeTokenConfig,
+    #[serde(default)]
+    pub api_key: ApiKeyConfig,
+    /// Single sign-on with an OpenID Connect provider, disabled if omitted
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
     pub mqtt: MqttConfig,
     pub mysql: MysqlConfig,
+    #[serde(default)]
+    pub alert: AlertConfig,
+    #[serde(default)]
+    pub webhook: WebhookConfig,
+    /// Destinations every stored record is forwarded to
+    #[serde(default)]
+    pub sink: Vec<SinkConfig>,
 }
 
 impl Config {
@@ -83,6 +527,13 @@ impl Config {
             // .add_source(config::Environment::with_prefix("RIOT"))
             .build()
             .expect("Failed to build config");
-        dbg!(settings.try_deserialize::<Config>().unwrap())
+        let config = settings.try_deserialize::<Config>().unwrap();
+        if config.riot.password_salt.is_some() {
+            warn!("`riot.password_salt` is no longer used: passwords are hashed with a random salt each");
+        }
+        if !config.alert.webhooks.is_empty() && config.alert.secret.is_empty() {
+            warn!("`alert.secret` is empty: receivers of the alert webhooks can not authenticate them");
+        }
+        dbg!(config)
     }
 }
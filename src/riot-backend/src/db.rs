@@ -1,21 +1,233 @@
-use std::time::Duration;
-
 use crate::config::CONFIG;
 // DB
 use crate::models::{
-    Device, NewDevice, NewRecord, NewTag, NewUser, Record, Tag, UpdateDevice, UpdateTag,
-    UpdateUser, User,
+    Alert, ApiKey, BulkResult, BulkUpdateDevice, DeliveryStatus, Device, DeviceLabel, DeviceShare,
+    DeviceStatus, DeviceTransfer, Geofence, GeofenceEvent, Location, LocationSource,
+    MemberOrganization, NewAlert, NewApiKey, NewDevice, NewDeviceShare, NewDeviceTransfer,
+    NewGeofence, NewGeofenceEvent, NewIdentity, NewLocation, NewMembership, NewOneTimeToken,
+    NewOrganization, NewPresenceEvent, NewRecord, NewSession, NewTag, NewTagShare, NewUser,
+    NewWebhook, NewWebhookDelivery, OrgMember, OrgRole, Organization, Permission, PresenceCause,
+    PresenceEvent, Record, Session, SharedDevice, SharedTag, Tag, TagShare, Totp, TransferStatus,
+    UpdateDevice, UpdateTag, UpdateUser, UpdateWebhook, User, Webhook, WebhookDelivery,
+    WebhookEvent,
 };
+use crate::utils::geo::{bounding_deltas, geofence_transitions, locate_payload, longitude_ranges};
+use crate::utils::tag_query::TagQuery;
 use chrono::NaiveDateTime;
 use diesel::dsl::exists;
 use diesel::mysql::Mysql;
 use diesel::result::Error as DieselErr;
-use diesel::{debug_query, BoolExpressionMethods, ExpressionMethods, QueryDsl, SelectableHelper};
+use diesel::{
+    debug_query, BoolExpressionMethods, BoxableExpression, ExpressionMethods,
+    NullableExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper,
+};
 use diesel_async::pooled_connection::deadpool::Pool;
 use diesel_async::pooled_connection::AsyncDieselConnectionManager;
 use diesel_async::AsyncMysqlConnection;
 use diesel_async::{AsyncConnection, RunQueryDsl};
 use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+/// Whose webhooks an event goes to: those of the members of an organization
+#[derive(Clone, Copy, Debug)]
+pub enum WebhookOwner {
+    /// The organization (by id)
+    Org(u64),
+    /// The organization owning the device (by id)
+    DeviceOrg(u64),
+}
+
+/// Sort key of a device listing, ties are broken by id
+#[derive(Deserialize, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceSort {
+    #[default]
+    Id,
+    Name,
+    Dtype,
+    Since,
+    LastUpdate,
+}
+
+/// Position right after the last device of a page, in the listing's sort key
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum DeviceCursor {
+    Id(u64),
+    Name(String, u64),
+    Dtype(u32, u64),
+    Since(NaiveDateTime, u64),
+    LastUpdate(NaiveDateTime, u64),
+}
+
+impl DeviceCursor {
+    /// Cursor pointing after the device
+    pub fn after(sort: DeviceSort, device: &Device) -> Self {
+        match sort {
+            DeviceSort::Id => DeviceCursor::Id(device.id),
+            DeviceSort::Name => DeviceCursor::Name(device.name.clone(), device.id),
+            DeviceSort::Dtype => DeviceCursor::Dtype(device.dtype, device.id),
+            DeviceSort::Since => DeviceCursor::Since(device.since, device.id),
+            DeviceSort::LastUpdate => DeviceCursor::LastUpdate(device.last_update, device.id),
+        }
+    }
+    pub fn sort(&self) -> DeviceSort {
+        match self {
+            DeviceCursor::Id(_) => DeviceSort::Id,
+            DeviceCursor::Name(..) => DeviceSort::Name,
+            DeviceCursor::Dtype(..) => DeviceSort::Dtype,
+            DeviceCursor::Since(..) => DeviceSort::Since,
+            DeviceCursor::LastUpdate(..) => DeviceSort::LastUpdate,
+        }
+    }
+}
+
+/// Filters, order and page of a device listing. Filters left `None` match everything.
+#[derive(Default, Debug)]
+pub struct DeviceSearch {
+    /// Words all found in the name or the description (full-text, case-insensitive)
+    pub text: Option<String>,
+    pub dtype: Option<u32>,
+    pub activated: Option<bool>,
+    pub status: Option<DeviceStatus>,
+    /// Tagged by any of these tags
+    pub tids: Option<Vec<u64>>,
+    /// Matching the boolean tag query
+    pub tags: Option<TagQuery>,
+    /// Has all of these labels
+    pub labels: Vec<(String, String)>,
+    pub updated_after: Option<NaiveDateTime>,
+    pub updated_before: Option<NaiveDateTime>,
+    pub sort: DeviceSort,
+    pub descending: bool,
+    pub cursor: Option<DeviceCursor>,
+    /// Page size, all devices if `None`
+    pub limit: Option<i64>,
+}
+
+/// Sessions of a user to revoke
+#[derive(Clone, Copy, Debug)]
+pub enum RevokeScope {
+    One(u64),
+    /// All but the given (current) session
+    Others(u64),
+    All,
+}
+
+/// Boolean-mode full-text query requiring every word of the text, each as a phrase so that no
+/// character acts as an operator. `None` if there is no word
+fn fulltext_query(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split_whitespace()
+        .map(|word| word.replace('"', ""))
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("+\"{word}\""))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// Keep devices having all of the labels
+fn with_labels<'a>(
+    mut query: crate::schema::device::BoxedQuery<'a, Mysql>,
+    labels: &'a [(String, String)],
+) -> crate::schema::device::BoxedQuery<'a, Mysql> {
+    use crate::schema::{device, device_label};
+    for (key_, value_) in labels {
+        query = query.filter(
+            device::id.eq_any(
+                device_label::table.select(device_label::did).filter(
+                    device_label::key
+                        .eq(key_)
+                        .and(device_label::value.eq(value_)),
+                ),
+            ),
+        );
+    }
+    query
+}
+
+/// Condition on the devices of a query on the `device` table
+type DeviceFilter = Box<
+    dyn BoxableExpression<crate::schema::device::table, Mysql, SqlType = diesel::sql_types::Bool>,
+>;
+
+/// Devices of the organization matching the tag query.
+/// A name matches the active tags of that name and their active descendants, found with a
+/// recursive CTE (`UNION` drops repeated tags, so a loop cannot recurse forever)
+fn tag_query_filter(oid_: u64, query: &TagQuery) -> DeviceFilter {
+    use diesel::dsl::{not, sql};
+    use diesel::sql_types::{BigInt, Bool, Text, Unsigned};
+    match query {
+        TagQuery::Tag(name_) => Box::new(
+            sql::<Bool>(
+                "EXISTS (SELECT 1 FROM `owns` WHERE `owns`.`did` = `device`.`id` \
+                AND `owns`.`tid` IN (WITH RECURSIVE `matched` (`id`) AS (\
+                SELECT `id` FROM `tag` WHERE `activated` AND `oid` = ",
+            )
+            .bind::<Unsigned<BigInt>, _>(oid_)
+            .sql(" AND `name` = ")
+            .bind::<Text, _>(name_.clone())
+            .sql(
+                " UNION SELECT `tag`.`id` FROM `tag` INNER JOIN `matched` \
+                ON `tag`.`parent` = `matched`.`id` WHERE `tag`.`activated`) \
+                SELECT `id` FROM `matched`))",
+            ),
+        ),
+        TagQuery::Not(inner) => Box::new(not(tag_query_filter(oid_, inner))),
+        TagQuery::And(lhs, rhs) => {
+            Box::new(tag_query_filter(oid_, lhs).and(tag_query_filter(oid_, rhs)))
+        }
+        TagQuery::Or(lhs, rhs) => {
+            Box::new(tag_query_filter(oid_, lhs).or(tag_query_filter(oid_, rhs)))
+        }
+    }
+}
+
+/// Devices of the organization matching the filters of a search (no order, cursor or limit)
+fn searched_devices<'a>(
+    oid_: u64,
+    search: &'a DeviceSearch,
+    terms: &'a Option<String>,
+) -> crate::schema::device::BoxedQuery<'a, Mysql> {
+    use crate::schema::{device, owns};
+    use diesel::dsl::sql;
+    use diesel::sql_types::{Bool, Text};
+    let mut query = device::table
+        .filter(device::oid.eq(oid_).and(device::trashed_at.is_null()))
+        .into_boxed();
+    if let Some(terms) = terms {
+        query = query.filter(
+            sql::<Bool>("MATCH(`name`, `desc`) AGAINST(")
+                .bind::<Text, _>(terms)
+                .sql(" IN BOOLEAN MODE)"),
+        );
+    }
+    if let Some(dtype_) = search.dtype {
+        query = query.filter(device::dtype.eq(dtype_));
+    }
+    if let Some(activated_) = search.activated {
+        query = query.filter(device::activated.eq(activated_));
+    }
+    if let Some(status_) = search.status {
+        query = query.filter(device::status.eq(status_));
+    }
+    if let Some(tids) = &search.tids {
+        query = query.filter(
+            device::id.eq_any(owns::table.select(owns::did).filter(owns::tid.eq_any(tids))),
+        );
+    }
+    if let Some(tags) = &search.tags {
+        query = query.filter(tag_query_filter(oid_, tags));
+    }
+    if let Some(after) = &search.updated_after {
+        query = query.filter(device::last_update.ge(after));
+    }
+    if let Some(before) = &search.updated_before {
+        query = query.filter(device::last_update.le(before));
+    }
+    with_labels(query, &search.labels)
+}
 
 #[derive(Clone)]
 pub struct DBClient {
@@ -41,19 +253,117 @@ impl DBClient {
                 .expect("Cannot build SQL pool."),
         }
     }
-    /// Register a user, return Ok(id) if successful
+    /// Register a user together with the user's personal organization, return: ID of the user
     pub async fn register_user<'a>(&self, form: &NewUser<'a>) -> Result<u64, DieselErr> {
-        use crate::schema::user;
+        use diesel_async::scoped_futures::ScopedFutureExt;
         // TODO: Corner case: email conflicts with another's username
         // Currently we avoid this situation by restrict the username format in the route handler
         let mut conn = self.pool.get().await.unwrap();
-        let query = diesel::insert_into(user::table).values(form);
-        debug!("{}", debug_query::<Mysql, _>(&query).to_string());
-        query.execute(&mut conn).await?;
+        conn.transaction(|conn| async move { insert_user(conn, form).await }.scope_boxed())
+            .await
+    }
+    /// Register an activated user on the first single sign-on, linked to the external identity.
+    /// return: ID of the user
+    pub async fn register_identity_user<'a>(
+        &self,
+        form: &NewUser<'a>,
+        issuer_: &str,
+        subject_: &str,
+    ) -> Result<u64, DieselErr> {
+        use crate::schema::{identity, user};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let id = insert_user(conn, form).await?;
+                // the provider vouches for the email
+                diesel::update(user::table.filter(user::id.eq(id)))
+                    .set(user::activated.eq(true))
+                    .execute(conn)
+                    .await?;
+                diesel::insert_into(identity::table)
+                    .values(&NewIdentity {
+                        uid: id,
+                        issuer: issuer_,
+                        subject: subject_,
+                    })
+                    .execute(conn)
+                    .await?;
+                Ok(id)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// User linked to the external identity
+    pub async fn get_user_by_identity(
+        &self,
+        issuer_: &str,
+        subject_: &str,
+    ) -> Result<User, DieselErr> {
+        use crate::schema::{identity, user};
+        let mut conn = self.pool.get().await.unwrap();
+        identity::table
+            .inner_join(user::table)
+            .select(User::as_select())
+            .filter(
+                identity::issuer
+                    .eq(issuer_)
+                    .and(identity::subject.eq(subject_)),
+            )
+            .first(&mut conn)
+            .await
+    }
+    /// return: ID of the link
+    pub async fn link_identity<'a>(&self, form: &NewIdentity<'a>) -> Result<u64, DieselErr> {
+        use crate::schema::identity;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::insert_into(identity::table)
+            .values(form)
+            .execute(&mut conn)
+            .await?;
         diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
-        // ! To get the correct `id``, must be in a single connection
-        let id: u64 = diesel::select(last_insert_id()).first(&mut conn).await?;
-        Ok(id)
+        diesel::select(last_insert_id()).first(&mut conn).await
+    }
+    /// Keep an issued one-time token, purging the expired ones
+    pub async fn insert_one_time_token<'a>(
+        &self,
+        form: &NewOneTimeToken<'a>,
+        now: &NaiveDateTime,
+    ) -> Result<(), DieselErr> {
+        use crate::schema::one_time_token::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::delete(one_time_token.filter(expires_at.le(now)))
+            .execute(&mut conn)
+            .await?;
+        diesel::insert_into(one_time_token)
+            .values(form)
+            .execute(&mut conn)
+            .await?;
+        Ok(())
+    }
+    /// Use up an unexpired one-time token, return: its UID.
+    /// Of concurrent callers (of any instance) only one gets it
+    pub async fn take_one_time_token(
+        &self,
+        token_hash_: &str,
+        now: &NaiveDateTime,
+    ) -> Result<Option<u64>, DieselErr> {
+        use crate::schema::one_time_token::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        let Some(owner) = one_time_token
+            .select(uid)
+            .filter(token_hash.eq(token_hash_).and(expires_at.gt(now)))
+            .first::<u64>(&mut conn)
+            .await
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let deleted = diesel::delete(one_time_token.filter(token_hash.eq(token_hash_)))
+            .execute(&mut conn)
+            .await?;
+        Ok((deleted == 1).then_some(owner))
     }
     pub async fn get_user_by_username_or_email(&self, keyword: &str) -> Result<User, DieselErr> {
         use crate::schema::user::dsl::*;
@@ -72,20 +382,366 @@ impl DBClient {
             .first(&mut conn)
             .await
     }
-    pub async fn get_user_by_api_key(&self, api_key_: &str) -> Result<User, DieselErr> {
-        use crate::schema::user::dsl::*;
+    /// Unrevoked, unexpired API key with the hash, and its user
+    pub async fn get_user_by_api_key(
+        &self,
+        key_hash_: &str,
+        now: &NaiveDateTime,
+    ) -> Result<(ApiKey, User), DieselErr> {
+        use crate::schema::{api_key, user};
         let mut conn = self.pool.get().await.unwrap();
-        user.select(User::as_select())
-            .filter(api_key.eq(api_key_))
+        api_key::table
+            .inner_join(user::table)
+            .select((ApiKey::as_select(), User::as_select()))
+            .filter(
+                api_key::key_hash
+                    .eq(key_hash_)
+                    .and(api_key::revoked_at.is_null())
+                    .and(
+                        api_key::expires_at
+                            .is_null()
+                            .or(api_key::expires_at.gt(now)),
+                    ),
+            )
             .first(&mut conn)
             .await
     }
+    /// Record the use of an API key, at most once a minute to spare the DB on busy keys
+    pub async fn touch_api_key(&self, id_: u64, now: &NaiveDateTime) -> Result<usize, DieselErr> {
+        use crate::schema::api_key::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(
+            api_key.filter(
+                id.eq(id_).and(
+                    last_used
+                        .is_null()
+                        .or(last_used.lt(*now - chrono::Duration::minutes(1))),
+                ),
+            ),
+        )
+        .set(last_used.eq(now))
+        .execute(&mut conn)
+        .await
+    }
+    /// return: ID of the key
+    pub async fn create_api_key<'a>(&self, form: &NewApiKey<'a>) -> Result<u64, DieselErr> {
+        use crate::schema::api_key;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::insert_into(api_key::table)
+            .values(form)
+            .execute(&mut conn)
+            .await?;
+        diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
+        diesel::select(last_insert_id()).first(&mut conn).await
+    }
+    /// Unrevoked API keys of the user (expired ones included), newest first
+    pub async fn get_user_api_keys(&self, uid_: u64) -> Result<Vec<ApiKey>, DieselErr> {
+        use crate::schema::api_key::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        api_key
+            .select(ApiKey::as_select())
+            .filter(uid.eq(uid_).and(revoked_at.is_null()))
+            .order(id.desc())
+            .load(&mut conn)
+            .await
+    }
+    /// return: rows affected
+    pub async fn revoke_api_key(
+        &self,
+        id_: u64,
+        uid_: u64,
+        now: &NaiveDateTime,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::api_key::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(api_key.filter(id.eq(id_).and(uid.eq(uid_)).and(revoked_at.is_null())))
+            .set(revoked_at.eq(now))
+            .execute(&mut conn)
+            .await
+    }
     pub async fn update_user<'a>(&self, form: &UpdateUser<'a>) -> Result<usize, DieselErr> {
         let mut conn = self.pool.get().await.unwrap();
         let query = diesel::update(form).set(form);
         debug!("{}", debug_query::<Mysql, _>(&query).to_string());
         query.execute(&mut conn).await
     }
+    /// Start a session, return: its ID
+    pub async fn create_session<'a>(&self, form: &NewSession<'a>) -> Result<u64, DieselErr> {
+        use crate::schema::session;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::insert_into(session::table)
+            .values(form)
+            .execute(&mut conn)
+            .await?;
+        diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
+        diesel::select(last_insert_id()).first(&mut conn).await
+    }
+    /// Unrevoked, unexpired session holding the refresh token
+    pub async fn get_session_by_refresh(
+        &self,
+        refresh_hash_: &str,
+        now: &NaiveDateTime,
+    ) -> Result<Session, DieselErr> {
+        use crate::schema::session::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        session
+            .select(Session::as_select())
+            .filter(
+                refresh_hash
+                    .eq(refresh_hash_)
+                    .and(revoked_at.is_null())
+                    .and(expires_at.gt(now)),
+            )
+            .first(&mut conn)
+            .await
+    }
+    /// Swap the refresh token of a session (only if it still holds `old_hash`)
+    /// and extend the session, return: rows affected
+    pub async fn rotate_session(
+        &self,
+        sid: u64,
+        old_hash: &str,
+        new_hash: &str,
+        ip_: Option<&str>,
+        now: &NaiveDateTime,
+        expires: &NaiveDateTime,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::session::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(
+            session.filter(
+                id.eq(sid)
+                    .and(refresh_hash.eq(old_hash))
+                    .and(revoked_at.is_null()),
+            ),
+        )
+        .set((
+            refresh_hash.eq(new_hash),
+            ip.eq(ip_),
+            last_seen.eq(now),
+            expires_at.eq(expires),
+        ))
+        .execute(&mut conn)
+        .await
+    }
+    /// Change the active organization kept for refreshing, return: rows affected
+    pub async fn set_session_org(&self, sid: u64, org_: Option<u64>) -> Result<usize, DieselErr> {
+        use crate::schema::session::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(session.filter(id.eq(sid).and(revoked_at.is_null())))
+            .set(org.eq(org_))
+            .execute(&mut conn)
+            .await
+    }
+    /// Unrevoked, unexpired sessions of the user, most recently seen first
+    pub async fn get_user_sessions(
+        &self,
+        uid_: u64,
+        now: &NaiveDateTime,
+    ) -> Result<Vec<Session>, DieselErr> {
+        use crate::schema::session::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        session
+            .select(Session::as_select())
+            .filter(
+                uid.eq(uid_)
+                    .and(revoked_at.is_null())
+                    .and(expires_at.gt(now)),
+            )
+            .order(last_seen.desc())
+            .load(&mut conn)
+            .await
+    }
+    /// Revoke sessions of the user and put their access tokens (all sharing the session `jti`)
+    /// on the revocation list until `token_expiry`, return: sessions revoked
+    pub async fn revoke_sessions(
+        &self,
+        uid_: u64,
+        scope: RevokeScope,
+        now: &NaiveDateTime,
+        token_expiry: &NaiveDateTime,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::{revoked_token, session};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let mut query = session::table
+                    .select((session::id, session::jti))
+                    .filter(session::uid.eq(uid_).and(session::revoked_at.is_null()))
+                    .into_boxed();
+                query = match scope {
+                    RevokeScope::One(sid) => query.filter(session::id.eq(sid)),
+                    RevokeScope::Others(sid) => query.filter(session::id.ne(sid)),
+                    RevokeScope::All => query,
+                };
+                let revoking: Vec<(u64, String)> = query.load(conn).await?;
+                if revoking.is_empty() {
+                    return Ok(0);
+                }
+                let sids: Vec<u64> = revoking.iter().map(|(sid, _)| *sid).collect();
+                let tokens: Vec<_> = revoking
+                    .iter()
+                    .map(|(_, jti)| {
+                        (
+                            revoked_token::jti.eq(jti),
+                            revoked_token::expires_at.eq(token_expiry),
+                        )
+                    })
+                    .collect();
+                // expired tokens no longer need to be listed
+                diesel::delete(revoked_token::table.filter(revoked_token::expires_at.le(now)))
+                    .execute(conn)
+                    .await?;
+                diesel::replace_into(revoked_token::table)
+                    .values(&tokens)
+                    .execute(conn)
+                    .await?;
+                diesel::update(session::table.filter(session::id.eq_any(&sids)))
+                    .set(session::revoked_at.eq(now))
+                    .execute(conn)
+                    .await
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    pub async fn is_token_revoked(&self, jti_: &str) -> Result<bool, DieselErr> {
+        use crate::schema::revoked_token::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::select(exists(revoked_token.filter(jti.eq(jti_))))
+            .get_result(&mut conn)
+            .await
+    }
+    pub async fn get_totp(&self, uid_: u64) -> Result<Totp, DieselErr> {
+        use crate::schema::totp::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        totp.select(Totp::as_select())
+            .filter(uid.eq(uid_))
+            .first(&mut conn)
+            .await
+    }
+    /// Start (or restart) an enrollment with a new secret, unless 2FA is already enabled.
+    /// return: rows affected
+    pub async fn enroll_totp(&self, uid_: u64, secret_: &str) -> Result<usize, DieselErr> {
+        use crate::schema::totp::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::delete(totp.filter(uid.eq(uid_).and(confirmed_at.is_null())))
+            .execute(&mut conn)
+            .await?;
+        diesel::insert_into(totp)
+            .values((uid.eq(uid_), secret.eq(secret_)))
+            .execute(&mut conn)
+            .await
+    }
+    /// Enable 2FA with the code of time `step` checked, replacing the recovery codes.
+    /// return: rows affected, 0 if not enrolling
+    pub async fn confirm_totp(
+        &self,
+        uid_: u64,
+        step: u64,
+        code_hashes: &[String],
+        now: &NaiveDateTime,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::{recovery_code, totp};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let confirmed = diesel::update(
+                    totp::table.filter(totp::uid.eq(uid_).and(totp::confirmed_at.is_null())),
+                )
+                .set((totp::confirmed_at.eq(now), totp::last_step.eq(step)))
+                .execute(conn)
+                .await?;
+                if confirmed == 0 {
+                    return Ok(0);
+                }
+                diesel::delete(recovery_code::table.filter(recovery_code::uid.eq(uid_)))
+                    .execute(conn)
+                    .await?;
+                let codes: Vec<_> = code_hashes
+                    .iter()
+                    .map(|hash| {
+                        (
+                            recovery_code::uid.eq(uid_),
+                            recovery_code::code_hash.eq(hash),
+                        )
+                    })
+                    .collect();
+                diesel::insert_into(recovery_code::table)
+                    .values(&codes)
+                    .execute(conn)
+                    .await?;
+                Ok(confirmed)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Accept the code of time `step` of an enabled 2FA, only if later than the last accepted one.
+    /// return: rows affected, 0 if the code was used
+    pub async fn use_totp_step(&self, uid_: u64, step: u64) -> Result<usize, DieselErr> {
+        use crate::schema::totp::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(
+            totp.filter(
+                uid.eq(uid_)
+                    .and(confirmed_at.is_not_null())
+                    .and(last_step.lt(step)),
+            ),
+        )
+        .set(last_step.eq(step))
+        .execute(&mut conn)
+        .await
+    }
+    /// Spend an unused recovery code, return: rows affected
+    pub async fn use_recovery_code(
+        &self,
+        uid_: u64,
+        code_hash_: &str,
+        now: &NaiveDateTime,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::recovery_code::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(
+            recovery_code.filter(
+                uid.eq(uid_)
+                    .and(code_hash.eq(code_hash_))
+                    .and(used_at.is_null()),
+            ),
+        )
+        .set(used_at.eq(now))
+        .execute(&mut conn)
+        .await
+    }
+    pub async fn count_recovery_codes(&self, uid_: u64) -> Result<i64, DieselErr> {
+        use crate::schema::recovery_code::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        recovery_code
+            .filter(uid.eq(uid_).and(used_at.is_null()))
+            .count()
+            .get_result(&mut conn)
+            .await
+    }
+    /// Disable 2FA and drop the recovery codes, return: rows of `totp` affected
+    pub async fn delete_totp(&self, uid_: u64) -> Result<usize, DieselErr> {
+        use crate::schema::{recovery_code, totp};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                diesel::delete(recovery_code::table.filter(recovery_code::uid.eq(uid_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(totp::table.filter(totp::uid.eq(uid_)))
+                    .execute(conn)
+                    .await
+            }
+            .scope_boxed()
+        })
+        .await
+    }
     pub async fn get_device_by_id(&self, id_: u64) -> Result<Device, DieselErr> {
         use crate::schema::device::dsl::*;
         let mut conn = self.pool.get().await.unwrap();
@@ -122,37 +778,267 @@ impl DBClient {
     pub async fn get_online_device_cnt(&self) -> Result<i64, DieselErr> {
         use crate::schema::device::dsl::*;
         let mut conn = self.pool.get().await.unwrap();
-        let idle_timestamp: NaiveDateTime =
-            (chrono::Utc::now() - Duration::from_secs(60 * 10)).naive_utc(); // 10 min
-
         device
-            .filter(last_update.ge(idle_timestamp))
+            .filter(status.eq(DeviceStatus::Online))
             .count()
             .get_result(&mut conn)
             .await
     }
-    pub async fn get_owned_devices(&self, uid_: u64) -> Result<Vec<Device>, DieselErr> {
-        use crate::schema::device::dsl::*;
+    /// Mark online devices whose heartbeat timed out as offline, return their IDs
+    pub async fn mark_timed_out_devices_offline(
+        &self,
+        now: &NaiveDateTime,
+    ) -> Result<Vec<u64>, DieselErr> {
+        use crate::schema::{device, presence_event};
+        use diesel::dsl::sql;
+        use diesel::sql_types::{Bool, Datetime};
+        use diesel_async::scoped_futures::ScopedFutureExt;
         let mut conn = self.pool.get().await.unwrap();
-        device
-            .select(Device::as_select())
-            .filter(uid.eq(uid_))
+        conn.transaction(|conn| {
+            async move {
+                let dids: Vec<u64> = device::table
+                    .select(device::id)
+                    .filter(device::status.eq(DeviceStatus::Online))
+                    .filter(
+                        sql::<Bool>("TIMESTAMPADD(SECOND, `heartbeat_timeout`, `last_update`) < ")
+                            .bind::<Datetime, _>(now),
+                    )
+                    .for_update()
+                    .get_results(conn)
+                    .await?;
+                if dids.is_empty() {
+                    return Ok(dids);
+                }
+                diesel::update(device::table.filter(device::id.eq_any(&dids)))
+                    // Assign `last_update` explicitly to avoid its `ON UPDATE CURRENT_TIMESTAMP`
+                    .set((
+                        device::status.eq(DeviceStatus::Offline),
+                        device::last_update.eq(device::last_update),
+                    ))
+                    .execute(conn)
+                    .await?;
+                let events: Vec<NewPresenceEvent> = dids
+                    .iter()
+                    .map(|did| NewPresenceEvent {
+                        did: *did,
+                        status: DeviceStatus::Offline,
+                        cause: PresenceCause::Timeout,
+                        timestamp: now,
+                    })
+                    .collect();
+                diesel::insert_into(presence_event::table)
+                    .values(&events)
+                    .execute(conn)
+                    .await?;
+                diesel::result::QueryResult::Ok(dids)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Mark a device as offline immediately, return `true` if it was not offline before
+    pub async fn mark_device_offline(
+        &self,
+        did_: u64,
+        cause_: PresenceCause,
+        now: &NaiveDateTime,
+    ) -> Result<bool, DieselErr> {
+        use crate::schema::{device, presence_event};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let changed = diesel::update(
+                    device::table.filter(
+                        device::id
+                            .eq(did_)
+                            .and(device::status.ne(DeviceStatus::Offline)),
+                    ),
+                )
+                .set((
+                    device::status.eq(DeviceStatus::Offline),
+                    device::last_update.eq(device::last_update),
+                ))
+                .execute(conn)
+                .await?;
+                if changed == 0 {
+                    return Ok(false);
+                }
+                diesel::insert_into(presence_event::table)
+                    .values(&NewPresenceEvent {
+                        did: did_,
+                        status: DeviceStatus::Offline,
+                        cause: cause_,
+                        timestamp: now,
+                    })
+                    .execute(conn)
+                    .await?;
+                diesel::result::QueryResult::Ok(true)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Latest presence transitions of a device, newest first
+    pub async fn get_presence_events(
+        &self,
+        did_: u64,
+        limit: i64,
+    ) -> Result<Vec<PresenceEvent>, DieselErr> {
+        use crate::schema::presence_event::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        presence_event
+            .select(PresenceEvent::as_select())
+            .filter(did.eq(did_))
+            .order(timestamp.desc())
+            .limit(limit)
             .get_results(&mut conn)
             .await
     }
-    /// Add a new device, return Ok(id) if successful
-    pub async fn add_device<'a>(&self, form: &NewDevice<'a>) -> Result<u64, DieselErr> {
+    /// Devices of the organization, outside the trash, having all of the given labels
+    pub async fn get_org_devices(
+        &self,
+        oid_: u64,
+        labels: &[(String, String)],
+    ) -> Result<Vec<Device>, DieselErr> {
         use crate::schema::device;
         let mut conn = self.pool.get().await.unwrap();
-        let query = diesel::insert_into(device::table).values(form);
-        debug!("{}", debug_query::<Mysql, _>(&query).to_string());
-        query.execute(&mut conn).await?;
-        diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
-        // ! To get the correct `id``, must be in a single connection
-        let id: u64 = diesel::select(last_insert_id()).first(&mut conn).await?;
-        Ok(id)
+        let query = device::table
+            .filter(device::oid.eq(oid_).and(device::trashed_at.is_null()))
+            .into_boxed();
+        with_labels(query, labels)
+            .select(Device::as_select())
+            .get_results(&mut conn)
+            .await
     }
-    pub async fn update_device<'a>(
+    /// A page of the organization's devices matching the search, and the number of all matching devices
+    pub async fn search_devices(
+        &self,
+        oid_: u64,
+        search: &DeviceSearch,
+    ) -> Result<(Vec<Device>, i64), DieselErr> {
+        use crate::schema::device;
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let terms = search.text.as_deref().and_then(fulltext_query);
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let total: i64 = searched_devices(oid_, search, &terms)
+                    .count()
+                    .get_result(conn)
+                    .await?;
+                let mut query = searched_devices(oid_, search, &terms);
+                // Keyset pagination: strictly after the cursor in (sort key, id) order
+                macro_rules! after {
+                    ($column:expr, $value:expr, $id:expr) => {
+                        if search.descending {
+                            query.filter(
+                                $column
+                                    .lt($value)
+                                    .or($column.eq($value).and(device::id.lt($id))),
+                            )
+                        } else {
+                            query.filter(
+                                $column
+                                    .gt($value)
+                                    .or($column.eq($value).and(device::id.gt($id))),
+                            )
+                        }
+                    };
+                }
+                query = match &search.cursor {
+                    None => query,
+                    Some(DeviceCursor::Id(id_)) if search.descending => {
+                        query.filter(device::id.lt(id_))
+                    }
+                    Some(DeviceCursor::Id(id_)) => query.filter(device::id.gt(id_)),
+                    Some(DeviceCursor::Name(name_, id_)) => {
+                        after!(device::name, name_, id_)
+                    }
+                    Some(DeviceCursor::Dtype(dtype_, id_)) => {
+                        after!(device::dtype, dtype_, id_)
+                    }
+                    Some(DeviceCursor::Since(since_, id_)) => {
+                        after!(device::since, since_, id_)
+                    }
+                    Some(DeviceCursor::LastUpdate(last_update_, id_)) => {
+                        after!(device::last_update, last_update_, id_)
+                    }
+                };
+                macro_rules! sorted {
+                    ($column:expr) => {
+                        if search.descending {
+                            query.order(($column.desc(), device::id.desc()))
+                        } else {
+                            query.order(($column.asc(), device::id.asc()))
+                        }
+                    };
+                }
+                query = match search.sort {
+                    DeviceSort::Id if search.descending => query.order(device::id.desc()),
+                    DeviceSort::Id => query.order(device::id.asc()),
+                    DeviceSort::Name => sorted!(device::name),
+                    DeviceSort::Dtype => sorted!(device::dtype),
+                    DeviceSort::Since => sorted!(device::since),
+                    DeviceSort::LastUpdate => sorted!(device::last_update),
+                };
+                if let Some(limit) = search.limit {
+                    query = query.limit(limit);
+                }
+                let devices = query.select(Device::as_select()).get_results(conn).await?;
+                diesel::result::QueryResult::Ok((devices, total))
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Labels of the devices
+    pub async fn get_device_labels(&self, dids: &[u64]) -> Result<Vec<DeviceLabel>, DieselErr> {
+        use crate::schema::device_label::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        device_label
+            .select(DeviceLabel::as_select())
+            .filter(did.eq_any(dids))
+            .order((did.asc(), key.asc()))
+            .get_results(&mut conn)
+            .await
+    }
+    /// Replace all labels of a device
+    pub async fn set_device_labels(
+        &self,
+        did_: u64,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<(), DieselErr> {
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| replace_device_labels(conn, did_, labels).scope_boxed())
+            .await
+    }
+    /// Add a device with its labels in one transaction, return: its ID
+    pub async fn add_device<'a>(
+        &self,
+        form: &NewDevice<'a>,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<u64, DieselErr> {
+        use crate::schema::device;
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let query = diesel::insert_into(device::table).values(form);
+                debug!("{}", debug_query::<Mysql, _>(&query).to_string());
+                query.execute(conn).await?;
+                diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
+                // ! To get the correct `id``, must be in a single connection
+                let id: u64 = diesel::select(last_insert_id()).first(conn).await?;
+                replace_device_labels(conn, id, labels).await?;
+                Ok(id)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    pub async fn update_device<'a>(
         &self,
         form: &UpdateDevice<'a>,
         only_for: Option<u64>,
@@ -190,11 +1076,12 @@ impl DBClient {
             .first(&mut conn)
             .await
     }
-    pub async fn get_owned_tags(&self, uid_: u64) -> Result<Vec<Tag>, DieselErr> {
+    /// Tags owned by the organization
+    pub async fn get_org_tags(&self, oid_: u64) -> Result<Vec<Tag>, DieselErr> {
         use crate::schema::tag::dsl::*;
         let mut conn = self.pool.get().await.unwrap();
         tag.select(Tag::as_select())
-            .filter(uid.eq(uid_))
+            .filter(oid.eq(oid_))
             .get_results(&mut conn)
             .await
     }
@@ -217,76 +1104,1785 @@ impl DBClient {
             query.set(form).execute(&mut conn).await
         }
     }
-    pub async fn device_belongs_to(&self, did_: u64, uid_: u64) -> Result<bool, DieselErr> {
-        use crate::schema::device::dsl::*;
+    /// Soft delete: deactivate the device, move it to the trash and its topic aside for
+    /// `placeholder`, cancel its pending transfer. return: rows affected
+    pub async fn trash_device(
+        &self,
+        did_: u64,
+        placeholder: &str,
+        now: &NaiveDateTime,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::{device::dsl::*, device_transfer};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                // MySQL applies the assignments in order:
+                // the old topic is saved before being replaced
+                let trashed = diesel::update(device.filter(id.eq(did_).and(trashed_at.is_null())))
+                    .set((
+                        activated.eq(false),
+                        trashed_at.eq(now),
+                        original_topic.eq(topic.nullable()),
+                        topic.eq(placeholder),
+                        last_update.eq(last_update),
+                    ))
+                    .execute(conn)
+                    .await?;
+                diesel::update(
+                    device_transfer::table.filter(
+                        device_transfer::did
+                            .eq(did_)
+                            .and(device_transfer::status.eq(TransferStatus::Pending)),
+                    ),
+                )
+                .set((
+                    device_transfer::status.eq(TransferStatus::Cancelled),
+                    device_transfer::resolved_at.eq(now),
+                ))
+                .execute(conn)
+                .await?;
+                diesel::result::QueryResult::Ok(trashed)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Take a device out of the trash, active with its original topic, return: rows affected.
+    /// Devices being purged stay in the trash
+    pub async fn restore_device(&self, did_: u64) -> Result<usize, DieselErr> {
+        use crate::schema::device::dsl::*;
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let saved: Option<String> = match device
+                    .select(original_topic)
+                    .filter(
+                        id.eq(did_)
+                            .and(trashed_at.is_not_null())
+                            .and(purging.eq(false)),
+                    )
+                    .for_update()
+                    .first(conn)
+                    .await
+                    .optional()?
+                {
+                    Some(saved) => saved,
+                    None => return Ok(0),
+                };
+                // devices deleted before the original topic was kept stay on the placeholder
+                let target = device.filter(id.eq(did_));
+                match saved {
+                    Some(saved) => {
+                        diesel::update(target)
+                            .set((
+                                activated.eq(true),
+                                trashed_at.eq(None::<NaiveDateTime>),
+                                topic.eq(saved),
+                                original_topic.eq(None::<String>),
+                                last_update.eq(last_update),
+                            ))
+                            .execute(conn)
+                            .await
+                    }
+                    None => {
+                        diesel::update(target)
+                            .set((
+                                activated.eq(true),
+                                trashed_at.eq(None::<NaiveDateTime>),
+                                last_update.eq(last_update),
+                            ))
+                            .execute(conn)
+                            .await
+                    }
+                }
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Soft-deleted devices of the organization
+    pub async fn get_trashed_devices(&self, oid_: u64) -> Result<Vec<Device>, DieselErr> {
+        use crate::schema::device::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        device
+            .select(Device::as_select())
+            .filter(oid.eq(oid_).and(trashed_at.is_not_null()))
+            .order(trashed_at.desc())
+            .get_results(&mut conn)
+            .await
+    }
+    /// Soft-deleted tags of the organization
+    pub async fn get_trashed_tags(&self, oid_: u64) -> Result<Vec<Tag>, DieselErr> {
+        use crate::schema::tag::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        tag.select(Tag::as_select())
+            .filter(oid.eq(oid_).and(activated.eq(false)))
+            .order(id.desc())
+            .get_results(&mut conn)
+            .await
+    }
+    /// Permanently remove a soft-deleted device with everything referring to it.
+    /// The device is first marked as being purged so that it can no longer be restored, then
+    /// records go in batches so that no single transaction grows too large.
+    /// A failed purge can be run again.
+    ///
+    /// return: whether the device was removed (`false` if not found or not in the trash)
+    pub async fn purge_device(&self, did_: u64) -> Result<bool, DieselErr> {
+        use crate::schema::{
+            alert, device, device_label, device_share, device_transfer, geofence_event, location,
+            owns, presence_event, record,
+        };
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        const BATCH: i64 = 5000;
+        let mut conn = self.pool.get().await.unwrap();
+        // Claim the purge: a restore racing with it either wins before or finds the mark.
+        // The mark may already be set by a failed purge, so the claim is checked by reading it back
+        let trashed =
+            device::table.filter(device::id.eq(did_).and(device::trashed_at.is_not_null()));
+        diesel::update(trashed)
+            .set((
+                device::purging.eq(true),
+                device::last_update.eq(device::last_update),
+            ))
+            .execute(&mut conn)
+            .await?;
+        let claimed: bool = diesel::select(exists(trashed.filter(device::purging.eq(true))))
+            .get_result(&mut conn)
+            .await?;
+        if !claimed {
+            return Ok(false);
+        }
+        loop {
+            let rids: Vec<u64> = record::table
+                .select(record::id)
+                .filter(record::did.eq(did_))
+                .limit(BATCH)
+                .load(&mut conn)
+                .await?;
+            if rids.is_empty() {
+                break;
+            }
+            diesel::delete(record::table.filter(record::id.eq_any(&rids)))
+                .execute(&mut conn)
+                .await?;
+        }
+        conn.transaction(|conn| {
+            async move {
+                diesel::delete(record::table.filter(record::did.eq(did_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(location::table.filter(location::did.eq(did_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(presence_event::table.filter(presence_event::did.eq(did_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(geofence_event::table.filter(geofence_event::did.eq(did_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(alert::table.filter(alert::did.eq(did_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(device_label::table.filter(device_label::did.eq(did_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(device_share::table.filter(device_share::did.eq(did_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(device_transfer::table.filter(device_transfer::did.eq(did_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(owns::table.filter(owns::did.eq(did_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(device::table.filter(device::id.eq(did_)))
+                    .execute(conn)
+                    .await?;
+                Ok(true)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Permanently remove a soft-deleted tag: its memberships and shares are dropped,
+    /// its children move up to its parent.
+    ///
+    /// return: whether the tag was removed (`false` if not found or still active)
+    pub async fn purge_tag(&self, tid_: u64) -> Result<bool, DieselErr> {
+        use crate::schema::{owns, tag, tag_share};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let parent: Option<Option<u64>> = tag::table
+                    .select(tag::parent)
+                    .filter(tag::id.eq(tid_).and(tag::activated.eq(false)))
+                    .for_update()
+                    .first(conn)
+                    .await
+                    .optional()?;
+                let parent = match parent {
+                    Some(parent) => parent,
+                    None => return Ok(false),
+                };
+                diesel::update(tag::table.filter(tag::parent.eq(tid_)))
+                    .set(tag::parent.eq(parent))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(owns::table.filter(owns::tid.eq(tid_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(tag_share::table.filter(tag_share::tid.eq(tid_)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(tag::table.filter(tag::id.eq(tid_)))
+                    .execute(conn)
+                    .await?;
+                Ok(true)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// The user's permission on the device, `None` if there is no access (or no such device)
+    pub async fn device_permission(
+        &self,
+        did_: u64,
+        uid_: u64,
+    ) -> Result<Option<Permission>, DieselErr> {
+        let mut conn = self.pool.get().await.unwrap();
+        device_permission_of(&mut conn, did_, uid_).await
+    }
+    /// The user has at least the `needed` permission on the device
+    pub async fn device_permitted(
+        &self,
+        did_: u64,
+        uid_: u64,
+        needed: Permission,
+    ) -> Result<bool, DieselErr> {
+        Ok(self
+            .device_permission(did_, uid_)
+            .await?
+            .is_some_and(|permission| permission >= needed))
+    }
+    /// Keep the devices on which the user has at least the `needed` permission
+    pub async fn permitted_dids(
+        &self,
+        uid_: u64,
+        dids: &[u64],
+        needed: Permission,
+    ) -> Result<Vec<u64>, DieselErr> {
+        use crate::schema::device;
+        let mut conn = self.pool.get().await.unwrap();
+        let existing: Vec<u64> = device::table
+            .select(device::id)
+            .filter(device::id.eq_any(dids))
+            .load(&mut conn)
+            .await?;
+        let mut permitted = Vec::with_capacity(existing.len());
+        for did_ in existing {
+            if device_permission_of(&mut conn, did_, uid_)
+                .await?
+                .is_some_and(|permission| permission >= needed)
+            {
+                permitted.push(did_);
+            }
+        }
+        Ok(permitted)
+    }
+    /// Devices under a tag the user can read
+    pub async fn get_visible_dids_under_tag(
+        &self,
+        tid_: u64,
+        uid_: u64,
+    ) -> Result<Vec<u64>, DieselErr> {
+        let dids = self.get_dids_under_tag(tid_).await?;
+        self.permitted_dids(uid_, &dids, Permission::Read).await
+    }
+    /// Grant (or change) access to a device
+    pub async fn share_device(&self, form: &NewDeviceShare) -> Result<usize, DieselErr> {
+        use crate::schema::device_share;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::replace_into(device_share::table)
+            .values(form)
+            .execute(&mut conn)
+            .await
+    }
+    pub async fn get_device_shares(&self, did_: u64) -> Result<Vec<DeviceShare>, DieselErr> {
+        use crate::schema::device_share::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        device_share
+            .select(DeviceShare::as_select())
+            .filter(did.eq(did_))
+            .order(created_at.asc())
+            .get_results(&mut conn)
+            .await
+    }
+    /// Revoke a user's access to a device, the device is also removed from the user's tags.
+    /// return: rows affected
+    pub async fn revoke_device_share(&self, did_: u64, uid_: u64) -> Result<usize, DieselErr> {
+        use crate::schema::device_share;
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let revoked = diesel::delete(
+                    device_share::table
+                        .filter(device_share::did.eq(did_).and(device_share::uid.eq(uid_))),
+                )
+                .execute(conn)
+                .await?;
+                untag_inaccessible(conn, &[did_], uid_).await?;
+                diesel::result::QueryResult::Ok(revoked)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Devices shared with the user, directly or through a tag, with the highest permission held
+    pub async fn get_shared_devices(&self, uid_: u64) -> Result<Vec<SharedDevice>, DieselErr> {
+        use crate::schema::{device, device_share, owns, tag, tag_share};
+        let mut conn = self.pool.get().await.unwrap();
+        let direct: Vec<(Device, Permission)> = device_share::table
+            .inner_join(device::table)
+            .select((Device::as_select(), device_share::permission))
+            .filter(device_share::uid.eq(uid_))
+            .get_results(&mut conn)
+            .await?;
+        let tags: Vec<(u64, u64, Permission)> = tag_share::table
+            .inner_join(tag::table)
+            .select((tag::id, tag::oid, tag_share::permission))
+            .filter(tag_share::uid.eq(uid_).and(tag::activated.eq(true)))
+            .get_results(&mut conn)
+            .await?;
+        let tids: Vec<u64> = tags.iter().map(|(tid_, _, _)| *tid_).collect();
+        let tagged: Vec<(u64, Device)> = owns::table
+            .inner_join(device::table)
+            .select((owns::tid, Device::as_select()))
+            .filter(owns::tid.eq_any(&tids))
+            .get_results(&mut conn)
+            .await?;
+        // only devices of the organization owning the tag are shared through a tag
+        let via_tags = tagged.into_iter().filter_map(|(tid_, dev)| {
+            let (_, owner, permission) = tags.iter().find(|(id_, _, _)| *id_ == tid_)?;
+            (dev.oid == *owner).then_some((dev, *permission))
+        });
+        let mut shared: BTreeMap<u64, SharedDevice> = BTreeMap::new();
+        for (dev, permission) in direct.into_iter().chain(via_tags) {
+            let entry = shared.entry(dev.id).or_insert(SharedDevice {
+                device: dev,
+                permission,
+            });
+            entry.permission = entry.permission.max(permission);
+        }
+        Ok(shared.into_values().collect())
+    }
+    pub async fn get_device_records(&self, did_: u64) -> Result<Vec<Record>, DieselErr> {
+        use crate::schema::record::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        record
+            .select(Record::as_select())
+            .filter(did.eq(did_))
+            .get_results(&mut conn)
+            .await
+    }
+    /// Store a record and bump the device's `last_update` (bringing it online),
+    /// return the stored record
+    pub async fn add_device_records<'a>(&self, form: &NewRecord<'a>) -> Result<Record, DieselErr> {
+        use crate::schema::{device, presence_event, record};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                // Insert the record
+                diesel::insert_into(record::table)
+                    .values(form)
+                    .execute(conn)
+                    .await?;
+                diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
+                let id: u64 = diesel::select(last_insert_id()).first(conn).await?;
+                let (prev_status, locate, owner): (DeviceStatus, bool, u64) = device::table
+                    .select((device::status, device::locate_from_payload, device::uid))
+                    .filter(device::id.eq(form.did))
+                    .for_update()
+                    .first(conn)
+                    .await?;
+                // Update last update
+                diesel::update(device::table.filter(device::id.eq(form.did)))
+                    .set((
+                        device::last_update.eq(form.timestamp),
+                        device::status.eq(DeviceStatus::Online),
+                    ))
+                    .execute(conn)
+                    .await?;
+                if prev_status != DeviceStatus::Online {
+                    diesel::insert_into(presence_event::table)
+                        .values(&NewPresenceEvent {
+                            did: form.did,
+                            status: DeviceStatus::Online,
+                            cause: PresenceCause::Heartbeat,
+                            timestamp: form.timestamp,
+                        })
+                        .execute(conn)
+                        .await?;
+                }
+                if let Some((latitude, longitude)) =
+                    locate.then(|| locate_payload(form.payload)).flatten()
+                {
+                    store_location(
+                        conn,
+                        owner,
+                        &NewLocation {
+                            did: form.did,
+                            latitude,
+                            longitude,
+                            source: LocationSource::Payload,
+                            timestamp: form.timestamp,
+                        },
+                    )
+                    .await?;
+                }
+                diesel::result::QueryResult::Ok(Record {
+                    id,
+                    did: form.did,
+                    payload: form.payload.to_vec(),
+                    timestamp: *form.timestamp,
+                })
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Append a location to the device's history, move the device there and raise geofence events
+    pub async fn add_device_location<'a>(&self, form: &NewLocation<'a>) -> Result<(), DieselErr> {
+        use crate::schema::device;
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let owner: u64 = device::table
+                    .select(device::uid)
+                    .filter(device::id.eq(form.did))
+                    .first(conn)
+                    .await?;
+                store_location(conn, owner, form).await
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Location history of a device in `[from, to]`, oldest first
+    pub async fn get_device_track(
+        &self,
+        did_: u64,
+        from: &NaiveDateTime,
+        to: &NaiveDateTime,
+    ) -> Result<Vec<Location>, DieselErr> {
+        use crate::schema::location::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        location
+            .select(Location::as_select())
+            .filter(did.eq(did_).and(timestamp.between(from, to)))
+            .order((timestamp.asc(), id.asc()))
+            .get_results(&mut conn)
+            .await
+    }
+    /// Activated devices of the organization, or shared with the user, located in the bounding box
+    pub async fn get_devices_within(
+        &self,
+        oid_: u64,
+        uid_: u64,
+        (min_lat, min_lng): (f64, f64),
+        (max_lat, max_lng): (f64, f64),
+    ) -> Result<Vec<Device>, DieselErr> {
+        use crate::schema::device::dsl::*;
+        let shared = self.shared_dids(uid_).await?;
+        let mut conn = self.pool.get().await.unwrap();
+        device
+            .select(Device::as_select())
+            .filter(oid.eq(oid_).or(id.eq_any(shared)))
+            .filter(activated.eq(true))
+            .filter(latitude.between(min_lat, max_lat))
+            .filter(longitude.between(min_lng, max_lng))
+            .get_results(&mut conn)
+            .await
+    }
+    /// Activated devices of the organization, or shared with the user, within `radius` meters
+    /// of the point
+    pub async fn get_devices_nearby(
+        &self,
+        oid_: u64,
+        uid_: u64,
+        (lat, lng): (f64, f64),
+        radius: f64,
+    ) -> Result<Vec<Device>, DieselErr> {
+        use crate::schema::device::dsl::*;
+        use diesel::dsl::sql;
+        use diesel::sql_types::{Bool, Double};
+        let shared = self.shared_dids(uid_).await?;
+        let mut conn = self.pool.get().await.unwrap();
+        // Bounding box first so the coordinate index can be used
+        let (lat_delta, lng_delta) = bounding_deltas(lat, radius);
+        let mut query = device
+            .select(Device::as_select())
+            .filter(oid.eq(oid_).or(id.eq_any(shared)))
+            .filter(activated.eq(true))
+            .filter(latitude.between(lat - lat_delta, lat + lat_delta))
+            .into_boxed();
+        query = match longitude_ranges(lng, lng_delta) {
+            ((min, max), None) => query.filter(longitude.between(min, max)),
+            ((min, max), Some((other_min, other_max))) => query.filter(
+                longitude
+                    .between(min, max)
+                    .or(longitude.between(other_min, other_max)),
+            ),
+        };
+        query
+            .filter(
+                sql::<Bool>("ST_Distance_Sphere(POINT(`longitude`, `latitude`), POINT(")
+                    .bind::<Double, _>(lng)
+                    .sql(", ")
+                    .bind::<Double, _>(lat)
+                    .sql(")) <= ")
+                    .bind::<Double, _>(radius),
+            )
+            .get_results(&mut conn)
+            .await
+    }
+    /// IDs of the devices shared with the user, directly or through a tag
+    async fn shared_dids(&self, uid_: u64) -> Result<Vec<u64>, DieselErr> {
+        Ok(self
+            .get_shared_devices(uid_)
+            .await?
+            .into_iter()
+            .map(|shared| shared.device.id)
+            .collect())
+    }
+    /// Add a new geofence, return Ok(id) if successful
+    pub async fn add_geofence<'a>(&self, form: &NewGeofence<'a>) -> Result<u64, DieselErr> {
+        use crate::schema::geofence;
+        let mut conn = self.pool.get().await.unwrap();
+        let query = diesel::insert_into(geofence::table).values(form);
+        debug!("{}", debug_query::<Mysql, _>(&query).to_string());
+        query.execute(&mut conn).await?;
+        diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
+        // ! To get the correct `id``, must be in a single connection
+        let id: u64 = diesel::select(last_insert_id()).first(&mut conn).await?;
+        Ok(id)
+    }
+    pub async fn get_owned_geofences(&self, uid_: u64) -> Result<Vec<Geofence>, DieselErr> {
+        use crate::schema::geofence::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        geofence
+            .select(Geofence::as_select())
+            .filter(uid.eq(uid_).and(activated.eq(true)))
+            .get_results(&mut conn)
+            .await
+    }
+    /// Soft delete, return: rows affected
+    pub async fn deactivate_geofence(&self, gid: u64, uid_: u64) -> Result<usize, DieselErr> {
+        use crate::schema::geofence::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(geofence.filter(id.eq(gid).and(uid.eq(uid_))))
+            .set(activated.eq(false))
+            .execute(&mut conn)
+            .await
+    }
+    pub async fn geofence_belongs_to(&self, gid: u64, uid_: u64) -> Result<bool, DieselErr> {
+        use crate::schema::geofence::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::select(exists(geofence.filter(id.eq(gid).and(uid.eq(uid_)))))
+            .get_result(&mut conn)
+            .await
+    }
+    /// Latest enter/exit events of a geofence, newest first
+    pub async fn get_geofence_events(
+        &self,
+        gid_: u64,
+        limit: i64,
+    ) -> Result<Vec<GeofenceEvent>, DieselErr> {
+        use crate::schema::geofence_event::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        geofence_event
+            .select(GeofenceEvent::as_select())
+            .filter(gid.eq(gid_))
+            .order((timestamp.desc(), id.desc()))
+            .limit(limit)
+            .get_results(&mut conn)
+            .await
+    }
+    /// Raise an alert, or merge it into the unresolved one with the same severity and message.
+    /// Return the alert and whether it is newly raised.
+    pub async fn raise_alert<'a>(&self, form: &NewAlert<'a>) -> Result<(Alert, bool), DieselErr> {
+        use crate::schema::alert::dsl::*;
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let duplicate: Option<u64> = alert
+                    .select(id)
+                    .filter(did.eq(form.did).and(resolved_at.is_null()))
+                    .filter(severity.eq(form.severity).and(message.eq(form.message)))
+                    .for_update()
+                    .first(conn)
+                    .await
+                    .optional()?;
+                let (aid, is_new) = match duplicate {
+                    Some(aid) => {
+                        diesel::update(alert.filter(id.eq(aid)))
+                            .set((count.eq(count + 1), last_raised_at.eq(form.last_raised_at)))
+                            .execute(conn)
+                            .await?;
+                        (aid, false)
+                    }
+                    None => {
+                        diesel::insert_into(alert)
+                            .values(form)
+                            .execute(conn)
+                            .await?;
+                        diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
+                        let aid: u64 = diesel::select(last_insert_id()).first(conn).await?;
+                        (aid, true)
+                    }
+                };
+                let raised: Alert = alert
+                    .select(Alert::as_select())
+                    .filter(id.eq(aid))
+                    .first(conn)
+                    .await?;
+                diesel::result::QueryResult::Ok((raised, is_new))
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    pub async fn get_alerts_of_devices(
+        &self,
+        dids: &[u64],
+        unresolved_only: bool,
+    ) -> Result<Vec<Alert>, DieselErr> {
+        use crate::schema::alert::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        let query = alert
+            .select(Alert::as_select())
+            .filter(did.eq_any(dids))
+            .order(last_raised_at.desc())
+            .limit(1000);
+        if unresolved_only {
+            query
+                .filter(resolved_at.is_null())
+                .get_results(&mut conn)
+                .await
+        } else {
+            query.get_results(&mut conn).await
+        }
+    }
+    /// The user has at least the `needed` permission on the device the alert is raised for
+    pub async fn alert_permitted(
+        &self,
+        aid: u64,
+        uid_: u64,
+        needed: Permission,
+    ) -> Result<bool, DieselErr> {
+        use crate::schema::alert;
+        let mut conn = self.pool.get().await.unwrap();
+        let did_: Option<u64> = alert::table
+            .select(alert::did)
+            .filter(alert::id.eq(aid))
+            .first(&mut conn)
+            .await
+            .optional()?;
+        let Some(did_) = did_ else {
+            return Ok(false);
+        };
+        Ok(device_permission_of(&mut conn, did_, uid_)
+            .await?
+            .is_some_and(|permission| permission >= needed))
+    }
+    /// return: rows affected (0 if already acknowledged)
+    pub async fn acknowledge_alert(
+        &self,
+        aid: u64,
+        by: u64,
+        now: &NaiveDateTime,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::alert::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(alert.filter(id.eq(aid).and(acknowledged_at.is_null())))
+            .set((acknowledged_at.eq(now), acknowledged_by.eq(by)))
+            .execute(&mut conn)
+            .await
+    }
+    /// return: rows affected (0 if already resolved)
+    pub async fn resolve_alert(&self, aid: u64, now: &NaiveDateTime) -> Result<usize, DieselErr> {
+        use crate::schema::alert::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(alert.filter(id.eq(aid).and(resolved_at.is_null())))
+            .set(resolved_at.eq(now))
+            .execute(&mut conn)
+            .await
+    }
+    /// Add a new webhook, return Ok(id) if successful
+    pub async fn add_webhook<'a>(&self, form: &NewWebhook<'a>) -> Result<u64, DieselErr> {
+        use crate::schema::webhook;
+        let mut conn = self.pool.get().await.unwrap();
+        let query = diesel::insert_into(webhook::table).values(form);
+        debug!("{}", debug_query::<Mysql, _>(&query).to_string());
+        query.execute(&mut conn).await?;
+        diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
+        // ! To get the correct `id``, must be in a single connection
+        let id: u64 = diesel::select(last_insert_id()).first(&mut conn).await?;
+        Ok(id)
+    }
+    /// Webhooks of the user ("deleted" webhooks excluded)
+    pub async fn get_owned_webhooks(&self, uid_: u64) -> Result<Vec<Webhook>, DieselErr> {
+        use crate::schema::webhook::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        webhook
+            .select(Webhook::as_select())
+            .filter(uid.eq(uid_).and(activated.eq(true)))
+            .get_results(&mut conn)
+            .await
+    }
+    pub async fn update_webhook<'a>(
+        &self,
+        form: &UpdateWebhook<'a>,
+        only_for: u64,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::webhook::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(form)
+            .filter(uid.eq(only_for))
+            .set(form)
+            .execute(&mut conn)
+            .await
+    }
+    pub async fn webhook_belongs_to(&self, wid: u64, uid_: u64) -> Result<bool, DieselErr> {
+        use crate::schema::webhook::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::select(exists(
+            webhook.filter(id.eq(wid).and(uid.eq(uid_)).and(activated.eq(true))),
+        ))
+        .get_result(&mut conn)
+        .await
+    }
+    /// Active webhooks subscribed to the event, of the current members of the organization
+    /// (or of the one owning the device)
+    pub async fn get_subscribed_webhooks(
+        &self,
+        owner: WebhookOwner,
+        event: WebhookEvent,
+    ) -> Result<Vec<Webhook>, DieselErr> {
+        use crate::schema::{device, membership, organization, webhook};
+        let mut conn = self.pool.get().await.unwrap();
+        let members = membership::table
+            .inner_join(organization::table)
+            .select(membership::uid)
+            .filter(organization::activated.eq(true))
+            .into_boxed();
+        let members = match owner {
+            WebhookOwner::Org(oid_) => members.filter(membership::oid.eq(oid_)),
+            WebhookOwner::DeviceOrg(did_) => members.filter(
+                membership::oid.eq_any(
+                    device::table
+                        .select(device::oid)
+                        .filter(device::id.eq(did_)),
+                ),
+            ),
+        };
+        let query = webhook::table
+            .select(Webhook::as_select())
+            .filter(webhook::activated.eq(true))
+            .filter(webhook::uid.eq_any(members));
+        let hooks: Vec<Webhook> = query.get_results(&mut conn).await?;
+        Ok(hooks
+            .into_iter()
+            .filter(|hook| hook.subscribes(event))
+            .collect())
+    }
+    pub async fn add_webhook_deliveries<'a>(
+        &self,
+        forms: &[NewWebhookDelivery<'a>],
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::webhook_delivery;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::insert_into(webhook_delivery::table)
+            .values(forms)
+            .execute(&mut conn)
+            .await
+    }
+    /// Pending deliveries whose next attempt is due, oldest first, with their (active) webhooks
+    pub async fn get_due_webhook_deliveries(
+        &self,
+        now: &NaiveDateTime,
+        limit: i64,
+    ) -> Result<Vec<(WebhookDelivery, Webhook)>, DieselErr> {
+        use crate::schema::{webhook, webhook_delivery};
+        let mut conn = self.pool.get().await.unwrap();
+        webhook_delivery::table
+            .inner_join(webhook::table)
+            .select((WebhookDelivery::as_select(), Webhook::as_select()))
+            .filter(webhook_delivery::status.eq(DeliveryStatus::Pending))
+            .filter(webhook::activated.eq(true))
+            .filter(webhook_delivery::next_attempt_at.le(now))
+            .order(webhook_delivery::next_attempt_at.asc())
+            .limit(limit)
+            .get_results(&mut conn)
+            .await
+    }
+    /// Record the result of a delivery attempt.
+    /// `next_attempt`: `None` if delivered or given up (dead letter)
+    pub async fn finish_webhook_attempt(
+        &self,
+        delivery_id: u64,
+        status_: DeliveryStatus,
+        response: Option<u16>,
+        error: Option<&str>,
+        next_attempt: Option<&NaiveDateTime>,
+        now: &NaiveDateTime,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::webhook_delivery::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        let query = diesel::update(webhook_delivery.filter(id.eq(delivery_id)));
+        let result = (
+            status.eq(status_),
+            attempts.eq(attempts + 1),
+            response_status.eq(response),
+            last_error.eq(error),
+        );
+        match (status_, next_attempt) {
+            (DeliveryStatus::Delivered, _) => {
+                query
+                    .set((result, delivered_at.eq(now)))
+                    .execute(&mut conn)
+                    .await
+            }
+            (_, Some(next)) => {
+                query
+                    .set((result, next_attempt_at.eq(next)))
+                    .execute(&mut conn)
+                    .await
+            }
+            (_, None) => query.set(result).execute(&mut conn).await,
+        }
+    }
+    /// Delivery log of a webhook, newest first
+    pub async fn get_webhook_deliveries(
+        &self,
+        wid_: u64,
+        status_: Option<DeliveryStatus>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, DieselErr> {
+        use crate::schema::webhook_delivery::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        let mut query = webhook_delivery
+            .select(WebhookDelivery::as_select())
+            .filter(wid.eq(wid_))
+            .into_boxed();
+        if let Some(status_) = status_ {
+            query = query.filter(status.eq(status_));
+        }
+        query
+            .order(id.desc())
+            .limit(limit)
+            .get_results(&mut conn)
+            .await
+    }
+    pub async fn webhook_delivery_belongs_to(
+        &self,
+        delivery_id: u64,
+        uid_: u64,
+    ) -> Result<bool, DieselErr> {
+        use crate::schema::{webhook, webhook_delivery};
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::select(exists(
+            webhook_delivery::table.inner_join(webhook::table).filter(
+                webhook_delivery::id
+                    .eq(delivery_id)
+                    .and(webhook::uid.eq(uid_)),
+            ),
+        ))
+        .get_result(&mut conn)
+        .await
+    }
+    /// Queue a dead letter again with a fresh attempt budget
+    /// return: rows affected (0 if it is not a dead letter)
+    pub async fn requeue_webhook_delivery(
+        &self,
+        delivery_id: u64,
+        now: &NaiveDateTime,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::webhook_delivery::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(
+            webhook_delivery.filter(id.eq(delivery_id).and(status.eq(DeliveryStatus::Dead))),
+        )
+        .set((
+            status.eq(DeliveryStatus::Pending),
+            attempts.eq(0),
+            next_attempt_at.eq(now),
+        ))
+        .execute(&mut conn)
+        .await
+    }
+    /// Remove delivered entries of the delivery log created before `before`
+    pub async fn purge_webhook_deliveries(
+        &self,
+        before: &NaiveDateTime,
+    ) -> Result<usize, DieselErr> {
+        use crate::schema::webhook_delivery::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::delete(
+            webhook_delivery.filter(
+                status
+                    .eq(DeliveryStatus::Delivered)
+                    .and(created_at.lt(before)),
+            ),
+        )
+        .execute(&mut conn)
+        .await
+    }
+    /// The user's permission on the tag, `None` if there is no access (or no such tag)
+    pub async fn tag_permission(
+        &self,
+        tid_: u64,
+        uid_: u64,
+    ) -> Result<Option<Permission>, DieselErr> {
+        let mut conn = self.pool.get().await.unwrap();
+        tag_permission_of(&mut conn, tid_, uid_).await
+    }
+    /// The user has at least the `needed` permission on the tag
+    pub async fn tag_permitted(
+        &self,
+        tid_: u64,
+        uid_: u64,
+        needed: Permission,
+    ) -> Result<bool, DieselErr> {
+        Ok(self
+            .tag_permission(tid_, uid_)
+            .await?
+            .is_some_and(|permission| permission >= needed))
+    }
+    /// Grant (or change) access to a tag and the owner's devices under it
+    pub async fn share_tag(&self, form: &NewTagShare) -> Result<usize, DieselErr> {
+        use crate::schema::tag_share;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::replace_into(tag_share::table)
+            .values(form)
+            .execute(&mut conn)
+            .await
+    }
+    pub async fn get_tag_shares(&self, tid_: u64) -> Result<Vec<TagShare>, DieselErr> {
+        use crate::schema::tag_share::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        tag_share
+            .select(TagShare::as_select())
+            .filter(tid.eq(tid_))
+            .order(created_at.asc())
+            .get_results(&mut conn)
+            .await
+    }
+    /// Revoke a user's access to a tag, devices no longer accessible are removed from the user's tags.
+    /// return: rows affected
+    pub async fn revoke_tag_share(&self, tid_: u64, uid_: u64) -> Result<usize, DieselErr> {
+        use crate::schema::{owns, tag_share};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let revoked = diesel::delete(
+                    tag_share::table.filter(tag_share::tid.eq(tid_).and(tag_share::uid.eq(uid_))),
+                )
+                .execute(conn)
+                .await?;
+                let dids: Vec<u64> = owns::table
+                    .select(owns::did)
+                    .filter(owns::tid.eq(tid_))
+                    .load(conn)
+                    .await?;
+                untag_inaccessible(conn, &dids, uid_).await?;
+                diesel::result::QueryResult::Ok(revoked)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Tags shared with the user
+    pub async fn get_shared_tags(&self, uid_: u64) -> Result<Vec<SharedTag>, DieselErr> {
+        use crate::schema::{tag, tag_share};
+        let mut conn = self.pool.get().await.unwrap();
+        let tags: Vec<(Tag, Permission)> = tag_share::table
+            .inner_join(tag::table)
+            .select((Tag::as_select(), tag_share::permission))
+            .filter(tag_share::uid.eq(uid_))
+            .order(tag::id.asc())
+            .get_results(&mut conn)
+            .await?;
+        Ok(tags
+            .into_iter()
+            .map(|(tag, permission)| SharedTag { tag, permission })
+            .collect())
+    }
+    /// get device IDs
+    pub async fn get_dids_under_tag(&self, tid_: u64) -> Result<Vec<u64>, DieselErr> {
+        use crate::schema::owns::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        owns.select(did)
+            .filter(tid.eq(tid_))
+            .get_results(&mut conn)
+            .await
+    }
+    /// get device IDs under any of the tags
+    pub async fn get_dids_under_tags(&self, tids: &[u64]) -> Result<Vec<u64>, DieselErr> {
+        use crate::schema::owns::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        owns.select(did)
+            .filter(tid.eq_any(tids))
+            .distinct()
+            .get_results(&mut conn)
+            .await
+    }
+    pub async fn tag_device(&self, tid_: u64, did_: u64) -> Result<usize, DieselErr> {
+        use crate::schema::owns::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::insert_into(owns)
+            .values((tid.eq(tid_), did.eq(did_)))
+            .execute(&mut conn)
+            .await
+    }
+    pub async fn untag_device(&self, tid_: u64, did_: u64) -> Result<usize, DieselErr> {
+        use crate::schema::owns::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::delete(owns)
+            .filter(tid.eq(tid_).and(did.eq(did_)))
+            .execute(&mut conn)
+            .await
+    }
+    /// Soft delete a tag and drop its memberships (not brought back on restore),
+    /// return: rows affected
+    pub async fn trash_tag(&self, tid_: u64) -> Result<usize, DieselErr> {
+        use crate::schema::{owns, tag};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let trashed = diesel::update(
+                    tag::table.filter(tag::id.eq(tid_).and(tag::activated.eq(true))),
+                )
+                .set(tag::activated.eq(false))
+                .execute(conn)
+                .await?;
+                diesel::delete(owns::table.filter(owns::tid.eq(tid_)))
+                    .execute(conn)
+                    .await?;
+                diesel::result::QueryResult::Ok(trashed)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Active tags of a device
+    pub async fn get_device_tags(&self, did_: u64) -> Result<Vec<Tag>, DieselErr> {
+        use crate::schema::{owns, tag};
+        let mut conn = self.pool.get().await.unwrap();
+        owns::table
+            .inner_join(tag::table)
+            .select(Tag::as_select())
+            .filter(owns::did.eq(did_).and(tag::activated.eq(true)))
+            .order(tag::id.asc())
+            .get_results(&mut conn)
+            .await
+    }
+    /// Devices under a tag
+    pub async fn get_devices_under_tag(&self, tid_: u64) -> Result<Vec<Device>, DieselErr> {
+        use crate::schema::{device, owns};
+        let mut conn = self.pool.get().await.unwrap();
+        owns::table
+            .inner_join(device::table)
+            .select(Device::as_select())
+            .filter(owns::tid.eq(tid_))
+            .order(device::id.asc())
+            .get_results(&mut conn)
+            .await
+    }
+    /// Apply the same changes to every device under a tag in one transaction,
+    /// devices `uid_` cannot write to are skipped. `last_update` is kept as is.
+    pub async fn bulk_update_tagged_devices<'a>(
+        &self,
+        tid_: u64,
+        form: &BulkUpdateDevice<'a>,
+        uid_: u64,
+    ) -> Result<Vec<BulkResult>, DieselErr> {
+        use crate::schema::{device, owns};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                // Devices in the trash stay as they are until restored
+                let devices: Vec<u64> = owns::table
+                    .inner_join(device::table)
+                    .select(device::id)
+                    .filter(owns::tid.eq(tid_).and(device::trashed_at.is_null()))
+                    .order(device::id.asc())
+                    .for_update()
+                    .get_results(conn)
+                    .await?;
+                let mut results = Vec::with_capacity(devices.len());
+                for did_ in devices {
+                    let permission = device_permission_of(conn, did_, uid_).await?;
+                    if permission < Some(Permission::Write) {
+                        results.push(BulkResult {
+                            did: did_,
+                            status: "skipped",
+                            message: "No write permission on the device".into(),
+                        });
+                        continue;
+                    }
+                    diesel::update(device::table.filter(device::id.eq(did_)))
+                        .set((form, device::last_update.eq(device::last_update)))
+                        .execute(conn)
+                        .await?;
+                    results.push(BulkResult {
+                        did: did_,
+                        status: "ok",
+                        message: String::new(),
+                    });
+                }
+                diesel::result::QueryResult::Ok(results)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// Create an organization with the user as its owner, return: its ID
+    pub async fn create_org(&self, name: &str, owner: u64) -> Result<u64, DieselErr> {
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                insert_organization(
+                    conn,
+                    &NewOrganization {
+                        name,
+                        personal_of: None,
+                    },
+                    owner,
+                )
+                .await
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    pub async fn get_org_by_id(&self, oid_: u64) -> Result<Organization, DieselErr> {
+        use crate::schema::organization::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        organization
+            .select(Organization::as_select())
+            .filter(id.eq(oid_))
+            .first(&mut conn)
+            .await
+    }
+    pub async fn get_personal_org(&self, uid_: u64) -> Result<Organization, DieselErr> {
+        use crate::schema::organization::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        organization
+            .select(Organization::as_select())
+            .filter(personal_of.eq(uid_))
+            .first(&mut conn)
+            .await
+    }
+    /// `key_hash`: `hash_token` of the API key
+    pub async fn get_org_by_api_key(&self, key_hash: &str) -> Result<Organization, DieselErr> {
+        use crate::schema::organization::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        organization
+            .select(Organization::as_select())
+            .filter(api_key.eq(key_hash).and(activated.eq(true)))
+            .first(&mut conn)
+            .await
+    }
+    /// return: rows affected
+    pub async fn rename_org(&self, oid_: u64, name_: &str) -> Result<usize, DieselErr> {
+        use crate::schema::organization::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(organization.filter(id.eq(oid_)))
+            .set(name.eq(name_))
+            .execute(&mut conn)
+            .await
+    }
+    /// `key_hash`: `hash_token` of the API key, return: rows affected
+    pub async fn set_org_api_key(&self, oid_: u64, key_hash: &str) -> Result<usize, DieselErr> {
+        use crate::schema::organization::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::update(organization.filter(id.eq(oid_)))
+            .set(api_key.eq(key_hash))
+            .execute(&mut conn)
+            .await
+    }
+    /// Role of the user in an active organization, `None` if not a member
+    pub async fn get_member_role(
+        &self,
+        oid_: u64,
+        uid_: u64,
+    ) -> Result<Option<OrgRole>, DieselErr> {
+        let mut conn = self.pool.get().await.unwrap();
+        member_role_of(&mut conn, oid_, uid_).await
+    }
+    /// Active organizations the user is a member of
+    pub async fn get_member_orgs(&self, uid_: u64) -> Result<Vec<MemberOrganization>, DieselErr> {
+        use crate::schema::{membership, organization};
+        let mut conn = self.pool.get().await.unwrap();
+        let orgs: Vec<(Organization, OrgRole)> = membership::table
+            .inner_join(organization::table)
+            .select((Organization::as_select(), membership::role))
+            .filter(
+                membership::uid
+                    .eq(uid_)
+                    .and(organization::activated.eq(true)),
+            )
+            .order(organization::id.asc())
+            .get_results(&mut conn)
+            .await?;
+        Ok(orgs
+            .into_iter()
+            .map(|(organization, role)| MemberOrganization { organization, role })
+            .collect())
+    }
+    pub async fn get_org_members(&self, oid_: u64) -> Result<Vec<OrgMember>, DieselErr> {
+        use crate::schema::{membership, user};
+        let mut conn = self.pool.get().await.unwrap();
+        membership::table
+            .inner_join(user::table)
+            .select((
+                membership::uid,
+                user::username,
+                membership::role,
+                membership::since,
+            ))
+            .filter(membership::oid.eq(oid_))
+            .order(membership::since.asc())
+            .get_results(&mut conn)
+            .await
+    }
+    /// Emails of the members of the organization with at least the `role`
+    pub async fn get_member_emails(
+        &self,
+        oid_: u64,
+        role: OrgRole,
+    ) -> Result<Vec<String>, DieselErr> {
+        use crate::schema::{membership, user};
+        let mut conn = self.pool.get().await.unwrap();
+        let members: Vec<(OrgRole, String)> = membership::table
+            .inner_join(user::table)
+            .select((membership::role, user::email))
+            .filter(membership::oid.eq(oid_))
+            .get_results(&mut conn)
+            .await?;
+        Ok(members
+            .into_iter()
+            .filter(|(role_, _)| *role_ >= role)
+            .map(|(_, email)| email)
+            .collect())
+    }
+    /// Add a member or change the member's role
+    pub async fn set_member(&self, form: &NewMembership) -> Result<usize, DieselErr> {
+        use crate::schema::membership;
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                let member = membership::oid
+                    .eq(form.oid)
+                    .and(membership::uid.eq(form.uid));
+                let existing = diesel::select(exists(membership::table.filter(member)))
+                    .get_result(conn)
+                    .await?;
+                if existing {
+                    diesel::update(membership::table.filter(member))
+                        .set(membership::role.eq(form.role))
+                        .execute(conn)
+                        .await
+                } else {
+                    diesel::insert_into(membership::table)
+                        .values(form)
+                        .execute(conn)
+                        .await
+                }
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    /// return: rows affected
+    pub async fn remove_member(&self, oid_: u64, uid_: u64) -> Result<usize, DieselErr> {
+        use crate::schema::membership::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        diesel::delete(membership.filter(oid.eq(oid_).and(uid.eq(uid_))))
+            .execute(&mut conn)
+            .await
+    }
+    /// Start a transfer, superseding any pending one of the device, return: its ID
+    pub async fn create_transfer(
+        &self,
+        form: &NewDeviceTransfer,
+        now: &NaiveDateTime,
+    ) -> Result<u64, DieselErr> {
+        use crate::schema::device_transfer;
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        let mut conn = self.pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                diesel::update(
+                    device_transfer::table.filter(
+                        device_transfer::did
+                            .eq(form.did)
+                            .and(device_transfer::status.eq(TransferStatus::Pending)),
+                    ),
+                )
+                .set((
+                    device_transfer::status.eq(TransferStatus::Cancelled),
+                    device_transfer::resolved_at.eq(now),
+                ))
+                .execute(conn)
+                .await?;
+                diesel::insert_into(device_transfer::table)
+                    .values(form)
+                    .execute(conn)
+                    .await?;
+                diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
+                diesel::select(last_insert_id()).first(conn).await
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+    pub async fn get_transfer_by_id(&self, id_: u64) -> Result<DeviceTransfer, DieselErr> {
+        use crate::schema::device_transfer::dsl::*;
+        let mut conn = self.pool.get().await.unwrap();
+        device_transfer
+            .select(DeviceTransfer::as_select())
+            .filter(id.eq(id_))
+            .first(&mut conn)
+            .await
+    }
+    /// Transfers sent or received by the user, newest first
+    pub async fn get_user_transfers(&self, uid_: u64) -> Result<Vec<DeviceTransfer>, DieselErr> {
+        use crate::schema::device_transfer::dsl::*;
         let mut conn = self.pool.get().await.unwrap();
-        diesel::select(exists(device.filter(id.eq(did_).and(uid.eq(uid_)))))
-            .get_result(&mut conn)
+        device_transfer
+            .select(DeviceTransfer::as_select())
+            .filter(from_uid.eq(uid_).or(to_uid.eq(uid_)))
+            .order(id.desc())
+            .load(&mut conn)
             .await
     }
-    pub async fn get_device_records(&self, did_: u64) -> Result<Vec<Record>, DieselErr> {
-        use crate::schema::record::dsl::*;
+    /// Cancel a pending transfer, return: rows affected
+    pub async fn cancel_transfer(&self, id_: u64, now: &NaiveDateTime) -> Result<usize, DieselErr> {
+        use crate::schema::device_transfer::dsl::*;
         let mut conn = self.pool.get().await.unwrap();
-        record
-            .select(Record::as_select())
-            .filter(did.eq(did_))
-            .get_results(&mut conn)
+        diesel::update(device_transfer.filter(id.eq(id_).and(status.eq(TransferStatus::Pending))))
+            .set((status.eq(TransferStatus::Cancelled), resolved_at.eq(now)))
+            .execute(&mut conn)
             .await
     }
-    pub async fn add_device_records<'a>(&self, form: &NewRecord<'a>) -> Result<(), DieselErr> {
-        use crate::schema::{device, record};
+    /// Hand a device over to the recipient of a pending transfer in one transaction:
+    /// move it to the recipient's personal organization, purge the records if not transferred,
+    /// drop the alerts, presence and geofence events, shares, labels and the memberships of tags
+    /// outside the new organization.
+    ///
+    /// `NotFound` if the transfer is no longer pending or the sender is no longer an admin of the
+    /// organization owning the device
+    pub async fn accept_transfer(
+        &self,
+        id_: u64,
+        now: &NaiveDateTime,
+    ) -> Result<DeviceTransfer, DieselErr> {
+        use crate::schema::{
+            alert, device, device_label, device_share, device_transfer, geofence_event, location,
+            organization, owns, presence_event, record, tag,
+        };
         use diesel_async::scoped_futures::ScopedFutureExt;
         let mut conn = self.pool.get().await.unwrap();
         conn.transaction(|conn| {
             async move {
-                // Insert the record
-                diesel::insert_into(record::table)
-                    .values(form)
+                let mut transfer = device_transfer::table
+                    .select(DeviceTransfer::as_select())
+                    .filter(
+                        device_transfer::id
+                            .eq(id_)
+                            .and(device_transfer::status.eq(TransferStatus::Pending)),
+                    )
+                    .for_update()
+                    .first(conn)
+                    .await?;
+                // Devices deleted (or being purged) since the offer cannot be accepted
+                let owner_oid: u64 = device::table
+                    .select(device::oid)
+                    .filter(
+                        device::id
+                            .eq(transfer.did)
+                            .and(device::trashed_at.is_null())
+                            .and(device::purging.eq(false)),
+                    )
+                    .for_update()
+                    .first(conn)
+                    .await?;
+                // The sender must still be able to give the device away
+                if member_role_of(conn, owner_oid, transfer.from_uid).await? < Some(OrgRole::Admin)
+                {
+                    return Err(DieselErr::NotFound);
+                }
+                let oid_: u64 = organization::table
+                    .select(organization::id)
+                    .filter(organization::personal_of.eq(transfer.to_uid))
+                    .first(conn)
+                    .await?;
+                diesel::update(device::table.filter(device::id.eq(transfer.did)))
+                    .set((
+                        device::uid.eq(transfer.to_uid),
+                        device::oid.eq(oid_),
+                        device::last_update.eq(device::last_update),
+                    ))
                     .execute(conn)
                     .await?;
-                // Update last update
-                diesel::update(device::table.filter(device::id.eq(form.did)))
-                    .set(device::last_update.eq(form.timestamp))
+                if !transfer.with_records {
+                    diesel::delete(location::table.filter(location::did.eq(transfer.did)))
+                        .execute(conn)
+                        .await?;
+                    diesel::delete(record::table.filter(record::did.eq(transfer.did)))
+                        .execute(conn)
+                        .await?;
+                }
+                // Alerts, events, shares and labels are the previous owner's business
+                diesel::delete(alert::table.filter(alert::did.eq(transfer.did)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(presence_event::table.filter(presence_event::did.eq(transfer.did)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(geofence_event::table.filter(geofence_event::did.eq(transfer.did)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(device_share::table.filter(device_share::did.eq(transfer.did)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(device_label::table.filter(device_label::did.eq(transfer.did)))
+                    .execute(conn)
+                    .await?;
+                let foreign_tags: Vec<u64> = tag::table
+                    .select(tag::id)
+                    .filter(tag::oid.ne(oid_))
+                    .inner_join(owns::table)
+                    .filter(owns::did.eq(transfer.did))
+                    .load(conn)
+                    .await?;
+                diesel::delete(
+                    owns::table.filter(
+                        owns::did
+                            .eq(transfer.did)
+                            .and(owns::tid.eq_any(&foreign_tags)),
+                    ),
+                )
+                .execute(conn)
+                .await?;
+                diesel::update(device_transfer::table.filter(device_transfer::id.eq(id_)))
+                    .set((
+                        device_transfer::status.eq(TransferStatus::Accepted),
+                        device_transfer::resolved_at.eq(now),
+                    ))
                     .execute(conn)
                     .await?;
-                diesel::result::QueryResult::Ok(())
+                transfer.status = TransferStatus::Accepted;
+                transfer.resolved_at = Some(*now);
+                Ok(transfer)
             }
             .scope_boxed()
         })
         .await
     }
-    pub async fn tag_belongs_to(&self, tid_: u64, uid_: u64) -> Result<bool, DieselErr> {
-        use crate::schema::tag::dsl::*;
+    /// At most `limit` records stored after the record `after`, by ID
+    pub async fn get_records_after(
+        &self,
+        after: u64,
+        limit: i64,
+    ) -> Result<Vec<Record>, DieselErr> {
+        use crate::schema::record::dsl::*;
         let mut conn = self.pool.get().await.unwrap();
-        diesel::select(exists(tag.filter(id.eq(tid_).and(uid.eq(uid_)))))
-            .get_result(&mut conn)
+        record
+            .select(Record::as_select())
+            .filter(id.gt(after))
+            .order(id.asc())
+            .limit(limit)
+            .get_results(&mut conn)
             .await
     }
-    /// get device IDs
-    pub async fn get_dids_under_tag(&self, tid_: u64) -> Result<Vec<u64>, DieselErr> {
-        use crate::schema::owns::dsl::*;
+    /// Records of the devices in `[from, to]`, newest first
+    pub async fn get_records_of_devices(
+        &self,
+        dids: &[u64],
+        from: &NaiveDateTime,
+        to: &NaiveDateTime,
+        limit: i64,
+    ) -> Result<Vec<Record>, DieselErr> {
+        use crate::schema::record;
         let mut conn = self.pool.get().await.unwrap();
-        owns.select(did)
-            .filter(tid.eq(tid_))
+        record::table
+            .select(Record::as_select())
+            .filter(
+                record::did
+                    .eq_any(dids)
+                    .and(record::timestamp.between(from, to)),
+            )
+            .order((record::timestamp.desc(), record::id.desc()))
+            .limit(limit)
             .get_results(&mut conn)
             .await
     }
-    pub async fn tag_device(&self, tid_: u64, did_: u64) -> Result<usize, DieselErr> {
-        use crate::schema::owns::dsl::*;
-        let mut conn = self.pool.get().await.unwrap();
-        diesel::insert_into(owns)
-            .values((tid.eq(tid_), did.eq(did_)))
-            .execute(&mut conn)
-            .await
+}
+
+/// Permission of the user on the device: the role in the owning organization, a direct share,
+/// or a share of one of the organization's active tags the device is under.
+/// Creating the device grants nothing by itself, so removed members lose their access
+async fn device_permission_of(
+    conn: &mut AsyncMysqlConnection,
+    did_: u64,
+    uid_: u64,
+) -> diesel::result::QueryResult<Option<Permission>> {
+    use crate::schema::{device, device_share, owns, tag, tag_share};
+    let oid_: Option<u64> = device::table
+        .select(device::oid)
+        .filter(device::id.eq(did_))
+        .first(conn)
+        .await
+        .optional()?;
+    let Some(oid_) = oid_ else {
+        return Ok(None);
+    };
+    let by_role = member_role_of(conn, oid_, uid_)
+        .await?
+        .map(OrgRole::permission);
+    let direct: Option<Permission> = device_share::table
+        .select(device_share::permission)
+        .filter(device_share::did.eq(did_).and(device_share::uid.eq(uid_)))
+        .first(conn)
+        .await
+        .optional()?;
+    let via_tags: Vec<Permission> = tag_share::table
+        .inner_join(tag::table)
+        .select(tag_share::permission)
+        .filter(
+            tag_share::uid
+                .eq(uid_)
+                .and(tag::oid.eq(oid_))
+                .and(tag::activated.eq(true))
+                .and(
+                    tag_share::tid.eq_any(owns::table.select(owns::tid).filter(owns::did.eq(did_))),
+                ),
+        )
+        .load(conn)
+        .await?;
+    Ok(direct.into_iter().chain(via_tags).chain(by_role).max())
+}
+
+/// Permission of the user on the tag: the role in the owning organization or a share
+async fn tag_permission_of(
+    conn: &mut AsyncMysqlConnection,
+    tid_: u64,
+    uid_: u64,
+) -> diesel::result::QueryResult<Option<Permission>> {
+    use crate::schema::{tag, tag_share};
+    let oid_: Option<u64> = tag::table
+        .select(tag::oid)
+        .filter(tag::id.eq(tid_))
+        .first(conn)
+        .await
+        .optional()?;
+    let Some(oid_) = oid_ else {
+        return Ok(None);
+    };
+    let by_role = member_role_of(conn, oid_, uid_)
+        .await?
+        .map(OrgRole::permission);
+    let shared: Option<Permission> = tag_share::table
+        .select(tag_share::permission)
+        .filter(tag_share::tid.eq(tid_).and(tag_share::uid.eq(uid_)))
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(by_role.max(shared))
+}
+
+/// Replace all labels of a device within an opened transaction
+async fn replace_device_labels(
+    conn: &mut AsyncMysqlConnection,
+    did_: u64,
+    labels: &BTreeMap<String, String>,
+) -> diesel::result::QueryResult<()> {
+    use crate::schema::device_label::dsl::*;
+    diesel::delete(device_label.filter(did.eq(did_)))
+        .execute(conn)
+        .await?;
+    if labels.is_empty() {
+        return Ok(());
     }
-    pub async fn untag_device(&self, tid_: u64, did_: u64) -> Result<usize, DieselErr> {
-        use crate::schema::owns::dsl::*;
-        let mut conn = self.pool.get().await.unwrap();
-        diesel::delete(owns)
-            .filter(tid.eq(tid_).and(did.eq(did_)))
-            .execute(&mut conn)
-            .await
+    let rows: Vec<DeviceLabel> = labels
+        .iter()
+        .map(|(key_, value_)| DeviceLabel {
+            did: did_,
+            key: key_.clone(),
+            value: value_.clone(),
+        })
+        .collect();
+    diesel::insert_into(device_label)
+        .values(&rows)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Role of the user in an active organization
+async fn member_role_of(
+    conn: &mut AsyncMysqlConnection,
+    oid_: u64,
+    uid_: u64,
+) -> diesel::result::QueryResult<Option<OrgRole>> {
+    use crate::schema::{membership, organization};
+    membership::table
+        .inner_join(organization::table)
+        .select(membership::role)
+        .filter(
+            membership::oid
+                .eq(oid_)
+                .and(membership::uid.eq(uid_))
+                .and(organization::activated.eq(true)),
+        )
+        .first(conn)
+        .await
+        .optional()
+}
+
+/// Insert the user and the personal organization, return: ID of the user
+async fn insert_user<'a>(
+    conn: &mut AsyncMysqlConnection,
+    form: &NewUser<'a>,
+) -> diesel::result::QueryResult<u64> {
+    use crate::schema::user;
+    let query = diesel::insert_into(user::table).values(form);
+    debug!("{}", debug_query::<Mysql, _>(&query).to_string());
+    query.execute(conn).await?;
+    diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
+    // ! To get the correct `id``, must be in a single connection
+    let id: u64 = diesel::select(last_insert_id()).first(conn).await?;
+    insert_organization(
+        conn,
+        &NewOrganization {
+            name: form.username,
+            personal_of: Some(id),
+        },
+        id,
+    )
+    .await?;
+    Ok(id)
+}
+
+/// Create an organization owned by `owner` within an opened transaction, return: its ID
+async fn insert_organization<'a>(
+    conn: &mut AsyncMysqlConnection,
+    form: &NewOrganization<'a>,
+    owner: u64,
+) -> diesel::result::QueryResult<u64> {
+    use crate::schema::{membership, organization};
+    diesel::insert_into(organization::table)
+        .values(form)
+        .execute(conn)
+        .await?;
+    diesel::sql_function!(fn last_insert_id() -> Unsigned<BigInt>);
+    let oid_: u64 = diesel::select(last_insert_id()).first(conn).await?;
+    diesel::insert_into(membership::table)
+        .values(&NewMembership {
+            oid: oid_,
+            uid: owner,
+            role: OrgRole::Owner,
+        })
+        .execute(conn)
+        .await?;
+    Ok(oid_)
+}
+
+/// After a revoke, drop the devices the user can no longer access from the user's own tags
+async fn untag_inaccessible(
+    conn: &mut AsyncMysqlConnection,
+    dids: &[u64],
+    uid_: u64,
+) -> diesel::result::QueryResult<()> {
+    use crate::schema::{owns, tag};
+    let own_tags: Vec<u64> = tag::table
+        .select(tag::id)
+        .filter(tag::uid.eq(uid_))
+        .load(conn)
+        .await?;
+    for did_ in dids {
+        if device_permission_of(conn, *did_, uid_).await?.is_none() {
+            diesel::delete(owns::table.filter(owns::did.eq(did_).and(owns::tid.eq_any(&own_tags))))
+                .execute(conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Insert a location within an opened transaction: move the device and raise geofence events
+async fn store_location<'a>(
+    conn: &mut AsyncMysqlConnection,
+    owner: u64,
+    form: &NewLocation<'a>,
+) -> diesel::result::QueryResult<()> {
+    use crate::schema::{device, geofence, geofence_event, location};
+    let prev: Option<(f64, f64)> = location::table
+        .select((location::latitude, location::longitude))
+        .filter(location::did.eq(form.did))
+        .order((location::timestamp.desc(), location::id.desc()))
+        .first(conn)
+        .await
+        .optional()?;
+    diesel::insert_into(location::table)
+        .values(form)
+        .execute(conn)
+        .await?;
+    diesel::update(device::table.filter(device::id.eq(form.did)))
+        // Assign `last_update` explicitly to avoid its `ON UPDATE CURRENT_TIMESTAMP`
+        .set((
+            device::latitude.eq(form.latitude),
+            device::longitude.eq(form.longitude),
+            device::last_update.eq(device::last_update),
+        ))
+        .execute(conn)
+        .await?;
+    let fences: Vec<Geofence> = geofence::table
+        .select(Geofence::as_select())
+        .filter(geofence::uid.eq(owner).and(geofence::activated.eq(true)))
+        .get_results(conn)
+        .await?;
+    let events: Vec<NewGeofenceEvent> =
+        geofence_transitions(&fences, prev, (form.latitude, form.longitude))
+            .into_iter()
+            .map(|(gid, transition)| NewGeofenceEvent {
+                gid,
+                did: form.did,
+                transition,
+                timestamp: form.timestamp,
+            })
+            .collect();
+    if !events.is_empty() {
+        diesel::insert_into(geofence_event::table)
+            .values(&events)
+            .execute(conn)
+            .await?;
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -295,7 +2891,17 @@ mod tests {
     use diesel_async::{pooled_connection::deadpool::Object, AsyncMysqlConnection, RunQueryDsl};
     use futures::future::join_all;
 
-    use super::DBClient;
+    use super::{fulltext_query, DBClient, DeviceCursor, DeviceSearch, DeviceSort, RevokeScope};
+
+    #[test]
+    fn fulltext_terms() {
+        assert_eq!(fulltext_query("lamp").as_deref(), Some("+\"lamp\""));
+        assert_eq!(
+            fulltext_query(" -hall  \"lamp*\" ").as_deref(),
+            Some("+\"-hall\" +\"lamp*\"")
+        );
+        assert_eq!(fulltext_query(" \"\" "), None);
+    }
 
     #[tokio::test]
     async fn multi_connections_with_raw_query() {
@@ -325,28 +2931,46 @@ mod tests {
         }
         println!("{:?}", tokio::join!(join_all(futures)));
     }
-    use chrono::NaiveDateTime;
+    use chrono::{Duration, NaiveDateTime, Utc};
     use moka::future::Cache;
+    use std::collections::BTreeMap;
     use uuid::Uuid;
 
     use crate::{
         app_context::AppState,
-        config::CONFIG,
+        config::{OneTimeTokenConfig, CONFIG},
         models::{
-            NewDevice, NewRecord, NewTag, NewUser, UpdateDevice, UpdateTag, UpdateUser,
-            UserPrivilege,
+            AlertSeverity, ApiKeyScope, DeviceStatus, NewAlert, NewApiKey, NewDevice,
+            NewDeviceShare, NewDeviceTransfer, NewIdentity, NewMembership, NewOneTimeToken,
+            NewRecord, NewSession, NewTag, NewTagShare, NewUser, OrgRole, Permission,
+            PresenceCause, TransferStatus, UpdateDevice, UpdateTag, UpdateUser, UserPrivilege,
+        },
+        utils::{
+            api_keys::{new_api_key, visible_prefix},
+            hub::RecordHub,
+            jwt::hash_token,
+            login_guard::LoginGuard,
+            one_time_token::{OneTimeTokens, TokenPurpose},
+            password::get_pwd_hash,
+            request_limit::RequestLimiter,
+            tag_query,
+            totp::{hash_recovery_code, new_recovery_codes, new_secret},
         },
-        utils::password::get_pwd_hash,
     };
 
     #[tokio::test]
     async fn full_db_raw() {
         let config = &CONFIG;
+        let db = DBClient::new(&DBClient::get_database_url());
         let app: AppState = AppState {
             env: config,
-            db: DBClient::new(&DBClient::get_database_url()),
+            tokens: OneTimeTokens::new(&config.one_time_token, &config.jwt.secret, &db),
+            db,
             rate_limit: Cache::new(1024),
-            one_time_code: Cache::new(1024),
+            login_guard: LoginGuard::new(&config.login),
+            request_limit: RequestLimiter::new(&config.rate_limit),
+            totp_challenge: Cache::new(1024),
+            hub: RecordHub::new(),
         };
 
         let uid = app
@@ -354,12 +2978,9 @@ mod tests {
             .register_user(&NewUser {
                 username: &format!("test{}", Uuid::new_v4()),
                 email: &format!("kisa{}ma@mail.com", Uuid::new_v4()),
-                hashed_password: &get_pwd_hash(
-                    &app.env.riot.password_salt.as_bytes(),
-                    "Aaa123,????".as_bytes(),
-                ),
+                hashed_password: &get_pwd_hash("Aaa123,????".as_bytes(), &app.env.password)
+                    .unwrap(),
                 privilege: UserPrivilege::Normal as u32,
-                api_key: None,
             })
             .await
             .expect("Register failed");
@@ -372,7 +2993,6 @@ mod tests {
                 hashed_password: None,
                 privilege: None,
                 activated: Some(true),
-                api_key: None,
             })
             .await
             .expect("Modify user failed");
@@ -385,20 +3005,68 @@ mod tests {
         assert_eq!(modified_user.activated, true);
         assert_eq!(modified_user.privilege, 4);
 
+        // One-time tokens in the DB: bound to the purpose, single-use, expiring
+        let tokens = OneTimeTokens::new(&OneTimeTokenConfig::default(), "secret", &app.db);
+        let token = tokens
+            .issue(TokenPurpose::ResetPassword, uid)
+            .await
+            .unwrap();
+        assert_eq!(tokens.consume(TokenPurpose::Login, &token).await, Ok(None));
+        assert_eq!(
+            tokens.consume(TokenPurpose::ResetPassword, &token).await,
+            Ok(Some(uid))
+        );
+        assert_eq!(
+            tokens.consume(TokenPurpose::ResetPassword, &token).await,
+            Ok(None)
+        );
+        let now = Utc::now().naive_utc();
+        let expired = format!("{:064}", uid);
+        app.db
+            .insert_one_time_token(
+                &NewOneTimeToken {
+                    token_hash: &expired,
+                    purpose: "login",
+                    uid,
+                    expires_at: &(now - Duration::seconds(1)),
+                },
+                &(now - Duration::seconds(2)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(app.db.take_one_time_token(&expired, &now).await, Ok(None));
+        // purged when the next one is issued
+        tokens.issue(TokenPurpose::Unlock, uid).await.unwrap();
+        assert_eq!(
+            app.db
+                .take_one_time_token(&expired, &(now - Duration::seconds(2)))
+                .await,
+            Ok(None)
+        );
+
         // Add a device
+        let personal = app
+            .db
+            .get_personal_org(modified_user.id)
+            .await
+            .expect("Personal organization missing");
         let topic = format!("api-key-for-me/yyy/test{}", Uuid::new_v4());
         let dvc = NewDevice {
             uid: modified_user.id,
+            oid: personal.id,
             name: "NewDeviceTest",
             desc: Some("Balalala"),
             dtype: 1,
             latitude: None,
             longitude: Some(12.3456),
             topic: &topic,
+            heartbeat_timeout: None,
+            locate_from_payload: Some(true),
+            metadata: None,
         };
         let did = app
             .db
-            .add_device(&dvc)
+            .add_device(&dvc, &BTreeMap::new())
             .await
             .expect("Create new device failed");
         app.db
@@ -413,6 +3081,9 @@ mod tests {
                     last_update: None,
                     activated: None,
                     topic: None,
+                    heartbeat_timeout: None,
+                    locate_from_payload: None,
+                    metadata: None,
                 },
                 Some(modified_user.id),
             )
@@ -426,6 +3097,70 @@ mod tests {
         println!("{:?}", modified_device);
         assert_eq!(modified_device.name, "Modified!");
         assert_eq!(modified_device.desc, Some("Ok...".into()));
+        // labels
+        let labels = BTreeMap::from([("firmware".to_string(), "1.2".to_string())]);
+        app.db.set_device_labels(did, &labels).await.unwrap();
+        let filter = [("firmware".to_string(), "1.2".to_string())];
+        let labeled = app.db.get_org_devices(personal.id, &filter).await.unwrap();
+        assert_eq!(labeled.len(), 1);
+        let filter = [("firmware".to_string(), "1.3".to_string())];
+        assert!(app
+            .db
+            .get_org_devices(personal.id, &filter)
+            .await
+            .unwrap()
+            .is_empty());
+        // search
+        let search = DeviceSearch {
+            text: Some("modif".into()),
+            labels: vec![("firmware".to_string(), "1.2".to_string())],
+            sort: DeviceSort::Name,
+            descending: true,
+            limit: Some(1),
+            ..Default::default()
+        };
+        let (page, total) = app.db.search_devices(personal.id, &search).await.unwrap();
+        assert_eq!((page.len(), total), (1, 1));
+        let search = DeviceSearch {
+            cursor: Some(DeviceCursor::after(DeviceSort::Name, &page[0])),
+            ..search
+        };
+        let (page, total) = app.db.search_devices(personal.id, &search).await.unwrap();
+        assert_eq!((page.len(), total), (0, 1));
+        // tag queries follow the hierarchy
+        let suffix = Uuid::new_v4().simple().to_string();
+        let mut hierarchy = vec![];
+        for (name, parent) in [("building", None), ("room", Some(0)), ("retired", None)] {
+            let tid = app
+                .db
+                .add_tag(&NewTag {
+                    uid,
+                    oid: personal.id,
+                    name: &format!("{name}_{suffix}"),
+                    desc: None,
+                    activated: true,
+                    parent: parent.map(|index: usize| hierarchy[index]),
+                })
+                .await
+                .expect("Create tag failed");
+            hierarchy.push(tid);
+        }
+        let room = hierarchy[1];
+        app.db.tag_device(room, did).await.unwrap();
+        let tagged = |query: String| DeviceSearch {
+            tags: Some(tag_query::parse(&query).unwrap()),
+            ..Default::default()
+        };
+        let search = tagged(format!("building_{suffix} AND NOT retired_{suffix}"));
+        let (page, _) = app.db.search_devices(personal.id, &search).await.unwrap();
+        assert_eq!(
+            page.iter().map(|device| device.id).collect::<Vec<_>>(),
+            vec![did]
+        );
+        let search = tagged(format!("NOT building_{suffix} OR retired_{suffix}"));
+        let (page, _) = app.db.search_devices(personal.id, &search).await.unwrap();
+        assert!(page.iter().all(|device| device.id != did));
+        app.db.untag_device(room, did).await.unwrap();
         // records
         app.db
             .add_device_records(&NewRecord {
@@ -442,15 +3177,61 @@ mod tests {
             .expect("Get records failed");
         println!("{:?}", records);
         assert!(!records.is_empty());
+        // presence
+        let online_device = app.db.get_device_by_id(did).await.unwrap();
+        assert_eq!(online_device.status, DeviceStatus::Online);
+        assert!(app
+            .db
+            .mark_device_offline(did, PresenceCause::LastWill, &Utc::now().naive_utc())
+            .await
+            .expect("Mark offline failed"));
+        let events = app
+            .db
+            .get_presence_events(did, 10)
+            .await
+            .expect("Get presence events failed");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].cause, PresenceCause::LastWill);
+        // location from payload
+        app.db
+            .add_device_records(&NewRecord {
+                did,
+                payload: br#"{"lat": 31.25, "lng": 121.5}"#,
+                timestamp: &Utc::now().naive_utc(),
+            })
+            .await
+            .expect("Add record failed!");
+        let located_device = app.db.get_device_by_id(did).await.unwrap();
+        assert_eq!(located_device.latitude, Some(31.25));
+        assert_eq!(located_device.longitude, Some(121.5));
+        // alerts, repeats merged until resolved
+        let now = Utc::now().naive_utc();
+        let new_alert = NewAlert {
+            did,
+            severity: AlertSeverity::Critical,
+            message: "Too hot",
+            raised_at: &now,
+            last_raised_at: &now,
+        };
+        let (first, is_new) = app.db.raise_alert(&new_alert).await.unwrap();
+        assert!(is_new);
+        let (repeat, is_new) = app.db.raise_alert(&new_alert).await.unwrap();
+        assert!(!is_new);
+        assert_eq!((repeat.id, repeat.count), (first.id, 2));
+        assert_eq!(app.db.resolve_alert(first.id, &now).await.unwrap(), 1);
+        let (reraised, is_new) = app.db.raise_alert(&new_alert).await.unwrap();
+        assert!(is_new && reraised.id != first.id);
 
         // tags
         let tid = app
             .db
             .add_tag(&NewTag {
                 uid,
+                oid: personal.id,
                 name: &format!("tag_{}", Uuid::new_v4()),
                 desc: None,
                 activated: true,
+                parent: None,
             })
             .await
             .expect("Create new tag failed");
@@ -462,6 +3243,7 @@ mod tests {
                     name: None,
                     desc: Some(Some("Modified!!!")),
                     activated: None,
+                    parent: None,
                 },
                 Some(uid),
             )
@@ -484,15 +3266,528 @@ mod tests {
             .expect("Get dids under the tag failed");
         println!("{:?}", res);
         assert!(!res.is_empty());
+        let tags = app.db.get_device_tags(did).await.unwrap();
+        assert!(tags.iter().any(|tag| tag.id == tid));
+        let doomed = app
+            .db
+            .add_tag(&NewTag {
+                uid,
+                oid: personal.id,
+                name: &format!("tag_{}", Uuid::new_v4()),
+                desc: None,
+                activated: true,
+                parent: None,
+            })
+            .await
+            .unwrap();
+        app.db.tag_device(doomed, did).await.unwrap();
+        assert_eq!(app.db.untag_device(doomed, did).await.unwrap(), 1);
+        app.db.tag_device(doomed, did).await.unwrap();
+        assert_eq!(app.db.trash_tag(doomed).await.unwrap(), 1);
+        assert!(app.db.get_dids_under_tag(doomed).await.unwrap().is_empty());
+        assert_eq!(app.db.get_device_tags(did).await.unwrap().len(), tags.len());
+
+        // sharing
+        let friend = app
+            .db
+            .register_user(&NewUser {
+                username: &format!("test{}", Uuid::new_v4()),
+                email: &format!("friend{}@mail.com", Uuid::new_v4()),
+                hashed_password: "",
+                privilege: UserPrivilege::Normal as u32,
+            })
+            .await
+            .expect("Register failed");
+        assert!(!app
+            .db
+            .device_permitted(did, friend, Permission::Read)
+            .await
+            .unwrap());
+        app.db
+            .share_tag(&NewTagShare {
+                tid,
+                uid: friend,
+                permission: Permission::Read,
+                granted_by: uid,
+            })
+            .await
+            .expect("Share tag failed");
+        assert_eq!(
+            app.db.device_permission(did, friend).await.unwrap(),
+            Some(Permission::Read)
+        );
+        app.db
+            .share_device(&NewDeviceShare {
+                did,
+                uid: friend,
+                permission: Permission::Write,
+                granted_by: uid,
+            })
+            .await
+            .expect("Share device failed");
+        assert!(app
+            .db
+            .device_permitted(did, friend, Permission::Write)
+            .await
+            .unwrap());
+        assert!(!app
+            .db
+            .device_permitted(did, friend, Permission::Admin)
+            .await
+            .unwrap());
+        let shared = app.db.get_shared_devices(friend).await.unwrap();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].permission, Permission::Write);
+        assert_eq!(app.db.revoke_device_share(did, friend).await.unwrap(), 1);
+        assert_eq!(app.db.revoke_tag_share(tid, friend).await.unwrap(), 1);
+        assert_eq!(app.db.device_permission(did, friend).await.unwrap(), None);
+        assert_eq!(
+            app.db.device_permission(did, uid).await.unwrap(),
+            Some(Permission::Owner)
+        );
+
+        // organizations
+        let oid = app
+            .db
+            .create_org(&format!("org_{}", Uuid::new_v4()), uid)
+            .await
+            .expect("Create organization failed");
+        assert_eq!(
+            app.db.get_member_role(oid, uid).await.unwrap(),
+            Some(OrgRole::Owner)
+        );
+        app.db
+            .set_member(&NewMembership {
+                oid,
+                uid: friend,
+                role: OrgRole::Viewer,
+            })
+            .await
+            .expect("Add member failed");
+        assert_eq!(app.db.get_org_members(oid).await.unwrap().len(), 2);
+        assert!(app
+            .db
+            .get_member_orgs(friend)
+            .await
+            .unwrap()
+            .iter()
+            .any(|member| member.organization.id == oid && member.role == OrgRole::Viewer));
+        // what a member creates belongs to the organization, not to the member
+        app.db
+            .set_member(&NewMembership {
+                oid,
+                uid: friend,
+                role: OrgRole::Member,
+            })
+            .await
+            .expect("Change role failed");
+        let org_topic = format!("api-key-for-me/yyy/test{}", Uuid::new_v4());
+        let org_did = app
+            .db
+            .add_device(
+                &NewDevice {
+                    uid: friend,
+                    oid,
+                    name: "OrgDevice",
+                    desc: None,
+                    dtype: 1,
+                    latitude: None,
+                    longitude: None,
+                    topic: &org_topic,
+                    heartbeat_timeout: None,
+                    locate_from_payload: None,
+                    metadata: None,
+                },
+                &BTreeMap::new(),
+            )
+            .await
+            .expect("Create org device failed");
+        let org_tid = app
+            .db
+            .add_tag(&NewTag {
+                uid: friend,
+                oid,
+                name: &format!("tag_{}", Uuid::new_v4()),
+                desc: None,
+                activated: true,
+                parent: None,
+            })
+            .await
+            .expect("Create org tag failed");
+        assert_eq!(
+            app.db.device_permission(org_did, friend).await.unwrap(),
+            Some(Permission::Write)
+        );
+        assert_eq!(
+            app.db.device_permission(org_did, uid).await.unwrap(),
+            Some(Permission::Owner)
+        );
+        assert_eq!(app.db.remove_member(oid, friend).await.unwrap(), 1);
+        assert_eq!(app.db.get_member_role(oid, friend).await.unwrap(), None);
+        assert_eq!(
+            app.db.device_permission(org_did, friend).await.unwrap(),
+            None
+        );
+        assert_eq!(app.db.tag_permission(org_tid, friend).await.unwrap(), None);
+        assert!(app
+            .db
+            .permitted_dids(friend, &[org_did], Permission::Read)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // transfers
+        let now = Utc::now().naive_utc();
+        let xid = app
+            .db
+            .create_transfer(
+                &NewDeviceTransfer {
+                    did,
+                    from_uid: uid,
+                    to_uid: friend,
+                    with_records: false,
+                },
+                &now,
+            )
+            .await
+            .expect("Create transfer failed");
+        let transfer = app.db.accept_transfer(xid, &now).await.unwrap();
+        assert_eq!(transfer.status, TransferStatus::Accepted);
+        assert!(app.db.accept_transfer(xid, &now).await.is_err());
+        let moved = app.db.get_device_by_id(did).await.unwrap();
+        assert_eq!(moved.uid, friend);
+        assert_eq!(moved.oid, app.db.get_personal_org(friend).await.unwrap().id);
+        assert!(app.db.get_device_records(did).await.unwrap().is_empty());
+        assert!(app.db.get_dids_under_tags(&[tid]).await.unwrap().is_empty());
+        // nothing of the previous owner is handed over
+        assert!(app
+            .db
+            .get_alerts_of_devices(&[did], false)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(app
+            .db
+            .get_presence_events(did, 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(app.db.get_device_labels(&[did]).await.unwrap().is_empty());
+        assert_eq!(app.db.get_user_transfers(uid).await.unwrap().len(), 1);
+
+        // trash
+        let placeholder = Uuid::new_v4().to_string();
+        let now = Utc::now().naive_utc();
+        let offered = app
+            .db
+            .create_transfer(
+                &NewDeviceTransfer {
+                    did,
+                    from_uid: friend,
+                    to_uid: uid,
+                    with_records: true,
+                },
+                &now,
+            )
+            .await
+            .expect("Create transfer failed");
+        assert_eq!(
+            app.db.trash_device(did, &placeholder, &now).await.unwrap(),
+            1
+        );
+        // a deleted device is not handed over
+        assert_eq!(
+            app.db.get_transfer_by_id(offered).await.unwrap().status,
+            TransferStatus::Cancelled
+        );
+        assert!(app.db.accept_transfer(offered, &now).await.is_err());
+        let trashed = app.db.get_device_by_id(did).await.unwrap();
+        assert_eq!(trashed.topic, placeholder);
+        assert!(trashed.trashed_at.is_some() && !trashed.activated);
+        assert_eq!(
+            trashed.original_topic.as_deref(),
+            Some(moved.topic.as_str())
+        );
+        assert!(app
+            .db
+            .get_trashed_devices(moved.oid)
+            .await
+            .unwrap()
+            .iter()
+            .any(|device| device.id == did));
+        assert_eq!(app.db.restore_device(did).await.unwrap(), 1);
+        let restored = app.db.get_device_by_id(did).await.unwrap();
+        assert_eq!((restored.activated, restored.topic), (true, moved.topic));
+        assert!(restored.trashed_at.is_none());
+        assert!(!app.db.purge_device(did).await.unwrap());
+        // deactivated is not deleted
+        app.db
+            .update_device(
+                &UpdateDevice {
+                    id: did,
+                    name: None,
+                    desc: None,
+                    dtype: None,
+                    latitude: None,
+                    longitude: None,
+                    last_update: None,
+                    activated: Some(false),
+                    topic: None,
+                    heartbeat_timeout: None,
+                    locate_from_payload: None,
+                    metadata: None,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(app
+            .db
+            .get_trashed_devices(moved.oid)
+            .await
+            .unwrap()
+            .iter()
+            .all(|device| device.id != did));
+        assert!(!app.db.purge_device(did).await.unwrap());
+        app.db.trash_device(did, &placeholder, &now).await.unwrap();
+        // a purge that started, then failed, can no longer be undone but can be run again
+        {
+            use crate::schema::device;
+            use diesel::{ExpressionMethods, QueryDsl};
+            let mut conn = app.db.pool.get().await.unwrap();
+            diesel::update(device::table.filter(device::id.eq(did)))
+                .set(device::purging.eq(true))
+                .execute(&mut conn)
+                .await
+                .unwrap();
+        }
+        assert_eq!(app.db.restore_device(did).await.unwrap(), 0);
+        assert!(app.db.purge_device(did).await.unwrap());
+        assert!(app.db.get_device_by_id(did).await.is_err());
+
+        // sessions
+        let now = Utc::now().naive_utc();
+        let expires = now + chrono::Duration::hours(1);
+        let (jti_a, jti_b) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let (hash_a, hash_b) = (hash_token(&jti_a), hash_token(&jti_b));
+        let mut sids = vec![];
+        for (hash, jti) in [(&hash_a, &jti_a), (&hash_b, &jti_b)] {
+            sids.push(
+                app.db
+                    .create_session(&NewSession {
+                        uid,
+                        refresh_hash: hash,
+                        jti,
+                        org: None,
+                        user_agent: Some("test"),
+                        ip: None,
+                        expires_at: &expires,
+                    })
+                    .await
+                    .expect("Create session failed"),
+            );
+        }
+        let session = app.db.get_session_by_refresh(&hash_a, &now).await.unwrap();
+        assert_eq!(
+            (session.id, session.jti.as_str()),
+            (sids[0], jti_a.as_str())
+        );
+        let rotated = hash_token("rotated");
+        assert_eq!(
+            app.db
+                .rotate_session(sids[0], &hash_a, &rotated, None, &now, &expires)
+                .await
+                .unwrap(),
+            1
+        );
+        // the old refresh token is single-use
+        assert_eq!(
+            app.db
+                .rotate_session(sids[0], &hash_a, &rotated, None, &now, &expires)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(app.db.get_session_by_refresh(&hash_a, &now).await.is_err());
+        assert_eq!(app.db.get_user_sessions(uid, &now).await.unwrap().len(), 2);
+        assert_eq!(
+            app.db
+                .revoke_sessions(uid, RevokeScope::Others(sids[0]), &now, &expires)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(app.db.is_token_revoked(&jti_b).await.unwrap());
+        assert!(!app.db.is_token_revoked(&jti_a).await.unwrap());
+        assert!(app.db.get_session_by_refresh(&hash_b, &now).await.is_err());
+        assert_eq!(
+            app.db
+                .revoke_sessions(uid, RevokeScope::All, &now, &expires)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(app.db.is_token_revoked(&jti_a).await.unwrap());
+        assert!(app
+            .db
+            .get_user_sessions(uid, &now)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // api keys
+        let key = new_api_key();
+        let kid = app
+            .db
+            .create_api_key(&NewApiKey {
+                uid,
+                oid: personal.id,
+                name: "test",
+                prefix: visible_prefix(&key),
+                key_hash: &hash_token(&key),
+                scopes: "read_records,write_records",
+                allowed_ips: None,
+                expires_at: Some(&expires),
+            })
+            .await
+            .expect("Create api key failed");
+        let (found, owner) = app
+            .db
+            .get_user_by_api_key(&hash_token(&key), &now)
+            .await
+            .unwrap();
+        assert_eq!((found.id, found.oid, owner.id), (kid, personal.id, uid));
+        assert!(found.permits(ApiKeyScope::WriteRecords));
+        // expired
+        assert!(app
+            .db
+            .get_user_by_api_key(&hash_token(&key), &expires)
+            .await
+            .is_err());
+        assert_eq!(app.db.touch_api_key(kid, &now).await.unwrap(), 1);
+        assert_eq!(app.db.touch_api_key(kid, &now).await.unwrap(), 0);
+        assert_eq!(app.db.revoke_api_key(kid, uid + 1, &now).await.unwrap(), 0);
+        assert_eq!(app.db.revoke_api_key(kid, uid, &now).await.unwrap(), 1);
+        assert!(app
+            .db
+            .get_user_by_api_key(&hash_token(&key), &now)
+            .await
+            .is_err());
+        assert!(app.db.get_user_api_keys(uid).await.unwrap().is_empty());
+
+        // totp
+        assert_eq!(app.db.enroll_totp(uid, &new_secret()).await.unwrap(), 1);
+        // not enabled before confirmed
+        assert_eq!(app.db.use_totp_step(uid, 1).await.unwrap(), 0);
+        // restart the enrollment
+        let secret = new_secret();
+        assert_eq!(app.db.enroll_totp(uid, &secret).await.unwrap(), 1);
+        let totp = app.db.get_totp(uid).await.unwrap();
+        assert_eq!(
+            (totp.secret.as_str(), totp.confirmed_at),
+            (secret.as_str(), None)
+        );
+        let codes = new_recovery_codes();
+        let hashes: Vec<_> = codes.iter().map(|c| hash_recovery_code(c)).collect();
+        assert_eq!(
+            app.db.confirm_totp(uid, 100, &hashes, &now).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            app.db.confirm_totp(uid, 100, &hashes, &now).await.unwrap(),
+            0
+        );
+        assert!(app.db.enroll_totp(uid, &new_secret()).await.is_err());
+        // a code works once
+        assert_eq!(app.db.use_totp_step(uid, 100).await.unwrap(), 0);
+        assert_eq!(app.db.use_totp_step(uid, 101).await.unwrap(), 1);
+        assert_eq!(
+            app.db
+                .use_recovery_code(uid, &hashes[0], &now)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            app.db
+                .use_recovery_code(uid, &hashes[0], &now)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(app.db.count_recovery_codes(uid).await.unwrap(), 9);
+        assert_eq!(app.db.delete_totp(uid).await.unwrap(), 1);
+        assert!(app.db.get_totp(uid).await.is_err());
+        assert_eq!(app.db.count_recovery_codes(uid).await.unwrap(), 0);
+
+        // identities
+        let sso_email = format!("sso{}@example.com", Uuid::new_v4().simple());
+        let sso_uid = app
+            .db
+            .register_identity_user(
+                &NewUser {
+                    username: &sso_email,
+                    email: &sso_email,
+                    hashed_password: "unusable",
+                    privilege: UserPrivilege::Normal as u32,
+                },
+                "https://sso.example.com",
+                &sso_email,
+            )
+            .await
+            .expect("Register identity user failed");
+        let sso_user = app
+            .db
+            .get_user_by_identity("https://sso.example.com", &sso_email)
+            .await
+            .unwrap();
+        assert_eq!(sso_user.id, sso_uid);
+        assert!(sso_user.activated);
+        assert!(app
+            .db
+            .get_user_by_identity("https://other.example.com", &sso_email)
+            .await
+            .is_err());
+        app.db
+            .link_identity(&NewIdentity {
+                uid,
+                issuer: "https://other.example.com",
+                subject: &sso_email,
+            })
+            .await
+            .expect("Link identity failed");
+        assert_eq!(
+            app.db
+                .get_user_by_identity("https://other.example.com", &sso_email)
+                .await
+                .unwrap()
+                .id,
+            uid
+        );
+        // an identity is linked to one user only
+        assert!(app
+            .db
+            .link_identity(&NewIdentity {
+                uid: sso_uid,
+                issuer: "https://other.example.com",
+                subject: &sso_email,
+            })
+            .await
+            .is_err());
     }
     #[tokio::test]
     async fn racing() {
         let config = &CONFIG;
+        let db = DBClient::new(&DBClient::get_database_url());
         let app: AppState = AppState {
             env: config,
-            db: DBClient::new(&DBClient::get_database_url()),
+            tokens: OneTimeTokens::new(&config.one_time_token, &config.jwt.secret, &db),
+            db,
             rate_limit: Cache::new(1024),
-            one_time_code: Cache::new(1024),
+            login_guard: LoginGuard::new(&config.login),
+            request_limit: RequestLimiter::new(&config.rate_limit),
+            totp_challenge: Cache::new(1024),
+            hub: RecordHub::new(),
         };
         let mut conn = app.db.pool.get().await.unwrap();
 
@@ -503,12 +3798,9 @@ mod tests {
                     .register_user(&NewUser {
                         username: &format!("racing{}{}", i, Uuid::new_v4()),
                         email: &format!("kisa{}ma@mail.com", Uuid::new_v4()),
-                        hashed_password: &get_pwd_hash(
-                            &app.env.riot.password_salt.as_bytes(),
-                            "Aaa123,????".as_bytes(),
-                        ),
+                        hashed_password: &get_pwd_hash("Aaa123,????".as_bytes(), &app.env.password)
+                            .unwrap(),
                         privilege: UserPrivilege::Normal as u32,
-                        api_key: None,
                     })
                     .await
                     .expect("Register failed");
@@ -1,6 +1,6 @@
 use std::{error::Error, fmt};
 
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{http::header, HttpResponse, ResponseError};
 use serde::{Deserialize, Serialize};
 
 use crate::models::Response;
@@ -42,6 +42,7 @@ pub enum ErrorMessage {
     TokenNotProvided,
     PermissionDenied,
     TooFast,
+    AccountLocked,
 }
 
 impl ToString for ErrorMessage {
@@ -89,6 +90,9 @@ impl ErrorMessage {
                 "You are not allowed to perform this action (resources not owned, or higher privilege required)".into()
             },
             ErrorMessage::TooFast => {"Your access is too frequent.".into()}
+            ErrorMessage::AccountLocked => {
+                "Too many failed logins, the account is locked for a while. Check your email to unlock it now".into()
+            }
         }
     }
 }
@@ -150,6 +154,17 @@ impl HttpError {
     }
 }
 
+impl HttpError {
+    /// The error response, telling the client to retry `secs` later
+    pub fn retry_after(&self, secs: u64) -> HttpResponse {
+        let mut response = self.error_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, secs.into());
+        response
+    }
+}
+
 impl fmt::Display for HttpError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -1,18 +1,24 @@
 use std::ops::Deref;
 
-use actix_web::{get, post, put, web, HttpResponse, Responder, ResponseError};
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use chrono::{Duration, Utc};
 use diesel::result::{DatabaseErrorKind, Error as DieselErr};
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, ToSchema};
-use uuid::Uuid;
 use validator::{Validate, ValidationError};
 
 use crate::{
+    app_context::{client_of, REFRESH_COOKIE},
+    db::RevokeScope,
     errors::{ErrorMessage, HttpError},
-    middlewares::{AuthenticatedUser, RequireAuth},
-    models::{NewUser, Response, UpdateUser, UserPrivilege},
-    utils::password::{get_pwd_hash, verify},
+    middlewares::{AuthenticatedUser, CurrentSession, RequireAuth},
+    models::{ActiveSession, NewUser, Response, UpdateUser, UserPrivilege},
+    utils::{
+        jwt::{hash_token, new_refresh_token},
+        one_time_token::TokenPurpose,
+        password::{get_pwd_hash, needs_rehash, verify},
+    },
     AppState,
 };
 
@@ -28,6 +34,13 @@ struct OneTimeCode {
     code: String,
 }
 
+#[derive(Deserialize, IntoParams)]
+/// Params in path, from the link confirming a new email
+struct EmailChangeCode {
+    email: String,
+    code: String,
+}
+
 fn validate_username(password: &str) -> Result<(), ValidationError> {
     let is_valid_username = password.chars().all(|c| c.is_alphanumeric());
 
@@ -96,13 +109,82 @@ pub struct UpdateUserForm {
         custom = "validate_username"
     )]
     pub username: Option<String>,
+    /// Set once confirmed with the link emailed to it
     #[validate(email)]
     pub email: Option<String>,
+    /// Required to change the email
+    pub current_password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to ask for a password reset email
+pub struct ForgotPasswordForm {
+    #[serde(alias = "username")]
+    #[serde(alias = "email")]
+    /// aliases: `username` `email`
+    pub account: String,
+}
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to set a new password with the emailed reset code
+pub struct ResetPasswordForm {
+    pub code: String,
+    #[validate(
+        length(min = 8, max = 64, message = "Password must be 8-64 characters"),
+        custom = "validate_pwd"
+    )]
+    pub password: String,
+}
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to change the password of the current user
+pub struct ChangePasswordForm {
+    pub current_password: String,
     #[validate(
         length(min = 8, max = 64, message = "Password must be 8-64 characters"),
         custom = "validate_pwd"
     )]
-    pub password: Option<String>,
+    pub new_password: String,
+}
+
+/// Response to a login of a user with 2FA enabled, to be continued at `/accounts/login/totp`
+fn totp_required(token: String) -> HttpResponse {
+    HttpResponse::Accepted().json(Response {
+        status: "totp_required",
+        message: token,
+    })
+}
+
+/// Issue a one-time token of the user, return: the link at `path` with the token as code,
+/// `None` (logged) if the token could not be stored
+async fn one_time_link(
+    app: &AppState,
+    purpose: TokenPurpose<'_>,
+    uid: u64,
+    path: &str,
+) -> Option<String> {
+    match app.tokens.issue(purpose, uid).await {
+        Ok(code) => {
+            let sep = if path.contains('?') { '&' } else { '?' };
+            let link = app.env.riot.host.to_string() + &format!("{path}{sep}code={code}");
+            debug!("OTC link = {link}");
+            Some(link)
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            None
+        }
+    }
+}
+
+/// Email a one-time link to unlock the account locked after failed logins
+pub(crate) async fn send_unlock_link(app: &AppState, uid: u64, email: &str) {
+    let Some(unlock_link) = one_time_link(app, TokenPurpose::Unlock, uid, "/unlock").await else {
+        return;
+    };
+    if let Err(e) = app.send_unlock_mail(email, &unlock_link).await {
+        error!("{}", e);
+    }
 }
 
 // account reg/login
@@ -145,13 +227,18 @@ pub(crate) async fn user_register(
         email,
         password,
     } = form.into_inner();
-    let api_key = Uuid::new_v4().to_string();
+    let hashed_password = match get_pwd_hash(password.as_bytes(), &app.env.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::HashingError).error_response();
+        }
+    };
     let user = NewUser {
         username: &username.unwrap_or_else(|| email.clone()), // Better performance when using lazy calc!
         email: &email,
-        hashed_password: &get_pwd_hash(app.env.riot.password_salt.as_bytes(), password.as_bytes()),
+        hashed_password: &hashed_password,
         privilege: UserPrivilege::Normal as u32,
-        api_key: Some(&api_key),
     };
 
     match app.db.register_user(&user).await {
@@ -169,6 +256,40 @@ pub(crate) async fn user_register(
     }
 }
 
+/// Whether the password matches the stored hash, a malformed hash matches nothing
+fn check_password(uid: u64, hash: &str, password: &str) -> bool {
+    verify(hash, password.as_bytes()).unwrap_or_else(|e| {
+        error!("Malformed password hash of user {}: {:?}", uid, e);
+        false
+    })
+}
+
+/// Replace a hash made with outdated parameters, the old one is kept if it fails
+async fn rehash_password(app: &AppState, uid: u64, password: &str) {
+    let hashed_password = match get_pwd_hash(password.as_bytes(), &app.env.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("{:?}", e);
+            return;
+        }
+    };
+    match app
+        .db
+        .update_user(&UpdateUser {
+            id: uid,
+            username: None,
+            email: None,
+            hashed_password: Some(&hashed_password),
+            privilege: None,
+            activated: None,
+        })
+        .await
+    {
+        Ok(_) => info!("Upgraded the password hash of user {}", uid),
+        Err(e) => error!("{:?}", e),
+    }
+}
+
 #[utoipa::path(
         post,
         context_path = "/api",
@@ -181,7 +302,9 @@ pub(crate) async fn user_register(
         ),
         responses(
             (status = 200, description = "Success and return user token in message, set the token Cookie", body = User),
+            (status = 202, description = "2FA enabled: status = `totp_required`, message = the challenge token for `/accounts/login/totp`", body = Response),
             (status = 403, description = "Failed: wrong credentials or suspended/non-valid account ", body = Response),
+            (status = 429, description = "Too many failed logins of the account (locked, an unlock link is emailed) or from the IP, retry after `Retry-After` seconds", body = Response),
             (status = 500, description = "Internal error, contact web admin", body = Response)
         ),
         params(),
@@ -195,51 +318,84 @@ pub(crate) async fn user_register(
 ///
 /// To login with an email, use `send_verification` endpoint instead
 pub(crate) async fn user_login(
+    req: HttpRequest,
     form: web::Json<LoginForm>,
     app: web::Data<AppState>,
 ) -> impl Responder {
     let LoginForm { account, password } = form.into_inner();
+    let (_, ip) = client_of(&req, &app.env.riot.trusted_proxies);
+
+    let user = match app.db.get_user_by_username_or_email(&account).await {
+        Ok(user) => Some(user),
+        Err(DieselErr::NotFound) => None,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let uid = user.as_ref().map(|user| user.id);
+    // Counted as a failure until the password is checked right
+    let attempt = match app.login_guard.begin(uid, ip.as_deref()).await {
+        Ok(attempt) => attempt,
+        Err(refusal) => {
+            info!("Login of {} from {:?} refused: {:?}", account, ip, refusal);
+            return refusal.error_response();
+        }
+    };
 
     // ! NOTE: We MUST perform this hash comparison using a special function provided in the library, otherwise
     // ! it can be vulnerable to time-based attacks.
-
-    match app.db.get_user_by_username_or_email(&account).await {
-        Ok(user) => {
-            debug!(
-                "{:?}, provided={:?}--{:?}",
-                user,
-                account.as_bytes(),
-                password.as_bytes()
-            );
-            if verify(&user.password, password.as_bytes()) {
-                let jwt_cookie = app.get_jwt_cookie(user.id);
-                if user.activated {
-                    let mut user = user.clone();
-                    user.password = "".into();
-                    HttpResponse::Ok().cookie(jwt_cookie.clone()).json(user)
-                } else {
-                    // TODO: rate limit
-                    let code = Uuid::new_v4();
-                    app.one_time_code.insert(code.to_string(), user.id).await;
-                    let verify_link =
-                        app.env.riot.host.to_string() + &format!("/verify?code={code}");
-                    debug!("OTC link = {verify_link}");
-                    if let Err(e) = app.send_verify_mail(&user.email, &verify_link).await {
-                        error!("{}", e);
-                    }
-                    HttpError::permission_denied(ErrorMessage::UserNotActivated).error_response()
-                }
-            } else {
-                HttpError::permission_denied(ErrorMessage::WrongCredentials).error_response()
+    let user = match user {
+        Some(user) if check_password(user.id, &user.password, &password) => user,
+        user => {
+            if let Some(user) = user.filter(|_| attempt.locks) {
+                info!("User {} locked after failed logins", user.id);
+                send_unlock_link(&app, user.id, &user.email).await;
             }
+            return HttpError::permission_denied(ErrorMessage::WrongCredentials).error_response();
         }
-        Err(DieselErr::NotFound) => {
-            HttpError::permission_denied(ErrorMessage::WrongCredentials).error_response()
+    };
+    app.login_guard.succeed(&attempt).await;
+    if needs_rehash(&user.password, &app.env.password) {
+        rehash_password(&app, user.id, &password).await;
+    }
+    if user.activated {
+        // The failures are kept until the second factor is right too
+        match app.totp_challenge_of(user.id).await {
+            Ok(Some(token)) => return totp_required(token),
+            Ok(None) => {}
+            Err(e) => {
+                error!("{:?}", e);
+                return HttpError::server_error(ErrorMessage::ServerError).error_response();
+            }
         }
-        Err(e) => {
-            error!("{:?}", e);
-            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        app.login_guard.reset(user.id).await;
+        let [jwt_cookie, refresh_cookie] = match app.start_session(user.id, None, &req).await {
+            Ok(cookies) => cookies,
+            Err(e) => {
+                error!("{:?}", e);
+                return HttpError::server_error(ErrorMessage::ServerError).error_response();
+            }
+        };
+        let mut user = user.clone();
+        user.password = "".into();
+        HttpResponse::Ok()
+            .cookie(jwt_cookie)
+            .cookie(refresh_cookie)
+            .json(user)
+    } else {
+        // TODO: rate limit
+        if let Some(verify_link) =
+            one_time_link(&app, TokenPurpose::VerifyEmail, user.id, "/verify").await
+        {
+            if let Err(e) = app
+                .send_verify_mail(&user.email, &verify_link, TokenPurpose::VerifyEmail)
+                .await
+            {
+                error!("{}", e);
+            }
         }
+        HttpError::permission_denied(ErrorMessage::UserNotActivated).error_response()
     }
 }
 
@@ -254,24 +410,242 @@ pub(crate) async fn user_login(
     params(),
     security(
         ("jwt_header" = []),
-        ("jwt_cookie" = [])
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
     )
 )]
 #[get(
     "/accounts/logout",
     wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
 )]
-/// Log out
-pub(crate) async fn user_logout() -> impl Responder {
-    let cookie = actix_web::cookie::Cookie::build("token", "")
-        .path("/")
-        .max_age(actix_web::cookie::time::Duration::new(0, 0))
-        .http_only(true)
-        .finish();
-    HttpResponse::Ok().cookie(cookie).json(Response {
-        status: "ok",
-        message: "".to_string(),
-    })
+/// Log out, the session is revoked on the server too
+pub(crate) async fn user_logout(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    session: Option<CurrentSession>,
+) -> impl Responder {
+    if let Some(session) = session {
+        if let Err(e) = app
+            .revoke_sessions(cur_user.id, RevokeScope::One(session.id))
+            .await
+        {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    let [jwt_cookie, refresh_cookie] = AppState::clear_session_cookies();
+    HttpResponse::Ok()
+        .cookie(jwt_cookie)
+        .cookie(refresh_cookie)
+        .json(Response {
+            status: "ok",
+            message: "".to_string(),
+        })
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/accounts/refresh",
+    tag = "Account",
+    responses(
+        (status = 200, description = "New access token in message and the token Cookie, the refresh Cookie is rotated", body = Response),
+        (status = 401, description = "Missing, expired or revoked refresh token", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("refresh_cookie" = [])
+    )
+)]
+#[post("/accounts/refresh")]
+/// Get a new access token with the refresh token (the `refresh` cookie).
+///
+/// The refresh token is single-use: a new one replaces it in the cookie.
+pub(crate) async fn refresh_token(req: HttpRequest, app: web::Data<AppState>) -> impl Responder {
+    let Some(refresh) = req.cookie(REFRESH_COOKIE) else {
+        return HttpError::new(ErrorMessage::InvalidToken, 401).error_response();
+    };
+    let old_hash = hash_token(refresh.value());
+    let now = Utc::now().naive_utc();
+    let session = match app.db.get_session_by_refresh(&old_hash, &now).await {
+        Ok(session) => session,
+        Err(DieselErr::NotFound) => {
+            return HttpError::new(ErrorMessage::InvalidToken, 401).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let new_refresh = new_refresh_token();
+    let (_, ip) = client_of(&req, &app.env.riot.trusted_proxies);
+    match app
+        .db
+        .rotate_session(
+            session.id,
+            &old_hash,
+            &hash_token(&new_refresh),
+            ip.as_deref(),
+            &now,
+            &(now + Duration::seconds(app.env.jwt.maxage)),
+        )
+        .await
+    {
+        Ok(1) => {}
+        // Rotated by a concurrent request
+        Ok(_) => return HttpError::new(ErrorMessage::InvalidToken, 401).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    let jwt_cookie = app.get_jwt_cookie(session.uid, session.org, session.id, &session.jti);
+    HttpResponse::Ok()
+        .cookie(jwt_cookie.clone())
+        .cookie(app.get_refresh_cookie(new_refresh))
+        .json(Response {
+            status: "ok",
+            message: jwt_cookie.value().to_string(),
+        })
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/accounts/sessions",
+    tag = "Account",
+    responses(
+        (status = 200, description = "Active sessions of the current user, most recently seen first", body = Vec<ActiveSession>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/accounts/sessions",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List the devices/browsers the current user is logged in on
+pub(crate) async fn user_sessions(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    cur_session: Option<CurrentSession>,
+) -> impl Responder {
+    let current = cur_session.map(|session| session.id);
+    match app
+        .db
+        .get_user_sessions(cur_user.id, &Utc::now().naive_utc())
+        .await
+    {
+        Ok(sessions) => HttpResponse::Ok().json(
+            sessions
+                .into_iter()
+                .map(|session| ActiveSession {
+                    current: Some(session.id) == current,
+                    session,
+                })
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    context_path = "/api",
+    path = "/accounts/sessions/{sid}",
+    tag = "Account",
+    responses(
+        (status = 200, description = "Session revoked, its tokens stop working immediately", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Session was not found or already revoked", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[delete(
+    "/accounts/sessions/{sid}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Log out one session of the current user
+pub(crate) async fn revoke_session(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let sid = path.into_inner();
+    match app
+        .revoke_sessions(cur_user.id, RevokeScope::One(sid))
+        .await
+    {
+        Ok(0) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Ok(_) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    context_path = "/api",
+    path = "/accounts/sessions",
+    tag = "Account",
+    responses(
+        (status = 200, description = "All sessions revoked (the current one too), message = number of sessions", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[delete(
+    "/accounts/sessions",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Log out everywhere
+pub(crate) async fn revoke_all_sessions(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    match app.revoke_sessions(cur_user.id, RevokeScope::All).await {
+        Ok(n) => {
+            let [jwt_cookie, refresh_cookie] = AppState::clear_session_cookies();
+            HttpResponse::Ok()
+                .cookie(jwt_cookie)
+                .cookie(refresh_cookie)
+                .json(Response {
+                    status: "ok",
+                    message: n.to_string(),
+                })
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
 }
 
 #[utoipa::path(
@@ -286,7 +660,9 @@ pub(crate) async fn user_logout() -> impl Responder {
         params(),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[get("/accounts/user_info", wrap = "RequireAuth::no_auth()")]
@@ -317,25 +693,33 @@ pub(crate) async fn user_info(cur_user: Option<AuthenticatedUser>) -> impl Respo
             {
                 "username": "new_name",
                 "email": "new_email@example.com",
-                "password": "raw!pass.word!",
+                "current_password": "pass.!w0rd",
             })
     ),
     responses(
         (status = 200, description = "Ok", body = Response),
+        (status = 202, description = "The username is updated, the new email is to be confirmed from the link sent to it", body = Response),
         (status = 304, description = "No change to be done", body = Response),
         (status = 401, description = "Not logged in", body = Response),
+        (status = 403, description = "Wrong or missing current password, to change the email", body = Response),
         (status = 500, description = "Server internal error", body = Response),
     ),
     security(
         ("jwt_header" = []),
-        ("jwt_cookie" = [])
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
     )
 )]
 #[put(
     "/accounts/user_info",
     wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
 )]
-/// Update user personal info(email/username/password)
+/// Update user personal info(email/username)
+///
+/// Changing the email takes the current password, the new address is only set once confirmed
+/// from the link sent to it (`GET /accounts/email/confirm`); the current one is notified.
+/// To change the password, use `PUT /accounts/password`
 pub(crate) async fn upd_user_info(
     app: web::Data<AppState>,
     cur_user: AuthenticatedUser,
@@ -349,20 +733,119 @@ pub(crate) async fn upd_user_info(
     let UpdateUserForm {
         username,
         email,
-        password,
+        current_password,
     } = form.into_inner();
+    let new_email = email.filter(|email| *email != cur_user.email);
+    if new_email.is_some()
+        && !current_password
+            .is_some_and(|password| check_password(cur_user.id, &cur_user.password, &password))
+    {
+        return HttpError::permission_denied(ErrorMessage::WrongCredentials).error_response();
+    }
+    if username.is_some() || new_email.is_none() {
+        match app
+            .db
+            .update_user(&UpdateUser {
+                id: cur_user.id,
+                username: username.as_deref(),
+                email: None,
+                hashed_password: None,
+                privilege: None,
+                activated: None,
+            })
+            .await
+        {
+            Ok(_) => {}
+            Err(diesel::result::Error::QueryBuilderError(_)) => {
+                return HttpError::not_modified(ErrorMessage::NoChange).error_response()
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                return HttpError::server_error(ErrorMessage::ServerError).error_response();
+            }
+        }
+    }
+    let Some(new_email) = new_email else {
+        return HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        });
+    };
+    let path = format!(
+        "/api/accounts/email/confirm?{}",
+        form_urlencoded::Serializer::new(String::new())
+            .append_pair("email", &new_email)
+            .finish()
+    );
+    let Some(confirm_link) = one_time_link(
+        &app,
+        TokenPurpose::ChangeEmail(&new_email),
+        cur_user.id,
+        &path,
+    )
+    .await
+    else {
+        return HttpError::server_error(ErrorMessage::ServerError).error_response();
+    };
+    if let Err(e) = app.send_email_change_mail(&new_email, &confirm_link).await {
+        error!("{}", e);
+        return HttpError::server_error(ErrorMessage::ServerError).error_response();
+    }
+    if let Err(e) = app
+        .send_email_notice_mail(&cur_user.email, &new_email)
+        .await
+    {
+        error!("{}", e);
+    }
+    HttpResponse::Accepted().json(Response {
+        status: "ok",
+        message: "Confirm the new email with the link sent to it.".into(),
+    })
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/accounts/email/confirm",
+    tag = "Account",
+    params(EmailChangeCode),
+    responses(
+        (status = 200, description = "Ok, the email is changed", body = Response),
+        (status = 403, description = "Invalid or expired code", body = Response),
+        (status = 409, description = "The email is used by another user", body = Response),
+        (status = 500, description = "Server internal error", body = Response),
+    )
+)]
+#[get("/accounts/email/confirm")]
+/// Confirm a new email address with the one-time code sent to it
+pub(crate) async fn confirm_email_change(
+    app: web::Data<AppState>,
+    query: web::Query<EmailChangeCode>,
+) -> impl Responder {
+    let EmailChangeCode { email, code } = query.into_inner();
+    let uid = match app
+        .tokens
+        .consume(TokenPurpose::ChangeEmail(&email), &code)
+        .await
+    {
+        Ok(Some(uid)) => uid,
+        Ok(None) => {
+            return HttpError::permission_denied(ErrorMessage::InvalidToken).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
     match app
         .db
         .update_user(&UpdateUser {
-            id: cur_user.id,
-            username: username.as_deref(),
-            email: email.as_deref(),
-            hashed_password: password
-                .map(|pwd| get_pwd_hash(app.env.riot.password_salt.as_bytes(), pwd.as_bytes()))
-                .as_deref(),
+            id: uid,
+            username: None,
+            email: Some(&email),
+            hashed_password: None,
             privilege: None,
             activated: None,
-            api_key: None,
         })
         .await
     {
@@ -370,8 +853,8 @@ pub(crate) async fn upd_user_info(
             status: "ok",
             message: "".into(),
         }),
-        Err(diesel::result::Error::QueryBuilderError(_)) => {
-            HttpError::not_modified(ErrorMessage::NoChange).error_response()
+        Err(DieselErr::DatabaseError(DatabaseErrorKind::UniqueViolation, _msg)) => {
+            HttpError::new(ErrorMessage::UserExist, 409).error_response()
         }
         Err(e) => {
             error!("{:?}", e);
@@ -409,13 +892,15 @@ pub(crate) async fn send_verification_email(
     }
     match app.db.get_user_by_username_or_email(account).await {
         Ok(user) => {
-            let code = Uuid::new_v4();
-            app.one_time_code.insert(code.to_string(), user.id).await;
-            let verify_link =
-                app.env.riot.host.to_string() + &format!("/api/accounts/verify?code={code}");
-            debug!("OTC link = {verify_link}");
-            if let Err(e) = app.send_verify_mail(&user.email, &verify_link).await {
-                error!("{}", e);
+            if let Some(verify_link) =
+                one_time_link(&app, TokenPurpose::Login, user.id, "/api/accounts/verify").await
+            {
+                if let Err(e) = app
+                    .send_verify_mail(&user.email, &verify_link, TokenPurpose::Login)
+                    .await
+                {
+                    error!("{}", e);
+                }
             }
             HttpResponse::Ok().json(Response {
                 status: "ok",
@@ -441,6 +926,7 @@ pub(crate) async fn send_verification_email(
     params(OneTimeCode),
     responses(
         (status = 200, description = "Ok, the user is now activated and logged-in", body = Response),
+        (status = 202, description = "2FA enabled: status = `totp_required`, message = the challenge token for `/accounts/login/totp`", body = Response),
         (status = 403, description = "Verification failed", body = Response),
         (status = 304, description = "No email provided", body = Response),
         (status = 500, description = "Server internal error", body = Response),
@@ -449,12 +935,23 @@ pub(crate) async fn send_verification_email(
 #[get("/accounts/verify")]
 /// Verify the email address of the user, or login by the email
 pub(crate) async fn verify_login_by_email(
+    req: HttpRequest,
     app: web::Data<AppState>,
     query: web::Query<OneTimeCode>,
 ) -> impl Responder {
     let code = &query.code;
-    dbg!(&app.one_time_code);
-    if let Some(uid) = &app.one_time_code.remove(code).await {
+    let uid = match app.tokens.consume(TokenPurpose::VerifyEmail, code).await {
+        Ok(None) => app.tokens.consume(TokenPurpose::Login, code).await,
+        verified => verified,
+    };
+    let uid = match uid {
+        Ok(uid) => uid,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    if let Some(uid) = &uid {
         // Activate the user
         app.db
             .update_user(&UpdateUser {
@@ -464,13 +961,27 @@ pub(crate) async fn verify_login_by_email(
                 hashed_password: None,
                 privilege: None,
                 activated: Some(true), // activate!
-                api_key: None,
             })
             .await
             .expect("User Activation Failed!");
-        let jwt_cookie = app.get_jwt_cookie(*uid);
+        match app.totp_challenge_of(*uid).await {
+            Ok(Some(token)) => return totp_required(token),
+            Ok(None) => {}
+            Err(e) => {
+                error!("{:?}", e);
+                return HttpError::server_error(ErrorMessage::ServerError).error_response();
+            }
+        }
+        let [jwt_cookie, refresh_cookie] = match app.start_session(*uid, None, &req).await {
+            Ok(cookies) => cookies,
+            Err(e) => {
+                error!("{:?}", e);
+                return HttpError::server_error(ErrorMessage::ServerError).error_response();
+            }
+        };
         HttpResponse::Ok()
             .cookie(jwt_cookie.clone())
+            .cookie(refresh_cookie)
             .json(Response {
                 status: "ok",
                 message: jwt_cookie.value().to_string(),
@@ -479,3 +990,239 @@ pub(crate) async fn verify_login_by_email(
         HttpError::permission_denied(ErrorMessage::InvalidToken).error_response()
     }
 }
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/accounts/unlock",
+    tag = "Account",
+    params(OneTimeCode),
+    responses(
+        (status = 200, description = "Ok, the failed logins of the account are forgotten", body = Response),
+        (status = 403, description = "Invalid or expired code", body = Response),
+    )
+)]
+#[get("/accounts/unlock")]
+/// Unlock the account locked after failed logins, with the code from the emailed link.
+/// The code works only once
+pub(crate) async fn unlock_account(
+    app: web::Data<AppState>,
+    query: web::Query<OneTimeCode>,
+) -> impl Responder {
+    let uid = match app.tokens.consume(TokenPurpose::Unlock, &query.code).await {
+        Ok(Some(uid)) => uid,
+        Ok(None) => {
+            return HttpError::permission_denied(ErrorMessage::InvalidToken).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    app.login_guard.reset(uid).await;
+    info!("User {} unlocked", uid);
+    HttpResponse::Ok().json(Response {
+        status: "ok",
+        message: "".into(),
+    })
+}
+
+#[utoipa::path(
+    put,
+    context_path = "/api",
+    path = "/accounts/password",
+    tag = "Account",
+    request_body(
+        content = ChangePasswordForm,
+        example = json!({"current_password": "pass.!w0rd", "new_password": "new.!pass0rd"})
+    ),
+    responses(
+        (status = 200, description = "Password changed, the other sessions are logged out", body = Response),
+        (status = 400, description = "Invalid new password", body = Response),
+        (status = 401, description = "Not logged in", body = Response),
+        (status = 403, description = "Wrong current password", body = Response),
+        (status = 500, description = "Server internal error", body = Response),
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[put(
+    "/accounts/password",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Change the password, confirming the current one
+pub(crate) async fn change_password(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    cur_session: Option<CurrentSession>,
+    form: web::Json<ChangePasswordForm>,
+) -> impl Responder {
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    if !check_password(cur_user.id, &cur_user.password, &form.current_password) {
+        return HttpError::permission_denied(ErrorMessage::WrongCredentials).error_response();
+    }
+    let hashed_password = match get_pwd_hash(form.new_password.as_bytes(), &app.env.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::HashingError).error_response();
+        }
+    };
+    if let Err(e) = app
+        .db
+        .update_user(&UpdateUser {
+            id: cur_user.id,
+            username: None,
+            email: None,
+            hashed_password: Some(&hashed_password),
+            privilege: None,
+            activated: None,
+        })
+        .await
+    {
+        error!("{:?}", e);
+        return HttpError::server_error(ErrorMessage::ServerError).error_response();
+    }
+    let scope = match cur_session {
+        Some(session) => RevokeScope::Others(session.id),
+        None => RevokeScope::All,
+    };
+    if let Err(e) = app.revoke_sessions(cur_user.id, scope).await {
+        error!("{:?}", e);
+    }
+    HttpResponse::Ok().json(Response {
+        status: "ok",
+        message: "".into(),
+    })
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/accounts/password/forgot",
+    tag = "Account",
+    request_body(
+        content = ForgotPasswordForm,
+        example = json!({"account": "egPerson@example.com"})
+    ),
+    responses(
+        (status = 200, description = "Ok", body = Response),
+        (status = 429, description = "Only 1 request is allowed in 60s", body = Response),
+    )
+)]
+#[post("/accounts/password/forgot")]
+/// Email a one-time password reset link to the user (specified by email/username).
+/// ONLY actually send if the user exists.
+pub(crate) async fn forgot_password(
+    app: web::Data<AppState>,
+    form: web::Json<ForgotPasswordForm>,
+) -> impl Responder {
+    let account = &form.account;
+    // Check access frequency, apart from the verification emails
+    let limit_key = format!("reset/{account}");
+    if app.rate_limit.get(&limit_key).await.is_some() {
+        return HttpError::too_many_requests(ErrorMessage::TooFast).error_response();
+    } else {
+        app.rate_limit.insert(limit_key, ()).await;
+    }
+    match app.db.get_user_by_username_or_email(account).await {
+        Ok(user) => {
+            if let Some(reset_link) = one_time_link(
+                &app,
+                TokenPurpose::ResetPassword,
+                user.id,
+                "/reset_password",
+            )
+            .await
+            {
+                if let Err(e) = app.send_reset_mail(&user.email, &reset_link).await {
+                    error!("{}", e);
+                }
+            }
+        }
+        // !Do not leak the info that the user not exists
+        Err(e) => error!("{:?}", e),
+    }
+    HttpResponse::Ok().json(Response {
+        status: "ok",
+        message: "If the user exists, the reset email has been sent.".into(),
+    })
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/accounts/password/reset",
+    tag = "Account",
+    request_body(
+        content = ResetPasswordForm,
+        example = json!({"code": "3b7f7c2e-...", "password": "new.!pass0rd"})
+    ),
+    responses(
+        (status = 200, description = "Password reset, every session is logged out", body = Response),
+        (status = 400, description = "Invalid new password", body = Response),
+        (status = 403, description = "Invalid or expired code", body = Response),
+        (status = 500, description = "Server internal error", body = Response),
+    )
+)]
+#[post("/accounts/password/reset")]
+/// Set a new password with the code from the reset email, the code works only once
+pub(crate) async fn reset_password(
+    app: web::Data<AppState>,
+    form: web::Json<ResetPasswordForm>,
+) -> impl Responder {
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    let uid = match app
+        .tokens
+        .consume(TokenPurpose::ResetPassword, &form.code)
+        .await
+    {
+        Ok(Some(uid)) => uid,
+        Ok(None) => {
+            return HttpError::permission_denied(ErrorMessage::InvalidToken).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let hashed_password = match get_pwd_hash(form.password.as_bytes(), &app.env.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::HashingError).error_response();
+        }
+    };
+    if let Err(e) = app
+        .db
+        .update_user(&UpdateUser {
+            id: uid,
+            username: None,
+            email: None,
+            hashed_password: Some(&hashed_password),
+            privilege: None,
+            activated: None,
+        })
+        .await
+    {
+        error!("{:?}", e);
+        return HttpError::server_error(ErrorMessage::ServerError).error_response();
+    }
+    if let Err(e) = app.revoke_sessions(uid, RevokeScope::All).await {
+        error!("{:?}", e);
+    }
+    HttpResponse::Ok().json(Response {
+        status: "ok",
+        message: "".into(),
+    })
+}
@@ -0,0 +1,341 @@
+use std::ops::Deref;
+
+use actix_web::{get, post, web, HttpResponse, Responder, ResponseError};
+use chrono::Utc;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, CurrentOrg, RequireAuth},
+    models::{AlertSeverity, Permission, Response},
+    utils::alerts::raise_alert,
+    UserPrivilege,
+};
+
+#[derive(Deserialize, IntoParams)]
+/// Param in query, also list resolved alerts (default: false)
+struct AlertFilter {
+    all: Option<bool>,
+}
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to raise an alert manually
+pub struct RaiseAlertForm {
+    pub severity: AlertSeverity,
+    #[validate(length(
+        min = 1,
+        max = 1024,
+        message = "Alert message must be 1~1024 characters"
+    ))]
+    pub message: String,
+}
+
+/// Alerts of the devices as a JSON response, newest first
+async fn alerts_response(app: &AppState, dids: &[u64], filter: &AlertFilter) -> HttpResponse {
+    let unresolved_only = !filter.all.unwrap_or(false);
+    match app.db.get_alerts_of_devices(dids, unresolved_only).await {
+        Ok(alerts) => HttpResponse::Ok().json(alerts),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/alerts",
+        tag = "Alert",
+        params(AlertFilter),
+        responses(
+            (status = 200, description = "Alerts of all devices of the current organization, newest first", body = Vec<Alert>),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/alerts",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List alerts of all devices of the current organization
+pub(crate) async fn user_alerts(
+    query: web::Query<AlertFilter>,
+    org: CurrentOrg,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let dids: Vec<u64> = match app.db.get_org_devices(org.id, &[]).await {
+        Ok(devices) => devices.into_iter().map(|device| device.id).collect(),
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    alerts_response(&app, &dids, &query).await
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/devices/{did}/alerts",
+        tag = "Alert",
+        params(AlertFilter),
+        responses(
+            (status = 200, description = "Alerts of the device, newest first", body = Vec<Alert>),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Device was not found or the device is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/devices/{did}/alerts",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List alerts of a device
+pub(crate) async fn device_alerts(
+    path: web::Path<u64>,
+    query: web::Query<AlertFilter>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let did = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Read)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    alerts_response(&app, &[did], &query).await
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/tags/{tid}/alerts",
+        tag = "Alert",
+        params(AlertFilter),
+        responses(
+            (status = 200, description = "Alerts of devices tagged by this tag, newest first", body = Vec<Alert>),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Tag was not found or the tag is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/tags/{tid}/alerts",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List alerts of devices tagged with this tag
+pub(crate) async fn tag_alerts(
+    path: web::Path<u64>,
+    query: web::Query<AlertFilter>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let tid = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Read)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app.db.get_visible_dids_under_tag(tid, cur_user.id).await {
+        Ok(dids) => alerts_response(&app, &dids, &query).await,
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/devices/{did}/alerts",
+    tag = "Alert",
+    request_body(
+        content = RaiseAlertForm,
+        example = json!({"severity": "critical", "message": "Temperature above 80°C"})
+    ),
+    responses(
+        (status = 200, description = "Alert raised (or merged into the same unresolved alert), message = alert id", body = Response),
+        (status = 400, description = "Bad input", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Device was not found or the device is not yours", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/devices/{did}/alerts",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Raise an alert for a device, notifying by email and webhooks unless it is a repeat
+pub(crate) async fn add_device_alert(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<RaiseAlertForm>,
+) -> impl Responder {
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    let did = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Write)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match raise_alert(&app.db, did, form.severity, &form.message).await {
+        Ok(alert) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: alert.id.to_string(),
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        post,
+        context_path = "/api",
+        path = "/alerts/{aid}/ack",
+        tag = "Alert",
+        responses(
+            (status = 200, description = "Acknowledged", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Alert was not found, not yours or already acknowledged", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[post(
+    "/alerts/{aid}/ack",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Acknowledge an alert
+pub(crate) async fn ack_alert(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let aid = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .alert_permitted(aid, cur_user.id, Permission::Write)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let now = Utc::now().naive_utc();
+    match app.db.acknowledge_alert(aid, cur_user.id, &now).await {
+        Ok(1) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        post,
+        context_path = "/api",
+        path = "/alerts/{aid}/resolve",
+        tag = "Alert",
+        responses(
+            (status = 200, description = "Resolved", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Alert was not found, not yours or already resolved", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[post(
+    "/alerts/{aid}/resolve",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Resolve an alert, the same alert raised again afterwards starts a new one
+pub(crate) async fn resolve_alert(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let aid = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .alert_permitted(aid, cur_user.id, Permission::Write)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let now = Utc::now().naive_utc();
+    match app.db.resolve_alert(aid, &now).await {
+        Ok(1) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
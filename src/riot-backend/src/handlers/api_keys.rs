@@ -0,0 +1,206 @@
+use std::{net::IpAddr, ops::Deref};
+
+use actix_web::{delete, get, post, web, HttpResponse, Responder, ResponseError};
+use chrono::{NaiveDateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, CurrentOrg, RequireAuth},
+    models::{ApiKeyScope, NewApiKey, Response},
+    utils::{
+        api_keys::{new_api_key, visible_prefix},
+        jwt::hash_token,
+    },
+    UserPrivilege,
+};
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to create an API key
+pub struct ApiKeyForm {
+    #[validate(length(min = 1, max = 256, message = "Name must be 1~256 characters"))]
+    pub name: String,
+    #[validate(length(min = 1, max = 4, message = "Grant 1~4 scopes"))]
+    pub scopes: Vec<ApiKeyScope>,
+    /// Unix timestamp in milliseconds, never expires if omitted
+    pub expires_at: Option<i64>,
+    /// IPs the key may be used from, any if omitted.
+    /// Keys restricted to IPs cannot be used over MQTT, where the client IP is unknown
+    #[validate(length(min = 1, max = 32, message = "Allow 1~32 IPs"))]
+    pub allowed_ips: Option<Vec<String>>,
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/api_keys",
+    tag = "ApiKey",
+    request_body(
+        content = ApiKeyForm,
+        example = json!({"name": "greenhouse sensors", "scopes": ["write_records"], "allowed_ips": null})
+    ),
+    responses(
+        (status = 200, description = "Key created, message = the key, shown only this once", body = Response),
+        (status = 400, description = "Invalid input", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/api_keys",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Create an API key, for devices (MQTT topic prefix) and scripts (HTTP).
+/// The key works in the current organization, as long as the user stays a member of it
+pub(crate) async fn create_api_key(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    org: CurrentOrg,
+    form: web::Json<ApiKeyForm>,
+) -> impl Responder {
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    let expires_at = match form.expires_at.map(NaiveDateTime::from_timestamp_millis) {
+        None => None,
+        Some(Some(expires_at)) if expires_at > Utc::now().naive_utc() => Some(expires_at),
+        Some(_) => return HttpError::bad_request("Expiry must be in the future").error_response(),
+    };
+    let allowed_ips = match &form.allowed_ips {
+        None => None,
+        Some(ips) => match ips
+            .iter()
+            .map(|ip| ip.trim().parse::<IpAddr>())
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(ips) => Some(
+                ips.iter()
+                    .map(IpAddr::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            Err(_) => return HttpError::bad_request("Invalid IP address").error_response(),
+        },
+    };
+    let scopes = form
+        .scopes
+        .iter()
+        .map(ApiKeyScope::as_str)
+        .collect::<Vec<_>>()
+        .join(",");
+    let key = new_api_key();
+    match app
+        .db
+        .create_api_key(&NewApiKey {
+            uid: cur_user.id,
+            oid: org.id,
+            name: &form.name,
+            prefix: visible_prefix(&key),
+            key_hash: &hash_token(&key),
+            scopes: &scopes,
+            allowed_ips: allowed_ips.as_deref(),
+            expires_at: expires_at.as_ref(),
+        })
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: key,
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/api_keys",
+    tag = "ApiKey",
+    responses(
+        (status = 200, description = "Unrevoked API keys of the current user, newest first", body = Vec<ApiKey>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/api_keys",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List API keys of the current user
+pub(crate) async fn user_api_keys(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    match app.db.get_user_api_keys(cur_user.id).await {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    context_path = "/api",
+    path = "/api_keys/{id}",
+    tag = "ApiKey",
+    responses(
+        (status = 200, description = "Key revoked", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Key was not found or already revoked", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[delete(
+    "/api_keys/{id}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Revoke an API key of the current user
+pub(crate) async fn revoke_api_key(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    match app
+        .db
+        .revoke_api_key(path.into_inner(), cur_user.id, &Utc::now().naive_utc())
+        .await
+    {
+        Ok(1) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
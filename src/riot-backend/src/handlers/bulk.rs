@@ -0,0 +1,301 @@
+use std::ops::Deref;
+
+use actix_web::{get, post, put, web, HttpResponse, Responder, ResponseError};
+use chrono::{Duration, NaiveDateTime, Utc};
+use log::{error, info};
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, RequireAuth},
+    models::{BulkResult, BulkUpdateDevice, Permission, WebhookEvent},
+    utils::{commands::publish_command, webhooks::emit_device},
+    UserPrivilege,
+};
+
+#[derive(Deserialize, IntoParams)]
+/// Params in query, time range in milliseconds since epoch (default: the last 24h)
+/// and max number of records to return (default: 1000)
+struct RecordRange {
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to update every device under a tag, at least one field is required
+pub struct BulkUpdateForm {
+    pub activated: Option<bool>,
+    pub dtype: Option<u32>,
+    #[validate(length(max = 10000, message = "Must be less than 10000 characters"))]
+    pub desc: Option<Option<String>>,
+}
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to publish a command to every active device under a tag
+pub struct BulkCommandForm {
+    #[validate(length(max = 65536, message = "Must be less than 65536 characters"))]
+    pub payload: String,
+    /// MQTT QoS, 0/1/2 (default: 1)
+    #[validate(range(max = 2, message = "QoS must be 0, 1 or 2"))]
+    pub qos: Option<u8>,
+    /// Retain the command on the broker (default: false)
+    pub retain: Option<bool>,
+}
+
+#[utoipa::path(
+    put,
+    context_path = "/api",
+    path = "/tags/{tid}/devices",
+    tag = "Tag",
+    request_body(content=BulkUpdateForm),
+    responses(
+        (status = 200, description = "Result per device under the tag", body = Vec<BulkResult>),
+        (status = 400, description = "Invalid input or nothing to update", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Tag was not found or not shared with you", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[put(
+    "/tags/{tid}/devices",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Activate/deactivate or update `dtype`/description of all devices under a tag,
+/// except those in the trash
+pub(crate) async fn bulk_update_devices(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<BulkUpdateForm>,
+) -> impl Responder {
+    let tid = path.into_inner();
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    if form.activated.is_none() && form.dtype.is_none() && form.desc.is_none() {
+        return HttpError::bad_request(ErrorMessage::NoChange).error_response();
+    }
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Write)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let changes = BulkUpdateDevice {
+        desc: form.desc.as_ref().map(|desc| desc.as_deref()),
+        dtype: form.dtype,
+        activated: form.activated,
+    };
+    match app
+        .db
+        .bulk_update_tagged_devices(tid, &changes, cur_user.id)
+        .await
+    {
+        Ok(results) => {
+            for result in results.iter().filter(|result| result.status == "ok") {
+                emit_device(&app.db, WebhookEvent::DeviceUpdated, result.did);
+            }
+            HttpResponse::Ok().json(results)
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/tags/{tid}/commands",
+    tag = "Tag",
+    request_body(content=BulkCommandForm),
+    responses(
+        (status = 200, description = "Result per device under the tag", body = Vec<BulkResult>),
+        (status = 400, description = "Invalid input", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Tag was not found or not shared with you", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/tags/{tid}/commands",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Publish a command to all active devices under a tag,
+/// each device receives it on `<topic>/$command`
+pub(crate) async fn bulk_command(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<BulkCommandForm>,
+) -> impl Responder {
+    let tid = path.into_inner();
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Write)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let qos = match form.qos.unwrap_or(1) {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    };
+    let devices = match app.db.get_devices_under_tag(tid).await {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let dids: Vec<u64> = devices.iter().map(|device| device.id).collect();
+    let writable = match app
+        .db
+        .permitted_dids(cur_user.id, &dids, Permission::Write)
+        .await
+    {
+        Ok(writable) => writable,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let mut results: Vec<BulkResult> = Vec::with_capacity(devices.len());
+    for device in devices {
+        if !writable.contains(&device.id) {
+            results.push(BulkResult {
+                did: device.id,
+                status: "skipped",
+                message: "No write permission on the device".into(),
+            });
+            continue;
+        }
+        if !device.activated {
+            results.push(BulkResult {
+                did: device.id,
+                status: "skipped",
+                message: "Device is deactivated".into(),
+            });
+            continue;
+        }
+        results.push(
+            match publish_command(
+                &device.topic,
+                form.payload.as_bytes().to_vec(),
+                qos,
+                form.retain.unwrap_or(false),
+            ) {
+                Ok(()) => BulkResult {
+                    did: device.id,
+                    status: "ok",
+                    message: String::new(),
+                },
+                Err(e) => BulkResult {
+                    did: device.id,
+                    status: "failed",
+                    message: e,
+                },
+            },
+        );
+    }
+    HttpResponse::Ok().json(results)
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/tags/{tid}/records",
+    tag = "Tag",
+    responses(
+        (status = 200, description = "Records of all devices under the tag, newest first", body = Vec<Record>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Tag was not found or not shared with you", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    params(RecordRange),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/tags/{tid}/records",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Records of all devices under a tag merged into one timeline
+pub(crate) async fn tag_records(
+    path: web::Path<u64>,
+    query: web::Query<RecordRange>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let tid = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Read)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let dids = match app.db.get_visible_dids_under_tag(tid, cur_user.id).await {
+        Ok(dids) => dids,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let now = Utc::now().naive_utc();
+    let to = query
+        .to
+        .and_then(NaiveDateTime::from_timestamp_millis)
+        .unwrap_or(now);
+    let from = query
+        .from
+        .and_then(NaiveDateTime::from_timestamp_millis)
+        .unwrap_or(to - Duration::days(1));
+    let limit = query.limit.unwrap_or(1000).clamp(1, 10000);
+    match app
+        .db
+        .get_records_of_devices(&dids, &from, &to, limit)
+        .await
+    {
+        Ok(records) => HttpResponse::Ok().json(records),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
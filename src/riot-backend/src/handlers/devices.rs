@@ -1,7 +1,15 @@
 use std::ops::Deref;
 
 use crate::{
-    models::{NewDevice, NewRecord, UpdateDevice},
+    db::{DeviceCursor, DeviceSearch, DeviceSort},
+    models::{
+        DeviceStatus, LabeledDevice, LocationSource, NewDevice, NewLocation, NewRecord, OrgRole,
+        Permission, UpdateDevice, WebhookEvent,
+    },
+    utils::{
+        tag_query::{self, descendants},
+        webhooks::emit_device,
+    },
     UserPrivilege,
 };
 use actix_web::{
@@ -9,21 +17,129 @@ use actix_web::{
     web::{self},
     HttpResponse, Responder, ResponseError,
 };
-use chrono::Utc;
+use chrono::{NaiveDateTime, Utc};
 use diesel::result::Error as DieselErr;
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
-use utoipa::ToSchema;
+use std::collections::BTreeMap;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
-use validator::Validate;
+use validator::{Validate, ValidationError};
 
 use crate::{
     errors::{ErrorMessage, HttpError},
-    middlewares::{AuthenticatedUser, RequireAuth},
+    middlewares::{AuthenticatedUser, CurrentOrg, RequireAuth},
     models::Response,
     AppState,
 };
 
+#[derive(Deserialize, IntoParams)]
+/// Params in query, filters (combined with AND), order and page of the device list
+struct DeviceFilter {
+    /// Words all found in the name or the description, full-text and case-insensitive.
+    /// Parts of words of at least two characters match too
+    q: Option<String>,
+    dtype: Option<u32>,
+    activated: Option<bool>,
+    status: Option<DeviceStatus>,
+    /// Tagged by this tag
+    tag: Option<u64>,
+    /// With `tag`, also devices under its descendant tags (default: false)
+    descendants: Option<bool>,
+    /// Boolean tag query, e.g. `site:hq AND type:sensor AND NOT retired`.
+    /// Operators: `AND`, `OR`, `NOT`, parentheses; quote names containing spaces.
+    /// A tag name also matches devices under its descendant tags.
+    tags: Option<String>,
+    /// Comma separated `key:value` labels the devices must all have, e.g. `firmware:1.2,site:hq`
+    labels: Option<String>,
+    /// `last_update` lower bound, milliseconds since epoch
+    updated_after: Option<i64>,
+    /// `last_update` upper bound, milliseconds since epoch
+    updated_before: Option<i64>,
+    /// Sort key (default: id), ties are broken by id
+    sort: Option<DeviceSort>,
+    /// Sort direction (default: asc)
+    order: Option<SortOrder>,
+    /// `X-Next-Cursor` of the previous page
+    cursor: Option<String>,
+    /// Page size (1-1000), all devices if omitted
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// Sort direction
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+const MAX_PAGE_SIZE: i64 = 1000;
+
+fn encode_cursor(cursor: &DeviceCursor) -> String {
+    hex::encode(serde_json::to_vec(cursor).unwrap_or_default())
+}
+
+fn decode_cursor(cursor: &str) -> Option<DeviceCursor> {
+    serde_json::from_slice(&hex::decode(cursor).ok()?).ok()
+}
+
+const MAX_LABELS: usize = 64;
+const MAX_METADATA_LEN: usize = 65536;
+
+fn validate_labels(labels: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    let mut err = ValidationError::new("Invalid labels");
+    if labels.len() > MAX_LABELS {
+        err.message = Some(format!("At most {MAX_LABELS} labels are allowed").into());
+        return Err(err);
+    }
+    for (key, value) in labels {
+        // no `:` nor `,`, which separate the labels of a filter
+        let is_valid_key = !key.is_empty()
+            && key.len() <= 64
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+        if !is_valid_key {
+            err.message =
+                Some("Label keys must be 1-64 characters of letters, digits or `_.-`".into());
+            return Err(err);
+        }
+        if value.chars().count() > 256 {
+            err.message = Some("Label values must be less than 256 characters".into());
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn validate_metadata(metadata: &serde_json::Value) -> Result<(), ValidationError> {
+    let mut err = ValidationError::new("Invalid metadata");
+    if !metadata.is_object() {
+        err.message = Some("Metadata must be a JSON object".into());
+        return Err(err);
+    }
+    if metadata.to_string().len() > MAX_METADATA_LEN {
+        err.message = Some(format!("Metadata must be less than {MAX_METADATA_LEN} bytes").into());
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// `a:1,b:2` => [("a", "1"), ("b", "2")]
+fn parse_label_filter(filter: &str) -> Result<Vec<(String, String)>, String> {
+    filter
+        .split(',')
+        .map(|pair| match pair.split_once(':') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(format!(
+                "Invalid label filter `{}`, expect `key:value`",
+                pair
+            )),
+        })
+        .collect()
+}
+
 #[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
 /// Web json form to add a new device
 pub struct NewDeviceForm {
@@ -40,6 +156,25 @@ pub struct NewDeviceForm {
     pub longitude: Option<f64>,
     #[validate(length(max = 512, message = "Topic must be less than 255 characters"))]
     pub topic: String, // TODO: validate topic format
+    #[validate(range(min = 10, max = 604800, message = "Heartbeat timeout must be 10s-7d"))]
+    /// Unit: seconds. Default: 600
+    pub heartbeat_timeout: Option<u32>,
+    /// Track the `lat`/`lng` fields of JSON payloads as the device's location. Default: false
+    pub locate_from_payload: Option<bool>,
+    #[validate(custom = "validate_labels")]
+    /// Key/value labels, e.g. `{"firmware": "1.2"}`
+    pub labels: Option<BTreeMap<String, String>>,
+    #[validate(custom = "validate_metadata")]
+    #[schema(value_type = Option<Object>)]
+    /// Arbitrary JSON object
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to replace all labels of a device
+pub struct DeviceLabelsForm {
+    #[validate(custom = "validate_labels")]
+    pub labels: BTreeMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
@@ -64,6 +199,15 @@ pub struct UpdateDeviceForm {
     pub longitude: Option<Option<f64>>,
     #[validate(length(max = 512, message = "Topic must be less than 255 characters"))]
     pub topic: Option<String>,
+    #[validate(range(min = 10, max = 604800, message = "Heartbeat timeout must be 10s-7d"))]
+    /// Unit: seconds
+    pub heartbeat_timeout: Option<u32>,
+    /// Track the `lat`/`lng` fields of JSON payloads as the device's location
+    pub locate_from_payload: Option<bool>,
+    #[validate(custom = "validate_metadata")]
+    #[schema(value_type = Option<Object>)]
+    /// Arbitrary JSON object, `null` to clear it
+    pub metadata: Option<Option<serde_json::Value>>,
 }
 
 #[utoipa::path(
@@ -72,28 +216,107 @@ pub struct UpdateDeviceForm {
         path = "/devices",
         tag = "Device",
         responses(
-            (status = 200, description = "Devices", body = Vec<Device>),
+            (status = 200, description = "Devices", body = Vec<LabeledDevice>, headers(
+                ("X-Total-Count" = i64, description = "Number of all devices matching the filters"),
+                ("X-Next-Cursor" = String, description = "Cursor of the next page, absent on the last page")
+            )),
+            (status = 400, description = "Malformed filter or cursor", body = Response),
             (status = 403, description = "Permission denied", body = Response),
             (status = 500, description = "Internal error, contact web admin", body = Response)
         ),
-        params(),
+        params(DeviceFilter),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[get(
     "/devices",
     wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
 )]
-/// List all devices owned by the user ("deleted" devices included)
+/// Search devices owned by the current organization, outside the trash
 pub(crate) async fn owned_devices(
-    cur_user: AuthenticatedUser,
+    org: CurrentOrg,
+    query: web::Query<DeviceFilter>,
     app: web::Data<AppState>,
 ) -> impl Responder {
-    let devices = app.db.get_owned_devices(cur_user.id).await;
-    match devices {
-        Ok(devices) => HttpResponse::Ok().json(devices),
+    let query = query.into_inner();
+    let tag_query = match query.tags.as_deref().map(tag_query::parse) {
+        Some(Ok(tag_query)) => Some(tag_query),
+        Some(Err(e)) => return HttpError::bad_request(e).error_response(),
+        None => None,
+    };
+    let labels = match query.labels.as_deref().map(parse_label_filter) {
+        Some(Ok(labels)) => labels,
+        Some(Err(e)) => return HttpError::bad_request(e).error_response(),
+        None => vec![],
+    };
+    let sort = query.sort.unwrap_or_default();
+    let cursor = match query.cursor.as_deref().map(decode_cursor) {
+        Some(Some(cursor)) if cursor.sort() == sort => Some(cursor),
+        Some(_) => return HttpError::bad_request("Invalid cursor").error_response(),
+        None => None,
+    };
+    let tids = match query.tag {
+        Some(tid) if query.descendants.unwrap_or(false) => {
+            match app.db.get_org_tags(org.id).await {
+                Ok(tags) => {
+                    let active: Vec<_> = tags.into_iter().filter(|tag| tag.activated).collect();
+                    Some(descendants(&active, [tid]).into_iter().collect())
+                }
+                Err(e) => {
+                    error!("{:?}", e);
+                    return HttpError::server_error(ErrorMessage::ServerError).error_response();
+                }
+            }
+        }
+        Some(tid) => Some(vec![tid]),
+        None => None,
+    };
+    let search = DeviceSearch {
+        text: query.q.filter(|q| !q.is_empty()),
+        dtype: query.dtype,
+        activated: query.activated,
+        status: query.status,
+        tids,
+        tags: tag_query,
+        labels,
+        updated_after: query
+            .updated_after
+            .and_then(NaiveDateTime::from_timestamp_millis),
+        updated_before: query
+            .updated_before
+            .and_then(NaiveDateTime::from_timestamp_millis),
+        sort,
+        descending: query.order == Some(SortOrder::Desc),
+        cursor,
+        limit: query.limit.map(|limit| limit.clamp(1, MAX_PAGE_SIZE)),
+    };
+    let (devices, total) = match app.db.search_devices(org.id, &search).await {
+        Ok(page) => page,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let next_cursor = match (search.limit, devices.last()) {
+        (Some(limit), Some(last)) if devices.len() as i64 == limit => {
+            Some(encode_cursor(&DeviceCursor::after(sort, last)))
+        }
+        _ => None,
+    };
+    let dids: Vec<u64> = devices.iter().map(|device| device.id).collect();
+    match app.db.get_device_labels(&dids).await {
+        Ok(labels) => {
+            let mut resp = HttpResponse::Ok();
+            resp.insert_header(("X-Total-Count", total.to_string()));
+            if let Some(next_cursor) = next_cursor {
+                resp.insert_header(("X-Next-Cursor", next_cursor));
+            }
+            resp.json(LabeledDevice::attach(devices, labels))
+        }
         Err(e) => {
             error!("{:?}", e);
             HttpError::server_error(ErrorMessage::ServerError).error_response()
@@ -114,28 +337,36 @@ pub(crate) async fn owned_devices(
                     "name":"test_device",
                     "desc":"(optional)McDonald",
                     "dtype":1,
-                    "latitude":114.514,
-                    "longitude":19.19810,
-                    "topic":"test-key/home/light"
+                    "latitude":19.19810,
+                    "longitude":114.514,
+                    "topic":"test-key/home/light",
+                    "heartbeat_timeout":600,
+                    "locate_from_payload":false,
+                    "labels":{"firmware":"1.2.0","serial":"SN-0042"},
+                    "metadata":{"installed":"2024-01-01"}
                 })
         ),
         responses(
             (status = 200, description = "Added a new device, message=device id", body = Response),
             (status = 401, description = "Unauthorized", body = Response),
+            (status = 403, description = "Viewers cannot add devices to the organization", body = Response),
             (status = 500, description = "Internal error, contact web admin", body = Response)
         ),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[post(
     "/devices",
     wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
 )]
-/// Add a new device
+/// Add a new device to the current organization
 pub(crate) async fn add_device(
     cur_user: AuthenticatedUser,
+    org: CurrentOrg,
     app: web::Data<AppState>,
     form: web::Json<NewDeviceForm>,
 ) -> impl Responder {
@@ -143,6 +374,9 @@ pub(crate) async fn add_device(
         info!("Illegal input detected: {:?}", e);
         return HttpError::new(e.to_string(), 400).error_response();
     }
+    if org.role < OrgRole::Member {
+        return HttpError::new(ErrorMessage::PermissionDenied, 403).error_response();
+    }
 
     let NewDeviceForm {
         name,
@@ -151,23 +385,41 @@ pub(crate) async fn add_device(
         latitude,
         longitude,
         topic,
+        heartbeat_timeout,
+        locate_from_payload,
+        labels,
+        metadata,
     } = form.into_inner();
 
     let device = NewDevice {
         uid: cur_user.id,
+        oid: org.id,
         name: &name,
         desc: desc.as_deref(),
         dtype,
         latitude,
         longitude,
         topic: &topic,
+        heartbeat_timeout,
+        locate_from_payload,
+        metadata: metadata.as_ref(),
     };
 
-    match app.db.add_device(&device).await {
-        Ok(id) => HttpResponse::Ok().json(Response {
-            status: "ok",
-            message: id.to_string(),
-        }),
+    match app
+        .db
+        .add_device(&device, &labels.unwrap_or_default())
+        .await
+    {
+        Ok(id) => {
+            if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+                record_api_location(&app, id, latitude, longitude).await;
+            }
+            emit_device(&app.db, WebhookEvent::DeviceCreated, id);
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: id.to_string(),
+            })
+        }
         Err(e) => {
             error!("{:?}", e);
             HttpError::server_error(ErrorMessage::ServerError).error_response()
@@ -181,12 +433,14 @@ pub(crate) async fn add_device(
             path = "/devices/{did}",
             tag = "Device",
             responses(
-                (status = 200, description = "Device info", body = Device),
+                (status = 200, description = "Device info", body = LabeledDevice),
                 (status = NOT_FOUND, description = "Device was not found")
             ),
             security(
                 ("jwt_header" = []),
-                ("jwt_cookie" = [])
+                ("jwt_cookie" = []),
+                ("api_key_header" = []),
+                ("api_key_query" = [])
             )
         )]
 #[get(
@@ -202,10 +456,21 @@ pub(crate) async fn device_info(
     let did = path.into_inner();
     match app.db.get_device_by_id(did).await {
         Ok(device) => {
-            if device.uid == cur_user.id {
-                HttpResponse::Ok().json(device)
+            if Ok(true)
+                == app
+                    .db
+                    .device_permitted(did, cur_user.id, Permission::Read)
+                    .await
+            {
             } else {
-                HttpError::not_found(ErrorMessage::UpdateFailed).error_response()
+                return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+            }
+            match app.db.get_device_labels(&[did]).await {
+                Ok(labels) => HttpResponse::Ok().json(LabeledDevice::attach(vec![device], labels)),
+                Err(e) => {
+                    error!("{:?}", e);
+                    HttpError::server_error(ErrorMessage::ServerError).error_response()
+                }
             }
         }
         Err(DieselErr::NotFound) => {
@@ -232,42 +497,45 @@ pub(crate) async fn device_info(
         ),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[delete(
     "/devices/{did}",
     wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
 )]
-/// Delete a device (soft delete, i.e. deactivate it)
+/// Delete a device (soft delete, i.e. deactivate it and move it to the trash)
 pub(crate) async fn del_device(
     path: web::Path<u64>,
     app: web::Data<AppState>,
     cur_user: AuthenticatedUser,
 ) -> impl Responder {
     let did = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Admin)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    // Give it a random UUID to avoid collision, the original topic is kept for restoring
     match app
         .db
-        .update_device(
-            &UpdateDevice {
-                id: did,
-                name: None,
-                desc: None,
-                dtype: None,
-                latitude: None,
-                longitude: None,
-                last_update: None,
-                activated: Some(false),
-                topic: Some(&Uuid::new_v4().to_string()), // Give it a random UUID to avoid collision
-            },
-            Some(cur_user.id),
-        )
+        .trash_device(did, &Uuid::new_v4().to_string(), &Utc::now().naive_utc())
         .await
     {
-        Ok(1) => HttpResponse::Ok().json(Response {
-            status: "ok",
-            message: "".into(),
-        }),
+        Ok(1) => {
+            app.hub.memberships_changed();
+            emit_device(&app.db, WebhookEvent::DeviceDeleted, did);
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
         Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
         Err(e) => {
             error!("{:?}", e);
@@ -291,7 +559,10 @@ pub(crate) async fn del_device(
                     "dtype":1,
                     "latitude":14.514,
                     "longitude":19.19810,
-                    "topic":"/test"
+                    "topic":"/test",
+                    "heartbeat_timeout":60,
+                    "locate_from_payload":true,
+                    "metadata":{"firmware_notes":"OTA pending"}
                 })
         ),
         responses(
@@ -303,7 +574,9 @@ pub(crate) async fn del_device(
         ),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[put(
@@ -331,7 +604,19 @@ pub(crate) async fn upd_device_info(
         latitude,
         longitude,
         topic,
+        heartbeat_timeout,
+        locate_from_payload,
+        metadata,
     } = form.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Write)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
     debug!("{:?}", latitude);
     match app
         .db
@@ -346,15 +631,24 @@ pub(crate) async fn upd_device_info(
                 last_update: None,
                 activated: None,
                 topic: topic.as_deref(),
+                heartbeat_timeout,
+                locate_from_payload,
+                metadata: metadata.as_ref().map(|inner| inner.as_ref()),
             },
-            Some(cur_user.id),
+            None,
         )
         .await
     {
-        Ok(1) => HttpResponse::Ok().json(Response {
-            status: "ok",
-            message: "".into(),
-        }),
+        Ok(1) => {
+            if let (Some(Some(latitude)), Some(Some(longitude))) = (latitude, longitude) {
+                record_api_location(&app, did, latitude, longitude).await;
+            }
+            emit_device(&app.db, WebhookEvent::DeviceUpdated, did);
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
         Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
         Err(e) => {
             error!("{:?}", e);
@@ -363,6 +657,69 @@ pub(crate) async fn upd_device_info(
     }
 }
 
+#[utoipa::path(
+        put,
+        context_path = "/api",
+        path = "/devices/{did}/labels",
+        tag = "Device",
+        request_body(
+            content = DeviceLabelsForm,
+            example = json!({"labels": {"firmware": "1.2.1", "serial": "SN-0042"}})
+        ),
+        responses(
+            (status = 200, description = "Labels replaced", body = Response),
+            (status = 400, description = "Invalid labels", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Device was not found or the device is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[put(
+    "/devices/{did}/labels",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Replace all labels of a device
+pub(crate) async fn upd_device_labels(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<DeviceLabelsForm>,
+) -> impl Responder {
+    let did = path.into_inner();
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Write)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app.db.set_device_labels(did, &form.labels).await {
+        Ok(()) => {
+            emit_device(&app.db, WebhookEvent::DeviceUpdated, did);
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
 #[utoipa::path(
         get,
         context_path = "/api",
@@ -377,7 +734,9 @@ pub(crate) async fn upd_device_info(
         ),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[get(
@@ -391,7 +750,12 @@ pub(crate) async fn device_records(
     cur_user: AuthenticatedUser,
 ) -> impl Responder {
     let did = path.into_inner();
-    if Ok(true) == app.db.device_belongs_to(did, cur_user.id).await {
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Read)
+            .await
+    {
     } else {
         return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
     }
@@ -418,13 +782,15 @@ pub(crate) async fn device_records(
         responses(
             (status = 200, description = "Insert record success", body = Response),
             (status = 401, description = "Unauthorized", body = Response),
-            (status = 404, description = "Device was not found or the device is not yours \
-        and you do not have enough privilege to delete it", body = Response),
+            (status = 404, description = "Device was not found, is deactivated or deleted, \
+        or you do not have write access to it", body = Response),
             (status = 500, description = "Internal error, contact web admin", body = Response)
         ),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[post(
@@ -439,10 +805,26 @@ pub(crate) async fn insert_device_records(
     cur_user: AuthenticatedUser,
 ) -> impl Responder {
     let did = path.into_inner();
-    if Ok(true) == app.db.device_belongs_to(did, cur_user.id).await {
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Write)
+            .await
+    {
     } else {
         return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
     }
+    // deactivated or deleted devices take no more records
+    match app.db.get_device_by_id(did).await {
+        Ok(device) if device.activated && device.trashed_at.is_none() => {}
+        Ok(_) | Err(DieselErr::NotFound) => {
+            return HttpError::not_found("Device was not found").error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
     let RecordForm { payload } = form.into_inner();
 
     match app
@@ -454,12 +836,13 @@ pub(crate) async fn insert_device_records(
         })
         .await
     {
-        Ok(_) => {
+        Ok(record) => {
             crate::handlers::SYSINFO_CACHE
                 .buffer
                 .write()
                 .await
                 .record_count += 1;
+            app.hub.publish(record);
 
             HttpResponse::Ok().json(Response {
                 status: "ok",
@@ -472,3 +855,20 @@ pub(crate) async fn insert_device_records(
         }
     }
 }
+
+/// Keep the location set through the API in the device's location history
+async fn record_api_location(app: &AppState, did: u64, latitude: f64, longitude: f64) {
+    let res = app
+        .db
+        .add_device_location(&NewLocation {
+            did,
+            latitude,
+            longitude,
+            source: LocationSource::Api,
+            timestamp: &Utc::now().naive_utc(),
+        })
+        .await;
+    if let Err(e) = res {
+        error!("Record device location failed: {:?}", e);
+    }
+}
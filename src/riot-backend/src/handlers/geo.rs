@@ -0,0 +1,432 @@
+use std::ops::Deref;
+
+use actix_web::{delete, get, post, web, HttpResponse, Responder, ResponseError};
+use chrono::{Duration, NaiveDateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, CurrentOrg, RequireAuth},
+    models::{Location, NewGeofence, Permission, Response},
+    UserPrivilege,
+};
+
+#[derive(Deserialize, IntoParams)]
+/// Params in query, bounding box of coordinates
+struct BoundingBox {
+    min_lat: f64,
+    min_lng: f64,
+    max_lat: f64,
+    max_lng: f64,
+}
+
+#[derive(Deserialize, IntoParams)]
+/// Params in query, center and radius (unit: meters)
+struct Nearby {
+    lat: f64,
+    lng: f64,
+    radius: f64,
+}
+
+#[derive(Deserialize, IntoParams)]
+/// Params in query, time range in milliseconds since epoch (default: the last 24h)
+struct TimeRange {
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+/// Param in query, max number of events to return (default: 100)
+struct EventLimit {
+    limit: Option<i64>,
+}
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to add a new geofence
+pub struct NewGeofenceForm {
+    #[validate(length(max = 256, message = "Geofence name must be less than 255 characters"))]
+    pub name: String,
+    #[validate(range(min=-90.0, max=90.0, message = "Invalid latitude"))]
+    pub latitude: f64,
+    #[validate(range(min=-180.0, max=180.0, message = "Invalid longitude"))]
+    pub longitude: f64,
+    #[validate(range(min = 1.0, max = 20000000.0, message = "Invalid radius"))]
+    /// Unit: meters
+    pub radius: f64,
+}
+
+/// GeoJSON `Feature` of a device track: a `LineString` (or a `Point` for a single location)
+/// ordered by time, with the timestamps (in milliseconds) in `properties.timestamps`
+fn track_to_geojson(did: u64, track: &[Location]) -> serde_json::Value {
+    let coordinates: Vec<[f64; 2]> = track
+        .iter()
+        .map(|loc| [loc.longitude, loc.latitude])
+        .collect();
+    let geometry = match coordinates.len() {
+        0 => serde_json::Value::Null,
+        1 => json!({"type": "Point", "coordinates": coordinates[0]}),
+        _ => json!({"type": "LineString", "coordinates": coordinates}),
+    };
+    json!({
+        "type": "Feature",
+        "geometry": geometry,
+        "properties": {
+            "did": did,
+            "timestamps": track.iter().map(|loc| loc.timestamp.timestamp_millis()).collect::<Vec<_>>(),
+        }
+    })
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/geo/devices",
+        tag = "Geo",
+        params(BoundingBox),
+        responses(
+            (status = 200, description = "Devices located in the bounding box", body = Vec<Device>),
+            (status = 400, description = "Bad input", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/geo/devices",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Devices of the current organization, or shared with the user, within a bounding box
+/// ("deleted" devices excluded)
+pub(crate) async fn devices_within(
+    query: web::Query<BoundingBox>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    org: CurrentOrg,
+) -> impl Responder {
+    let BoundingBox {
+        min_lat,
+        min_lng,
+        max_lat,
+        max_lng,
+    } = query.into_inner();
+    if min_lat > max_lat || min_lng > max_lng {
+        return HttpError::bad_request(ErrorMessage::InvalidUserInput).error_response();
+    }
+    match app
+        .db
+        .get_devices_within(org.id, cur_user.id, (min_lat, min_lng), (max_lat, max_lng))
+        .await
+    {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/geo/devices/nearby",
+        tag = "Geo",
+        params(Nearby),
+        responses(
+            (status = 200, description = "Devices within the radius", body = Vec<Device>),
+            (status = 400, description = "Bad input", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/geo/devices/nearby",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Devices of the current organization, or shared with the user, within a radius of a point
+/// ("deleted" devices excluded)
+pub(crate) async fn devices_nearby(
+    query: web::Query<Nearby>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    org: CurrentOrg,
+) -> impl Responder {
+    let Nearby { lat, lng, radius } = query.into_inner();
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) || radius <= 0.0 {
+        return HttpError::bad_request(ErrorMessage::InvalidUserInput).error_response();
+    }
+    match app
+        .db
+        .get_devices_nearby(org.id, cur_user.id, (lat, lng), radius)
+        .await
+    {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/devices/{did}/track",
+        tag = "Geo",
+        params(TimeRange),
+        responses(
+            (status = 200, description = "GeoJSON `Feature` of the track, `properties.timestamps` holds \
+        the time (ms) of each coordinate"),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Device was not found or the device is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/devices/{did}/track",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Location history of a device as GeoJSON
+pub(crate) async fn device_track(
+    path: web::Path<u64>,
+    query: web::Query<TimeRange>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let did = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Read)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let now = Utc::now().naive_utc();
+    let to = query
+        .to
+        .and_then(NaiveDateTime::from_timestamp_millis)
+        .unwrap_or(now);
+    let from = query
+        .from
+        .and_then(NaiveDateTime::from_timestamp_millis)
+        .unwrap_or(to - Duration::days(1));
+    match app.db.get_device_track(did, &from, &to).await {
+        Ok(track) => HttpResponse::Ok()
+            .content_type("application/geo+json")
+            .json(track_to_geojson(did, &track)),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/geofences",
+        tag = "Geo",
+        responses(
+            (status = 200, description = "Geofences owned by the user", body = Vec<Geofence>),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/geofences",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List all geofences owned ("deleted" geofences excluded)
+pub(crate) async fn owned_geofences(
+    cur_user: AuthenticatedUser,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    match app.db.get_owned_geofences(cur_user.id).await {
+        Ok(fences) => HttpResponse::Ok().json(fences),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/geofences",
+    tag = "Geo",
+    request_body(
+        content = NewGeofenceForm,
+        example = json!({"name": "Warehouse", "latitude": 31.2304, "longitude": 121.4737, "radius": 500.0})
+    ),
+    responses(
+        (status = 200, description = "Added a new geofence, message = geofence id", body = Response),
+        (status = 400, description = "Bad input", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/geofences",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Add a new geofence: a circle of `radius` meters around the center. Enter/exit events are raised
+/// as the user's devices report locations from then on, not for where they already are
+pub(crate) async fn add_geofence(
+    cur_user: AuthenticatedUser,
+    app: web::Data<AppState>,
+    form: web::Json<NewGeofenceForm>,
+) -> impl Responder {
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    let NewGeofenceForm {
+        name,
+        latitude,
+        longitude,
+        radius,
+    } = form.into_inner();
+    match app
+        .db
+        .add_geofence(&NewGeofence {
+            uid: cur_user.id,
+            name: &name,
+            latitude,
+            longitude,
+            radius,
+        })
+        .await
+    {
+        Ok(id) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: id.to_string(),
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        delete,
+        context_path = "/api",
+        path = "/geofences/{gid}",
+        tag = "Geo",
+        responses(
+            (status = 200, description = "Delete success", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Geofence was not found or is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[delete(
+    "/geofences/{gid}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Soft delete a geofence (i.e. deactivate), its events are kept
+pub(crate) async fn del_geofence(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let gid = path.into_inner();
+    match app.db.deactivate_geofence(gid, cur_user.id).await {
+        Ok(1) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/geofences/{gid}/events",
+        tag = "Geo",
+        params(EventLimit),
+        responses(
+            (status = 200, description = "Enter/exit events of the geofence, newest first", body = Vec<GeofenceEvent>),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Geofence was not found or is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/geofences/{gid}/events",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Enter/exit events of a geofence
+pub(crate) async fn geofence_events(
+    path: web::Path<u64>,
+    query: web::Query<EventLimit>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let gid = path.into_inner();
+    if Ok(true) == app.db.geofence_belongs_to(gid, cur_user.id).await {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    match app.db.get_geofence_events(gid, limit).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
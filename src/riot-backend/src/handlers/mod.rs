@@ -1,9 +1,35 @@
 pub mod accounts;
+pub mod alerts;
+pub mod api_keys;
+pub mod bulk;
 pub mod devices;
+pub mod geo;
+pub mod oidc;
+pub mod orgs;
+pub mod presence;
 pub mod riot;
+pub mod shares;
+pub mod streams;
 pub mod tags;
+pub mod totp;
+pub mod transfers;
+pub mod trash;
+pub mod webhooks;
 
 pub use accounts::*;
+pub use alerts::*;
+pub use api_keys::*;
+pub use bulk::*;
 pub use devices::*;
+pub use geo::*;
+pub use oidc::*;
+pub use orgs::*;
+pub use presence::*;
 pub use riot::*;
+pub use shares::*;
+pub use streams::*;
 pub use tags::*;
+pub use totp::*;
+pub use transfers::*;
+pub use trash::*;
+pub use webhooks::*;
@@ -0,0 +1,315 @@
+use actix_web::{
+    cookie::{self, Cookie, SameSite},
+    get,
+    http::header,
+    web, HttpRequest, HttpResponse, Responder, ResponseError,
+};
+use diesel::result::{DatabaseErrorKind, Error as DieselErr};
+use log::{error, info};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    app_context::AppState,
+    config::OidcConfig,
+    errors::{ErrorMessage, HttpError},
+    models::{NewIdentity, NewUser, Response, User},
+    utils::{
+        oidc::{
+            authorize_url, discover, exchange_code, random_token, verify_id_token, IdClaims,
+            OidcError,
+        },
+        password::get_pwd_hash,
+    },
+};
+
+/// Holds `state`, `nonce` and the PKCE verifier between the login and the callback
+const OIDC_COOKIE: &str = "oidc";
+const OIDC_PATH: &str = "/api/accounts/oidc";
+/// Unit: seconds. Time to complete the login at the provider
+const OIDC_MAXAGE: i64 = 10 * 60;
+
+#[derive(Deserialize, IntoParams)]
+/// Query of the redirect back from the provider
+struct OidcCallback {
+    code: Option<String>,
+    state: Option<String>,
+    /// Set by the provider if the login failed or was denied
+    error: Option<String>,
+}
+
+fn redirect_uri(app: &AppState) -> String {
+    app.env.riot.host.to_string() + OIDC_PATH + "/callback"
+}
+
+fn oidc_cookie(value: String, max_age: i64) -> Cookie<'static> {
+    Cookie::build(OIDC_COOKIE, value)
+        .path(OIDC_PATH)
+        .max_age(cookie::time::Duration::new(max_age, 0))
+        .http_only(true)
+        // sent along with the top-level redirect back from the provider
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
+fn oidc_error_response(e: OidcError) -> HttpResponse {
+    error!("{}", e);
+    match e {
+        OidcError::Provider(_) => HttpError::new("Identity provider unavailable", 502),
+        OidcError::Token(_) => HttpError::permission_denied(ErrorMessage::InvalidToken),
+    }
+    .error_response()
+}
+
+/// Username for a new user: the preferred one if it is valid here, the email otherwise
+fn new_username<'a>(claims: &'a IdClaims, email: &'a str) -> &'a str {
+    match claims.preferred_username.as_deref() {
+        Some(name)
+            if (6..=64).contains(&name.chars().count())
+                && name.chars().all(|c| c.is_alphanumeric()) =>
+        {
+            name
+        }
+        _ => email,
+    }
+}
+
+/// Find the user linked to the identity, link it to the user with the same email, or create the user
+async fn identity_user(
+    app: &AppState,
+    config: &OidcConfig,
+    issuer: &str,
+    claims: &IdClaims,
+) -> Result<User, HttpResponse> {
+    let server_error = |e: DieselErr| {
+        error!("{:?}", e);
+        HttpError::server_error(ErrorMessage::ServerError).error_response()
+    };
+    match app.db.get_user_by_identity(issuer, &claims.sub).await {
+        Ok(user) => return Ok(user),
+        Err(DieselErr::NotFound) => {}
+        Err(e) => return Err(server_error(e)),
+    }
+    let Some(email) = claims.email.as_deref() else {
+        return Err(HttpError::permission_denied(
+            "The identity provider did not share an email address",
+        )
+        .error_response());
+    };
+    let uid = match app.db.get_user_by_username_or_email(email).await {
+        Ok(user) if user.email == email => {
+            if !(config.link_by_email && claims.email_verified) {
+                return Err(HttpError::new(ErrorMessage::EmailExist, 409).error_response());
+            }
+            app.db
+                .link_identity(&NewIdentity {
+                    uid: user.id,
+                    issuer,
+                    subject: &claims.sub,
+                })
+                .await
+                .map_err(server_error)?;
+            info!(
+                "Linked identity {} of {} to user {}",
+                claims.sub, issuer, user.id
+            );
+            return Ok(user);
+        }
+        Ok(_) | Err(DieselErr::NotFound) => {
+            // Unusable random password, a password can be set by resetting it
+            let hashed_password = get_pwd_hash(random_token().as_bytes(), &app.env.password)
+                .map_err(|e| {
+                    error!("{:?}", e);
+                    HttpError::server_error(ErrorMessage::HashingError).error_response()
+                })?;
+            let mut form = NewUser {
+                username: new_username(claims, email),
+                email,
+                hashed_password: &hashed_password,
+                privilege: config.default_privilege,
+            };
+            match app
+                .db
+                .register_identity_user(&form, issuer, &claims.sub)
+                .await
+            {
+                // the preferred username is taken
+                Err(DieselErr::DatabaseError(DatabaseErrorKind::UniqueViolation, _))
+                    if form.username != email =>
+                {
+                    form.username = email;
+                    app.db
+                        .register_identity_user(&form, issuer, &claims.sub)
+                        .await
+                }
+                result => result,
+            }
+            .map_err(|e| match e {
+                DieselErr::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                    HttpError::new(ErrorMessage::UserExist, 409).error_response()
+                }
+                e => server_error(e),
+            })?
+        }
+        Err(e) => return Err(server_error(e)),
+    };
+    info!(
+        "Registered user {} for identity {} of {}",
+        uid, claims.sub, issuer
+    );
+    app.db.get_user_by_id(uid).await.map_err(server_error)
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/accounts/oidc",
+    tag = "Account",
+    responses(
+        (status = 200, description = "Single sign-on is available, message = name of the provider", body = Response),
+        (status = 404, description = "Single sign-on is not configured", body = Response),
+    )
+)]
+#[get("/accounts/oidc")]
+/// Whether single sign-on is available, for the login page
+pub(crate) async fn oidc_info(app: web::Data<AppState>) -> impl Responder {
+    match &app.env.oidc {
+        Some(config) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: config.name.clone(),
+        }),
+        None => HttpError::not_found("Single sign-on is not configured").error_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/accounts/oidc/login",
+    tag = "Account",
+    responses(
+        (status = 302, description = "Redirect to the identity provider"),
+        (status = 404, description = "Single sign-on is not configured", body = Response),
+        (status = 502, description = "The identity provider is unavailable", body = Response),
+    )
+)]
+#[get("/accounts/oidc/login")]
+/// Start a single sign-on login at the identity provider, to be opened in the browser
+pub(crate) async fn oidc_login(app: web::Data<AppState>) -> impl Responder {
+    let Some(config) = &app.env.oidc else {
+        return HttpError::not_found("Single sign-on is not configured").error_response();
+    };
+    let (state, nonce, verifier) = (random_token(), random_token(), random_token());
+    let location = match discover(&config.issuer).await.and_then(|provider| {
+        authorize_url(
+            &provider,
+            config,
+            &redirect_uri(&app),
+            &state,
+            &nonce,
+            &verifier,
+        )
+    }) {
+        Ok(location) => location,
+        Err(e) => return oidc_error_response(e),
+    };
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, location))
+        .cookie(oidc_cookie(
+            format!("{state}.{nonce}.{verifier}"),
+            OIDC_MAXAGE,
+        ))
+        .finish()
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/accounts/oidc/callback",
+    tag = "Account",
+    params(OidcCallback),
+    responses(
+        (status = 302, description = "Logged in (set the token Cookie) and redirected to the dashboard, \
+            or to the login page with `totp` = the challenge token if 2FA is enabled"),
+        (status = 403, description = "Login denied, expired or forged", body = Response),
+        (status = 409, description = "An user with the email exists and cannot be linked", body = Response),
+        (status = 502, description = "The identity provider is unavailable", body = Response),
+    )
+)]
+#[get("/accounts/oidc/callback")]
+/// Redirect back from the identity provider, log in as the user linked to the identity.
+///
+/// Users are created on their first login with the configured default privilege
+pub(crate) async fn oidc_callback(
+    req: HttpRequest,
+    app: web::Data<AppState>,
+    query: web::Query<OidcCallback>,
+) -> impl Responder {
+    let Some(config) = &app.env.oidc else {
+        return HttpError::not_found("Single sign-on is not configured").error_response();
+    };
+    if let Some(e) = &query.error {
+        info!("OIDC login refused by the provider: {}", e);
+        return HttpError::permission_denied(ErrorMessage::PermissionDenied).error_response();
+    }
+    let cookie = req.cookie(OIDC_COOKIE);
+    let mut parts = cookie.as_ref().map(|c| c.value().splitn(3, '.'));
+    let (Some(state), Some(nonce), Some(verifier)) = (
+        parts.as_mut().and_then(Iterator::next),
+        parts.as_mut().and_then(Iterator::next),
+        parts.as_mut().and_then(Iterator::next),
+    ) else {
+        return HttpError::permission_denied(ErrorMessage::InvalidToken).error_response();
+    };
+    // the login must have been started by this browser
+    let (Some(code), true) = (&query.code, query.state.as_deref() == Some(state)) else {
+        return HttpError::permission_denied(ErrorMessage::InvalidToken).error_response();
+    };
+    let provider = match discover(&config.issuer).await {
+        Ok(provider) => provider,
+        Err(e) => return oidc_error_response(e),
+    };
+    let redirect_uri = redirect_uri(&app);
+    let claims = match exchange_code(&provider, config, &redirect_uri, code, verifier).await {
+        Ok(id_token) => verify_id_token(&provider, &config.client_id, &id_token, nonce).await,
+        Err(e) => Err(e),
+    };
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(e) => return oidc_error_response(e),
+    };
+    let user = match identity_user(&app, config, &provider.issuer, &claims).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    if !user.activated {
+        return HttpError::permission_denied(ErrorMessage::UserNotActivated).error_response();
+    }
+    let host = app.env.riot.host.to_string();
+    match app.totp_challenge_of(user.id).await {
+        Ok(Some(token)) => {
+            return HttpResponse::Found()
+                .insert_header((header::LOCATION, host + &format!("/login?totp={token}")))
+                .cookie(oidc_cookie("".into(), 0))
+                .finish()
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    let [jwt_cookie, refresh_cookie] = match app.start_session(user.id, None, &req).await {
+        Ok(cookies) => cookies,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, host + "/dashboard"))
+        .cookie(jwt_cookie)
+        .cookie(refresh_cookie)
+        .cookie(oidc_cookie("".into(), 0))
+        .finish()
+}
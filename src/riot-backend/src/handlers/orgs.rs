@@ -0,0 +1,548 @@
+use std::ops::Deref;
+
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder, ResponseError};
+use diesel::result::Error as DieselErr;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, CurrentSession, RequireAuth},
+    models::{NewMembership, OrgRole, Response},
+    utils::{api_keys::new_api_key, jwt::hash_token},
+    UserPrivilege,
+};
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to create or rename an organization
+pub struct OrgForm {
+    #[validate(length(min = 1, max = 255, message = "Must be 1-255 characters"))]
+    pub name: String,
+}
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to add a member or change a member's role
+pub struct MemberForm {
+    /// Username or email of the member
+    #[validate(length(min = 1, max = 255, message = "Must be 1-255 characters"))]
+    pub user: String,
+    pub role: OrgRole,
+}
+
+/// Role of the user in the organization, 404 if not a member
+async fn role_in(app: &AppState, oid: u64, uid: u64) -> Result<OrgRole, HttpResponse> {
+    match app.db.get_member_role(oid, uid).await {
+        Ok(Some(role)) => Ok(role),
+        Ok(None) => Err(HttpError::not_found(ErrorMessage::UpdateFailed).error_response()),
+        Err(e) => {
+            error!("{:?}", e);
+            Err(HttpError::server_error(ErrorMessage::ServerError).error_response())
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/orgs",
+    tag = "Organization",
+    responses(
+        (status = 200, description = "Organizations you are a member of", body = Vec<MemberOrganization>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/orgs",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List organizations the current user is a member of (the personal one included)
+pub(crate) async fn member_orgs(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    match app.db.get_member_orgs(cur_user.id).await {
+        Ok(orgs) => HttpResponse::Ok().json(orgs),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/orgs",
+    tag = "Organization",
+    request_body(
+        content = OrgForm,
+        example = json!({"name": "Greenhouse team"})
+    ),
+    responses(
+        (status = 200, description = "Organization created, message = oid", body = Response),
+        (status = 400, description = "Invalid input", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/orgs",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Create an organization owned by the current user
+pub(crate) async fn create_org(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<OrgForm>,
+) -> impl Responder {
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    match app.db.create_org(&form.name, cur_user.id).await {
+        Ok(oid) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: oid.to_string(),
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/orgs/{oid}",
+    tag = "Organization",
+    responses(
+        (status = 200, description = "Organization info", body = Organization),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Organization was not found or you are not a member", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/orgs/{oid}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Get info of an organization
+pub(crate) async fn org_info(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let oid = path.into_inner();
+    if let Err(resp) = role_in(&app, oid, cur_user.id).await {
+        return resp;
+    }
+    match app.db.get_org_by_id(oid).await {
+        Ok(org) => HttpResponse::Ok().json(org),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    context_path = "/api",
+    path = "/orgs/{oid}",
+    tag = "Organization",
+    request_body(
+        content = OrgForm,
+        example = json!({"name": "Greenhouse team"})
+    ),
+    responses(
+        (status = 200, description = "Organization renamed", body = Response),
+        (status = 400, description = "Invalid input", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 403, description = "Only admins can rename the organization", body = Response),
+        (status = 404, description = "Organization was not found or you are not a member", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[put(
+    "/orgs/{oid}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Rename an organization
+pub(crate) async fn rename_org(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<OrgForm>,
+) -> impl Responder {
+    let oid = path.into_inner();
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    match role_in(&app, oid, cur_user.id).await {
+        Ok(role) if role >= OrgRole::Admin => {}
+        Ok(_) => return HttpError::new(ErrorMessage::PermissionDenied, 403).error_response(),
+        Err(resp) => return resp,
+    }
+    match app.db.rename_org(oid, &form.name).await {
+        Ok(_) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/orgs/{oid}/members",
+    tag = "Organization",
+    responses(
+        (status = 200, description = "Members of the organization", body = Vec<OrgMember>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Organization was not found or you are not a member", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/orgs/{oid}/members",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List members of an organization
+pub(crate) async fn org_members(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let oid = path.into_inner();
+    if let Err(resp) = role_in(&app, oid, cur_user.id).await {
+        return resp;
+    }
+    match app.db.get_org_members(oid).await {
+        Ok(members) => HttpResponse::Ok().json(members),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    context_path = "/api",
+    path = "/orgs/{oid}/members",
+    tag = "Organization",
+    request_body(
+        content = MemberForm,
+        example = json!({"user": "alice", "role": "member"})
+    ),
+    responses(
+        (status = 200, description = "Member added or role changed", body = Response),
+        (status = 400, description = "Invalid input, or the organization is a personal one", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 403, description = "Only admins manage members, only owners hand out admin/owner", body = Response),
+        (status = 404, description = "Organization or user was not found, or you are not a member", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[put(
+    "/orgs/{oid}/members",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Add a member to an organization, or change the member's role
+pub(crate) async fn set_member(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<MemberForm>,
+) -> impl Responder {
+    let oid = path.into_inner();
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    let granter = match role_in(&app, oid, cur_user.id).await {
+        Ok(role) if role >= OrgRole::Admin => role,
+        Ok(_) => return HttpError::new(ErrorMessage::PermissionDenied, 403).error_response(),
+        Err(resp) => return resp,
+    };
+    match app.db.get_org_by_id(oid).await {
+        Ok(org) if org.personal_of.is_some() => {
+            return HttpError::bad_request("Personal organizations cannot have members")
+                .error_response()
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    let uid = match app.db.get_user_by_username_or_email(&form.user).await {
+        Ok(user) => user.id,
+        Err(DieselErr::NotFound) => {
+            return HttpError::not_found("User was not found").error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    if uid == cur_user.id {
+        return HttpError::bad_request("You cannot change your own role").error_response();
+    }
+    let current = match app.db.get_member_role(oid, uid).await {
+        Ok(role) => role,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    // admins manage viewers/members, only owners touch admins and owners
+    if granter != OrgRole::Owner && (form.role >= OrgRole::Admin || current >= Some(OrgRole::Admin))
+    {
+        return HttpError::new(ErrorMessage::PermissionDenied, 403).error_response();
+    }
+    match app
+        .db
+        .set_member(&NewMembership {
+            oid,
+            uid,
+            role: form.role,
+        })
+        .await
+    {
+        Ok(_) => {
+            app.hub.memberships_changed();
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    context_path = "/api",
+    path = "/orgs/{oid}/members/{uid}",
+    tag = "Organization",
+    responses(
+        (status = 200, description = "Member removed", body = Response),
+        (status = 400, description = "The last owner cannot leave", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 403, description = "Only admins remove members, only owners remove admins/owners", body = Response),
+        (status = 404, description = "Member was not found or you are not a member", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[delete(
+    "/orgs/{oid}/members/{uid}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Remove a member from an organization (members can also leave by themselves)
+pub(crate) async fn remove_member(
+    path: web::Path<(u64, u64)>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let (oid, uid) = path.into_inner();
+    let remover = match role_in(&app, oid, cur_user.id).await {
+        Ok(role) => role,
+        Err(resp) => return resp,
+    };
+    let members = match app.db.get_org_members(oid).await {
+        Ok(members) => members,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let role = match members.iter().find(|member| member.uid == uid) {
+        Some(member) => member.role,
+        None => return HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+    };
+    if uid != cur_user.id
+        && (remover < OrgRole::Admin || (role >= OrgRole::Admin && remover != OrgRole::Owner))
+    {
+        return HttpError::new(ErrorMessage::PermissionDenied, 403).error_response();
+    }
+    if role == OrgRole::Owner
+        && members
+            .iter()
+            .filter(|member| member.role == OrgRole::Owner)
+            .count()
+            == 1
+    {
+        return HttpError::bad_request("The last owner cannot leave").error_response();
+    }
+    match app.db.remove_member(oid, uid).await {
+        Ok(1) => {
+            app.hub.memberships_changed();
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/orgs/{oid}/switch",
+    tag = "Organization",
+    responses(
+        (status = 200, description = "Switched, message = the new JWT token (also set in the cookie)", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Organization was not found or you are not a member", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/orgs/{oid}/switch",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Make the organization the active one of the session;
+/// device and tag listings and creations then work on this organization
+pub(crate) async fn switch_org(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    session: CurrentSession,
+) -> impl Responder {
+    let oid = path.into_inner();
+    if let Err(resp) = role_in(&app, oid, cur_user.id).await {
+        return resp;
+    }
+    // Kept for the access tokens issued on refresh
+    match app.db.set_session_org(session.id, Some(oid)).await {
+        Ok(1) => {}
+        Ok(_) => return HttpError::new(ErrorMessage::InvalidToken, 401).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    let jwt_cookie = app.get_jwt_cookie(cur_user.id, Some(oid), session.id, &session.jti);
+    HttpResponse::Ok()
+        .cookie(jwt_cookie.clone())
+        .json(Response {
+            status: "ok",
+            message: jwt_cookie.value().to_string(),
+        })
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/orgs/{oid}/api_key",
+    tag = "Organization",
+    responses(
+        (status = 200, description = "New API key of the organization, message = the key", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 403, description = "Only admins can generate the API key", body = Response),
+        (status = 404, description = "Organization was not found or you are not a member", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/orgs/{oid}/api_key",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Generate (or regenerate) the API key used in MQTT topics of the organization's devices.
+/// Only its hash is kept, the key is shown once
+pub(crate) async fn gen_org_api_key(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let oid = path.into_inner();
+    match role_in(&app, oid, cur_user.id).await {
+        Ok(role) if role >= OrgRole::Admin => {}
+        Ok(_) => return HttpError::new(ErrorMessage::PermissionDenied, 403).error_response(),
+        Err(resp) => return resp,
+    }
+    let api_key = new_api_key();
+    match app.db.set_org_api_key(oid, &hash_token(&api_key)).await {
+        Ok(_) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: api_key,
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use actix_web::{get, web, HttpResponse, Responder, ResponseError};
+use chrono::naive::serde::ts_milliseconds;
+use chrono::NaiveDateTime;
+use log::error;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, CurrentOrg, RequireAuth},
+    models::{Device, DeviceStatus, Permission},
+    UserPrivilege,
+};
+
+#[derive(Deserialize, IntoParams)]
+/// Param in query, max number of presence events to return (default: 100)
+struct EventLimit {
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Presence of a single device
+pub struct DevicePresence {
+    pub did: u64,
+    pub name: String,
+    pub status: DeviceStatus,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub last_update: NaiveDateTime,
+    /// Unit: seconds
+    pub heartbeat_timeout: u32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Presence overview of a group of (activated) devices
+pub struct PresenceSummary {
+    pub online: usize,
+    pub offline: usize,
+    pub never_seen: usize,
+    pub devices: Vec<DevicePresence>,
+}
+
+impl PresenceSummary {
+    fn of(devices: Vec<Device>) -> Self {
+        let devices: Vec<DevicePresence> = devices
+            .into_iter()
+            .filter(|device| device.activated)
+            .map(|device| DevicePresence {
+                did: device.id,
+                name: device.name,
+                status: device.status,
+                last_update: device.last_update,
+                heartbeat_timeout: device.heartbeat_timeout,
+            })
+            .collect();
+        let count = |status| devices.iter().filter(|d| d.status == status).count();
+        PresenceSummary {
+            online: count(DeviceStatus::Online),
+            offline: count(DeviceStatus::Offline),
+            never_seen: count(DeviceStatus::NeverSeen),
+            devices,
+        }
+    }
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/presence",
+        tag = "Device",
+        responses(
+            (status = 200, description = "Presence of all devices of the current organization", body = PresenceSummary),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/presence",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Presence of all devices of the current organization ("deleted" devices excluded)
+pub(crate) async fn user_presence(org: CurrentOrg, app: web::Data<AppState>) -> impl Responder {
+    match app.db.get_org_devices(org.id, &[]).await {
+        Ok(devices) => HttpResponse::Ok().json(PresenceSummary::of(devices)),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/tags/{tid}/presence",
+        tag = "Tag",
+        responses(
+            (status = 200, description = "Presence of devices tagged by this tag", body = PresenceSummary),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Tag was not found or the tag is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/tags/{tid}/presence",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Presence of devices tagged with this tag ("deleted" devices excluded)
+pub(crate) async fn tag_presence(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let tid = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Read)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let dids = match app.db.get_visible_dids_under_tag(tid, cur_user.id).await {
+        Ok(dids) => dids,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    match app.db.get_device_by_ids(dids.as_ref()).await {
+        Ok(devices) => HttpResponse::Ok().json(PresenceSummary::of(devices)),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/devices/{did}/presence",
+        tag = "Device",
+        params(EventLimit),
+        responses(
+            (status = 200, description = "Online/offline transitions of the device, newest first", body = Vec<PresenceEvent>),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Device was not found or the device is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/devices/{did}/presence",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Presence history of a device
+pub(crate) async fn device_presence_events(
+    path: web::Path<u64>,
+    query: web::Query<EventLimit>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let did = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Read)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    match app.db.get_presence_events(did, limit).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
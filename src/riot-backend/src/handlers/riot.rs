@@ -12,6 +12,7 @@ use tokio::sync::RwLock;
 use utoipa::ToSchema;
 
 use crate::db::DBClient;
+use crate::utils::sinks::{SinkStatus, SINK_STATUS};
 
 pub static SYSINFO: Lazy<RwLock<System>> = Lazy::new(|| {
     let mut sysinfo = System::new_all();
@@ -106,6 +107,8 @@ pub struct ServerStatistic<'a> {
     load_avg_1_5_15: [f64; 3],
     #[schema(value_type=Vec<CachedSysinfo>)]
     last_30min: VecDeque<CachedSysinfo>,
+    /// Health of the configured record sinks
+    sinks: Vec<SinkStatus>,
 }
 
 // ROUTES
@@ -149,6 +152,7 @@ pub(crate) async fn healthchecker() -> impl Responder {
                 sysinfo.load_average().fifteen * 100.0,
             ],
             last_30min: SYSINFO_CACHE.cache.read().await.clone(),
+            sinks: SINK_STATUS.read().await.clone(),
         })
     }
 }
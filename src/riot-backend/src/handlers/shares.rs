@@ -0,0 +1,506 @@
+use std::ops::Deref;
+
+use actix_web::{delete, get, put, web, HttpResponse, Responder, ResponseError};
+use diesel::result::Error as DieselErr;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, RequireAuth},
+    models::{NewDeviceShare, NewTagShare, OrgRole, Permission, Response},
+    UserPrivilege,
+};
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to grant (or change) a user's access
+pub struct ShareForm {
+    /// Username or email of the grantee
+    #[validate(length(min = 1, max = 255, message = "Must be 1-255 characters"))]
+    pub user: String,
+    /// `read`, `write` or `admin`
+    pub permission: Permission,
+}
+
+/// Check the form against the granter's permission and resolve the grantee
+async fn grantee_of(
+    app: &AppState,
+    oid: u64,
+    granter: Permission,
+    form: &ShareForm,
+) -> Result<u64, HttpResponse> {
+    if form.permission == Permission::Owner {
+        return Err(HttpError::bad_request("Ownership cannot be shared").error_response());
+    }
+    // admins manage read/write shares, only the owner hands out admin
+    if form.permission == Permission::Admin && granter != Permission::Owner {
+        return Err(HttpError::new(ErrorMessage::PermissionDenied, 403).error_response());
+    }
+    let grantee = match app.db.get_user_by_username_or_email(&form.user).await {
+        Ok(user) => user.id,
+        Err(DieselErr::NotFound) => {
+            return Err(HttpError::not_found("User was not found").error_response())
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return Err(HttpError::server_error(ErrorMessage::ServerError).error_response());
+        }
+    };
+    match app.db.get_member_role(oid, grantee).await {
+        Ok(Some(OrgRole::Owner)) => {
+            return Err(HttpError::bad_request(
+                "The owners of the organization already have full access",
+            )
+            .error_response())
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("{:?}", e);
+            return Err(HttpError::server_error(ErrorMessage::ServerError).error_response());
+        }
+    }
+    Ok(grantee)
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/devices/{did}/shares",
+    tag = "Share",
+    responses(
+        (status = 200, description = "Users the device is shared with", body = Vec<DeviceShare>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Device was not found or you are not its admin", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/devices/{did}/shares",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List who a device is shared with
+pub(crate) async fn device_shares(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let did = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Admin)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app.db.get_device_shares(did).await {
+        Ok(shares) => HttpResponse::Ok().json(shares),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    context_path = "/api",
+    path = "/devices/{did}/shares",
+    tag = "Share",
+    request_body(
+        content = ShareForm,
+        example = json!({"user": "alice", "permission": "write"})
+    ),
+    responses(
+        (status = 200, description = "Access granted", body = Response),
+        (status = 400, description = "Invalid input", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 403, description = "Only the owner can grant admin access", body = Response),
+        (status = 404, description = "Device or user was not found, or you are not the device admin", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[put(
+    "/devices/{did}/shares",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Grant a user access to a device, or change the access level
+pub(crate) async fn share_device(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<ShareForm>,
+) -> impl Responder {
+    let did = path.into_inner();
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    let granter = match app.db.device_permission(did, cur_user.id).await {
+        Ok(Some(permission)) if permission >= Permission::Admin => permission,
+        Ok(_) => return HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let oid = match app.db.get_device_by_id(did).await {
+        Ok(device) => device.oid,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let uid = match grantee_of(&app, oid, granter, &form).await {
+        Ok(uid) => uid,
+        Err(resp) => return resp,
+    };
+    match app
+        .db
+        .share_device(&NewDeviceShare {
+            did,
+            uid,
+            permission: form.permission,
+            granted_by: cur_user.id,
+        })
+        .await
+    {
+        Ok(_) => {
+            app.hub.memberships_changed();
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    context_path = "/api",
+    path = "/devices/{did}/shares/{uid}",
+    tag = "Share",
+    responses(
+        (status = 200, description = "Access revoked", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Share was not found or you are not the device admin", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[delete(
+    "/devices/{did}/shares/{uid}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Revoke a user's access to a device (grantees can also drop their own access)
+pub(crate) async fn revoke_device_share(
+    path: web::Path<(u64, u64)>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let (did, uid) = path.into_inner();
+    if uid == cur_user.id
+        || Ok(true)
+            == app
+                .db
+                .device_permitted(did, cur_user.id, Permission::Admin)
+                .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app.db.revoke_device_share(did, uid).await {
+        Ok(1) => {
+            app.hub.memberships_changed();
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/tags/{tid}/shares",
+    tag = "Share",
+    responses(
+        (status = 200, description = "Users the tag is shared with", body = Vec<TagShare>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Tag was not found or you are not its admin", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/tags/{tid}/shares",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List who a tag is shared with
+pub(crate) async fn tag_shares(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let tid = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Admin)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app.db.get_tag_shares(tid).await {
+        Ok(shares) => HttpResponse::Ok().json(shares),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    context_path = "/api",
+    path = "/tags/{tid}/shares",
+    tag = "Share",
+    request_body(
+        content = ShareForm,
+        example = json!({"user": "alice@example.com", "permission": "read"})
+    ),
+    responses(
+        (status = 200, description = "Access granted", body = Response),
+        (status = 400, description = "Invalid input", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 403, description = "Only the owner can grant admin access", body = Response),
+        (status = 404, description = "Tag or user was not found, or you are not the tag admin", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[put(
+    "/tags/{tid}/shares",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Grant a user access to a tag and to the owner's devices under it
+pub(crate) async fn share_tag(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<ShareForm>,
+) -> impl Responder {
+    let tid = path.into_inner();
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    let granter = match app.db.tag_permission(tid, cur_user.id).await {
+        Ok(Some(permission)) if permission >= Permission::Admin => permission,
+        Ok(_) => return HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let oid = match app.db.get_tag_by_id(tid).await {
+        Ok(tag) => tag.oid,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let uid = match grantee_of(&app, oid, granter, &form).await {
+        Ok(uid) => uid,
+        Err(resp) => return resp,
+    };
+    match app
+        .db
+        .share_tag(&NewTagShare {
+            tid,
+            uid,
+            permission: form.permission,
+            granted_by: cur_user.id,
+        })
+        .await
+    {
+        Ok(_) => {
+            app.hub.memberships_changed();
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    context_path = "/api",
+    path = "/tags/{tid}/shares/{uid}",
+    tag = "Share",
+    responses(
+        (status = 200, description = "Access revoked", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Share was not found or you are not the tag admin", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[delete(
+    "/tags/{tid}/shares/{uid}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Revoke a user's access to a tag (grantees can also drop their own access)
+pub(crate) async fn revoke_tag_share(
+    path: web::Path<(u64, u64)>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let (tid, uid) = path.into_inner();
+    if uid == cur_user.id
+        || Ok(true)
+            == app
+                .db
+                .tag_permitted(tid, cur_user.id, Permission::Admin)
+                .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app.db.revoke_tag_share(tid, uid).await {
+        Ok(1) => {
+            app.hub.memberships_changed();
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/shared/devices",
+    tag = "Share",
+    responses(
+        (status = 200, description = "Devices shared with you, with the highest permission you hold", body = Vec<SharedDevice>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/shared/devices",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Devices shared with me, directly or through a tag
+pub(crate) async fn shared_devices(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    match app.db.get_shared_devices(cur_user.id).await {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/shared/tags",
+    tag = "Share",
+    responses(
+        (status = 200, description = "Tags shared with you", body = Vec<SharedTag>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/shared/tags",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Tags shared with me
+pub(crate) async fn shared_tags(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    match app.db.get_shared_tags(cur_user.id).await {
+        Ok(tags) => HttpResponse::Ok().json(tags),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
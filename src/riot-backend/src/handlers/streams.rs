@@ -0,0 +1,303 @@
+use std::time::Duration;
+
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective},
+    web::{self, Bytes},
+    HttpRequest, HttpResponse, Responder, ResponseError,
+};
+use actix_ws::Message;
+use diesel::result::Error as DieselErr;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use utoipa::ToSchema;
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, RequireAuth},
+    models::{Permission, Response},
+    utils::hub::Subscription,
+    UserPrivilege,
+};
+
+/// Unit: seconds. Comment line sent on idle SSE connections so proxies keep them open
+const SSE_KEEP_ALIVE: u64 = 15;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+/// WebSocket text message to change the subscription of the connection
+pub enum StreamCommand {
+    /// Start receiving records of these devices / all devices under these tags,
+    /// including the devices added to the tags later on
+    Subscribe {
+        #[serde(default)]
+        devices: Vec<u64>,
+        #[serde(default)]
+        tags: Vec<u64>,
+    },
+    /// Stop receiving records of these devices / all devices under these tags. A device still
+    /// under a subscribed tag keeps being received
+    Unsubscribe {
+        #[serde(default)]
+        devices: Vec<u64>,
+        #[serde(default)]
+        tags: Vec<u64>,
+    },
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/devices/{did}/stream",
+        tag = "Record",
+        responses(
+            (status = 200, description = "`text/event-stream` of records (JSON, same as `Record`) \
+        of the device, pushed as soon as they are stored", body = Record),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Device was not found, is deleted, or is not readable by you", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/devices/{did}/stream",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Live records of a device (Server-Sent Events).
+/// The stream ends once the user can no longer read the device, or the device is deleted
+pub(crate) async fn device_record_stream(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let did = path.into_inner();
+    let uid = cur_user.id;
+    match readable(&app, did, uid).await {
+        Ok(true) => {}
+        Ok(false) => return HttpError::not_found("Device was not found").error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    let state = (app.hub.subscribe(), app.hub.watch_memberships(), app);
+    let events = futures::stream::unfold(state, move |(mut rx, mut memberships, app)| async move {
+        loop {
+            let keep_alive = tokio::time::sleep(Duration::from_secs(SSE_KEEP_ALIVE));
+            let event = tokio::select! {
+                _ = keep_alive => ": keep-alive\n\n".to_string(),
+                Ok(()) = memberships.changed() => match readable(&app, did, uid).await {
+                    Ok(true) => continue,
+                    Ok(false) => return None,
+                    Err(e) => {
+                        // the access is checked again on the next change
+                        error!("{:?}", e);
+                        continue;
+                    }
+                },
+                record = rx.recv() => match record {
+                    Ok(record) if record.did == did => format!(
+                        "event: record\nid: {}\ndata: {}\n\n",
+                        record.id,
+                        serde_json::to_string(&record).unwrap()
+                    ),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => format!("event: lagged\ndata: {skipped}\n\n"),
+                    Err(RecvError::Closed) => return None,
+                },
+            };
+            return Some((
+                Ok::<_, actix_web::Error>(Bytes::from(event)),
+                (rx, memberships, app),
+            ));
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(events)
+}
+
+/// The device is readable by the user and not in the trash
+async fn readable(app: &AppState, did: u64, uid: u64) -> Result<bool, DieselErr> {
+    if !app.db.device_permitted(did, uid, Permission::Read).await? {
+        return Ok(false);
+    }
+    match app.db.get_device_by_id(did).await {
+        Ok(device) => Ok(device.trashed_at.is_none()),
+        Err(DieselErr::NotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/records/stream",
+        tag = "Record",
+        request_body(
+            content = StreamCommand,
+            description = "WebSocket text messages sent by the client to (un)subscribe. \
+        Each command is answered with a `Response` listing the subscribed device ids in `message`; \
+        records (JSON, same as `Record`) are pushed as text messages afterwards.",
+            example = json!({"action": "subscribe", "devices": [1, 2], "tags": [3]})
+        ),
+        responses(
+            (status = 101, description = "Switching to the WebSocket protocol"),
+            (status = 400, description = "Not a WebSocket handshake", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/records/stream",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Live records of multiple devices/tags (WebSocket)
+pub(crate) async fn record_stream_ws(
+    req: HttpRequest,
+    body: web::Payload,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let (response, mut session, mut msg_stream) = match actix_ws::handle(&req, body) {
+        Ok(handshake) => handshake,
+        Err(e) => return HttpError::bad_request(e.to_string()).error_response(),
+    };
+    let uid = cur_user.id;
+    let mut rx = app.hub.subscribe();
+    let mut memberships = app.hub.watch_memberships();
+    actix_web::rt::spawn(async move {
+        let mut subscription = Subscription::default();
+        loop {
+            tokio::select! {
+                Ok(()) = memberships.changed() => {
+                    if let Err(e) = refresh_subscription(&app, uid, &mut subscription).await {
+                        error!("{:?}", e);
+                    }
+                }
+                msg = msg_stream.recv() => match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        let reply = apply_stream_command(&app, uid, &mut subscription, &text).await;
+                        if session.text(serde_json::to_string(&reply).unwrap()).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(reason))) => {
+                        let _ = session.close(reason).await;
+                        return;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        debug!("WebSocket protocol error: {:?}", e);
+                        break;
+                    }
+                    None => break,
+                },
+                record = rx.recv() => match record {
+                    Ok(record) if subscription.matches(&record) => {
+                        if session.text(serde_json::to_string(&record).unwrap()).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("WebSocket subscriber of user {uid} lagged, {skipped} records skipped");
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+        let _ = session.close(None).await;
+    });
+    response
+}
+
+/// Check a `StreamCommand` against the access of the user and apply it to the subscription
+async fn apply_stream_command(
+    app: &AppState,
+    uid: u64,
+    subscription: &mut Subscription,
+    text: &str,
+) -> Response {
+    let command = match serde_json::from_str::<StreamCommand>(text) {
+        Ok(command) => command,
+        Err(e) => {
+            return Response {
+                status: "fail",
+                message: e.to_string(),
+            }
+        }
+    };
+    match command {
+        StreamCommand::Subscribe { devices, tags } => {
+            for &did in &devices {
+                if Ok(true) != app.db.device_permitted(did, uid, Permission::Read).await {
+                    return Response {
+                        status: "fail",
+                        message: ErrorMessage::UpdateFailed.into(),
+                    };
+                }
+            }
+            for &tid in &tags {
+                if Ok(true) != app.db.tag_permitted(tid, uid, Permission::Read).await {
+                    return Response {
+                        status: "fail",
+                        message: ErrorMessage::UpdateFailed.into(),
+                    };
+                }
+            }
+            subscription.add(devices, tags);
+        }
+        StreamCommand::Unsubscribe { devices, tags } => subscription.remove(devices, tags),
+    }
+    if let Err(e) = refresh_subscription(app, uid, subscription).await {
+        error!("{:?}", e);
+        return Response {
+            status: "error",
+            message: ErrorMessage::ServerError.into(),
+        };
+    }
+    Response {
+        status: "ok",
+        message: serde_json::to_string(&subscription.dids()).unwrap(),
+    }
+}
+
+/// Check the access to the subscribed devices and tags again, and resolve the tags
+async fn refresh_subscription(
+    app: &AppState,
+    uid: u64,
+    subscription: &mut Subscription,
+) -> Result<(), DieselErr> {
+    let dids = app
+        .db
+        .permitted_dids(uid, &subscription.devices(), Permission::Read)
+        .await?;
+    let (mut tids, mut tagged) = (vec![], vec![]);
+    for tid in subscription.tags() {
+        if app.db.tag_permitted(tid, uid, Permission::Read).await? {
+            tagged.extend(app.db.get_visible_dids_under_tag(tid, uid).await?);
+            tids.push(tid);
+        }
+    }
+    subscription.refresh(dids, tids, tagged);
+    Ok(())
+}
@@ -2,9 +2,14 @@ use std::ops::Deref;
 
 use crate::{
     app_context::AppState,
+    db::WebhookOwner,
     errors::{ErrorMessage, HttpError},
-    middlewares::AuthenticatedUser,
-    models::{NewTag, Response, UpdateTag},
+    middlewares::{AuthenticatedUser, CurrentOrg},
+    models::{NewTag, OrgRole, Permission, Response, Tag, UpdateTag, WebhookEvent},
+    utils::{
+        tag_query::{creates_cycle, descendants},
+        webhooks::{emit, emit_tag},
+    },
     UserPrivilege,
 };
 use actix_web::{
@@ -15,11 +20,14 @@ use actix_web::{
 use diesel::result::{DatabaseErrorKind, Error as DieselErr};
 use log::{error, info};
 use serde::{Deserialize, Serialize};
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 use validator::Validate;
 
 use crate::middlewares::RequireAuth;
 
+/// Tags only hold devices of their own organization
+const CROSS_ORG_TAGGING: &str = "The device and the tag belong to different organizations";
+
 #[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
 /// Web json form to update a tag
 pub struct UpdateTagForm {
@@ -27,6 +35,8 @@ pub struct UpdateTagForm {
     pub name: Option<String>,
     #[validate(length(max = 10000, message = "Must be less than 10000 characters"))]
     pub desc: Option<Option<String>>,
+    /// Move the tag under another tag, `null` to make it a top-level tag
+    pub parent: Option<Option<u64>>,
 }
 
 #[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
@@ -42,6 +52,41 @@ pub struct NewTagForm {
     pub name: String,
     #[validate(length(max = 10000, message = "Must be less than 10000 characters"))]
     pub desc: Option<String>,
+    /// Parent tag, e.g. building > floor > room
+    pub parent: Option<u64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+/// Param in query, also include devices under descendant tags (default: false)
+struct TagScope {
+    descendants: Option<bool>,
+}
+
+/// A parent must be an active tag of the same organization and must not be a descendant of the tag itself
+async fn check_parent(
+    app: &AppState,
+    oid: u64,
+    tid: Option<u64>,
+    parent: u64,
+) -> Result<(), HttpResponse> {
+    let tags = match app.db.get_org_tags(oid).await {
+        Ok(tags) => tags,
+        Err(e) => {
+            error!("{:?}", e);
+            return Err(HttpError::server_error(ErrorMessage::ServerError).error_response());
+        }
+    };
+    if !tags.iter().any(|tag| tag.id == parent && tag.activated) {
+        return Err(HttpError::not_found(ErrorMessage::UpdateFailed).error_response());
+    }
+    if let Some(tid) = tid {
+        if creates_cycle(&tags, tid, parent) {
+            return Err(
+                HttpError::bad_request("A tag cannot be nested under itself").error_response(),
+            );
+        }
+    }
+    Ok(())
 }
 // tags
 #[utoipa::path(
@@ -50,26 +95,25 @@ pub struct NewTagForm {
         path = "/tags",
         tag = "Tag",
         responses(
-            (status = 200, description = "Tags owned by the user", body = Vec<Tag>),
+            (status = 200, description = "Tags owned by the current organization", body = Vec<Tag>),
             (status = 403, description = "Permission denied", body = Response),
             (status = 500, description = "Internal error, contact web admin", body = Response)
         ),
         params(),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[get(
     "/tags",
     wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
 )]
-/// List all tags owned ("deleted" tags included)
-pub(crate) async fn owned_tags(
-    cur_user: AuthenticatedUser,
-    app: web::Data<AppState>,
-) -> impl Responder {
-    let tags = app.db.get_owned_tags(cur_user.id).await;
+/// List all tags owned by the current organization ("deleted" tags included)
+pub(crate) async fn owned_tags(org: CurrentOrg, app: web::Data<AppState>) -> impl Responder {
+    let tags = app.db.get_org_tags(org.id).await;
     match tags {
         Ok(tags) => HttpResponse::Ok().json(tags),
         Err(e) => {
@@ -88,20 +132,25 @@ pub(crate) async fn owned_tags(
         responses(
         (status = 200, description = "Added a new tag, message = tid", body = Response),
         (status = 401, description = "Unauthorized", body = Response),
+        (status = 403, description = "Viewers cannot add tags to the organization", body = Response),
+        (status = 404, description = "Parent tag was not found or is not yours", body = Response),
         (status = 500, description = "Internal error, contact web admin", body = Response)
     ),
     security(
         ("jwt_header" = []),
-        ("jwt_cookie" = [])
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
     )
 )]
 #[post(
     "/tags",
     wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
 )]
-/// Add a new tag
+/// Add a new tag to the current organization
 pub(crate) async fn add_tag(
     cur_user: AuthenticatedUser,
+    org: CurrentOrg,
     app: web::Data<AppState>,
     form: web::Json<NewTagForm>,
 ) -> impl Responder {
@@ -109,21 +158,34 @@ pub(crate) async fn add_tag(
         info!("Illegal input detected: {:?}", e);
         return HttpError::new(e.to_string(), 400).error_response();
     }
+    if org.role < OrgRole::Member {
+        return HttpError::new(ErrorMessage::PermissionDenied, 403).error_response();
+    }
 
-    let NewTagForm { name, desc } = form.into_inner();
+    let NewTagForm { name, desc, parent } = form.into_inner();
+    if let Some(parent) = parent {
+        if let Err(resp) = check_parent(&app, org.id, None, parent).await {
+            return resp;
+        }
+    }
 
     let tag = NewTag {
         uid: cur_user.id,
+        oid: org.id,
         name: &name,
         desc: desc.as_deref(),
         activated: true,
+        parent,
     };
 
     match app.db.add_tag(&tag).await {
-        Ok(id) => HttpResponse::Ok().json(Response {
-            status: "ok",
-            message: id.to_string(),
-        }),
+        Ok(id) => {
+            emit_tag(&app.db, WebhookEvent::TagCreated, id);
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: id.to_string(),
+            })
+        }
         Err(e) => {
             error!("{:?}", e);
             HttpError::new(ErrorMessage::ServerError, 500).error_response()
@@ -142,7 +204,9 @@ pub(crate) async fn add_tag(
         ),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[get(
@@ -157,9 +221,14 @@ pub(crate) async fn tag_info(
 ) -> impl Responder {
     let tid = path.into_inner();
     match app.db.get_tag_by_id(tid).await {
-        Ok(device) => {
-            if device.uid == cur_user.id {
-                HttpResponse::Ok().json(device)
+        Ok(tag) => {
+            if Ok(true)
+                == app
+                    .db
+                    .tag_permitted(tid, cur_user.id, Permission::Read)
+                    .await
+            {
+                HttpResponse::Ok().json(tag)
             } else {
                 HttpError::not_found(ErrorMessage::UpdateFailed).error_response()
             }
@@ -188,37 +257,40 @@ pub(crate) async fn tag_info(
         ),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[delete(
     "/tags/{tid}",
     wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
 )]
-/// Soft delete a tag (i.e. deactivate)
+/// Soft delete a tag (i.e. deactivate), devices are untagged
 pub(crate) async fn del_tag(
     path: web::Path<u64>,
     app: web::Data<AppState>,
     cur_user: AuthenticatedUser,
 ) -> impl Responder {
     let tid = path.into_inner();
-    match app
-        .db
-        .update_tag(
-            &UpdateTag {
-                id: tid,
-                name: None,
-                desc: None,
-                activated: Some(false),
-            },
-            Some(cur_user.id),
-        )
-        .await
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Admin)
+            .await
     {
-        Ok(1) => HttpResponse::Ok().json(Response {
-            status: "ok",
-            message: "".into(),
-        }),
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app.db.trash_tag(tid).await {
+        Ok(1) => {
+            app.hub.memberships_changed();
+            emit_tag(&app.db, WebhookEvent::TagDeleted, tid);
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
         Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
         Err(e) => {
             error!("{:?}", e);
@@ -235,6 +307,7 @@ pub(crate) async fn del_tag(
         request_body(content=UpdateTagForm),
         responses(
             (status = 200, description = "Update successed", body = Response),
+            (status = 400, description = "Invalid input, or the new parent is under this tag", body = Response),
             (status = 401, description = "Unauthorized", body = Response),
             (status = 404, description = "Tag was not found or the tag is not yours \
         and you do not have enough privilege to delete it", body = Response),
@@ -242,7 +315,9 @@ pub(crate) async fn del_tag(
         ),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[put(
@@ -263,7 +338,33 @@ pub(crate) async fn upd_tag_info(
         return HttpError::new(e.to_string(), 400).error_response();
     }
 
-    let UpdateTagForm { name, desc } = form.into_inner();
+    let oid = match app.db.get_tag_by_id(tid).await {
+        Ok(tag) => tag.oid,
+        Err(DieselErr::NotFound) => {
+            return HttpError::not_found(ErrorMessage::UpdateFailed).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Write)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+
+    let UpdateTagForm { name, desc, parent } = form.into_inner();
+    // the new parent is looked up among the tags of the owning organization
+    if let Some(Some(parent)) = parent {
+        if let Err(resp) = check_parent(&app, oid, Some(tid), parent).await {
+            return resp;
+        }
+    }
 
     match app
         .db
@@ -273,15 +374,20 @@ pub(crate) async fn upd_tag_info(
                 name: name.as_deref(),
                 desc: desc.as_ref().map(|inner| inner.as_deref()),
                 activated: None,
+                parent,
             },
-            Some(cur_user.id),
+            None,
         )
         .await
     {
-        Ok(1) => HttpResponse::Ok().json(Response {
-            status: "ok",
-            message: "".into(),
-        }),
+        Ok(1) => {
+            app.hub.memberships_changed();
+            emit_tag(&app.db, WebhookEvent::TagUpdated, tid);
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
         Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
         Err(e) => {
             error!("{:?}", e);
@@ -302,27 +408,64 @@ pub(crate) async fn upd_tag_info(
         and you do not have enough privilege to delete it", body = Response),
             (status = 500, description = "Internal error, contact web admin", body = Response)
         ),
+        params(TagScope),
         security(
             ("jwt_header" = []),
-            ("jwt_cookie" = [])
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
         )
     )]
 #[get(
     "/tags/{tid}/devices",
     wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
 )]
-/// Devices tagged with this tag
+/// Devices tagged with this tag (or any of its descendants)
 pub(crate) async fn tagged_devices(
     path: web::Path<u64>,
+    query: web::Query<TagScope>,
     app: web::Data<AppState>,
     cur_user: AuthenticatedUser,
 ) -> impl Responder {
     let tid = path.into_inner();
-    if Ok(true) == app.db.tag_belongs_to(tid, cur_user.id).await {
+    let oid = match app.db.get_tag_by_id(tid).await {
+        Ok(tag) => tag.oid,
+        Err(DieselErr::NotFound) => {
+            return HttpError::not_found(ErrorMessage::UpdateFailed).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Read)
+            .await
+    {
     } else {
         return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
     }
-    let dids = app.db.get_dids_under_tag(tid).await;
+    let dids = if query.descendants.unwrap_or(false) {
+        match app.db.get_org_tags(oid).await {
+            Ok(tags) => {
+                let active: Vec<Tag> = tags.into_iter().filter(|tag| tag.activated).collect();
+                let tids: Vec<u64> = descendants(&active, [tid]).into_iter().collect();
+                match app.db.get_dids_under_tags(&tids).await {
+                    Ok(dids) => {
+                        app.db
+                            .permitted_dids(cur_user.id, &dids, Permission::Read)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        app.db.get_visible_dids_under_tag(tid, cur_user.id).await
+    };
     let dids = match dids {
         Ok(dids) => dids,
         Err(e) => {
@@ -347,6 +490,7 @@ pub(crate) async fn tagged_devices(
     request_body(content=TagDeviceForm),
     responses(
         (status = 200, description = "Tagged", body = Response),
+        (status = 400, description = "The tag or the device is deleted, or they belong to different organizations", body = Response),
         (status = 401, description = "Unauthorized", body = Response),
         (status = 404, description = "Device/tag was not found or the device/tag is not yours \
         and you do not have enough privilege to delete it", body = Response),
@@ -354,7 +498,9 @@ pub(crate) async fn tagged_devices(
     ),
     security(
         ("jwt_header" = []),
-        ("jwt_cookie" = [])
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
     )
 )]
 #[post(
@@ -375,20 +521,60 @@ pub(crate) async fn tag_device(
         return HttpError::new(e.to_string(), 400).error_response();
     }
 
-    if Ok(true) == app.db.tag_belongs_to(tid, cur_user.id).await {
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Write)
+            .await
+    {
     } else {
         return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
     }
     let TagDeviceForm { did } = form.into_inner();
-    if Ok(true) == app.db.device_belongs_to(did, cur_user.id).await {
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Write)
+            .await
+    {
     } else {
         return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
     }
+    let oid = match (
+        app.db.get_tag_by_id(tid).await,
+        app.db.get_device_by_id(did).await,
+    ) {
+        (Ok(tag), Ok(device)) => {
+            if !tag.activated {
+                return HttpError::bad_request("Cannot tag with a deleted tag").error_response();
+            }
+            if !device.activated {
+                return HttpError::bad_request("Cannot tag a deleted device").error_response();
+            }
+            if tag.oid != device.oid {
+                return HttpError::bad_request(CROSS_ORG_TAGGING).error_response();
+            }
+            tag.oid
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
     match app.db.tag_device(tid, did).await {
-        Ok(_) => HttpResponse::Ok().json(Response {
-            status: "ok",
-            message: "".into(),
-        }),
+        Ok(_) => {
+            app.hub.memberships_changed();
+            emit(
+                &app.db,
+                WebhookOwner::Org(oid),
+                WebhookEvent::TagDeviceAdded,
+                serde_json::json!({"tid": tid, "did": did}),
+            );
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
         Err(DieselErr::DatabaseError(DatabaseErrorKind::UniqueViolation, _msg)) => {
             HttpError::new(ErrorMessage::TagExist, 409).error_response()
         }
@@ -400,52 +586,148 @@ pub(crate) async fn tag_device(
 }
 
 #[utoipa::path(
-    post,
+    delete,
     context_path = "/api",
-    path = "/tags/{tid}/devices",
+    path = "/tags/{tid}/devices/{did}",
     tag = "Tag",
-    request_body(content=TagDeviceForm),
     responses(
         (status = 200, description = "Delete success", body = Response),
+        (status = 400, description = "The device and the tag belong to different organizations", body = Response),
         (status = 401, description = "Unauthorized", body = Response),
-        (status = 404, description = "Device/tag was not found or the device/tag is not yours \
+        (status = 404, description = "Device/tag was not found, the device is not tagged, or the device/tag is not yours \
     and you do not have enough privilege to delete it", body = Response),
         (status = 500, description = "Internal error, contact web admin", body = Response)
     ),
     security(
         ("jwt_header" = []),
-        ("jwt_cookie" = [])
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
     )
 )]
-#[post(
-    "/tags/{tid}/devices",
+#[delete(
+    "/tags/{tid}/devices/{did}",
     wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
 )]
 /// Remove a device tag
 pub(crate) async fn untag_device(
-    path: web::Path<u64>,
+    path: web::Path<(u64, u64)>,
     app: web::Data<AppState>,
     cur_user: AuthenticatedUser,
-    form: web::Json<TagDeviceForm>,
 ) -> impl Responder {
-    let tid = path.into_inner();
-    if Ok(true) == app.db.tag_belongs_to(tid, cur_user.id).await {
+    let (tid, did) = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Write)
+            .await
+    {
     } else {
         return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
     }
-    let TagDeviceForm { did } = form.into_inner();
-    if Ok(true) == app.db.device_belongs_to(did, cur_user.id).await {
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Write)
+            .await
+    {
     } else {
         return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
     }
+    let oid = match (
+        app.db.get_tag_by_id(tid).await,
+        app.db.get_device_by_id(did).await,
+    ) {
+        (Ok(tag), Ok(device)) if tag.oid != device.oid => {
+            return HttpError::bad_request(CROSS_ORG_TAGGING).error_response();
+        }
+        (Ok(tag), Ok(_)) => tag.oid,
+        (Err(e), _) | (_, Err(e)) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
     match app.db.untag_device(tid, did).await {
-        Ok(_) => HttpResponse::Ok().json(Response {
-            status: "ok",
-            message: "".into(),
-        }),
+        Ok(0) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Ok(_) => {
+            app.hub.memberships_changed();
+            emit(
+                &app.db,
+                WebhookOwner::Org(oid),
+                WebhookEvent::TagDeviceRemoved,
+                serde_json::json!({"tid": tid, "did": did}),
+            );
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
         Err(e) => {
             error!("{:?}", e);
             HttpError::new(ErrorMessage::ServerError, 500).error_response()
         }
     }
 }
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/devices/{did}/tags",
+    tag = "Tag",
+    responses(
+        (status = 200, description = "Active tags of the device you can read", body = Vec<Tag>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Device was not found or is not accessible to you", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/devices/{did}/tags",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List tags of a device (those the current user can read)
+pub(crate) async fn device_tags(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let did = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Read)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let tags = match app.db.get_device_tags(did).await {
+        Ok(tags) => tags,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let mut visible = Vec::with_capacity(tags.len());
+    for tag in tags {
+        match app
+            .db
+            .tag_permitted(tag.id, cur_user.id, Permission::Read)
+            .await
+        {
+            Ok(true) => visible.push(tag),
+            Ok(false) => {}
+            Err(e) => {
+                error!("{:?}", e);
+                return HttpError::server_error(ErrorMessage::ServerError).error_response();
+            }
+        }
+    }
+    HttpResponse::Ok().json(visible)
+}
@@ -0,0 +1,394 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use chrono::Utc;
+use diesel::result::Error as DieselErr;
+use log::error;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    app_context::{client_of, AppState},
+    errors::{ErrorMessage, HttpError},
+    handlers::send_unlock_link,
+    middlewares::{AuthenticatedUser, RequireAuth},
+    models::Response,
+    utils::totp::{
+        hash_recovery_code, is_totp_code, new_recovery_codes, new_secret, otpauth_uri, verify_code,
+    },
+    UserPrivilege,
+};
+
+/// Wrong codes allowed per login challenge
+const MAX_ATTEMPTS: u8 = 5;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form carrying a TOTP code or a recovery code
+pub struct TotpCodeForm {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form for the second step of a login
+pub struct TotpLoginForm {
+    /// Challenge token returned by the first step
+    pub token: String,
+    /// TOTP code or recovery code
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// A started enrollment, to be confirmed with a code
+pub struct TotpEnrollment {
+    /// Base32 secret, for manual entry
+    pub secret: String,
+    /// `otpauth://` URI, for the QR code
+    pub uri: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct TotpStatus {
+    pub enabled: bool,
+    /// Unused recovery codes left
+    pub recovery_codes: i64,
+}
+
+/// Check a TOTP code or a recovery code of a user with 2FA enabled, spending it on success
+async fn check_second_factor(app: &AppState, uid: u64, code: &str) -> Result<bool, DieselErr> {
+    if is_totp_code(code) {
+        let totp = app.db.get_totp(uid).await?;
+        match verify_code(&totp.secret, code, Utc::now().timestamp() as u64) {
+            Some(step) => Ok(app.db.use_totp_step(uid, step).await? == 1),
+            None => Ok(false),
+        }
+    } else {
+        Ok(app
+            .db
+            .use_recovery_code(uid, &hash_recovery_code(code), &Utc::now().naive_utc())
+            .await?
+            == 1)
+    }
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/accounts/totp",
+    tag = "Account",
+    responses(
+        (status = 200, description = "2FA status of the current user", body = TotpStatus),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/accounts/totp",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Whether 2FA is enabled for the current user
+pub(crate) async fn totp_status(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let enabled = match app.db.get_totp(cur_user.id).await {
+        Ok(totp) => totp.confirmed_at.is_some(),
+        Err(DieselErr::NotFound) => false,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    match app.db.count_recovery_codes(cur_user.id).await {
+        Ok(recovery_codes) => HttpResponse::Ok().json(TotpStatus {
+            enabled,
+            recovery_codes,
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/accounts/totp",
+    tag = "Account",
+    responses(
+        (status = 200, description = "Enrollment started, confirm it with a code", body = TotpEnrollment),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 409, description = "2FA is already enabled", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/accounts/totp",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Start enrolling a TOTP authenticator, a pending enrollment is replaced
+pub(crate) async fn enroll_totp(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    match app.db.get_totp(cur_user.id).await {
+        Ok(totp) if totp.confirmed_at.is_some() => {
+            return HttpError::new("2FA is already enabled", 409).error_response()
+        }
+        Ok(_) | Err(DieselErr::NotFound) => {}
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    let secret = new_secret();
+    match app.db.enroll_totp(cur_user.id, &secret).await {
+        Ok(_) => HttpResponse::Ok().json(TotpEnrollment {
+            uri: otpauth_uri("RIoT", &cur_user.email, &secret),
+            secret,
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/accounts/totp/confirm",
+    tag = "Account",
+    request_body(content = TotpCodeForm, example = json!({"code": "123456"})),
+    responses(
+        (status = 200, description = "2FA enabled, return the recovery codes, shown only this once", body = Vec<String>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 403, description = "Wrong code", body = Response),
+        (status = 404, description = "No enrollment pending", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/accounts/totp/confirm",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Confirm the enrollment with a code from the authenticator, enabling 2FA
+pub(crate) async fn confirm_totp(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<TotpCodeForm>,
+) -> impl Responder {
+    let totp = match app.db.get_totp(cur_user.id).await {
+        Ok(totp) if totp.confirmed_at.is_none() => totp,
+        Ok(_) | Err(DieselErr::NotFound) => {
+            return HttpError::not_found("No enrollment pending").error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let Some(step) = verify_code(&totp.secret, &form.code, Utc::now().timestamp() as u64) else {
+        return HttpError::permission_denied(ErrorMessage::WrongCredentials).error_response();
+    };
+    let codes = new_recovery_codes();
+    let hashes: Vec<_> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    match app
+        .db
+        .confirm_totp(cur_user.id, step, &hashes, &Utc::now().naive_utc())
+        .await
+    {
+        Ok(1) => HttpResponse::Ok().json(codes),
+        Ok(_) => HttpError::not_found("No enrollment pending").error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/accounts/totp/disable",
+    tag = "Account",
+    request_body(content = TotpCodeForm, example = json!({"code": "123456"})),
+    responses(
+        (status = 200, description = "2FA disabled", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 403, description = "Wrong code", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/accounts/totp/disable",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Disable 2FA of the current user, confirming with a TOTP code or a recovery code
+pub(crate) async fn disable_totp(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<TotpCodeForm>,
+) -> impl Responder {
+    match check_second_factor(&app, cur_user.id, &form.code).await {
+        Ok(true) => {}
+        Ok(false) | Err(DieselErr::NotFound) => {
+            return HttpError::permission_denied(ErrorMessage::WrongCredentials).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    match app.db.delete_totp(cur_user.id).await {
+        Ok(_) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/accounts/{uid}/totp/reset",
+    tag = "Account",
+    params(("uid" = u64, Path, description = "User whose 2FA is reset")),
+    responses(
+        (status = 200, description = "2FA of the user disabled", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "The user has no 2FA", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/accounts/{uid}/totp/reset",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Admin as u32)"
+)]
+/// Admin: disable 2FA of a user who lost both the authenticator and the recovery codes
+pub(crate) async fn reset_totp(path: web::Path<u64>, app: web::Data<AppState>) -> impl Responder {
+    match app.db.delete_totp(path.into_inner()).await {
+        Ok(1) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/accounts/login/totp",
+    tag = "Account",
+    request_body(content = TotpLoginForm, example = json!({"token": "<challenge token>", "code": "123456"})),
+    responses(
+        (status = 200, description = "Logged in, set the token Cookie", body = User),
+        (status = 403, description = "Wrong code, or the challenge is invalid / expired / failed too many times", body = Response),
+        (status = 429, description = "Too many failed logins of the account or from the IP, see `Retry-After`", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    )
+)]
+#[post("/accounts/login/totp")]
+/// Second step of a login when 2FA is enabled, with a TOTP code or a recovery code
+///
+/// The challenge token is returned by `login` / `verify` (status `totp_required`), it lasts 5 minutes
+pub(crate) async fn login_totp(
+    req: HttpRequest,
+    app: web::Data<AppState>,
+    form: web::Json<TotpLoginForm>,
+) -> impl Responder {
+    // Taken out while it is checked, so that concurrent guesses do not share its attempts
+    let Some((uid, attempts)) = app.totp_challenge.remove(&form.token).await else {
+        return HttpError::permission_denied(ErrorMessage::InvalidToken).error_response();
+    };
+    let (_, ip) = client_of(&req, &app.env.riot.trusted_proxies);
+    // Wrong codes count as failed logins of the account too
+    let attempt = match app.login_guard.begin(Some(uid), ip.as_deref()).await {
+        Ok(attempt) => attempt,
+        Err(refusal) => {
+            app.totp_challenge
+                .insert(form.token.clone(), (uid, attempts))
+                .await;
+            return refusal.error_response();
+        }
+    };
+    match check_second_factor(&app, uid, &form.code).await {
+        Ok(true) => {}
+        Ok(false) | Err(DieselErr::NotFound) => {
+            if attempts + 1 < MAX_ATTEMPTS {
+                app.totp_challenge
+                    .insert(form.token.clone(), (uid, attempts + 1))
+                    .await;
+            }
+            if attempt.locks {
+                match app.db.get_user_by_id(uid).await {
+                    Ok(user) => send_unlock_link(&app, uid, &user.email).await,
+                    Err(e) => error!("{:?}", e),
+                }
+            }
+            return HttpError::permission_denied(ErrorMessage::WrongCredentials).error_response();
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    app.login_guard.succeed(&attempt).await;
+    app.login_guard.reset(uid).await;
+    let mut user = match app.db.get_user_by_id(uid).await {
+        Ok(user) => user,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let [jwt_cookie, refresh_cookie] = match app.start_session(uid, None, &req).await {
+        Ok(cookies) => cookies,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    user.password = "".into();
+    HttpResponse::Ok()
+        .cookie(jwt_cookie)
+        .cookie(refresh_cookie)
+        .json(user)
+}
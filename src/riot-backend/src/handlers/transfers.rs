@@ -0,0 +1,308 @@
+use std::ops::Deref;
+
+use actix_web::{delete, get, post, web, HttpResponse, Responder, ResponseError};
+use chrono::Utc;
+use diesel::result::Error as DieselErr;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, RequireAuth},
+    models::{NewDeviceTransfer, OrgRole, Response, TransferStatus, WebhookEvent},
+    utils::{one_time_token::TokenPurpose, webhooks::emit_device},
+    UserPrivilege,
+};
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to transfer a device to another user
+pub struct TransferForm {
+    /// Email of the recipient
+    #[validate(email(message = "Must be a valid email"))]
+    pub email: String,
+    /// Hand over the records too, otherwise they are purged on acceptance
+    pub with_records: Option<bool>,
+}
+
+#[derive(Deserialize, IntoParams)]
+/// Param in query, one-time code from the transfer email
+struct TransferCode {
+    code: String,
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/devices/{did}/transfer",
+    tag = "Transfer",
+    request_body(
+        content = TransferForm,
+        example = json!({"email": "alice@example.com", "with_records": true})
+    ),
+    responses(
+        (status = 200, description = "Transfer started and emailed to the recipient, message = transfer id", body = Response),
+        (status = 400, description = "Invalid input", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Device or recipient was not found, or you are not an admin of the organization owning the device", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/devices/{did}/transfer",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Offer a device to another user, who accepts via the emailed link.
+/// A pending transfer of the same device is superseded.
+pub(crate) async fn transfer_device(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<TransferForm>,
+) -> impl Responder {
+    let did = path.into_inner();
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    let device = match app.db.get_device_by_id(did).await {
+        Ok(device) if device.activated => device,
+        Ok(_) | Err(DieselErr::NotFound) => {
+            return HttpError::not_found(ErrorMessage::UpdateFailed).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    // Giving a device away takes an admin of the organization owning it
+    match app.db.get_member_role(device.oid, cur_user.id).await {
+        Ok(role) if role >= Some(OrgRole::Admin) => {}
+        Ok(_) => return HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    let recipient = match app.db.get_user_by_username_or_email(&form.email).await {
+        Ok(user) if user.activated => user,
+        Ok(_) | Err(DieselErr::NotFound) => {
+            return HttpError::not_found("User was not found").error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    if recipient.id == cur_user.id {
+        return HttpError::bad_request("You already own the device").error_response();
+    }
+    let xid = match app
+        .db
+        .create_transfer(
+            &NewDeviceTransfer {
+                did,
+                from_uid: cur_user.id,
+                to_uid: recipient.id,
+                with_records: form.with_records.unwrap_or(false),
+            },
+            &Utc::now().naive_utc(),
+        )
+        .await
+    {
+        Ok(xid) => xid,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let code = match app
+        .tokens
+        .issue(TokenPurpose::Transfer(xid), recipient.id)
+        .await
+    {
+        Ok(code) => code,
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let accept_link = app.env.riot.host.to_string() + &format!("/transfer?id={xid}&code={code}");
+    debug!("OTC link = {accept_link}");
+    if let Err(e) = app
+        .send_transfer_mail(&recipient.email, &cur_user.username, &device, &accept_link)
+        .await
+    {
+        error!("{}", e);
+    }
+    HttpResponse::Ok().json(Response {
+        status: "ok",
+        message: xid.to_string(),
+    })
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/transfers",
+    tag = "Transfer",
+    responses(
+        (status = 200, description = "Transfers sent or received by you, newest first", body = Vec<DeviceTransfer>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/transfers",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List device transfers of the current user, both directions (the audit trail included)
+pub(crate) async fn user_transfers(
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    match app.db.get_user_transfers(cur_user.id).await {
+        Ok(transfers) => HttpResponse::Ok().json(transfers),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/transfers/{id}/accept",
+    tag = "Transfer",
+    params(TransferCode),
+    responses(
+        (status = 200, description = "Device transferred to you", body = DeviceTransfer),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 403, description = "Invalid or expired code", body = Response),
+        (status = 404, description = "Transfer was not found, not for you, no longer pending, or the device was deleted", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/transfers/{id}/accept",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Accept a transfer with the emailed code: the device moves to your personal organization
+pub(crate) async fn accept_transfer(
+    path: web::Path<u64>,
+    query: web::Query<TransferCode>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let xid = path.into_inner();
+    match app.db.get_transfer_by_id(xid).await {
+        Ok(transfer)
+            if transfer.to_uid == cur_user.id && transfer.status == TransferStatus::Pending => {}
+        Ok(_) | Err(DieselErr::NotFound) => {
+            return HttpError::not_found(ErrorMessage::UpdateFailed).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    match app
+        .tokens
+        .consume(TokenPurpose::Transfer(xid), &query.code)
+        .await
+    {
+        Ok(Some(uid)) if uid == cur_user.id => {}
+        Ok(_) => return HttpError::permission_denied(ErrorMessage::InvalidToken).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    match app.db.accept_transfer(xid, &Utc::now().naive_utc()).await {
+        Ok(transfer) => {
+            app.hub.memberships_changed();
+            emit_device(&app.db, WebhookEvent::DeviceUpdated, transfer.did);
+            HttpResponse::Ok().json(transfer)
+        }
+        Err(DieselErr::NotFound) => {
+            HttpError::not_found(ErrorMessage::UpdateFailed).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    context_path = "/api",
+    path = "/transfers/{id}",
+    tag = "Transfer",
+    responses(
+        (status = 200, description = "Transfer cancelled", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Transfer was not found, not yours, or no longer pending", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[delete(
+    "/transfers/{id}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Cancel a pending transfer (by the sender) or decline it (by the recipient)
+pub(crate) async fn cancel_transfer(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let xid = path.into_inner();
+    match app.db.get_transfer_by_id(xid).await {
+        Ok(transfer) if transfer.from_uid == cur_user.id || transfer.to_uid == cur_user.id => {}
+        Ok(_) | Err(DieselErr::NotFound) => {
+            return HttpError::not_found(ErrorMessage::UpdateFailed).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    match app.db.cancel_transfer(xid, &Utc::now().naive_utc()).await {
+        Ok(1) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use actix_web::{delete, get, post, web, HttpResponse, Responder, ResponseError};
+use diesel::result::{DatabaseErrorKind, Error as DieselErr};
+use log::{error, info};
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, CurrentOrg, RequireAuth},
+    models::{Permission, Response, UpdateTag, WebhookEvent},
+    utils::webhooks::{emit_device, emit_tag},
+    UserPrivilege,
+};
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/trash/devices",
+    tag = "Trash",
+    responses(
+        (status = 200, description = "Deleted devices of the current organization, latest first", body = Vec<Device>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/trash/devices",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List soft-deleted devices of the current organization
+pub(crate) async fn trashed_devices(org: CurrentOrg, app: web::Data<AppState>) -> impl Responder {
+    match app.db.get_trashed_devices(org.id).await {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    context_path = "/api",
+    path = "/trash/tags",
+    tag = "Trash",
+    responses(
+        (status = 200, description = "Deleted tags of the current organization, latest first", body = Vec<Tag>),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[get(
+    "/trash/tags",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List soft-deleted tags of the current organization
+pub(crate) async fn trashed_tags(org: CurrentOrg, app: web::Data<AppState>) -> impl Responder {
+    match app.db.get_trashed_tags(org.id).await {
+        Ok(tags) => HttpResponse::Ok().json(tags),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/trash/devices/{did}/restore",
+    tag = "Trash",
+    responses(
+        (status = 200, description = "Device restored with its original topic", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Device was not found in the trash or you are not its admin", body = Response),
+        (status = 409, description = "The original topic is taken by another device", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/trash/devices/{did}/restore",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Restore a soft-deleted device
+pub(crate) async fn restore_device(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let did = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Admin)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app.db.restore_device(did).await {
+        Ok(1) => {
+            app.hub.memberships_changed();
+            emit_device(&app.db, WebhookEvent::DeviceUpdated, did);
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(DieselErr::DatabaseError(DatabaseErrorKind::UniqueViolation, _msg)) => {
+            HttpError::new("The original topic is taken by another device", 409).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    context_path = "/api",
+    path = "/trash/devices/{did}",
+    tag = "Trash",
+    responses(
+        (status = 202, description = "Purge scheduled", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Device was not found in the trash or you are not its admin", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[delete(
+    "/trash/devices/{did}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Permanently remove a soft-deleted device with its records, in the background
+pub(crate) async fn purge_device(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let did = path.into_inner();
+    match app.db.get_device_by_id(did).await {
+        Ok(device) if device.trashed_at.is_some() => {}
+        Ok(_) | Err(DieselErr::NotFound) => {
+            return HttpError::not_found(ErrorMessage::UpdateFailed).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    if Ok(true)
+        == app
+            .db
+            .device_permitted(did, cur_user.id, Permission::Admin)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let db = app.db.clone();
+    let hub = app.hub.clone();
+    tokio::spawn(async move {
+        match db.purge_device(did).await {
+            Ok(true) => {
+                hub.memberships_changed();
+                info!("Purged device {}", did)
+            }
+            Ok(false) => info!("Device {} was restored before being purged", did),
+            Err(e) => error!("Purge device {} failed: {:?}", did, e),
+        }
+    });
+    HttpResponse::Accepted().json(Response {
+        status: "ok",
+        message: "".into(),
+    })
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/trash/tags/{tid}/restore",
+    tag = "Trash",
+    responses(
+        (status = 200, description = "Tag restored", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Tag was not found in the trash or you are not its admin", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/trash/tags/{tid}/restore",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Restore a soft-deleted tag
+pub(crate) async fn restore_tag(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let tid = path.into_inner();
+    match app.db.get_tag_by_id(tid).await {
+        Ok(tag) if !tag.activated => {}
+        Ok(_) | Err(DieselErr::NotFound) => {
+            return HttpError::not_found(ErrorMessage::UpdateFailed).error_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    }
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Admin)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app
+        .db
+        .update_tag(
+            &UpdateTag {
+                id: tid,
+                name: None,
+                desc: None,
+                activated: Some(true),
+                parent: None,
+            },
+            None,
+        )
+        .await
+    {
+        Ok(1) => {
+            app.hub.memberships_changed();
+            emit_tag(&app.db, WebhookEvent::TagUpdated, tid);
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    context_path = "/api",
+    path = "/trash/tags/{tid}",
+    tag = "Trash",
+    responses(
+        (status = 200, description = "Tag purged, its children moved up to its parent", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 404, description = "Tag was not found in the trash or you are not its admin", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[delete(
+    "/trash/tags/{tid}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Permanently remove a soft-deleted tag
+pub(crate) async fn purge_tag(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let tid = path.into_inner();
+    if Ok(true)
+        == app
+            .db
+            .tag_permitted(tid, cur_user.id, Permission::Admin)
+            .await
+    {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app.db.purge_tag(tid).await {
+        Ok(true) => {
+            app.hub.memberships_changed();
+            HttpResponse::Ok().json(Response {
+                status: "ok",
+                message: "".into(),
+            })
+        }
+        Ok(false) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
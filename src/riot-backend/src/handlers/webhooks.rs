@@ -0,0 +1,446 @@
+use std::ops::Deref;
+
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder, ResponseError};
+use chrono::Utc;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use crate::{
+    app_context::AppState,
+    errors::{ErrorMessage, HttpError},
+    middlewares::{AuthenticatedUser, RequireAuth},
+    models::{DeliveryStatus, NewWebhook, Response, UpdateWebhook, WebhookEvent},
+    utils::webhooks::{check_target, new_secret, ping},
+    UserPrivilege,
+};
+
+#[derive(Deserialize, IntoParams)]
+/// Params in query, filter by delivery status and max number of deliveries to return (default: 100)
+struct DeliveryFilter {
+    status: Option<DeliveryStatus>,
+    limit: Option<i64>,
+}
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to register a webhook
+pub struct NewWebhookForm {
+    #[validate(
+        url,
+        length(max = 1024, message = "URL must be less than 1024 characters")
+    )]
+    pub url: String,
+    #[validate(length(min = 1, max = 16, message = "Subscribe to 1~16 events"))]
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Validate, Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Web json form to update a webhook, omitted fields are unchanged
+pub struct UpdateWebhookForm {
+    #[validate(
+        url,
+        length(max = 1024, message = "URL must be less than 1024 characters")
+    )]
+    pub url: Option<String>,
+    #[validate(length(min = 1, max = 16, message = "Subscribe to 1~16 events"))]
+    pub events: Option<Vec<WebhookEvent>>,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+/// A registered webhook, with its secret shown only this once
+pub struct CreatedWebhook {
+    pub id: u64,
+    /// Key of the `X-RIoT-Signature` HMAC
+    pub secret: String,
+}
+
+/// Comma separated event names, as stored in `webhook.events`
+fn join_events(events: &[WebhookEvent]) -> String {
+    events
+        .iter()
+        .map(WebhookEvent::as_str)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/webhooks",
+        tag = "Webhook",
+        responses(
+            (status = 200, description = "Webhooks registered by the user", body = Vec<Webhook>),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/webhooks",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// List all webhooks owned ("deleted" webhooks excluded)
+pub(crate) async fn owned_webhooks(
+    cur_user: AuthenticatedUser,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    match app.db.get_owned_webhooks(cur_user.id).await {
+        Ok(hooks) => HttpResponse::Ok().json(hooks),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    context_path = "/api",
+    path = "/webhooks",
+    tag = "Webhook",
+    request_body(
+        content = NewWebhookForm,
+        description = "Each delivery is a POST of `{\"event\": .., \"timestamp\": .., \"data\": ..}` with headers \
+    `X-RIoT-Event`, `X-RIoT-Delivery` (delivery id), `X-RIoT-Timestamp` (seconds) and \
+    `X-RIoT-Signature` = `sha256=` + hex HMAC-SHA256 of `{X-RIoT-Timestamp}.{body}` keyed by the webhook secret. \
+    Non-2xx responses are retried with exponential backoff until they become dead letters. \
+    The URL must be http(s), to a host with public addresses only, and redirects are not followed.",
+        example = json!({"url": "https://example.com/riot", "events": ["record.created", "device.offline"]})
+    ),
+    responses(
+        (status = 200, description = "Registered a new webhook, its secret is only shown this once", body = CreatedWebhook),
+        (status = 400, description = "Bad input, or the URL is not allowed", body = Response),
+        (status = 401, description = "Unauthorized", body = Response),
+        (status = 500, description = "Internal error, contact web admin", body = Response)
+    ),
+    security(
+        ("jwt_header" = []),
+        ("jwt_cookie" = []),
+        ("api_key_header" = []),
+        ("api_key_query" = [])
+    )
+)]
+#[post(
+    "/webhooks",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Register a webhook, its secret is generated.
+/// The URL must be http(s), to a host with public addresses only
+pub(crate) async fn add_webhook(
+    cur_user: AuthenticatedUser,
+    app: web::Data<AppState>,
+    form: web::Json<NewWebhookForm>,
+) -> impl Responder {
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    let NewWebhookForm { url, events } = form.into_inner();
+    if let Err(reason) = check_target(&url).await {
+        info!("Refused webhook URL {}: {}", url, reason);
+        return HttpError::bad_request(reason).error_response();
+    }
+    let secret = new_secret();
+    match app
+        .db
+        .add_webhook(&NewWebhook {
+            uid: cur_user.id,
+            url: &url,
+            secret: &secret,
+            events: &join_events(&events),
+        })
+        .await
+    {
+        Ok(id) => HttpResponse::Ok().json(CreatedWebhook { id, secret }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        put,
+        context_path = "/api",
+        path = "/webhooks/{wid}",
+        tag = "Webhook",
+        request_body(content = UpdateWebhookForm),
+        responses(
+            (status = 200, description = "Update successed", body = Response),
+            (status = 400, description = "Bad input", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Webhook was not found or is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[put(
+    "/webhooks/{wid}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Update the URL or the event filter of a webhook
+pub(crate) async fn upd_webhook(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+    form: web::Json<UpdateWebhookForm>,
+) -> impl Responder {
+    let wid = path.into_inner();
+    if let Err(e) = form.deref().validate() {
+        info!("Illegal input detected: {:?}", e);
+        return HttpError::new(e.to_string(), 400).error_response();
+    }
+    if Ok(true) == app.db.webhook_belongs_to(wid, cur_user.id).await {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let UpdateWebhookForm { url, events } = form.into_inner();
+    if url.is_none() && events.is_none() {
+        return HttpError::bad_request(ErrorMessage::InvalidUserInput).error_response();
+    }
+    if let Some(url) = &url {
+        if let Err(reason) = check_target(url).await {
+            info!("Refused webhook URL {}: {}", url, reason);
+            return HttpError::bad_request(reason).error_response();
+        }
+    }
+    let events = events.as_deref().map(join_events);
+    match app
+        .db
+        .update_webhook(
+            &UpdateWebhook {
+                id: wid,
+                url: url.as_deref(),
+                events: events.as_deref(),
+                activated: None,
+            },
+            cur_user.id,
+        )
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        delete,
+        context_path = "/api",
+        path = "/webhooks/{wid}",
+        tag = "Webhook",
+        responses(
+            (status = 200, description = "Delete success", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Webhook was not found or is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[delete(
+    "/webhooks/{wid}",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Soft delete a webhook (i.e. deactivate), its pending deliveries are no longer attempted
+pub(crate) async fn del_webhook(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let wid = path.into_inner();
+    match app
+        .db
+        .update_webhook(
+            &UpdateWebhook {
+                id: wid,
+                url: None,
+                events: None,
+                activated: Some(false),
+            },
+            cur_user.id,
+        )
+        .await
+    {
+        Ok(1) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        post,
+        context_path = "/api",
+        path = "/webhooks/{wid}/ping",
+        tag = "Webhook",
+        responses(
+            (status = 200, description = "A `webhook.ping` delivery is queued", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Webhook was not found or is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[post(
+    "/webhooks/{wid}/ping",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Send a test event to a webhook, check the result in its delivery log
+pub(crate) async fn ping_webhook(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let wid = path.into_inner();
+    let hook = match app.db.get_owned_webhooks(cur_user.id).await {
+        Ok(hooks) => hooks.into_iter().find(|hook| hook.id == wid),
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpError::server_error(ErrorMessage::ServerError).error_response();
+        }
+    };
+    let Some(hook) = hook else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    };
+    match ping(&app.db, &hook).await {
+        Ok(_) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        get,
+        context_path = "/api",
+        path = "/webhooks/{wid}/deliveries",
+        tag = "Webhook",
+        params(DeliveryFilter),
+        responses(
+            (status = 200, description = "Delivery log of the webhook, newest first. \
+        `status=dead` lists the dead letters", body = Vec<WebhookDelivery>),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Webhook was not found or is not yours", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[get(
+    "/webhooks/{wid}/deliveries",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Delivery log of a webhook
+pub(crate) async fn webhook_deliveries(
+    path: web::Path<u64>,
+    query: web::Query<DeliveryFilter>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let wid = path.into_inner();
+    if Ok(true) == app.db.webhook_belongs_to(wid, cur_user.id).await {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    match app
+        .db
+        .get_webhook_deliveries(wid, query.status, limit)
+        .await
+    {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+        post,
+        context_path = "/api",
+        path = "/webhooks/deliveries/{id}/retry",
+        tag = "Webhook",
+        responses(
+            (status = 200, description = "The dead letter is queued again", body = Response),
+            (status = 401, description = "Unauthorized", body = Response),
+            (status = 404, description = "Delivery was not found, not yours or not a dead letter", body = Response),
+            (status = 500, description = "Internal error, contact web admin", body = Response)
+        ),
+        security(
+            ("jwt_header" = []),
+            ("jwt_cookie" = []),
+            ("api_key_header" = []),
+            ("api_key_query" = [])
+        )
+    )]
+#[post(
+    "/webhooks/deliveries/{id}/retry",
+    wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+)]
+/// Retry a dead letter
+pub(crate) async fn retry_webhook_delivery(
+    path: web::Path<u64>,
+    app: web::Data<AppState>,
+    cur_user: AuthenticatedUser,
+) -> impl Responder {
+    let id = path.into_inner();
+    if Ok(true) == app.db.webhook_delivery_belongs_to(id, cur_user.id).await {
+    } else {
+        return HttpError::not_found(ErrorMessage::UpdateFailed).error_response();
+    }
+    match app
+        .db
+        .requeue_webhook_delivery(id, &Utc::now().naive_utc())
+        .await
+    {
+        Ok(1) => HttpResponse::Ok().json(Response {
+            status: "ok",
+            message: "".into(),
+        }),
+        Ok(_) => HttpError::not_found(ErrorMessage::UpdateFailed).error_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError).error_response()
+        }
+    }
+}
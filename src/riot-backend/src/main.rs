@@ -33,7 +33,15 @@ use utoipa::{
     Modify, OpenApi,
 };
 
-use crate::{app_context::AppState, errors::HttpError};
+use crate::{
+    app_context::AppState,
+    errors::HttpError,
+    middlewares::{RateLimit, API_KEY_HEADER},
+    utils::{
+        hub::RecordHub, login_guard::LoginGuard, one_time_token::OneTimeTokens,
+        request_limit::RequestLimiter, sinks::SinkStatus,
+    },
+};
 use actix_cors::Cors;
 
 #[actix_web::main]
@@ -45,16 +53,38 @@ async fn main() -> std::io::Result<()> {
     let mqtt_db_conn = DBClient::new(&DBClient::get_database_url());
     let mqtt_host = config.mqtt.host.as_str();
     let mqtt_port = config.mqtt.port;
+    // Shared by the MQTT daemon and the HTTP workers
+    let record_hub = RecordHub::new();
+    let mqtt_hub = record_hub.clone();
+    // Shared by the MQTT daemon (ingestion quotas) and the HTTP workers
+    let request_limit = RequestLimiter::new(&config.rate_limit);
+    let mqtt_limit = request_limit.clone();
     // Embedded MQTT Listening Daemon
     thread::Builder::new()
         .name("MQTT-Listener".into())
         .spawn(move || {
             info!("Start MQTT thread");
-            utils::mqtt_instance::mqtt_listening(mqtt_db_conn, mqtt_host, mqtt_port);
+            utils::mqtt_instance::mqtt_listening(
+                mqtt_db_conn,
+                mqtt_hub,
+                mqtt_limit,
+                mqtt_host,
+                mqtt_port,
+            );
         })
         .expect("Failed to create MQTT listener!");
     // System info metrics tracker daemon
     tokio::spawn(SYSINFO_CACHE.new_daemon());
+    // Device heartbeat timeout daemon
+    tokio::spawn(utils::presence::presence_daemon());
+    // Alerts reported in record payloads
+    tokio::spawn(utils::alerts::alert_daemon(record_hub.clone()));
+    // Webhook event delivery
+    tokio::spawn(utils::webhooks::webhook_daemon(record_hub.clone()));
+    // Forward records to the configured sinks
+    tokio::spawn(utils::sinks::sink_daemon(record_hub.clone()));
+    // Commands published to devices
+    tokio::spawn(utils::commands::command_daemon());
 
     // Generate OpenAPI docs
 
@@ -66,18 +96,71 @@ async fn main() -> std::io::Result<()> {
             user_register,
             user_login,
             user_logout,
+            refresh_token,
+            user_sessions,
+            revoke_session,
+            revoke_all_sessions,
             user_info,
+            //api keys
+            create_api_key,
+            user_api_keys,
+            revoke_api_key,
             upd_user_info,
+            confirm_email_change,
+            change_password,
+            forgot_password,
+            reset_password,
+            totp_status,
+            enroll_totp,
+            confirm_totp,
+            disable_totp,
+            reset_totp,
+            login_totp,
+            oidc_info,
+            oidc_login,
+            oidc_callback,
             send_verification_email,
             verify_login_by_email,
+            unlock_account,
             //devices
             add_device,
             owned_devices,
             device_info,
             upd_device_info,
+            upd_device_labels,
             device_records,
             insert_device_records,
             del_device,
+            //streams
+            device_record_stream,
+            record_stream_ws,
+            //presence
+            user_presence,
+            tag_presence,
+            device_presence_events,
+            //geo
+            devices_within,
+            devices_nearby,
+            device_track,
+            owned_geofences,
+            add_geofence,
+            del_geofence,
+            geofence_events,
+            //alerts
+            user_alerts,
+            device_alerts,
+            tag_alerts,
+            add_device_alert,
+            ack_alert,
+            resolve_alert,
+            //webhooks
+            owned_webhooks,
+            add_webhook,
+            upd_webhook,
+            del_webhook,
+            ping_webhook,
+            webhook_deliveries,
+            retry_webhook_delivery,
             //tags
             owned_tags,
             add_tag,
@@ -87,24 +170,116 @@ async fn main() -> std::io::Result<()> {
             tagged_devices,
             tag_device,
             untag_device,
+            device_tags,
+            bulk_update_devices,
+            bulk_command,
+            tag_records,
+            //shares
+            device_shares,
+            share_device,
+            revoke_device_share,
+            tag_shares,
+            share_tag,
+            revoke_tag_share,
+            shared_devices,
+            shared_tags,
+            //organizations
+            member_orgs,
+            create_org,
+            org_info,
+            rename_org,
+            org_members,
+            set_member,
+            remove_member,
+            switch_org,
+            gen_org_api_key,
+            //transfers
+            transfer_device,
+            user_transfers,
+            accept_transfer,
+            cancel_transfer,
+            //trash
+            trashed_devices,
+            trashed_tags,
+            restore_device,
+            purge_device,
+            restore_tag,
+            purge_tag,
         ),
         components(schemas(
             User,
+            Session,
+            ActiveSession,
+            models::ApiKey,
+            ApiKeyScope,
+            ApiKeyForm,
             Device,
+            LabeledDevice,
+            DeviceSort,
+            SortOrder,
             Tag,
             Record,
+            DeviceStatus,
+            PresenceCause,
+            PresenceEvent,
+            PresenceSummary,
+            DevicePresence,
+            Location,
+            LocationSource,
+            Geofence,
+            GeofenceEvent,
+            GeofenceTransition,
+            NewGeofenceForm,
+            Alert,
+            AlertSeverity,
+            RaiseAlertForm,
+            Webhook,
+            WebhookEvent,
+            WebhookDelivery,
+            DeliveryStatus,
+            NewWebhookForm,
+            CreatedWebhook,
+            UpdateWebhookForm,
             ServerStatistic,
             RegisterForm,
             LoginForm,
             UpdateUserForm,
+            ForgotPasswordForm,
+            ResetPasswordForm,
+            ChangePasswordForm,
+            TotpCodeForm,
+            TotpLoginForm,
+            TotpEnrollment,
+            TotpStatus,
             NewDeviceForm,
             RecordForm,
             UpdateDeviceForm,
+            DeviceLabelsForm,
             UpdateTagForm,
             TagDeviceForm,
             NewTagForm,
+            BulkUpdateForm,
+            BulkCommandForm,
+            BulkResult,
+            Permission,
+            DeviceShare,
+            TagShare,
+            SharedDevice,
+            SharedTag,
+            ShareForm,
+            Organization,
+            OrgRole,
+            MemberOrganization,
+            OrgMember,
+            OrgForm,
+            MemberForm,
+            DeviceTransfer,
+            TransferStatus,
+            TransferForm,
+            StreamCommand,
             Response,
             CachedSysinfo,
+            SinkStatus,
         )),
         modifiers(&SecurityJwt)
     )]
@@ -134,6 +309,34 @@ async fn main() -> std::io::Result<()> {
                     )
                 ))
             );
+            components.add_security_scheme(
+                "api_key_header",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                    API_KEY_HEADER.to_string(),
+                    "User API key (`POST /api/api_keys`), used instead of the JWT token. \
+                        The key's scopes must cover the endpoint: `read_records` to read devices, \
+                        tags and their data, `write_records` to submit records, `manage_devices` for \
+                        other changes (shares, transfers and trash included), `manage_webhooks` for \
+                        webhooks, `admin` for accounts, API keys, organizations and anything else"
+                        .to_string(),
+                ))),
+            );
+            components.add_security_scheme(
+                "api_key_query",
+                SecurityScheme::ApiKey(ApiKey::Query(ApiKeyValue::with_description(
+                    "api_key".to_string(),
+                    "Same as the `X-API-Key` header, only if `api_key.query` is enabled in the config"
+                        .to_string(),
+                ))),
+            );
+            components.add_security_scheme(
+                "refresh_cookie",
+                SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                    "refresh".to_string(),
+                    "Opaque refresh token of the login session, only sent to `/api/accounts/refresh`"
+                        .to_string(),
+                ))),
+            );
         }
     }
     let openapi = ApiDoc::openapi();
@@ -173,15 +376,20 @@ async fn main() -> std::io::Result<()> {
     info!("Database init finished!");
 
     // Register services (API endpoints and user interfaces routes)
+    let db = DBClient::new(&DBClient::get_database_url());
     let app_state = AppState {
         env: config,
-        db: DBClient::new(&DBClient::get_database_url()),
+        tokens: OneTimeTokens::new(&config.one_time_token, &config.jwt.secret, &db),
+        db,
         rate_limit: Cache::builder()
             .time_to_idle(Duration::from_secs(60)) // idle, 60s
             .build(),
-        one_time_code: Cache::builder()
-            .time_to_live(Duration::from_secs(60 * 60 * 24)) // live, 24h
+        login_guard: LoginGuard::new(&config.login),
+        request_limit,
+        totp_challenge: Cache::builder()
+            .time_to_live(Duration::from_secs(60 * 5)) // live, 5min
             .build(),
+        hub: record_hub,
     };
     let is_debug = app_state.env.riot.debug;
     info!("IN DEBUG MODE");
@@ -197,6 +405,15 @@ async fn main() -> std::io::Result<()> {
                     .allowed_origin(host)
                     .allow_any_method()
                     .allow_any_header()
+                    // Our own response headers, readable by the frontend
+                    .expose_headers([
+                        "x-total-count",
+                        "x-next-cursor",
+                        "x-ratelimit-limit",
+                        "x-ratelimit-remaining",
+                        "x-ratelimit-reset",
+                        "retry-after",
+                    ])
                     .max_age(600)
             })
             .app_data(web::Data::clone(&app_data))
@@ -218,25 +435,79 @@ async fn main() -> std::io::Result<()> {
                                 HttpError::bad_request(err.to_string()).into()
                             }),
                     )
+                    .wrap(RateLimit)
                     // RIoT tag
                     .service(healthchecker)
                     //users
                     .service(user_register)
                     .service(user_login)
                     .service(user_logout)
+                    .service(refresh_token)
+                    .service(user_sessions)
+                    .service(revoke_session)
+                    .service(revoke_all_sessions)
                     .service(user_info)
                     .service(upd_user_info)
+                    .service(confirm_email_change)
+                    .service(change_password)
+                    .service(forgot_password)
+                    .service(reset_password)
+                    .service(totp_status)
+                    .service(enroll_totp)
+                    .service(confirm_totp)
+                    .service(disable_totp)
+                    .service(reset_totp)
+                    .service(login_totp)
+                    .service(oidc_info)
+                    .service(oidc_login)
+                    .service(oidc_callback)
                     .service(send_verification_email)
                     .service(verify_login_by_email)
+                    .service(unlock_account)
                     // Logged-in users only:
+                    // api keys
+                    .service(create_api_key)
+                    .service(user_api_keys)
+                    .service(revoke_api_key)
                     // devices
                     .service(add_device)
                     .service(owned_devices)
                     .service(device_info)
                     .service(upd_device_info)
+                    .service(upd_device_labels)
                     .service(device_records)
                     .service(insert_device_records)
                     .service(del_device)
+                    // streams
+                    .service(device_record_stream)
+                    .service(record_stream_ws)
+                    // presence
+                    .service(user_presence)
+                    .service(tag_presence)
+                    .service(device_presence_events)
+                    // geo
+                    .service(devices_within)
+                    .service(devices_nearby)
+                    .service(device_track)
+                    .service(owned_geofences)
+                    .service(add_geofence)
+                    .service(del_geofence)
+                    .service(geofence_events)
+                    // alerts
+                    .service(user_alerts)
+                    .service(device_alerts)
+                    .service(tag_alerts)
+                    .service(add_device_alert)
+                    .service(ack_alert)
+                    .service(resolve_alert)
+                    // webhooks
+                    .service(owned_webhooks)
+                    .service(add_webhook)
+                    .service(upd_webhook)
+                    .service(del_webhook)
+                    .service(ping_webhook)
+                    .service(webhook_deliveries)
+                    .service(retry_webhook_delivery)
                     // tags
                     .service(add_tag)
                     .service(owned_tags)
@@ -244,7 +515,44 @@ async fn main() -> std::io::Result<()> {
                     .service(upd_tag_info)
                     .service(tagged_devices)
                     .service(tag_device)
-                    .service(del_tag),
+                    .service(untag_device)
+                    .service(device_tags)
+                    .service(del_tag)
+                    // bulk operations on tagged devices
+                    .service(bulk_update_devices)
+                    .service(bulk_command)
+                    .service(tag_records)
+                    // sharing
+                    .service(device_shares)
+                    .service(share_device)
+                    .service(revoke_device_share)
+                    .service(tag_shares)
+                    .service(share_tag)
+                    .service(revoke_tag_share)
+                    .service(shared_devices)
+                    .service(shared_tags)
+                    // organizations
+                    .service(member_orgs)
+                    .service(create_org)
+                    .service(org_info)
+                    .service(rename_org)
+                    .service(org_members)
+                    .service(set_member)
+                    .service(remove_member)
+                    .service(switch_org)
+                    .service(gen_org_api_key)
+                    // device transfers
+                    .service(transfer_device)
+                    .service(user_transfers)
+                    .service(accept_transfer)
+                    .service(cancel_transfer)
+                    // trash
+                    .service(trashed_devices)
+                    .service(trashed_tags)
+                    .service(restore_device)
+                    .service(purge_device)
+                    .service(restore_tag)
+                    .service(purge_tag),
                 // pipes
                 // TODO...
                 // Admin only:
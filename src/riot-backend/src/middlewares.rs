@@ -1,7 +1,10 @@
 use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
-use actix_web::error::{ErrorForbidden, ErrorUnauthorized};
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized};
 
+use actix_web::http::header::HeaderName;
 use actix_web::{http, web, FromRequest, HttpMessage};
+use chrono::Utc;
+use diesel::result::Error as DieselErr;
 
 use futures_util::future::{ready, LocalBoxFuture, Ready};
 use futures_util::FutureExt;
@@ -10,9 +13,12 @@ use serde::Deserialize;
 use std::rc::Rc;
 use std::task::{Context, Poll};
 
+use crate::app_context::client_ip;
 use crate::errors::{ErrorMessage, ErrorResponse, HttpError};
-use crate::models::{User, UserPrivilege};
-use crate::utils::jwt::parse_token;
+use crate::models::{OrgRole, User, UserPrivilege};
+use crate::utils::api_keys::required_scope;
+use crate::utils::jwt::{hash_token, parse_token};
+use crate::utils::request_limit::Client;
 use crate::AppState;
 
 pub struct AuthenticatedUser(User);
@@ -44,6 +50,83 @@ impl std::ops::Deref for AuthenticatedUser {
     }
 }
 
+/// Login session the request is authenticated with (`sid` claim of the JWT),
+/// absent for API key requests
+#[derive(Clone, Debug)]
+pub struct CurrentSession {
+    pub id: u64,
+    /// Shared by all access tokens of the session
+    pub jti: String,
+}
+
+impl FromRequest for CurrentSession {
+    type Error = HttpError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let value = req.extensions().get::<CurrentSession>().cloned();
+        ready(value.ok_or(HttpError::permission_denied(
+            "Authentication Error: This requires a login session.",
+        )))
+    }
+}
+
+/// Organization chosen in the session (`org` claim of the JWT), or the one of the API key
+#[derive(Clone, Copy)]
+struct SessionOrg(Option<u64>);
+
+/// The organization the user is working in: the one switched to in the session
+/// if the user is still a member of it, otherwise the user's personal organization
+pub struct CurrentOrg {
+    pub id: u64,
+    pub role: OrgRole,
+}
+
+impl FromRequest for CurrentOrg {
+    type Error = HttpError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let user = req.extensions().get::<User>().map(|user| user.id);
+        let session = req.extensions().get::<SessionOrg>().and_then(|org| org.0);
+        let app_state = req.app_data::<web::Data<AppState>>().cloned();
+        async move {
+            let (Some(uid), Some(app_state)) = (user, app_state) else {
+                return Err(HttpError::permission_denied(
+                    "Authentication Error: You are not logged-in an account with enough privilege to perform this.",
+                ));
+            };
+            if let Some(oid) = session {
+                match app_state.db.get_member_role(oid, uid).await {
+                    Ok(Some(role)) => return Ok(CurrentOrg { id: oid, role }),
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("{:?}", e);
+                        return Err(HttpError::server_error(ErrorMessage::ServerError));
+                    }
+                }
+            }
+            match app_state.db.get_personal_org(uid).await {
+                Ok(org) => Ok(CurrentOrg {
+                    id: org.id,
+                    role: OrgRole::Owner,
+                }),
+                Err(e) => {
+                    error!("{:?}", e);
+                    Err(HttpError::server_error(ErrorMessage::ServerError))
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
 pub struct RequireAuth {
     pub priv_needed: Rc<u32>,
 }
@@ -87,9 +170,39 @@ pub struct AuthMiddleware<S> {
     service: Rc<S>,
     least_priv: Rc<u32>,
 }
+/// Header carrying an API key
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
 #[derive(Debug, Deserialize)]
-pub struct ApiKey {
-    pub api_key: String,
+/// Param in query, accepted only if `api_key.query` is enabled in the config
+struct ApiKeyParam {
+    api_key: String,
+}
+
+/// API key of the request, from the `X-API-Key` header or, if enabled, the `api_key` query parameter
+fn api_key_of(req: &ServiceRequest, query_enabled: bool) -> Option<String> {
+    req.headers()
+        .get(API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .map(String::from)
+        .or_else(|| {
+            query_enabled
+                .then(|| web::Query::<ApiKeyParam>::from_query(req.query_string()).ok())
+                .flatten()
+                .map(|param| param.into_inner().api_key)
+        })
+}
+
+/// JWT access token of the request, from the `token` Cookie or the `Authorization` header
+fn token_of(req: &ServiceRequest) -> Option<String> {
+    req.cookie("token")
+        .map(|c| c.value().to_string())
+        .or_else(|| {
+            req.headers()
+                .get(http::header::AUTHORIZATION)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|token| token.strip_prefix("Bearer ").map(String::from))
+        })
 }
 
 impl<S> Service<ServiceRequest> for AuthMiddleware<S>
@@ -111,23 +224,37 @@ where
     fn call(&self, req: ServiceRequest) -> Self::Future {
         // Check api key first
         let app_state = req.app_data::<web::Data<AppState>>().unwrap();
-        let apikey = req
-            .app_data::<web::Query<ApiKey>>()
-            .map(|key| key.api_key.clone());
+        let apikey = api_key_of(&req, app_state.env.api_key.query);
         let cloned_app_state = app_state.clone();
         if let Some(key) = apikey {
             let srv = Rc::clone(&self.service);
             let least_priv = self.least_priv.clone();
+            let scope = required_scope(req.method(), req.path(), *least_priv);
+            let ip = client_ip(req.request(), &app_state.env.riot.trusted_proxies);
             return async move {
-                let result = cloned_app_state.db.get_user_by_api_key(key.as_str()).await;
-                let user = result.map_err(|_e| {
+                let now = Utc::now().naive_utc();
+                let result = cloned_app_state
+                    .db
+                    .get_user_by_api_key(&hash_token(&key), &now)
+                    .await;
+                let (key, user) = result.map_err(|_e| {
                     ErrorUnauthorized(ErrorResponse {
                         status: "fail".to_string(),
-                        message: ErrorMessage::UserNotActivated.to_string(),
+                        message: ErrorMessage::InvalidToken.to_string(),
                     })
                 })?;
+                if !key.permits(scope) || !key.allows_ip(ip) {
+                    return Err(ErrorForbidden(ErrorResponse {
+                        status: "fail".to_string(),
+                        message: ErrorMessage::PermissionDenied.to_string(),
+                    }));
+                }
                 if &user.privilege >= &least_priv {
+                    if let Err(e) = cloned_app_state.db.touch_api_key(key.id, &now).await {
+                        error!("{:?}", e);
+                    }
                     req.extensions_mut().insert::<User>(user);
+                    req.extensions_mut().insert(SessionOrg(Some(key.oid)));
                     let res = srv.call(req).await?;
                     Ok(res)
                 } else {
@@ -141,15 +268,7 @@ where
             .boxed_local();
         }
 
-        let token = req
-            .cookie("token")
-            .map(|c| c.value().to_string())
-            .or_else(|| {
-                req.headers()
-                    .get(http::header::AUTHORIZATION)
-                    .map(|h| h.to_str().unwrap())
-                    .and_then(|token| token.strip_prefix("Bearer ").map(String::from))
-            });
+        let token = token_of(&req);
 
         // No token in Cookie or Header
         if token.is_none() {
@@ -158,8 +277,15 @@ where
             return async move { srv.call(req).await }.boxed_local();
         }
 
-        let user_id = match parse_token(token.unwrap(), app_state.env.jwt.secret.as_bytes()) {
-            Ok(id) => id,
+        let claims = match parse_token(token.unwrap(), app_state.env.jwt.secret.as_bytes()) {
+            // Tokens issued before sessions were introduced cannot be revoked, refuse them
+            Ok(claims) if claims.sid != 0 && !claims.jti.is_empty() => claims,
+            Ok(_) => {
+                return Box::pin(ready(Err(ErrorUnauthorized(ErrorResponse {
+                    status: "fail".to_string(),
+                    message: ErrorMessage::InvalidToken.to_string(),
+                }))));
+            }
             Err(e) => {
                 error!("{}", e);
                 return Box::pin(ready(Err(ErrorUnauthorized(ErrorResponse {
@@ -175,9 +301,25 @@ where
         let srv = Rc::clone(&self.service);
 
         async move {
+            match cloned_app_state.db.is_token_revoked(&claims.jti).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    return Err(ErrorUnauthorized(ErrorResponse {
+                        status: "fail".to_string(),
+                        message: ErrorMessage::InvalidToken.to_string(),
+                    }))
+                }
+                Err(e) => {
+                    error!("{:?}", e);
+                    return Err(ErrorInternalServerError(ErrorResponse {
+                        status: "fail".to_string(),
+                        message: ErrorMessage::ServerError.to_string(),
+                    }));
+                }
+            }
             let result = cloned_app_state
                 .db
-                .get_user_by_id(user_id.parse::<u64>().unwrap())
+                .get_user_by_id(claims.sub.parse::<u64>().unwrap())
                 .await;
 
             let user = result.map_err(|_e| {
@@ -189,6 +331,11 @@ where
 
             if &user.privilege >= &least_priv {
                 req.extensions_mut().insert::<User>(user);
+                req.extensions_mut().insert(SessionOrg(claims.org));
+                req.extensions_mut().insert(CurrentSession {
+                    id: claims.sid,
+                    jti: claims.jti,
+                });
                 let res = srv.call(req).await?;
                 Ok(res)
             } else {
@@ -202,3 +349,354 @@ where
         .boxed_local()
     }
 }
+
+/// Limits of `[rate_limit]`, counted against the API key, the user of the login session, or the IP.
+///
+/// Wraps the whole API, before `RequireAuth`: invalid credentials count against the IP,
+/// they are refused later
+pub struct RateLimit;
+
+impl<S> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<
+            ServiceRequest,
+            Response = ServiceResponse<actix_web::body::BoxBody>,
+            Error = actix_web::Error,
+        > + 'static,
+{
+    type Response = ServiceResponse<actix_web::body::BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+/// Who the request counts against
+async fn rate_limit_client(
+    app_state: &AppState,
+    api_key: Option<String>,
+    token: Option<String>,
+    ip: String,
+) -> Client {
+    let limiter = &app_state.request_limit;
+    if let Some(key) = api_key {
+        let hash = hash_token(&key);
+        let privilege = limiter
+            .privilege_of(&format!("key:{hash}"), async {
+                let now = Utc::now().naive_utc();
+                let (_, user) = app_state.db.get_user_by_api_key(&hash, &now).await?;
+                Ok::<_, DieselErr>(user.privilege)
+            })
+            .await;
+        if let Ok(privilege) = privilege {
+            return Client::ApiKey { hash, privilege };
+        }
+    } else if let Some(uid) = token
+        .and_then(|token| parse_token(token, app_state.env.jwt.secret.as_bytes()).ok())
+        .and_then(|claims| claims.sub.parse::<u64>().ok())
+    {
+        let privilege = limiter
+            .privilege_of(&format!("user:{uid}"), async {
+                let user = app_state.db.get_user_by_id(uid).await?;
+                Ok::<_, DieselErr>(user.privilege)
+            })
+            .await;
+        if let Ok(privilege) = privilege {
+            return Client::User { id: uid, privilege };
+        }
+    }
+    Client::Ip(ip)
+}
+
+impl<S> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<
+            ServiceRequest,
+            Response = ServiceResponse<actix_web::body::BoxBody>,
+            Error = actix_web::Error,
+        > + 'static,
+{
+    type Response = ServiceResponse<actix_web::body::BoxBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, actix_web::Error>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = Rc::clone(&self.service);
+        let app_state = req.app_data::<web::Data<AppState>>().unwrap().clone();
+        if !app_state.request_limit.enabled() {
+            return async move { srv.call(req).await }.boxed_local();
+        }
+        let api_key = api_key_of(&req, app_state.env.api_key.query);
+        let token = token_of(&req);
+        let ip = client_ip(req.request(), &app_state.env.riot.trusted_proxies)
+            .map(|ip| ip.to_string())
+            .unwrap_or_default();
+        let path = req.path();
+        let path = path.strip_prefix("/api").unwrap_or(path).to_string();
+        async move {
+            let client = rate_limit_client(&app_state, api_key, token, ip).await;
+            let Some(quota) = app_state.request_limit.check(&path, &client).await else {
+                return srv.call(req).await;
+            };
+            let mut res = match quota.retry_after {
+                Some(secs) => req.into_response(
+                    HttpError::too_many_requests(ErrorMessage::TooFast).retry_after(secs),
+                ),
+                None => srv.call(req).await?,
+            };
+            let headers = res.headers_mut();
+            headers.insert(
+                HeaderName::from_static("x-ratelimit-limit"),
+                quota.limit.into(),
+            );
+            headers.insert(
+                HeaderName::from_static("x-ratelimit-remaining"),
+                quota.remaining.into(),
+            );
+            headers.insert(
+                HeaderName::from_static("x-ratelimit-reset"),
+                quota.reset.into(),
+            );
+            Ok(res)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        cookie::Cookie, get, http::StatusCode, test, web, App, HttpResponse, Responder,
+    };
+    use chrono::{Duration, Utc};
+    use moka::future::Cache;
+    use uuid::Uuid;
+
+    use super::{AuthenticatedUser, RequireAuth, API_KEY_HEADER};
+    use crate::{
+        app_context::AppState,
+        config::Config,
+        db::{DBClient, RevokeScope},
+        models::{NewApiKey, NewSession, NewUser, UserPrivilege},
+        utils::{
+            api_keys::{new_api_key, visible_prefix},
+            hub::RecordHub,
+            jwt::hash_token,
+            login_guard::LoginGuard,
+            one_time_token::OneTimeTokens,
+            password::get_pwd_hash,
+            request_limit::RequestLimiter,
+        },
+    };
+
+    #[get(
+        "/devices/whoami",
+        wrap = "RequireAuth::with_priv_level(UserPrivilege::Normal as u32)"
+    )]
+    async fn whoami(cur_user: Option<AuthenticatedUser>) -> impl Responder {
+        match cur_user {
+            Some(user) => HttpResponse::Ok().body(user.id.to_string()),
+            None => HttpResponse::Accepted().finish(),
+        }
+    }
+
+    async fn new_key(app: &AppState, uid: u64, scopes: &str) -> String {
+        let key = new_api_key();
+        app.db
+            .create_api_key(&NewApiKey {
+                uid,
+                name: "test",
+                prefix: visible_prefix(&key),
+                key_hash: &hash_token(&key),
+                scopes,
+                allowed_ips: None,
+                expires_at: None,
+            })
+            .await
+            .unwrap();
+        key
+    }
+
+    #[actix_web::test]
+    /// Each way of authenticating, through the middleware into a handler
+    async fn auth_paths() {
+        let mut env = Config::init();
+        env.api_key.query = false;
+        let login_guard = LoginGuard::new(&env.login);
+        let request_limit = RequestLimiter::new(&env.rate_limit);
+        let db = DBClient::new(&DBClient::get_database_url());
+        let tokens = OneTimeTokens::new(&env.one_time_token, &env.jwt.secret, &db);
+        let app_state = AppState {
+            env: Box::leak(Box::new(env)),
+            db,
+            tokens,
+            rate_limit: Cache::new(1024),
+            login_guard,
+            request_limit,
+            totp_challenge: Cache::new(1024),
+            hub: RecordHub::new(),
+        };
+        let uid = app_state
+            .db
+            .register_user(&NewUser {
+                username: &format!("auth{}", Uuid::new_v4().simple()),
+                email: &format!("auth{}@mail.com", Uuid::new_v4().simple()),
+                hashed_password: &get_pwd_hash(b"Aaa123,????", &Default::default()).unwrap(),
+                privilege: UserPrivilege::Normal as u32,
+            })
+            .await
+            .unwrap();
+        let jti = Uuid::new_v4().to_string();
+        let expires = Utc::now().naive_utc() + Duration::hours(1);
+        let sid = app_state
+            .db
+            .create_session(&NewSession {
+                uid,
+                refresh_hash: &hash_token(&jti),
+                jti: &jti,
+                org: None,
+                user_agent: None,
+                ip: None,
+                expires_at: &expires,
+            })
+            .await
+            .unwrap();
+        let token = app_state
+            .get_jwt_cookie(uid, None, sid, &jti)
+            .value()
+            .to_string();
+        let reader = new_key(&app_state, uid, "read_records").await;
+        let writer = new_key(&app_state, uid, "write_records").await;
+
+        let srv = test::init_service(
+            App::new()
+                .app_data(web::Data::new(app_state.clone()))
+                .service(whoami),
+        )
+        .await;
+
+        // no credentials: anonymous
+        let res = test::call_service(
+            &srv,
+            test::TestRequest::get().uri("/devices/whoami").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        // JWT in the cookie and in the header
+        let res = test::call_service(
+            &srv,
+            test::TestRequest::get()
+                .uri("/devices/whoami")
+                .cookie(Cookie::new("token", token.clone()))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, uid.to_string());
+        let res = test::call_service(
+            &srv,
+            test::TestRequest::get()
+                .uri("/devices/whoami")
+                .insert_header(("Authorization", format!("Bearer {token}")))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // API key in the header, checked against its scopes
+        let res = test::call_service(
+            &srv,
+            test::TestRequest::get()
+                .uri("/devices/whoami")
+                .insert_header((API_KEY_HEADER, reader.clone()))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, uid.to_string());
+        let res = test::try_call_service(
+            &srv,
+            test::TestRequest::get()
+                .uri("/devices/whoami")
+                .insert_header((API_KEY_HEADER, writer))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(
+            res.err().unwrap().as_response_error().status_code(),
+            StatusCode::FORBIDDEN
+        );
+        let res = test::try_call_service(
+            &srv,
+            test::TestRequest::get()
+                .uri("/devices/whoami")
+                .insert_header((API_KEY_HEADER, new_api_key()))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(
+            res.err().unwrap().as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+        // API key in the query: ignored unless enabled
+        let res = test::call_service(
+            &srv,
+            test::TestRequest::get()
+                .uri(&format!("/devices/whoami?api_key={reader}"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let mut env = Config::init();
+        env.api_key.query = true;
+        let query_srv = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState {
+                    env: Box::leak(Box::new(env)),
+                    ..app_state.clone()
+                }))
+                .service(whoami),
+        )
+        .await;
+        let res = test::call_service(
+            &query_srv,
+            test::TestRequest::get()
+                .uri(&format!("/devices/whoami?api_key={reader}"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // revoked session: its JWT stops working at once
+        let now = Utc::now().naive_utc();
+        app_state
+            .db
+            .revoke_sessions(uid, RevokeScope::All, &now, &expires)
+            .await
+            .unwrap();
+        let res = test::try_call_service(
+            &srv,
+            test::TestRequest::get()
+                .uri("/devices/whoami")
+                .cookie(Cookie::new("token", token))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(
+            res.err().unwrap().as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
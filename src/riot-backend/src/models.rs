@@ -1,10 +1,13 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
 use chrono::NaiveDateTime;
 
-use chrono::naive::serde::ts_milliseconds;
+use chrono::naive::serde::{ts_milliseconds, ts_milliseconds_option};
 use diesel::deserialize::Queryable;
 use diesel::mysql::Mysql;
 use diesel::query_builder::AsChangeset;
-use diesel::{Identifiable, Insertable, Selectable};
+use diesel::{AsExpression, FromSqlRow, Identifiable, Insertable, Selectable};
 
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
@@ -23,6 +26,41 @@ pub struct Response {
 
 // Internal Data Structures & SQL Schemas
 
+/// Map a field-less enum to a `VARCHAR` column holding its snake_case name
+macro_rules! varchar_enum {
+    ($ty:ident { $($variant:ident => $name:literal),+ $(,)? }) => {
+        impl $ty {
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $($ty::$variant => $name,)+
+                }
+            }
+        }
+        impl diesel::serialize::ToSql<diesel::sql_types::Varchar, Mysql> for $ty {
+            fn to_sql<'b>(
+                &'b self,
+                out: &mut diesel::serialize::Output<'b, '_, Mysql>,
+            ) -> diesel::serialize::Result {
+                use std::io::Write;
+                out.write_all(self.as_str().as_bytes())?;
+                Ok(diesel::serialize::IsNull::No)
+            }
+        }
+        impl diesel::deserialize::FromSql<diesel::sql_types::Varchar, Mysql> for $ty {
+            fn from_sql(bytes: diesel::mysql::MysqlValue<'_>) -> diesel::deserialize::Result<Self> {
+                let value =
+                    <String as diesel::deserialize::FromSql<diesel::sql_types::Varchar, Mysql>>::from_sql(
+                        bytes,
+                    )?;
+                match value.as_str() {
+                    $($name => Ok($ty::$variant),)+
+                    _ => Err(format!("Unrecognized {} value: {}", stringify!($ty), value).into()),
+                }
+            }
+        }
+    };
+}
+
 /// Handy enum to set a proper privilege value, only for convenience, not a strong type constraint.
 /// Reserved values for future uses.
 #[allow(unused)]
@@ -50,7 +88,6 @@ pub struct User {
     pub email: String,
     pub password: String,
     pub privilege: u32,
-    pub api_key: Option<String>,
     /// Precision: milliseconds
     #[serde(with = "ts_milliseconds")]
     pub since: NaiveDateTime,
@@ -66,7 +103,27 @@ pub struct NewUser<'a> {
     #[diesel(column_name = password)]
     pub hashed_password: &'a str,
     pub privilege: u32,
-    pub api_key: Option<&'a str>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::identity)]
+#[diesel(check_for_backend(Mysql))]
+/// External identity to link to a user
+pub struct NewIdentity<'a> {
+    pub uid: u64,
+    pub issuer: &'a str,
+    pub subject: &'a str,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::one_time_token)]
+#[diesel(check_for_backend(Mysql))]
+/// Issued one-time token, by its keyed hash
+pub struct NewOneTimeToken<'a> {
+    pub token_hash: &'a str,
+    pub purpose: &'a str,
+    pub uid: u64,
+    pub expires_at: &'a NaiveDateTime,
 }
 
 #[derive(Clone, Debug, AsChangeset, Identifiable)]
@@ -80,9 +137,201 @@ pub struct UpdateUser<'a> {
     pub hashed_password: Option<&'a str>,
     pub privilege: Option<u32>,
     pub activated: Option<bool>,
-    pub api_key: Option<Option<&'a str>>, // TODO: use js-option for tri-state semantic
 }
 
+#[derive(
+    ToSchema, Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, Debug, PartialEq, Eq,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+#[serde(rename_all = "snake_case")]
+/// What an API key may be used for
+pub enum ApiKeyScope {
+    /// Read devices, tags and their records
+    ReadRecords,
+    /// Publish records (MQTT or HTTP)
+    WriteRecords,
+    /// Create, modify and delete devices, tags and the like
+    ManageDevices,
+    /// Webhooks, their secrets and delivery logs
+    ManageWebhooks,
+    /// Everything the user can do, account management included
+    Admin,
+}
+varchar_enum!(ApiKeyScope {
+    ReadRecords => "read_records",
+    WriteRecords => "write_records",
+    ManageDevices => "manage_devices",
+    ManageWebhooks => "manage_webhooks",
+    Admin => "admin",
+});
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::api_key)]
+#[diesel(check_for_backend(Mysql))]
+/// Named API key of a user, the key itself is only shown once on creation
+pub struct ApiKey {
+    pub id: u64,
+    pub uid: u64,
+    /// Organization the key works in
+    pub oid: u64,
+    pub name: String,
+    /// Leading characters of the key
+    pub prefix: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    /// Granted scopes, comma separated, e.g. "read_records,write_records"
+    pub scopes: String,
+    /// Comma separated IPs the key may be used from, any if absent
+    pub allowed_ips: Option<String>,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds_option")]
+    pub expires_at: Option<NaiveDateTime>,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds_option")]
+    pub last_used: Option<NaiveDateTime>,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: NaiveDateTime,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds_option")]
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl ApiKey {
+    /// `admin` grants every scope
+    pub fn permits(&self, scope: ApiKeyScope) -> bool {
+        self.scopes
+            .split(',')
+            .any(|s| s == scope.as_str() || s == ApiKeyScope::Admin.as_str())
+    }
+    /// Whether the key may be used from `ip` (`None` if unknown)
+    pub fn allows_ip(&self, ip: Option<IpAddr>) -> bool {
+        match &self.allowed_ips {
+            None => true,
+            Some(allowed) => ip.is_some_and(|ip| {
+                allowed
+                    .split(',')
+                    .any(|a| a.trim().parse::<IpAddr>() == Ok(ip))
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::api_key)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewApiKey<'a> {
+    pub uid: u64,
+    pub oid: u64,
+    pub name: &'a str,
+    pub prefix: &'a str,
+    pub key_hash: &'a str,
+    pub scopes: &'a str,
+    pub allowed_ips: Option<&'a str>,
+    pub expires_at: Option<&'a NaiveDateTime>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::session)]
+#[diesel(check_for_backend(Mysql))]
+/// Logged-in session of a user
+pub struct Session {
+    pub id: u64,
+    pub uid: u64,
+    #[serde(skip_serializing)]
+    pub refresh_hash: String,
+    #[serde(skip_serializing)]
+    pub jti: String,
+    /// Active organization
+    pub org: Option<u64>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: NaiveDateTime,
+    /// Last time the session was refreshed. Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub last_seen: NaiveDateTime,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub expires_at: NaiveDateTime,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds_option")]
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::session)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewSession<'a> {
+    pub uid: u64,
+    pub refresh_hash: &'a str,
+    pub jti: &'a str,
+    pub org: Option<u64>,
+    pub user_agent: Option<&'a str>,
+    pub ip: Option<&'a str>,
+    pub expires_at: &'a NaiveDateTime,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Clone, Debug)]
+/// Session listed to its user
+pub struct ActiveSession {
+    #[serde(flatten)]
+    pub session: Session,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+#[derive(Selectable, Queryable, Clone, Debug)]
+#[diesel(table_name = crate::schema::totp)]
+#[diesel(check_for_backend(Mysql))]
+/// TOTP second factor of a user, enabled once confirmed
+pub struct Totp {
+    /// Base32 shared secret
+    pub secret: String,
+    pub confirmed_at: Option<NaiveDateTime>,
+}
+
+#[derive(
+    ToSchema, Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, Debug, PartialEq, Eq,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+#[serde(rename_all = "snake_case")]
+/// Presence of a device
+pub enum DeviceStatus {
+    /// No data received since registration
+    NeverSeen,
+    /// Data received within the heartbeat timeout
+    Online,
+    /// Heartbeat timed out, or the device's MQTT Last Will arrived
+    Offline,
+}
+varchar_enum!(DeviceStatus {
+    NeverSeen => "never_seen",
+    Online => "online",
+    Offline => "offline",
+});
+
+#[derive(
+    ToSchema, Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, Debug, PartialEq, Eq,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+#[serde(rename_all = "snake_case")]
+/// What triggered a presence transition
+pub enum PresenceCause {
+    /// A record arrived
+    Heartbeat,
+    /// No record within the heartbeat timeout
+    Timeout,
+    /// MQTT Last Will message published by the broker
+    LastWill,
+}
+varchar_enum!(PresenceCause {
+    Heartbeat => "heartbeat",
+    Timeout => "timeout",
+    LastWill => "last_will",
+});
+
 #[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
 #[diesel(table_name = crate::schema::device)]
 #[diesel(check_for_backend(Mysql))]
@@ -105,6 +354,59 @@ pub struct Device {
     #[serde(with = "ts_milliseconds")]
     pub last_update: NaiveDateTime,
     pub activated: bool,
+    pub status: DeviceStatus,
+    /// Unit: seconds. Goes offline if no data is received within this period
+    pub heartbeat_timeout: u32,
+    /// Track the `lat`/`lng` fields of JSON payloads as the device's location
+    pub locate_from_payload: bool,
+    /// Arbitrary JSON object, e.g. firmware version, serial number, install date
+    #[schema(value_type = Option<Object>)]
+    pub metadata: Option<serde_json::Value>,
+    /// Owning organization
+    pub oid: u64,
+    /// Topic before the device was deleted, given back on restore
+    pub original_topic: Option<String>,
+    /// When the device was deleted, `None` unless it is in the trash. Precision: milliseconds
+    #[serde(with = "ts_milliseconds_option")]
+    pub trashed_at: Option<NaiveDateTime>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Clone, Debug)]
+/// IoT device with its labels
+pub struct LabeledDevice {
+    #[serde(flatten)]
+    pub device: Device,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl LabeledDevice {
+    /// Pair devices with their labels
+    pub fn attach(devices: Vec<Device>, labels: Vec<DeviceLabel>) -> Vec<Self> {
+        let mut by_device: BTreeMap<u64, BTreeMap<String, String>> = BTreeMap::new();
+        for label in labels {
+            by_device
+                .entry(label.did)
+                .or_default()
+                .insert(label.key, label.value);
+        }
+        devices
+            .into_iter()
+            .map(|device| LabeledDevice {
+                labels: by_device.remove(&device.id).unwrap_or_default(),
+                device,
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Selectable, Queryable, Insertable, Clone, Debug)]
+#[diesel(table_name = crate::schema::device_label)]
+#[diesel(check_for_backend(Mysql))]
+/// Key/value label of a device
+pub struct DeviceLabel {
+    pub did: u64,
+    pub key: String,
+    pub value: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Insertable)]
@@ -112,6 +414,7 @@ pub struct Device {
 #[diesel(check_for_backend(Mysql))]
 pub struct NewDevice<'a> {
     pub uid: u64,
+    pub oid: u64,
     pub name: &'a str,
     pub desc: Option<&'a str>,
     pub dtype: u32,
@@ -120,6 +423,11 @@ pub struct NewDevice<'a> {
     /// Precision: 64 bits
     pub longitude: Option<f64>,
     pub topic: &'a str,
+    /// Unit: seconds. Use the default if `None`
+    pub heartbeat_timeout: Option<u32>,
+    pub locate_from_payload: Option<bool>,
+    #[serde(skip_deserializing)]
+    pub metadata: Option<&'a serde_json::Value>,
 }
 
 #[derive(AsChangeset, Clone, Debug, Identifiable)]
@@ -138,6 +446,29 @@ pub struct UpdateDevice<'a> {
     pub last_update: Option<&'a NaiveDateTime>,
     pub activated: Option<bool>,
     pub topic: Option<&'a str>,
+    /// Unit: seconds
+    pub heartbeat_timeout: Option<u32>,
+    pub locate_from_payload: Option<bool>,
+    pub metadata: Option<Option<&'a serde_json::Value>>,
+}
+
+#[derive(AsChangeset, Clone, Debug)]
+#[diesel(table_name = crate::schema::device)]
+#[diesel(check_for_backend(Mysql))]
+/// Changes applied to every device under a tag
+pub struct BulkUpdateDevice<'a> {
+    pub desc: Option<Option<&'a str>>,
+    pub dtype: Option<u32>,
+    pub activated: Option<bool>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Clone, Debug)]
+/// Outcome of a bulk operation on a single device.
+/// `status` is "ok", "skipped" or "failed".
+pub struct BulkResult {
+    pub did: u64,
+    pub status: &'static str,
+    pub message: String,
 }
 
 #[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Insertable, Clone, Debug)]
@@ -150,6 +481,10 @@ pub struct Tag {
     pub name: String,
     pub desc: Option<String>,
     pub activated: bool,
+    /// Parent tag, e.g. building > floor > room
+    pub parent: Option<u64>,
+    /// Owning organization
+    pub oid: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Insertable)]
@@ -157,9 +492,11 @@ pub struct Tag {
 #[diesel(check_for_backend(Mysql))]
 pub struct NewTag<'a> {
     pub uid: u64,
+    pub oid: u64,
     pub name: &'a str,
     pub desc: Option<&'a str>,
     pub activated: bool,
+    pub parent: Option<u64>,
 }
 
 #[derive(AsChangeset, Clone, Debug, Identifiable)]
@@ -170,6 +507,270 @@ pub struct UpdateTag<'a> {
     pub name: Option<&'a str>,
     pub desc: Option<Option<&'a str>>,
     pub activated: Option<bool>,
+    pub parent: Option<Option<u64>>,
+}
+
+#[derive(
+    ToSchema,
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+#[serde(rename_all = "snake_case")]
+/// Access level of a user to a device or tag, each level includes the ones before it
+pub enum Permission {
+    /// View the device/tag and its records
+    Read,
+    /// Update it and upload records
+    Write,
+    /// Delete it and share it with others
+    Admin,
+    /// Implied by ownership, cannot be granted
+    Owner,
+}
+varchar_enum!(Permission {
+    Read => "read",
+    Write => "write",
+    Admin => "admin",
+    Owner => "owner",
+});
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Insertable, Clone, Debug)]
+#[diesel(table_name = crate::schema::device_share)]
+#[diesel(check_for_backend(Mysql))]
+/// Access to a device granted to another user
+pub struct DeviceShare {
+    pub did: u64,
+    /// Grantee
+    pub uid: u64,
+    pub permission: Permission,
+    pub granted_by: u64,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::device_share)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewDeviceShare {
+    pub did: u64,
+    pub uid: u64,
+    pub permission: Permission,
+    pub granted_by: u64,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Insertable, Clone, Debug)]
+#[diesel(table_name = crate::schema::tag_share)]
+#[diesel(check_for_backend(Mysql))]
+/// Access to a tag (and the owner's devices under it) granted to another user
+pub struct TagShare {
+    pub tid: u64,
+    /// Grantee
+    pub uid: u64,
+    pub permission: Permission,
+    pub granted_by: u64,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::tag_share)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewTagShare {
+    pub tid: u64,
+    pub uid: u64,
+    pub permission: Permission,
+    pub granted_by: u64,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Clone, Debug)]
+/// Device shared with the current user
+pub struct SharedDevice {
+    #[serde(flatten)]
+    pub device: Device,
+    pub permission: Permission,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Clone, Debug)]
+/// Tag shared with the current user
+pub struct SharedTag {
+    #[serde(flatten)]
+    pub tag: Tag,
+    pub permission: Permission,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::organization)]
+#[diesel(check_for_backend(Mysql))]
+/// Organization (team) owning devices and tags
+pub struct Organization {
+    pub id: u64,
+    pub name: String,
+    /// Set for the personal organization of a user, which cannot have other members
+    pub personal_of: Option<u64>,
+    /// SHA-256 (hex) of the API key, which is only shown once when generated
+    #[serde(skip_serializing)]
+    pub api_key: Option<String>,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub since: NaiveDateTime,
+    pub activated: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::organization)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewOrganization<'a> {
+    pub name: &'a str,
+    pub personal_of: Option<u64>,
+}
+
+#[derive(
+    ToSchema,
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+#[serde(rename_all = "snake_case")]
+/// Role of a member in an organization, each role includes the ones before it
+pub enum OrgRole {
+    /// Read devices, tags and records of the organization
+    Viewer,
+    /// Also add and update devices and tags
+    Member,
+    /// Also delete them and manage members
+    Admin,
+    /// Also hand out the admin/owner roles
+    Owner,
+}
+varchar_enum!(OrgRole {
+    Viewer => "viewer",
+    Member => "member",
+    Admin => "admin",
+    Owner => "owner",
+});
+
+impl OrgRole {
+    /// Permission on the devices and tags owned by the organization
+    pub fn permission(self) -> Permission {
+        match self {
+            OrgRole::Viewer => Permission::Read,
+            OrgRole::Member => Permission::Write,
+            OrgRole::Admin => Permission::Admin,
+            OrgRole::Owner => Permission::Owner,
+        }
+    }
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Insertable, Clone, Debug)]
+#[diesel(table_name = crate::schema::membership)]
+#[diesel(check_for_backend(Mysql))]
+pub struct Membership {
+    pub oid: u64,
+    pub uid: u64,
+    pub role: OrgRole,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub since: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::membership)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewMembership {
+    pub oid: u64,
+    pub uid: u64,
+    pub role: OrgRole,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Clone, Debug)]
+/// Organization the current user is a member of
+pub struct MemberOrganization {
+    #[serde(flatten)]
+    pub organization: Organization,
+    pub role: OrgRole,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Queryable, Clone, Debug)]
+/// Member of an organization
+pub struct OrgMember {
+    pub uid: u64,
+    pub username: String,
+    pub role: OrgRole,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub since: NaiveDateTime,
+}
+
+#[derive(
+    ToSchema, Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, Debug, PartialEq, Eq,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+#[serde(rename_all = "snake_case")]
+/// State of a device transfer
+pub enum TransferStatus {
+    /// Waiting for the recipient
+    Pending,
+    Accepted,
+    /// Cancelled by the sender or declined by the recipient
+    Cancelled,
+}
+varchar_enum!(TransferStatus {
+    Pending => "pending",
+    Accepted => "accepted",
+    Cancelled => "cancelled",
+});
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::device_transfer)]
+#[diesel(check_for_backend(Mysql))]
+/// Transfer of a device to another user
+pub struct DeviceTransfer {
+    pub id: u64,
+    pub did: u64,
+    /// Sender (the owner at the time)
+    pub from_uid: u64,
+    /// Recipient
+    pub to_uid: u64,
+    /// Whether records are handed over, otherwise they are purged on acceptance
+    pub with_records: bool,
+    pub status: TransferStatus,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: NaiveDateTime,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds_option")]
+    pub resolved_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::device_transfer)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewDeviceTransfer {
+    pub did: u64,
+    pub from_uid: u64,
+    pub to_uid: u64,
+    pub with_records: bool,
 }
 
 #[derive(
@@ -179,13 +780,13 @@ pub struct UpdateTag<'a> {
 #[diesel(check_for_backend(Mysql))]
 /// Device data record
 pub struct Record {
-    id: u64,
+    pub id: u64,
     /// Device id
-    did: u64,
-    payload: Vec<u8>,
+    pub did: u64,
+    pub payload: Vec<u8>,
     /// Precision: milliseconds
     #[serde(with = "ts_milliseconds")]
-    timestamp: NaiveDateTime,
+    pub timestamp: NaiveDateTime,
 }
 
 #[derive(Clone, Debug, Insertable)]
@@ -209,3 +810,364 @@ pub struct Owns {
     /// Device id
     did: u64,
 }
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::presence_event)]
+#[diesel(check_for_backend(Mysql))]
+/// Online/offline transition of a device
+pub struct PresenceEvent {
+    pub id: u64,
+    /// Device id
+    pub did: u64,
+    /// Status after the transition
+    pub status: DeviceStatus,
+    pub cause: PresenceCause,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::presence_event)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewPresenceEvent<'a> {
+    pub did: u64,
+    pub status: DeviceStatus,
+    pub cause: PresenceCause,
+    /// Precision: milliseconds
+    pub timestamp: &'a NaiveDateTime,
+}
+
+#[derive(
+    ToSchema, Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, Debug, PartialEq, Eq,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+#[serde(rename_all = "snake_case")]
+/// Where a device location comes from
+pub enum LocationSource {
+    /// Set through the device API
+    Api,
+    /// Decoded from the `lat`/`lng` fields of a JSON payload
+    Payload,
+}
+varchar_enum!(LocationSource {
+    Api => "api",
+    Payload => "payload",
+});
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::location)]
+#[diesel(check_for_backend(Mysql))]
+/// Historical location of a device
+pub struct Location {
+    pub id: u64,
+    /// Device id
+    pub did: u64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub source: LocationSource,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::location)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewLocation<'a> {
+    pub did: u64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub source: LocationSource,
+    /// Precision: milliseconds
+    pub timestamp: &'a NaiveDateTime,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::geofence)]
+#[diesel(check_for_backend(Mysql))]
+/// Circular area raising enter/exit events for the owner's devices
+pub struct Geofence {
+    pub id: u64,
+    pub uid: u64,
+    pub name: String,
+    /// Center
+    pub latitude: f64,
+    /// Center
+    pub longitude: f64,
+    /// Unit: meters
+    pub radius: f64,
+    pub activated: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::geofence)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewGeofence<'a> {
+    pub uid: u64,
+    pub name: &'a str,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Unit: meters
+    pub radius: f64,
+}
+
+#[derive(
+    ToSchema, Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, Debug, PartialEq, Eq,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum GeofenceTransition {
+    Enter,
+    Exit,
+}
+varchar_enum!(GeofenceTransition {
+    Enter => "enter",
+    Exit => "exit",
+});
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::geofence_event)]
+#[diesel(check_for_backend(Mysql))]
+/// A device entered/exited a geofence
+pub struct GeofenceEvent {
+    pub id: u64,
+    /// Geofence id
+    pub gid: u64,
+    /// Device id
+    pub did: u64,
+    pub transition: GeofenceTransition,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::geofence_event)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewGeofenceEvent<'a> {
+    pub gid: u64,
+    pub did: u64,
+    pub transition: GeofenceTransition,
+    /// Precision: milliseconds
+    pub timestamp: &'a NaiveDateTime,
+}
+
+#[derive(
+    ToSchema, Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, Debug, PartialEq, Eq,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+varchar_enum!(AlertSeverity {
+    Info => "info",
+    Warning => "warning",
+    Critical => "critical",
+});
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::alert)]
+#[diesel(check_for_backend(Mysql))]
+/// Alert raised for a device. Repeated alerts (same severity and message) are merged while unresolved
+pub struct Alert {
+    pub id: u64,
+    /// Device id
+    pub did: u64,
+    pub severity: AlertSeverity,
+    pub message: String,
+    /// Times raised while unresolved
+    pub count: u32,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub raised_at: NaiveDateTime,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub last_raised_at: NaiveDateTime,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds_option")]
+    pub acknowledged_at: Option<NaiveDateTime>,
+    /// User id
+    pub acknowledged_by: Option<u64>,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds_option")]
+    pub resolved_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::alert)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewAlert<'a> {
+    pub did: u64,
+    pub severity: AlertSeverity,
+    pub message: &'a str,
+    /// Precision: milliseconds
+    pub raised_at: &'a NaiveDateTime,
+    /// Precision: milliseconds
+    pub last_raised_at: &'a NaiveDateTime,
+}
+
+#[derive(
+    ToSchema, Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, Debug, PartialEq, Eq,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+/// Event that can be delivered to webhooks
+pub enum WebhookEvent {
+    /// A record is stored, data = `Record`
+    #[serde(rename = "record.created")]
+    RecordCreated,
+    /// data = `Device`
+    #[serde(rename = "device.created")]
+    DeviceCreated,
+    /// data = `Device`
+    #[serde(rename = "device.updated")]
+    DeviceUpdated,
+    /// data = `Device`
+    #[serde(rename = "device.deleted")]
+    DeviceDeleted,
+    /// Heartbeat timeout or Last Will received, data = `Device`
+    #[serde(rename = "device.offline")]
+    DeviceOffline,
+    /// data = `Tag`
+    #[serde(rename = "tag.created")]
+    TagCreated,
+    /// data = `Tag`
+    #[serde(rename = "tag.updated")]
+    TagUpdated,
+    /// data = `Tag`
+    #[serde(rename = "tag.deleted")]
+    TagDeleted,
+    /// data = `{"tid": .., "did": ..}`
+    #[serde(rename = "tag.device_added")]
+    TagDeviceAdded,
+    /// data = `{"tid": .., "did": ..}`
+    #[serde(rename = "tag.device_removed")]
+    TagDeviceRemoved,
+    /// A new alert of a device, data = `{"alert": Alert, "device": {"id", "name", "uid"}}`
+    #[serde(rename = "alert.raised")]
+    AlertRaised,
+    /// Test delivery requested by the user, always delivered regardless of the filter
+    #[serde(rename = "webhook.ping")]
+    Ping,
+}
+varchar_enum!(WebhookEvent {
+    RecordCreated => "record.created",
+    DeviceCreated => "device.created",
+    DeviceUpdated => "device.updated",
+    DeviceDeleted => "device.deleted",
+    DeviceOffline => "device.offline",
+    TagCreated => "tag.created",
+    TagUpdated => "tag.updated",
+    TagDeleted => "tag.deleted",
+    TagDeviceAdded => "tag.device_added",
+    TagDeviceRemoved => "tag.device_removed",
+    AlertRaised => "alert.raised",
+    Ping => "webhook.ping",
+});
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::webhook)]
+#[diesel(check_for_backend(Mysql))]
+/// Endpoint receiving signed event payloads
+pub struct Webhook {
+    pub id: u64,
+    /// Owner id
+    pub uid: u64,
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature in the `X-RIoT-Signature` header, only shown on creation
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// Subscribed events, comma separated, e.g. "record.created,device.offline"
+    pub events: String,
+    pub activated: bool,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: NaiveDateTime,
+}
+
+impl Webhook {
+    pub fn subscribes(&self, event: WebhookEvent) -> bool {
+        event == WebhookEvent::Ping || self.events.split(',').any(|e| e == event.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::webhook)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewWebhook<'a> {
+    pub uid: u64,
+    pub url: &'a str,
+    pub secret: &'a str,
+    pub events: &'a str,
+}
+
+#[derive(Clone, Debug, AsChangeset, Identifiable)]
+#[diesel(table_name = crate::schema::webhook)]
+#[diesel(check_for_backend(Mysql))]
+pub struct UpdateWebhook<'a> {
+    pub id: u64,
+    pub url: Option<&'a str>,
+    pub events: Option<&'a str>,
+    pub activated: Option<bool>,
+}
+
+#[derive(
+    ToSchema, Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, Debug, PartialEq, Eq,
+)]
+#[diesel(sql_type = diesel::sql_types::Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Waiting for the (next) attempt
+    Pending,
+    Delivered,
+    /// Retries exhausted (dead letter), can be re-queued by the user
+    Dead,
+}
+varchar_enum!(DeliveryStatus {
+    Pending => "pending",
+    Delivered => "delivered",
+    Dead => "dead",
+});
+
+#[derive(ToSchema, Serialize, Deserialize, Selectable, Queryable, Identifiable, Clone, Debug)]
+#[diesel(table_name = crate::schema::webhook_delivery)]
+#[diesel(check_for_backend(Mysql))]
+/// A webhook event delivery and the result of its last attempt
+pub struct WebhookDelivery {
+    pub id: u64,
+    /// Webhook id
+    pub wid: u64,
+    pub event: WebhookEvent,
+    /// JSON body sent
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub next_attempt_at: NaiveDateTime,
+    /// HTTP status of the last attempt, if it got a response
+    pub response_status: Option<u16>,
+    pub last_error: Option<String>,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: NaiveDateTime,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds_option")]
+    pub delivered_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = crate::schema::webhook_delivery)]
+#[diesel(check_for_backend(Mysql))]
+pub struct NewWebhookDelivery<'a> {
+    pub wid: u64,
+    pub event: WebhookEvent,
+    pub payload: &'a str,
+    /// Precision: milliseconds
+    pub next_attempt_at: &'a NaiveDateTime,
+    /// Precision: milliseconds
+    pub created_at: &'a NaiveDateTime,
+}
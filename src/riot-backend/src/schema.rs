@@ -1,5 +1,44 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    alert (id) {
+        id -> Unsigned<Bigint>,
+        did -> Unsigned<Bigint>,
+        #[max_length = 16]
+        severity -> Varchar,
+        #[max_length = 1024]
+        message -> Varchar,
+        count -> Unsigned<Integer>,
+        raised_at -> Datetime,
+        last_raised_at -> Datetime,
+        acknowledged_at -> Nullable<Datetime>,
+        acknowledged_by -> Nullable<Unsigned<Bigint>>,
+        resolved_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    api_key (id) {
+        id -> Unsigned<Bigint>,
+        uid -> Unsigned<Bigint>,
+        #[max_length = 256]
+        name -> Varchar,
+        #[max_length = 16]
+        prefix -> Varchar,
+        #[max_length = 64]
+        key_hash -> Char,
+        #[max_length = 256]
+        scopes -> Varchar,
+        #[max_length = 1024]
+        allowed_ips -> Nullable<Varchar>,
+        expires_at -> Nullable<Datetime>,
+        last_used -> Nullable<Datetime>,
+        created_at -> Datetime,
+        revoked_at -> Nullable<Datetime>,
+        oid -> Unsigned<Bigint>,
+    }
+}
+
 diesel::table! {
     device (id) {
         id -> Unsigned<Bigint>,
@@ -15,6 +54,133 @@ diesel::table! {
         activated -> Bool,
         #[max_length = 512]
         topic -> Varchar,
+        #[max_length = 16]
+        status -> Varchar,
+        heartbeat_timeout -> Unsigned<Integer>,
+        locate_from_payload -> Bool,
+        metadata -> Nullable<Json>,
+        oid -> Unsigned<Bigint>,
+        #[max_length = 512]
+        original_topic -> Nullable<Varchar>,
+        trashed_at -> Nullable<Datetime>,
+        purging -> Bool,
+    }
+}
+
+diesel::table! {
+    device_label (did, key) {
+        did -> Unsigned<Bigint>,
+        #[max_length = 64]
+        key -> Varchar,
+        #[max_length = 256]
+        value -> Varchar,
+    }
+}
+
+diesel::table! {
+    device_share (did, uid) {
+        did -> Unsigned<Bigint>,
+        uid -> Unsigned<Bigint>,
+        #[max_length = 16]
+        permission -> Varchar,
+        granted_by -> Unsigned<Bigint>,
+        created_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    device_transfer (id) {
+        id -> Unsigned<Bigint>,
+        did -> Unsigned<Bigint>,
+        from_uid -> Unsigned<Bigint>,
+        to_uid -> Unsigned<Bigint>,
+        with_records -> Bool,
+        #[max_length = 16]
+        status -> Varchar,
+        created_at -> Datetime,
+        resolved_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    geofence (id) {
+        id -> Unsigned<Bigint>,
+        uid -> Unsigned<Bigint>,
+        #[max_length = 256]
+        name -> Varchar,
+        latitude -> Double,
+        longitude -> Double,
+        radius -> Double,
+        activated -> Bool,
+    }
+}
+
+diesel::table! {
+    geofence_event (id) {
+        id -> Unsigned<Bigint>,
+        gid -> Unsigned<Bigint>,
+        did -> Unsigned<Bigint>,
+        #[max_length = 16]
+        transition -> Varchar,
+        timestamp -> Datetime,
+    }
+}
+
+diesel::table! {
+    identity (id) {
+        id -> Unsigned<Bigint>,
+        uid -> Unsigned<Bigint>,
+        #[max_length = 256]
+        issuer -> Varchar,
+        #[max_length = 256]
+        subject -> Varchar,
+        created_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    location (id) {
+        id -> Unsigned<Bigint>,
+        did -> Unsigned<Bigint>,
+        latitude -> Double,
+        longitude -> Double,
+        #[max_length = 16]
+        source -> Varchar,
+        timestamp -> Datetime,
+    }
+}
+
+diesel::table! {
+    membership (oid, uid) {
+        oid -> Unsigned<Bigint>,
+        uid -> Unsigned<Bigint>,
+        #[max_length = 16]
+        role -> Varchar,
+        since -> Datetime,
+    }
+}
+
+diesel::table! {
+    one_time_token (token_hash) {
+        #[max_length = 64]
+        token_hash -> Char,
+        #[max_length = 32]
+        purpose -> Varchar,
+        uid -> Unsigned<Bigint>,
+        expires_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    organization (id) {
+        id -> Unsigned<Bigint>,
+        #[max_length = 256]
+        name -> Varchar,
+        personal_of -> Nullable<Unsigned<Bigint>>,
+        #[max_length = 64]
+        api_key -> Nullable<Varchar>,
+        since -> Datetime,
+        activated -> Bool,
     }
 }
 
@@ -25,6 +191,18 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    presence_event (id) {
+        id -> Unsigned<Bigint>,
+        did -> Unsigned<Bigint>,
+        #[max_length = 16]
+        status -> Varchar,
+        #[max_length = 16]
+        cause -> Varchar,
+        timestamp -> Datetime,
+    }
+}
+
 diesel::table! {
     record (id) {
         id -> Unsigned<Bigint>,
@@ -34,6 +212,44 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    recovery_code (id) {
+        id -> Unsigned<Bigint>,
+        uid -> Unsigned<Bigint>,
+        #[max_length = 64]
+        code_hash -> Char,
+        used_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    revoked_token (jti) {
+        #[max_length = 64]
+        jti -> Varchar,
+        expires_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    session (id) {
+        id -> Unsigned<Bigint>,
+        uid -> Unsigned<Bigint>,
+        #[max_length = 64]
+        refresh_hash -> Char,
+        #[max_length = 64]
+        jti -> Varchar,
+        org -> Nullable<Unsigned<Bigint>>,
+        #[max_length = 512]
+        user_agent -> Nullable<Varchar>,
+        #[max_length = 64]
+        ip -> Nullable<Varchar>,
+        created_at -> Datetime,
+        last_seen -> Datetime,
+        expires_at -> Datetime,
+        revoked_at -> Nullable<Datetime>,
+    }
+}
+
 diesel::table! {
     tag (id) {
         id -> Unsigned<Bigint>,
@@ -42,6 +258,30 @@ diesel::table! {
         name -> Varchar,
         desc -> Nullable<Text>,
         activated -> Bool,
+        parent -> Nullable<Unsigned<Bigint>>,
+        oid -> Unsigned<Bigint>,
+    }
+}
+
+diesel::table! {
+    tag_share (tid, uid) {
+        tid -> Unsigned<Bigint>,
+        uid -> Unsigned<Bigint>,
+        #[max_length = 16]
+        permission -> Varchar,
+        granted_by -> Unsigned<Bigint>,
+        created_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    totp (uid) {
+        uid -> Unsigned<Bigint>,
+        #[max_length = 64]
+        secret -> Varchar,
+        last_step -> Unsigned<Bigint>,
+        created_at -> Datetime,
+        confirmed_at -> Nullable<Datetime>,
     }
 }
 
@@ -55,17 +295,100 @@ diesel::table! {
         #[max_length = 256]
         password -> Varchar,
         privilege -> Unsigned<Integer>,
-        #[max_length = 64]
-        api_key -> Nullable<Varchar>,
         since -> Datetime,
         activated -> Bool,
     }
 }
 
+diesel::table! {
+    webhook (id) {
+        id -> Unsigned<Bigint>,
+        uid -> Unsigned<Bigint>,
+        #[max_length = 1024]
+        url -> Varchar,
+        #[max_length = 64]
+        secret -> Varchar,
+        #[max_length = 512]
+        events -> Varchar,
+        activated -> Bool,
+        created_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    webhook_delivery (id) {
+        id -> Unsigned<Bigint>,
+        wid -> Unsigned<Bigint>,
+        #[max_length = 32]
+        event -> Varchar,
+        payload -> Mediumtext,
+        #[max_length = 16]
+        status -> Varchar,
+        attempts -> Unsigned<Integer>,
+        next_attempt_at -> Datetime,
+        response_status -> Nullable<Unsigned<Smallint>>,
+        #[max_length = 1024]
+        last_error -> Nullable<Varchar>,
+        created_at -> Datetime,
+        delivered_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::joinable!(alert -> device (did));
+diesel::joinable!(alert -> user (acknowledged_by));
+diesel::joinable!(api_key -> organization (oid));
+diesel::joinable!(api_key -> user (uid));
+diesel::joinable!(device -> organization (oid));
 diesel::joinable!(device -> user (uid));
+diesel::joinable!(device_label -> device (did));
+diesel::joinable!(device_share -> device (did));
+diesel::joinable!(device_transfer -> device (did));
+diesel::joinable!(geofence -> user (uid));
+diesel::joinable!(geofence_event -> device (did));
+diesel::joinable!(geofence_event -> geofence (gid));
+diesel::joinable!(identity -> user (uid));
+diesel::joinable!(location -> device (did));
+diesel::joinable!(membership -> organization (oid));
+diesel::joinable!(membership -> user (uid));
+diesel::joinable!(one_time_token -> user (uid));
+diesel::joinable!(organization -> user (personal_of));
 diesel::joinable!(owns -> device (did));
 diesel::joinable!(owns -> tag (tid));
+diesel::joinable!(presence_event -> device (did));
 diesel::joinable!(record -> device (did));
+diesel::joinable!(recovery_code -> user (uid));
+diesel::joinable!(session -> user (uid));
+diesel::joinable!(tag -> organization (oid));
 diesel::joinable!(tag -> user (uid));
+diesel::joinable!(tag_share -> tag (tid));
+diesel::joinable!(totp -> user (uid));
+diesel::joinable!(webhook -> user (uid));
+diesel::joinable!(webhook_delivery -> webhook (wid));
 
-diesel::allow_tables_to_appear_in_same_query!(device, owns, record, tag, user,);
+diesel::allow_tables_to_appear_in_same_query!(
+    alert,
+    api_key,
+    device,
+    device_label,
+    device_share,
+    device_transfer,
+    geofence,
+    geofence_event,
+    identity,
+    location,
+    membership,
+    one_time_token,
+    organization,
+    owns,
+    presence_event,
+    record,
+    recovery_code,
+    revoked_token,
+    session,
+    tag,
+    tag_share,
+    totp,
+    user,
+    webhook,
+    webhook_delivery,
+);
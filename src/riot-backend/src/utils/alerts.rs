@@ -0,0 +1,214 @@
+use chrono::Utc;
+use diesel::result::Error as DieselErr;
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    config::CONFIG,
+    db::{DBClient, WebhookOwner},
+    models::{Alert, AlertSeverity, NewAlert, OrgRole, Record, WebhookEvent},
+    utils::{
+        email::{send_email_smtp, smtp_mailer},
+        hub::RecordHub,
+        webhooks::{emit, envelope, post_signed, HTTP_CLIENT},
+    },
+};
+
+/// Max length of an alert message, longer ones are truncated
+const MAX_MESSAGE_LEN: usize = 1024;
+
+/// Decode an alert from the `alert` field of a JSON object payload (the frontend parsers' alert field):
+/// a string is the message, `true` or a non-zero number raises a generic alert.
+/// The optional `severity` field is one of `info`/`warning`/`critical` (default: `warning`).
+pub fn alert_from_payload(payload: &[u8]) -> Option<(AlertSeverity, String)> {
+    let value: Value = serde_json::from_slice(payload).ok()?;
+    let message = match value.get("alert")? {
+        Value::String(msg) if !msg.trim().is_empty() => msg.trim().to_string(),
+        Value::Bool(true) => "Alert reported by the device".to_string(),
+        Value::Number(n) if n.as_f64().is_some_and(|n| n != 0.0) => {
+            format!("Alert reported by the device: {n}")
+        }
+        _ => return None,
+    };
+    let severity = match value.get("severity").and_then(Value::as_str) {
+        Some("info") => AlertSeverity::Info,
+        Some("critical") => AlertSeverity::Critical,
+        _ => AlertSeverity::Warning,
+    };
+    Some((severity, message))
+}
+
+/// Raise an alert, notify through all channels if it is not a duplicate of an unresolved one
+pub async fn raise_alert(
+    db: &DBClient,
+    did: u64,
+    severity: AlertSeverity,
+    message: &str,
+) -> Result<Alert, DieselErr> {
+    let message: String = message.chars().take(MAX_MESSAGE_LEN).collect();
+    let now = Utc::now().naive_utc();
+    let (alert, is_new) = db
+        .raise_alert(&NewAlert {
+            did,
+            severity,
+            message: &message,
+            raised_at: &now,
+            last_raised_at: &now,
+        })
+        .await?;
+    if is_new {
+        let db = db.clone();
+        let to_notify = alert.clone();
+        tokio::spawn(async move { notify(&db, &to_notify).await });
+    }
+    Ok(alert)
+}
+
+/// Fan out a new alert: email the admins of the organization owning the device, queue
+/// `alert.raised` for the webhooks of its members and POST it to the configured webhooks,
+/// signed with `[alert] secret`
+async fn notify(db: &DBClient, alert: &Alert) {
+    let device = match db.get_device_by_id(alert.did).await {
+        Ok(device) => device,
+        Err(e) => {
+            error!("Alert notification failed: {:?}", e);
+            return;
+        }
+    };
+    if CONFIG.alert.email {
+        let admins = async {
+            let emails = db.get_member_emails(device.oid, OrgRole::Admin).await?;
+            Ok::<_, Box<dyn std::error::Error>>((emails, smtp_mailer(&CONFIG.email)?))
+        };
+        match admins.await {
+            Ok((emails, mailer)) => {
+                for email in emails {
+                    let sent = send_email_smtp(
+                        &mailer,
+                        &format!("RIoT <{}>", CONFIG.email.addr),
+                        &format!("<{}>", email),
+                        &format!("RIoT Alert: {}", device.name),
+                        format!(
+                            include_str!("../alert.tplt"),
+                            severity = alert.severity.as_str(),
+                            device = device.name,
+                            did = device.id,
+                            message = alert.message,
+                            raised_at = alert.raised_at,
+                        ),
+                    )
+                    .await;
+                    if let Err(e) = sent {
+                        error!("Alert email to {} failed: {}", email, e);
+                    }
+                }
+            }
+            Err(e) => error!("Alert email failed: {}", e),
+        }
+    }
+    let event = WebhookEvent::AlertRaised;
+    let data = json!({
+        "alert": alert,
+        "device": {"id": device.id, "name": device.name, "uid": device.uid, "oid": device.oid},
+    });
+    emit(db, WebhookOwner::Org(device.oid), event, &data);
+    let payload = envelope(event, &data, &Utc::now().naive_utc());
+    for url in CONFIG.alert.webhooks.iter() {
+        let posted = post_signed(
+            &HTTP_CLIENT,
+            url,
+            &CONFIG.alert.secret,
+            alert.id,
+            event,
+            &payload,
+        )
+        .await;
+        if let Err((_, reason)) = posted {
+            warn!("Alert webhook {} failed: {}", url, reason);
+        }
+    }
+}
+
+/// Raise the alert reported in the payload of a stored record, if any
+async fn inspect(db: &DBClient, record: &Record) {
+    if let Some((severity, message)) = alert_from_payload(&record.payload) {
+        info!("Device {} raised an alert: {}", record.did, message);
+        if let Err(e) = raise_alert(db, record.did, severity, &message).await {
+            error!("Raise alert failed: {:?}", e);
+        }
+    }
+}
+
+/// Alert daemon: raise alerts reported in the payloads of stored records
+pub async fn alert_daemon(hub: RecordHub) {
+    let db = DBClient::new(&DBClient::get_database_url());
+    let mut rx = hub.subscribe();
+    // ID of the last record received from the hub
+    let mut last = 0;
+    // Records up to this ID were read again from the DB after lagging, skipped if still buffered
+    let mut caught_up = 0;
+    loop {
+        match rx.recv().await {
+            Ok(record) if record.id <= caught_up => {}
+            Ok(record) => {
+                last = last.max(record.id);
+                inspect(&db, &record).await;
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                // Only the records after one already seen can be told apart
+                let missed = match last {
+                    0 => Err("no record received yet".to_string()),
+                    _ => db
+                        .get_records_after(last, skipped as i64)
+                        .await
+                        .map_err(|e| format!("{:?}", e)),
+                };
+                match missed {
+                    Ok(missed) => {
+                        warn!(
+                            "Alert daemon lagged, {} of {skipped} records read again",
+                            missed.len()
+                        );
+                        for record in missed.iter() {
+                            inspect(&db, record).await;
+                        }
+                        if let Some(record) = missed.last() {
+                            last = record.id;
+                            caught_up = record.id;
+                        }
+                    }
+                    Err(e) => error!(
+                        "Alert daemon lagged, {skipped} records not inspected: {}",
+                        e
+                    ),
+                }
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::alert_from_payload;
+    use crate::models::AlertSeverity;
+
+    #[test]
+    fn payload_alert() {
+        assert_eq!(
+            alert_from_payload(br#"{"value": 3, "alert": "Too hot", "severity": "critical"}"#),
+            Some((AlertSeverity::Critical, "Too hot".to_string()))
+        );
+        assert_eq!(
+            alert_from_payload(br#"{"alert": true}"#).map(|(severity, _)| severity),
+            Some(AlertSeverity::Warning)
+        );
+        assert!(alert_from_payload(br#"{"alert": 2}"#).is_some());
+        assert_eq!(alert_from_payload(br#"{"alert": 0}"#), None);
+        assert_eq!(alert_from_payload(br#"{"alert": false}"#), None);
+        assert_eq!(alert_from_payload(br#"{"alert": " "}"#), None);
+        assert_eq!(alert_from_payload(br#"{"value": 3}"#), None);
+        assert_eq!(alert_from_payload(&[1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! API keys of users and organizations: `riot_<48 hex>`, stored as their SHA-256, with the first
+//! characters of user keys kept visible.
+use actix_web::http::Method;
+
+use crate::models::{ApiKeyScope, UserPrivilege};
+
+/// All keys start with it, so that leaked keys are easy to scan for
+pub const KEY_PREFIX: &str = "riot_";
+/// Characters of a key kept visible to tell keys apart
+const VISIBLE_LEN: usize = 12;
+
+/// A new random key
+pub fn new_api_key() -> String {
+    KEY_PREFIX.to_string() + &hex::encode(rand::random::<[u8; 24]>())
+}
+
+/// Visible part of a key
+pub fn visible_prefix(key: &str) -> &str {
+    match key.char_indices().nth(VISIBLE_LEN) {
+        Some((end, _)) => &key[..end],
+        None => key,
+    }
+}
+
+/// Routes of devices, tags and their data. The other ones (accounts, API keys, organizations,
+/// and any new route until it is listed) need the `admin` scope
+const DEVICE_ROUTES: [&str; 10] = [
+    "/devices",
+    "/tags",
+    "/records",
+    "/alerts",
+    "/presence",
+    "/geo",
+    "/geofences",
+    "/shared",
+    "/trash",
+    "/transfers",
+];
+
+/// Whether the path is the route or under it
+fn is_under(path: &str, route: &str) -> bool {
+    path.strip_prefix(route)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Scope an API key needs for the request, `least_priv` being the privilege the route requires
+pub fn required_scope(method: &Method, path: &str, least_priv: u32) -> ApiKeyScope {
+    let path = path.strip_prefix("/api").unwrap_or(path);
+    let read = method == Method::GET || method == Method::HEAD;
+    if least_priv > UserPrivilege::Normal as u32 {
+        ApiKeyScope::Admin
+    } else if is_under(path, "/webhooks") {
+        ApiKeyScope::ManageWebhooks
+    } else if !DEVICE_ROUTES.iter().any(|route| is_under(path, route)) {
+        ApiKeyScope::Admin
+    } else if read
+        && !path.ends_with("/shares")
+        && !is_under(path, "/trash")
+        && !is_under(path, "/transfers")
+    {
+        ApiKeyScope::ReadRecords
+    } else if !read && path.ends_with("/records") {
+        ApiKeyScope::WriteRecords
+    } else {
+        ApiKeyScope::ManageDevices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::ApiKey, utils::jwt::hash_token};
+
+    #[test]
+    fn key_layout() {
+        let key = new_api_key();
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(key.len(), KEY_PREFIX.len() + 48);
+        assert_eq!(visible_prefix(&key), &key[..12]);
+        assert_eq!(visible_prefix("short"), "short");
+        assert_ne!(new_api_key(), key);
+    }
+
+    #[test]
+    fn scopes() {
+        let normal = UserPrivilege::Normal as u32;
+        assert_eq!(
+            required_scope(&Method::GET, "/api/devices/1/records", normal),
+            ApiKeyScope::ReadRecords
+        );
+        assert_eq!(
+            required_scope(&Method::POST, "/api/devices/1/records", normal),
+            ApiKeyScope::WriteRecords
+        );
+        assert_eq!(
+            required_scope(&Method::DELETE, "/api/devices/1", normal),
+            ApiKeyScope::ManageDevices
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/api/api_keys", normal),
+            ApiKeyScope::Admin
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/api/devices", UserPrivilege::Admin as u32),
+            ApiKeyScope::Admin
+        );
+        // management GETs are not readings
+        for path in [
+            "/api/accounts/sessions",
+            "/api/orgs/1/members",
+            "/api/api_keys",
+        ] {
+            assert_eq!(
+                required_scope(&Method::GET, path, normal),
+                ApiKeyScope::Admin
+            );
+        }
+        assert_eq!(
+            required_scope(&Method::GET, "/api/webhooks", normal),
+            ApiKeyScope::ManageWebhooks
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/api/devices/1/shares", normal),
+            ApiKeyScope::ManageDevices
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/api/trash/devices", normal),
+            ApiKeyScope::ManageDevices
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/api/geo/devices/nearby", normal),
+            ApiKeyScope::ReadRecords
+        );
+        // unknown routes are denied by default
+        assert_eq!(
+            required_scope(&Method::GET, "/api/devicesx", normal),
+            ApiKeyScope::Admin
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/api/new_feature", normal),
+            ApiKeyScope::Admin
+        );
+
+        let mut key = ApiKey {
+            id: 1,
+            uid: 1,
+            name: "test".into(),
+            prefix: "riot_0123456".into(),
+            key_hash: hash_token("riot_0123456"),
+            scopes: "read_records,write_records".into(),
+            allowed_ips: Some("10.0.0.1, ::1".into()),
+            expires_at: None,
+            last_used: None,
+            created_at: Default::default(),
+            revoked_at: None,
+        };
+        assert!(key.permits(ApiKeyScope::WriteRecords));
+        assert!(!key.permits(ApiKeyScope::ManageDevices));
+        assert!(key.allows_ip("::1".parse().ok()));
+        assert!(!key.allows_ip("10.0.0.2".parse().ok()));
+        assert!(!key.allows_ip(None));
+        key.scopes = "admin".into();
+        key.allowed_ips = None;
+        assert!(key.permits(ApiKeyScope::ManageDevices));
+        assert!(key.allows_ip(None));
+    }
+}
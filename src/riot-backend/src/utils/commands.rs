@@ -0,0 +1,68 @@
+//! Commands sent from the server down to devices.
+//!
+//! A device with topic `<topic>` receives its commands on `<topic>/$command`.
+//! (API keys are stored hashed, so commands cannot be addressed under the key the device publishes with.)
+use std::{sync::Mutex, time::Duration};
+
+use log::{error, info};
+use once_cell::sync::Lazy;
+use rumqttc::{AsyncClient, EventLoop, QoS};
+use uuid::Uuid;
+
+use crate::{config::CONFIG, utils::mqtt_instance::mqtt_instancer::MqttDaemon};
+
+/// Topic suffix on which devices receive commands
+pub const COMMAND_SUFFIX: &str = "/$command";
+
+/// Client shared by the HTTP workers, driven by [`command_daemon`]
+static COMMANDER: Lazy<(AsyncClient, Mutex<Option<EventLoop>>)> = Lazy::new(|| {
+    let (client, eventloop) = MqttDaemon::new_daemon(
+        ("RIOT_COMMANDER".to_string() + &Uuid::new_v4().to_string()).as_str(),
+        &CONFIG.mqtt.host,
+        CONFIG.mqtt.port,
+    );
+    (client, Mutex::new(Some(eventloop)))
+});
+
+/// Full topic a device listens to for commands
+pub fn command_topic(device_topic: &str) -> String {
+    format!("{}{}", device_topic, COMMAND_SUFFIX)
+}
+
+/// Queue a command for publishing, fails immediately if the outgoing queue is full
+pub fn publish_command(
+    device_topic: &str,
+    payload: Vec<u8>,
+    qos: QoS,
+    retain: bool,
+) -> Result<(), String> {
+    COMMANDER
+        .0
+        .try_publish(command_topic(device_topic), qos, retain, payload)
+        .map_err(|e| e.to_string())
+}
+
+/// Drive the command client's connection
+pub async fn command_daemon() {
+    let Some(mut eventloop) = COMMANDER.1.lock().unwrap().take() else {
+        return;
+    };
+    info!("Command publisher started");
+    loop {
+        // The event loop reconnects by itself on the next poll
+        if let Err(e) = eventloop.poll().await {
+            error!("Command publisher connection error: {:?}", e);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::command_topic;
+
+    #[test]
+    fn topic_layout() {
+        assert_eq!(command_topic("home/lamp"), "home/lamp/$command");
+    }
+}
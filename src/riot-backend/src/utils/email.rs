@@ -1,5 +1,27 @@
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::PoolConfig;
 use lettre::{message::header, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
 
+use crate::config::EmailConfig;
+
+pub fn smtp_mailer(
+    config: &EmailConfig,
+) -> Result<AsyncSmtpTransport<Tokio1Executor>, lettre::transport::smtp::Error> {
+    let smtp_credentials = Credentials::new(
+        config.smtp_username.to_string(),
+        config.smtp_password.to_string(),
+    );
+
+    Ok(
+        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_relay_server)?
+            // Add credentials for authentication
+            .credentials(smtp_credentials)
+            // Connection pool settings
+            .pool_config(PoolConfig::new().max_size(20))
+            .build(),
+    )
+}
+
 pub async fn send_email_smtp(
     mailer: &AsyncSmtpTransport<Tokio1Executor>,
     from: &str,
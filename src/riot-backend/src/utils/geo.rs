@@ -0,0 +1,180 @@
+use serde_json::Value;
+
+use crate::models::{Geofence, GeofenceTransition};
+
+/// Mean earth radius. Unit: meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+/// A bit less than a degree of latitude (or of longitude on the equator), so that boxes are wide
+/// enough. Unit: meters
+const METERS_PER_DEGREE: f64 = 111_000.0;
+
+/// Great-circle distance between two coordinates. Unit: meters
+pub fn haversine_distance(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lng2 - lng1).to_radians();
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// Half height and half width (unit: degrees) of a box around a point at `lat` holding the circle of
+/// `radius` meters. Every longitude is in the box when the circle gets near a pole
+pub fn bounding_deltas(lat: f64, radius: f64) -> (f64, f64) {
+    let lat_delta = (radius / METERS_PER_DEGREE).min(180.0);
+    let cos = lat.to_radians().cos();
+    let lng_delta = if lat.abs() + lat_delta >= 90.0 || cos < 0.01 {
+        360.0
+    } else {
+        (radius / (METERS_PER_DEGREE * cos)).min(360.0)
+    };
+    (lat_delta, lng_delta)
+}
+
+/// Longitude ranges (unit: degrees) covered by `lng ± lng_delta`. A box crossing the antimeridian
+/// is split into one range on each side of it
+pub fn longitude_ranges(lng: f64, lng_delta: f64) -> ((f64, f64), Option<(f64, f64)>) {
+    let (min, max) = (lng - lng_delta, lng + lng_delta);
+    if lng_delta >= 180.0 {
+        ((-180.0, 180.0), None)
+    } else if min < -180.0 {
+        ((-180.0, max), Some((min + 360.0, 180.0)))
+    } else if max > 180.0 {
+        ((min, 180.0), Some((-180.0, max - 360.0)))
+    } else {
+        ((min, max), None)
+    }
+}
+
+/// Decode `(latitude, longitude)` from a JSON object payload,
+/// using the same `lat`/`lng` fields the frontend parsers plot (`latitude`/`longitude` also accepted)
+pub fn locate_payload(payload: &[u8]) -> Option<(f64, f64)> {
+    let value: Value = serde_json::from_slice(payload).ok()?;
+    let field = |names: &[&str]| names.iter().find_map(|name| value.get(name)?.as_f64());
+    let lat = field(&["lat", "latitude"])?;
+    let lng = field(&["lng", "lon", "longitude"])?;
+    if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lng) {
+        Some((lat, lng))
+    } else {
+        None
+    }
+}
+
+/// Geofences (by id) entered or exited when moving from `prev` to `cur`
+pub fn geofence_transitions(
+    fences: &[Geofence],
+    prev: Option<(f64, f64)>,
+    cur: (f64, f64),
+) -> Vec<(u64, GeofenceTransition)> {
+    let inside = |fence: &Geofence, (lat, lng): (f64, f64)| {
+        haversine_distance(fence.latitude, fence.longitude, lat, lng) <= fence.radius
+    };
+    fences
+        .iter()
+        .filter_map(|fence| {
+            let was_inside = prev.is_some_and(|prev| inside(fence, prev));
+            match (was_inside, inside(fence, cur)) {
+                (false, true) => Some((fence.id, GeofenceTransition::Enter)),
+                (true, false) => Some((fence.id, GeofenceTransition::Exit)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fence(id: u64, latitude: f64, longitude: f64, radius: f64) -> Geofence {
+        Geofence {
+            id,
+            uid: 1,
+            name: format!("fence{id}"),
+            latitude,
+            longitude,
+            radius,
+            activated: true,
+        }
+    }
+
+    #[test]
+    fn distance() {
+        // Shanghai -> Beijing, about 1068 km
+        let d = haversine_distance(31.2304, 121.4737, 39.9042, 116.4074);
+        assert!((d - 1_068_000.0).abs() < 5_000.0, "{d}");
+        assert_eq!(haversine_distance(10.0, 20.0, 10.0, 20.0), 0.0);
+    }
+
+    #[test]
+    fn bounding_box() {
+        let (lat_delta, lng_delta) = bounding_deltas(0.0, 111_000.0);
+        assert!((lat_delta - 1.0).abs() < 1e-9 && (lng_delta - 1.0).abs() < 1e-9);
+        // a degree of longitude is half as long at 60°
+        let (lat_delta, lng_delta) = bounding_deltas(60.0, 10_000.0);
+        assert!((lng_delta - 2.0 * lat_delta).abs() < 1e-9);
+        // the points at the radius are in the box
+        for (lat, radius) in [(0.0, 500.0), (45.0, 20_000.0), (-70.0, 1_000.0)] {
+            let (lat_delta, lng_delta) = bounding_deltas(lat, radius);
+            assert!(haversine_distance(lat, 0.0, lat + lat_delta, 0.0) >= radius);
+            assert!(haversine_distance(lat, 0.0, lat, lng_delta) >= radius);
+        }
+        assert_eq!(bounding_deltas(89.95, 10_000.0).1, 360.0);
+        assert_eq!(bounding_deltas(-90.0, 1.0).1, 360.0);
+    }
+
+    #[test]
+    fn antimeridian() {
+        assert_eq!(longitude_ranges(10.0, 1.0), ((9.0, 11.0), None));
+        assert_eq!(
+            longitude_ranges(179.5, 1.0),
+            ((178.5, 180.0), Some((-180.0, -179.5)))
+        );
+        assert_eq!(
+            longitude_ranges(-179.5, 1.0),
+            ((-180.0, -178.5), Some((179.5, 180.0)))
+        );
+        assert_eq!(longitude_ranges(0.0, 360.0), ((-180.0, 180.0), None));
+        // a point just across the antimeridian, inside the radius, is in one of the ranges
+        let (lat, lng, radius) = (10.0, 179.99, 5_000.0);
+        let (lat_delta, lng_delta) = bounding_deltas(lat, radius);
+        let other = (lat + lat_delta / 2.0, -179.99);
+        assert!(haversine_distance(lat, lng, other.0, other.1) <= radius);
+        let (first, second) = longitude_ranges(lng, lng_delta);
+        assert!([Some(first), second]
+            .into_iter()
+            .flatten()
+            .any(|(min, max)| (min..=max).contains(&other.1)));
+    }
+
+    #[test]
+    fn payload_location() {
+        assert_eq!(
+            locate_payload(br#"{"lat": 31.5, "lng": 121.25, "value": 3}"#),
+            Some((31.5, 121.25))
+        );
+        assert_eq!(
+            locate_payload(br#"{"latitude": -1, "longitude": 2}"#),
+            Some((-1.0, 2.0))
+        );
+        assert_eq!(locate_payload(br#"{"lat": 91, "lng": 0}"#), None);
+        assert_eq!(locate_payload(br#"{"lat": "31.5", "lng": 121}"#), None);
+        assert_eq!(locate_payload(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn enter_and_exit() {
+        let fences = [fence(1, 0.0, 0.0, 1000.0), fence(2, 1.0, 1.0, 1000.0)];
+        assert_eq!(
+            geofence_transitions(&fences, None, (0.0, 0.001)),
+            vec![(1, GeofenceTransition::Enter)]
+        );
+        assert_eq!(
+            geofence_transitions(&fences, Some((0.0, 0.001)), (1.0, 1.001)),
+            vec![
+                (1, GeofenceTransition::Exit),
+                (2, GeofenceTransition::Enter)
+            ]
+        );
+        assert!(geofence_transitions(&fences, Some((0.0, 0.001)), (0.0, 0.002)).is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::watch;
+
+use crate::models::Record;
+
+/// Broadcast hub of freshly stored records, shared by the HTTP workers and the MQTT daemon.
+///
+/// Every live connection (SSE / WebSocket) holds its own receiver and filters by its subscription.
+#[derive(Clone)]
+pub struct RecordHub {
+    sender: Sender<Record>,
+    /// Bumped when devices join or leave tags, or users gain or lose access to them
+    memberships: watch::Sender<u64>,
+}
+
+impl RecordHub {
+    /// Max records buffered for a slow subscriber before it starts lagging (dropping the oldest)
+    const CAPACITY: usize = 1024;
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(Self::CAPACITY);
+        let (memberships, _) = watch::channel(0);
+        RecordHub {
+            sender,
+            memberships,
+        }
+    }
+    /// Push a stored record to all live subscribers
+    pub fn publish(&self, record: Record) {
+        // Err only means there is no subscriber at the moment
+        let _ = self.sender.send(record);
+    }
+    pub fn subscribe(&self) -> Receiver<Record> {
+        self.sender.subscribe()
+    }
+    /// Tell the subscribers to resolve their tags and check their access again
+    pub fn memberships_changed(&self) {
+        self.memberships.send_modify(|version| *version += 1);
+    }
+    pub fn watch_memberships(&self) -> watch::Receiver<u64> {
+        self.memberships.subscribe()
+    }
+}
+
+impl Default for RecordHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Devices a single live connection is interested in: the ones subscribed to, and those under
+/// the tags subscribed to, resolved again whenever memberships change
+#[derive(Default, Debug)]
+pub struct Subscription {
+    devices: HashSet<u64>,
+    tags: HashSet<u64>,
+    /// Devices under `tags`
+    tagged: HashSet<u64>,
+}
+
+impl Subscription {
+    pub fn add(
+        &mut self,
+        dids: impl IntoIterator<Item = u64>,
+        tids: impl IntoIterator<Item = u64>,
+    ) {
+        self.devices.extend(dids);
+        self.tags.extend(tids);
+    }
+    pub fn remove(
+        &mut self,
+        dids: impl IntoIterator<Item = u64>,
+        tids: impl IntoIterator<Item = u64>,
+    ) {
+        for did in dids {
+            self.devices.remove(&did);
+        }
+        for tid in tids {
+            self.tags.remove(&tid);
+        }
+    }
+    /// Keep only the devices and tags still accessible, with the devices now under the tags
+    pub fn refresh(
+        &mut self,
+        dids: impl IntoIterator<Item = u64>,
+        tids: impl IntoIterator<Item = u64>,
+        tagged: impl IntoIterator<Item = u64>,
+    ) {
+        self.devices = dids.into_iter().collect();
+        self.tags = tids.into_iter().collect();
+        self.tagged = tagged.into_iter().collect();
+    }
+    pub fn matches(&self, record: &Record) -> bool {
+        self.devices.contains(&record.did) || self.tagged.contains(&record.did)
+    }
+    pub fn devices(&self) -> Vec<u64> {
+        self.devices.iter().copied().collect()
+    }
+    pub fn tags(&self) -> Vec<u64> {
+        self.tags.iter().copied().collect()
+    }
+    /// All devices matched, sorted
+    pub fn dids(&self) -> Vec<u64> {
+        let mut dids: Vec<u64> = self.devices.union(&self.tagged).copied().collect();
+        dids.sort_unstable();
+        dids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::{RecordHub, Subscription};
+    use crate::models::Record;
+
+    fn record(did: u64) -> Record {
+        Record {
+            id: 1,
+            did,
+            payload: vec![1, 2, 3],
+            timestamp: NaiveDateTime::from_timestamp_millis(1662921288000).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_to_filtered_subscribers() {
+        let hub = RecordHub::new();
+        let mut rx = hub.subscribe();
+        let mut sub = Subscription::default();
+        sub.add([2, 3], []);
+
+        hub.publish(record(1));
+        hub.publish(record(3));
+        let mut received = vec![];
+        for _ in 0..2 {
+            let rec = rx.recv().await.unwrap();
+            if sub.matches(&rec) {
+                received.push(rec.did);
+            }
+        }
+        assert_eq!(received, vec![3]);
+
+        sub.remove([3], []);
+        assert_eq!(sub.dids(), vec![2]);
+        assert!(!sub.matches(&record(3)));
+    }
+
+    #[tokio::test]
+    async fn tags_follow_memberships() {
+        let hub = RecordHub::new();
+        let mut changes = hub.watch_memberships();
+        let mut sub = Subscription::default();
+        sub.add([1], [10]);
+        sub.refresh(sub.devices(), sub.tags(), [1, 2]);
+        assert_eq!(sub.dids(), vec![1, 2]);
+
+        // device 3 joins the tag, device 2 leaves it
+        hub.memberships_changed();
+        changes.changed().await.unwrap();
+        sub.refresh(sub.devices(), sub.tags(), [3]);
+        assert!(sub.matches(&record(3)) && !sub.matches(&record(2)));
+        // still subscribed to device 1 itself
+        sub.remove([], [10]);
+        sub.refresh(sub.devices(), sub.tags(), []);
+        assert_eq!(sub.dids(), vec![1]);
+    }
+
+    #[test]
+    fn publish_without_subscriber() {
+        // Must not panic when nobody is listening
+        RecordHub::new().publish(record(1));
+    }
+}
@@ -1,6 +1,7 @@
 use chrono::{Duration, Utc};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::errors::{ErrorMessage, HttpError};
 
@@ -12,10 +13,22 @@ pub struct JwtClaims {
     pub iat: usize,
     /// expiration time
     pub exp: usize,
+    /// active organization, the personal one if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org: Option<u64>,
+    /// session the token is issued for
+    #[serde(default)]
+    pub sid: u64,
+    /// token id, checked against the revocation list
+    #[serde(default)]
+    pub jti: String,
 }
 
 pub fn generate_token(
     user_id: &str,
+    org: Option<u64>,
+    sid: u64,
+    jti: &str,
     secret: &[u8],
     expires_in_seconds: i64,
 ) -> Result<String, jsonwebtoken::errors::Error> {
@@ -30,6 +43,9 @@ pub fn generate_token(
         sub: user_id.to_string(),
         exp,
         iat,
+        org,
+        sid,
+        jti: jti.to_string(),
     };
 
     encode(
@@ -39,18 +55,29 @@ pub fn generate_token(
     )
 }
 
-pub fn parse_token<T: Into<String>>(token: T, secret: &[u8]) -> Result<String, HttpError> {
+pub fn parse_token<T: Into<String>>(token: T, secret: &[u8]) -> Result<JwtClaims, HttpError> {
     let decoded = decode::<JwtClaims>(
         &token.into(),
         &DecodingKey::from_secret(secret),
         &Validation::new(Algorithm::HS256),
     );
     match decoded {
-        Ok(token) => Ok(token.claims.sub),
+        Ok(token) => Ok(token.claims),
         Err(_) => Err(HttpError::new(ErrorMessage::InvalidToken.to_string(), 401)),
     }
 }
 
+/// A random opaque refresh token
+pub fn new_refresh_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Unsalted SHA-256 digest (hex) of a random token (refresh token, API key...), to store and
+/// look it up by. Not for passwords: the tokens are long and random, so no salt is needed
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -60,9 +87,27 @@ mod tests {
         let user_id = "1";
         let secret = b"RiotSecret!";
 
-        let token = generate_token(&user_id, secret, 60 * 60 * 24).unwrap();
-        let decoded_user_id = parse_token(&token, secret).unwrap();
+        let token = generate_token(user_id, None, 3, "jti-1", secret, 60 * 60 * 24).unwrap();
+        let decoded = parse_token(&token, secret).unwrap();
         println!("{:?}", token);
-        assert_eq!(decoded_user_id, user_id);
+        assert_eq!(decoded.sub, user_id);
+        assert_eq!(decoded.org, None);
+        assert_eq!((decoded.sid, decoded.jti.as_str()), (3, "jti-1"));
+
+        let token = generate_token(user_id, Some(7), 3, "jti-2", secret, 60).unwrap();
+        assert_eq!(parse_token(&token, secret).unwrap().org, Some(7));
+    }
+
+    #[test]
+    fn refresh_tokens() {
+        let token = new_refresh_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, new_refresh_token());
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_ne!(hash_token(&token), token);
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
     }
 }
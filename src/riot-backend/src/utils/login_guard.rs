@@ -0,0 +1,272 @@
+//! Brute-force protection of password (and second factor) logins: attempts are counted per
+//! account and per IP, further attempts are delayed progressively, and refused altogether past
+//! a limit. An attempt is counted before the credentials are checked, so that concurrent ones can
+//! not all pass before the first failure is recorded.
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::HttpResponse;
+use moka::future::Cache;
+
+use crate::config::LoginConfig;
+use crate::errors::{ErrorMessage, HttpError};
+
+#[derive(Clone, Copy, Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+type Entry = Arc<Mutex<Failures>>;
+
+/// Why a login attempt is refused before checking the password
+#[derive(Debug, PartialEq)]
+pub enum Refusal {
+    /// Too early after the last failure, or too many failures from the IP. v: seconds to wait
+    TooFast(u64),
+    /// Too many failures of the account. v: seconds until it is unlocked
+    Locked(u64),
+}
+
+impl Refusal {
+    /// `429` with `Retry-After`
+    pub fn error_response(&self) -> HttpResponse {
+        let (message, secs) = match *self {
+            Refusal::TooFast(secs) => (ErrorMessage::TooFast, secs),
+            Refusal::Locked(secs) => (ErrorMessage::AccountLocked, secs),
+        };
+        HttpError::too_many_requests(message).retry_after(secs)
+    }
+}
+
+/// A login attempt, counted as failed unless it `succeed`s
+#[derive(Debug)]
+pub struct Attempt {
+    uid: Option<u64>,
+    ip: Option<String>,
+    /// Whether the account is locked by this attempt if it fails
+    pub locks: bool,
+}
+
+#[derive(Clone)]
+pub struct LoginGuard {
+    config: LoginConfig,
+    /// k: UID
+    accounts: Cache<u64, Entry>,
+    /// k: IP
+    ips: Cache<String, Entry>,
+}
+
+/// Seconds (rounded up) until `wait` has passed since `since`, `None` if it has passed
+fn remaining(since: Instant, wait: Duration) -> Option<u64> {
+    let left = wait.saturating_sub(since.elapsed());
+    (!left.is_zero()).then(|| left.as_secs() + u64::from(left.subsec_nanos() > 0))
+}
+
+async fn entry<K>(cache: &Cache<K, Entry>, key: K) -> Entry
+where
+    K: Hash + Eq + Send + Sync + 'static,
+{
+    cache
+        .get_with(key, async {
+            Arc::new(Mutex::new(Failures {
+                count: 0,
+                last: Instant::now(),
+            }))
+        })
+        .await
+}
+
+impl LoginGuard {
+    pub fn new(config: &LoginConfig) -> Self {
+        // Failures are forgotten `lockout` after the last one, entries idle that long are dropped
+        let ttl = Duration::from_secs(config.lockout);
+        LoginGuard {
+            config: config.clone(),
+            accounts: Cache::builder()
+                .max_capacity(100_000)
+                .time_to_idle(ttl)
+                .build(),
+            ips: Cache::builder()
+                .max_capacity(100_000)
+                .time_to_idle(ttl)
+                .build(),
+        }
+    }
+
+    /// Wait before the next attempt: 0 up to `delay_after` failures, then doubled after each one
+    fn delay(&self, count: u32) -> Duration {
+        if count < self.config.delay_after {
+            return Duration::ZERO;
+        }
+        let exp = (count - self.config.delay_after).min(16);
+        Duration::from_secs((1u64 << exp).min(self.config.max_delay))
+    }
+
+    /// Count an attempt before verifying the credentials, `uid` is `None` if the account does not
+    /// exist. The account and the IP are checked and counted at once
+    pub async fn begin(&self, uid: Option<u64>, ip: Option<&str>) -> Result<Attempt, Refusal> {
+        let lockout = Duration::from_secs(self.config.lockout);
+        let ip_entry = match ip {
+            Some(ip) => Some(entry(&self.ips, ip.to_string()).await),
+            None => None,
+        };
+        let account_entry = match uid {
+            Some(uid) => Some(entry(&self.accounts, uid).await),
+            None => None,
+        };
+        // Always the IP first, then the account
+        let mut ip_failures = ip_entry.as_ref().map(|entry| entry.lock().unwrap());
+        let mut account_failures = account_entry.as_ref().map(|entry| entry.lock().unwrap());
+        for failures in ip_failures.iter_mut().chain(account_failures.iter_mut()) {
+            if failures.last.elapsed() >= lockout {
+                failures.count = 0;
+            }
+        }
+        if let Some(failures) = &ip_failures {
+            let wait = if failures.count >= self.config.ip_max_failures {
+                lockout
+            } else {
+                self.delay(failures.count)
+            };
+            if let Some(secs) = remaining(failures.last, wait) {
+                return Err(Refusal::TooFast(secs));
+            }
+        }
+        if let Some(failures) = &account_failures {
+            if failures.count >= self.config.max_failures {
+                if let Some(secs) = remaining(failures.last, lockout) {
+                    return Err(Refusal::Locked(secs));
+                }
+            }
+            if let Some(secs) = remaining(failures.last, self.delay(failures.count)) {
+                return Err(Refusal::TooFast(secs));
+            }
+        }
+        let now = Instant::now();
+        for failures in ip_failures.iter_mut().chain(account_failures.iter_mut()) {
+            failures.count += 1;
+            failures.last = now;
+        }
+        Ok(Attempt {
+            uid,
+            ip: ip.map(str::to_string),
+            locks: account_failures.is_some_and(|f| f.count == self.config.max_failures),
+        })
+    }
+
+    /// The credentials of the attempt were right: it is not a failure.
+    /// The earlier failures of the account are kept until `reset`
+    pub async fn succeed(&self, attempt: &Attempt) {
+        let ip_entry = match &attempt.ip {
+            Some(ip) => self.ips.get(ip).await,
+            None => None,
+        };
+        let account_entry = match attempt.uid {
+            Some(uid) => self.accounts.get(&uid).await,
+            None => None,
+        };
+        for entry in ip_entry.iter().chain(account_entry.iter()) {
+            let mut failures = entry.lock().unwrap();
+            failures.count = failures.count.saturating_sub(1);
+        }
+    }
+
+    /// Forget the failures of the account, after a complete login or with the unlock link
+    pub async fn reset(&self, uid: u64) {
+        self.accounts.invalidate(&uid).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn delays_and_lockout() {
+        let guard = LoginGuard::new(&LoginConfig {
+            max_failures: 3,
+            ip_max_failures: 5,
+            lockout: 60,
+            delay_after: 100,
+            max_delay: 60,
+        });
+        let ip = Some("10.0.0.1");
+        assert!(!guard.begin(Some(1), ip).await.unwrap().locks);
+        assert!(!guard.begin(Some(1), ip).await.unwrap().locks);
+        // right credentials: not counted
+        let attempt = guard.begin(Some(1), ip).await.unwrap();
+        assert!(attempt.locks);
+        guard.succeed(&attempt).await;
+        // locked on the 3rd failure
+        assert!(guard.begin(Some(1), ip).await.unwrap().locks);
+        assert!(matches!(
+            guard.begin(Some(1), ip).await,
+            Err(Refusal::Locked(59..=60))
+        ));
+        assert!(guard.begin(Some(1), None).await.is_err());
+        // other accounts are fine
+        let attempt = guard.begin(Some(2), ip).await.unwrap();
+        guard.succeed(&attempt).await;
+        guard.reset(1).await;
+        let attempt = guard.begin(Some(1), ip).await.unwrap();
+        guard.succeed(&attempt).await;
+
+        // the IP is blocked on its 5th failure, whatever the account
+        assert!(guard.begin(None, ip).await.is_ok());
+        assert!(guard.begin(Some(2), ip).await.is_ok());
+        assert!(matches!(
+            guard.begin(Some(3), ip).await,
+            Err(Refusal::TooFast(59..=60))
+        ));
+        assert!(guard.begin(Some(3), Some("10.0.0.2")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn progressive_delay() {
+        let guard = LoginGuard::new(&LoginConfig {
+            max_failures: 100,
+            ip_max_failures: 100,
+            lockout: 60,
+            delay_after: 2,
+            max_delay: 4,
+        });
+        assert_eq!(guard.delay(1), Duration::ZERO);
+        assert_eq!(guard.delay(2), Duration::from_secs(1));
+        assert_eq!(guard.delay(3), Duration::from_secs(2));
+        assert_eq!(guard.delay(4), Duration::from_secs(4));
+        assert_eq!(guard.delay(40), Duration::from_secs(4));
+
+        assert!(guard.begin(Some(1), None).await.is_ok());
+        assert!(guard.begin(Some(1), None).await.is_ok());
+        assert!(matches!(
+            guard.begin(Some(1), None).await,
+            Err(Refusal::TooFast(1))
+        ));
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(guard.begin(Some(1), None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn concurrent_attempts() {
+        let guard = LoginGuard::new(&LoginConfig {
+            max_failures: 3,
+            ip_max_failures: 100,
+            lockout: 60,
+            delay_after: 100,
+            max_delay: 60,
+        });
+        // a parallel burst gets no more attempts than the limit
+        let attempts = futures::future::join_all((0..20).map(|i| {
+            let guard = guard.clone();
+            tokio::spawn(async move { guard.begin(Some(1), Some(&format!("10.0.1.{i}"))).await })
+        }))
+        .await;
+        let allowed = attempts
+            .into_iter()
+            .filter(|attempt| matches!(attempt, Ok(Ok(_))))
+            .count();
+        assert_eq!(allowed, 3);
+    }
+}
@@ -1,4 +1,18 @@
+pub mod alerts;
+pub mod api_keys;
+pub mod commands;
 pub mod email;
+pub mod geo;
+pub mod hub;
 pub mod jwt;
+pub mod login_guard;
 pub mod mqtt_instance;
+pub mod oidc;
+pub mod one_time_token;
 pub mod password;
+pub mod presence;
+pub mod request_limit;
+pub mod sinks;
+pub mod tag_query;
+pub mod totp;
+pub mod webhooks;
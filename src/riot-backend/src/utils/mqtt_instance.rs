@@ -3,7 +3,14 @@ use log::{debug, error};
 use rumqttc::Packet;
 use uuid::Uuid;
 
-use crate::{db::DBClient, models::NewRecord};
+use crate::{
+    db::DBClient,
+    models::{ApiKeyScope, NewRecord, Permission, PresenceCause, WebhookEvent},
+    utils::{
+        commands::COMMAND_SUFFIX, hub::RecordHub, jwt::hash_token, presence::LAST_WILL_SUFFIX,
+        request_limit::RequestLimiter, webhooks::emit_device,
+    },
+};
 
 use self::mqtt_instancer::MqttDaemon;
 /// MQTT util class
@@ -23,9 +30,22 @@ pub mod mqtt_instancer {
     }
 }
 
+/// Owner of the API key a message was published with
+#[derive(Clone, Copy)]
+enum Publisher {
+    User(u64),
+    Org(u64),
+}
+
 #[actix_web::main]
 /// MQTT Listening daemon
-pub async fn mqtt_listening(db: DBClient, host: &str, port: u16) {
+pub async fn mqtt_listening(
+    db: DBClient,
+    hub: RecordHub,
+    limit: RequestLimiter,
+    host: &str,
+    port: u16,
+) {
     // !important: enough randomness to avoid being kicked by a malicious client with the same id
     let (mut client, mut eventloop) = MqttDaemon::new_daemon(
         ("MQTT_DAEMON".to_string() + &Uuid::new_v4().to_string()).as_str(),
@@ -62,7 +82,11 @@ pub async fn mqtt_listening(db: DBClient, host: &str, port: u16) {
                 "got topic={} payload={:?}",
                 published.topic, published.payload
             );
-            //topic must be start with user's api key
+            // Our own commands to devices
+            if published.topic.ends_with(COMMAND_SUFFIX) {
+                continue 'eventloop;
+            }
+            //topic must be start with user's (or organization's) api key
             let mut try_split = published.topic.split('/');
             let api_key = match try_split.next() {
                 Some(api_key) => api_key,
@@ -71,19 +95,63 @@ pub async fn mqtt_listening(db: DBClient, host: &str, port: u16) {
                     continue 'eventloop;
                 }
             };
-            let user = match db.get_user_by_api_key(api_key).await {
-                Ok(user) => user,
-                Err(_) => {
-                    error!("Unable to find the user with ApiKey= {:?}", api_key);
+            let now = Utc::now().naive_utc();
+            let publisher = match db.get_user_by_api_key(&hash_token(api_key), &now).await {
+                // the client IP is unknown behind the broker, so IP-restricted keys are refused
+                Ok((key, user))
+                    if key.permits(ApiKeyScope::WriteRecords) && key.allows_ip(None) =>
+                {
+                    if let Err(e) = db.touch_api_key(key.id, &now).await {
+                        error!("{:?}", e);
+                    }
+                    Publisher::User(user.id)
+                }
+                Ok((key, _)) => {
+                    error!("ApiKey {} is not allowed to publish over MQTT", key.prefix);
                     continue 'eventloop;
                 }
+                Err(_) => match db.get_org_by_api_key(&hash_token(api_key)).await {
+                    Ok(org) => Publisher::Org(org.id),
+                    Err(_) => {
+                        error!("Unable to find the user with ApiKey= {:?}", api_key);
+                        continue 'eventloop;
+                    }
+                },
             };
             let topic = &published.topic[(api_key.len() + 1)..]; // with api key stripped
+            let (topic, is_last_will) = match topic.strip_suffix(LAST_WILL_SUFFIX) {
+                Some(device_topic) => (device_topic, true),
+                None => (topic, false),
+            };
+            let quota_key = match publisher {
+                Publisher::User(uid) => format!("user:{uid}"),
+                Publisher::Org(oid) => format!("org:{oid}"),
+            };
+            if !is_last_will && !limit.take_mqtt(&quota_key).await {
+                debug!("Dropped message over the MQTT quota of {}", quota_key);
+                continue 'eventloop;
+            }
             let device = db.get_device_by_topic(topic).await;
             let device = match device {
                 Ok(device) => {
-                    if device.uid != user.id {
-                        error!("Device not owned by the user");
+                    match publisher {
+                        Publisher::User(uid) => {
+                            if Ok(true)
+                                != db.device_permitted(device.id, uid, Permission::Write).await
+                            {
+                                error!("Device not writable by the user");
+                                continue 'eventloop;
+                            }
+                        }
+                        Publisher::Org(oid) => {
+                            if device.oid != oid {
+                                error!("Device not owned by the organization");
+                                continue 'eventloop;
+                            }
+                        }
+                    }
+                    if !device.activated {
+                        debug!("Dropped message from deactivated device {}", device.id);
                         continue 'eventloop;
                     }
                     device
@@ -96,6 +164,21 @@ pub async fn mqtt_listening(db: DBClient, host: &str, port: u16) {
                     continue 'eventloop;
                 }
             };
+            if is_last_will {
+                match db
+                    .mark_device_offline(
+                        device.id,
+                        PresenceCause::LastWill,
+                        &Utc::now().naive_utc(),
+                    )
+                    .await
+                {
+                    Ok(true) => emit_device(&db, WebhookEvent::DeviceOffline, device.id),
+                    Ok(false) => {}
+                    Err(e) => error!("Mark device offline failed: {:?}", e),
+                }
+                continue 'eventloop;
+            }
             let res = db
                 .add_device_records(&NewRecord {
                     did: device.id,
@@ -103,16 +186,20 @@ pub async fn mqtt_listening(db: DBClient, host: &str, port: u16) {
                     timestamp: &Utc::now().naive_utc(),
                 })
                 .await;
-            if let Err(e) = res {
-                error!("Insert record failed: {:?}", e);
-                continue 'eventloop;
-            } else {
-                crate::handlers::SYSINFO_CACHE
-                    .buffer
-                    .write()
-                    .await
-                    .record_count += 1;
-                continue 'eventloop;
+            match res {
+                Err(e) => {
+                    error!("Insert record failed: {:?}", e);
+                    continue 'eventloop;
+                }
+                Ok(record) => {
+                    crate::handlers::SYSINFO_CACHE
+                        .buffer
+                        .write()
+                        .await
+                        .record_count += 1;
+                    hub.publish(record);
+                    continue 'eventloop;
+                }
             }
         }
     }
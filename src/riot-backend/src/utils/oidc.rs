@@ -0,0 +1,369 @@
+//! OpenID Connect single sign-on: authorization code flow with PKCE (S256).
+use std::fmt;
+use std::time::Duration;
+
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::OidcConfig;
+
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build HTTP client")
+});
+
+#[derive(Debug)]
+pub enum OidcError {
+    /// The provider is unreachable or misbehaves
+    Provider(String),
+    /// The ID token is not acceptable
+    Token(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::Provider(e) => write!(f, "OIDC provider error: {e}"),
+            OidcError::Token(e) => write!(f, "Invalid ID token: {e}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(e: reqwest::Error) -> Self {
+        OidcError::Provider(e.to_string())
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for OidcError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        OidcError::Token(e.to_string())
+    }
+}
+
+/// Provider metadata, from the discovery document
+#[derive(Deserialize, Debug)]
+pub struct Provider {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// Claims of the ID token used to find or create the user
+#[derive(Deserialize, Debug)]
+pub struct IdClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Fetch the discovery document of the issuer
+pub async fn discover(issuer: &str) -> Result<Provider, OidcError> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    let provider: Provider = HTTP_CLIENT
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if provider.issuer != issuer {
+        return Err(OidcError::Provider(format!(
+            "issuer mismatch, configured {issuer} but the provider reports {}",
+            provider.issuer
+        )));
+    }
+    Ok(provider)
+}
+
+/// Random value for `state`, `nonce` and the PKCE verifier (64 unreserved characters)
+pub fn random_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Base64url without padding
+fn base64url(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::with_capacity((data.len() * 4).div_ceil(3));
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+        }
+    }
+    out
+}
+
+/// S256 code challenge of the PKCE verifier
+pub fn pkce_challenge(verifier: &str) -> String {
+    base64url(&Sha256::digest(verifier.as_bytes()))
+}
+
+/// Where to send the user to log in at the provider
+pub fn authorize_url(
+    provider: &Provider,
+    config: &OidcConfig,
+    redirect_uri: &str,
+    state: &str,
+    nonce: &str,
+    verifier: &str,
+) -> Result<String, OidcError> {
+    let mut url = reqwest::Url::parse(&provider.authorization_endpoint)
+        .map_err(|e| OidcError::Provider(e.to_string()))?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("scope", &config.scopes)
+        .append_pair("state", state)
+        .append_pair("nonce", nonce)
+        .append_pair("code_challenge", &pkce_challenge(verifier))
+        .append_pair("code_challenge_method", "S256");
+    Ok(url.into())
+}
+
+/// Redeem the authorization code, return: the ID token
+pub async fn exchange_code(
+    provider: &Provider,
+    config: &OidcConfig,
+    redirect_uri: &str,
+    code: &str,
+    verifier: &str,
+) -> Result<String, OidcError> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("client_id", &config.client_id),
+        ("code_verifier", verifier),
+    ];
+    if let Some(secret) = &config.client_secret {
+        form.push(("client_secret", secret));
+    }
+    let response = HTTP_CLIENT
+        .post(&provider.token_endpoint)
+        .form(&form)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(OidcError::Provider(format!(
+            "token endpoint answered {}: {}",
+            response.status(),
+            response.text().await.unwrap_or_default()
+        )));
+    }
+    Ok(response.json::<TokenResponse>().await?.id_token)
+}
+
+/// Check the signature (against the provider's JWKS), issuer, audience, expiry and nonce of the ID token
+pub async fn verify_id_token(
+    provider: &Provider,
+    client_id: &str,
+    id_token: &str,
+    nonce: &str,
+) -> Result<IdClaims, OidcError> {
+    let header = decode_header(id_token)?;
+    let jwks: JwkSet = HTTP_CLIENT
+        .get(&provider.jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    }
+    .ok_or_else(|| OidcError::Token("signing key not found".into()))?;
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[client_id]);
+    validation.set_issuer(&[&provider.issuer]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    let claims = decode::<IdClaims>(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims;
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(OidcError::Token("nonce mismatch".into()));
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use chrono::Utc;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    const SECRET: &[u8] = b"mock provider signing key";
+
+    #[test]
+    fn pkce() {
+        // RFC 7636, appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-1B0K1JK0gK4Gi1kYHJ94uP7S8rjXjpVC6CK0fsvRq3ZOfBc"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+        assert_eq!(base64url(b"f"), "Zg");
+        assert_eq!(base64url(b"fo"), "Zm8");
+        assert_eq!(base64url(b"foo"), "Zm9v");
+        assert_eq!(random_token().len(), 64);
+    }
+
+    /// Mock provider: discovery, JWKS (an HMAC key) and a token endpoint issuing `id_token`
+    /// for the code "good" if the PKCE verifier matches `challenge`
+    async fn token(
+        form: web::Form<HashMap<String, String>>,
+        state: web::Data<(String, String)>,
+    ) -> HttpResponse {
+        let (issuer, challenge) = state.get_ref();
+        if form.get("code").map(String::as_str) != Some("good")
+            || form
+                .get("code_verifier")
+                .map(|v| pkce_challenge(v))
+                .as_ref()
+                != Some(challenge)
+        {
+            return HttpResponse::BadRequest().json(json!({"error": "invalid_grant"}));
+        }
+        let header = Header {
+            kid: Some("k1".into()),
+            ..Default::default()
+        };
+        let id_token = encode(
+            &header,
+            &json!({
+                "iss": issuer,
+                "aud": "riot",
+                "sub": "alice",
+                "exp": Utc::now().timestamp() + 60,
+                "nonce": "n0nce",
+                "email": "alice@example.com",
+                "email_verified": true,
+            }),
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap();
+        HttpResponse::Ok().json(json!({"id_token": id_token, "token_type": "Bearer"}))
+    }
+
+    #[actix_web::test]
+    async fn mock_provider() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let verifier = random_token();
+        let state = web::Data::new((issuer.clone(), pkce_challenge(&verifier)));
+        let discovery = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+            "jwks_uri": format!("{issuer}/jwks"),
+        });
+        let jwks =
+            json!({"keys": [{"kty": "oct", "kid": "k1", "alg": "HS256", "k": base64url(SECRET)}]});
+        let server = HttpServer::new(move || {
+            let (discovery, jwks) = (discovery.clone(), jwks.clone());
+            App::new()
+                .app_data(state.clone())
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(move || {
+                        let discovery = discovery.clone();
+                        async move { HttpResponse::Ok().json(discovery) }
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    web::get().to(move || {
+                        let jwks = jwks.clone();
+                        async move { HttpResponse::Ok().json(jwks) }
+                    }),
+                )
+                .route("/token", web::post().to(token))
+        })
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let config = OidcConfig {
+            name: "mock".into(),
+            issuer: issuer.clone(),
+            client_id: "riot".into(),
+            client_secret: None,
+            scopes: "openid email".into(),
+            default_privilege: 4,
+            link_by_email: true,
+        };
+        let provider = discover(&issuer).await.unwrap();
+        let url = authorize_url(
+            &provider,
+            &config,
+            "http://riot/cb",
+            "st",
+            "n0nce",
+            &verifier,
+        )
+        .unwrap();
+        assert!(url.starts_with(&format!(
+            "{issuer}/authorize?response_type=code&client_id=riot"
+        )));
+        assert!(url.contains(&format!("code_challenge={}", pkce_challenge(&verifier))));
+        assert!(url.contains("redirect_uri=http%3A%2F%2Friot%2Fcb"));
+
+        // wrong verifier
+        assert!(matches!(
+            exchange_code(
+                &provider,
+                &config,
+                "http://riot/cb",
+                "good",
+                &random_token()
+            )
+            .await,
+            Err(OidcError::Provider(_))
+        ));
+        let id_token = exchange_code(&provider, &config, "http://riot/cb", "good", &verifier)
+            .await
+            .unwrap();
+        let claims = verify_id_token(&provider, "riot", &id_token, "n0nce")
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(claims.email.as_deref(), Some("alice@example.com"));
+        assert!(claims.email_verified);
+        assert!(matches!(
+            verify_id_token(&provider, "riot", &id_token, "replayed").await,
+            Err(OidcError::Token(_))
+        ));
+        assert!(matches!(
+            verify_id_token(&provider, "another client", &id_token, "n0nce").await,
+            Err(OidcError::Token(_))
+        ));
+        // issuer must match exactly
+        assert!(discover(&format!("{issuer}/")).await.is_err());
+        handle.stop(true).await;
+    }
+}
@@ -0,0 +1,322 @@
+//! One-time tokens of emailed links (email verification, login, password reset, email change,
+//! device transfer, account unlock). Tokens are random, only their hash keyed with the JWT secret and bound to the
+//! purpose is stored, so that a leaked store can not be used to forge links.
+//! A token expires after the TTL of its purpose, and works only once.
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{Duration as ChronoDuration, NaiveDateTime, Utc};
+use diesel::result::Error as DieselErr;
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use moka::future::Cache;
+use sha2::Sha256;
+
+use crate::config::{OneTimeTokenConfig, TokenStoreKind};
+use crate::db::DBClient;
+use crate::models::NewOneTimeToken;
+
+const TOKEN_LENGTH: usize = 32;
+
+/// What a token is for, a token of one purpose is refused for another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenPurpose<'a> {
+    VerifyEmail,
+    Login,
+    ResetPassword,
+    /// Confirm a new email address. v: the new address
+    ChangeEmail(&'a str),
+    /// Accept a device transfer. v: transfer id
+    Transfer(u64),
+    Unlock,
+}
+
+impl TokenPurpose<'_> {
+    /// Name of the purpose, as stored
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::VerifyEmail => "verify_email",
+            TokenPurpose::Login => "login",
+            TokenPurpose::ResetPassword => "reset_password",
+            TokenPurpose::ChangeEmail(_) => "change_email",
+            TokenPurpose::Transfer(_) => "transfer",
+            TokenPurpose::Unlock => "unlock",
+        }
+    }
+
+    /// What the token is bound to, e.g. a transfer token to its transfer
+    fn binding(&self) -> String {
+        match self {
+            TokenPurpose::ChangeEmail(email) => format!("change_email/{email}"),
+            TokenPurpose::Transfer(xid) => format!("transfer/{xid}"),
+            purpose => purpose.as_str().to_string(),
+        }
+    }
+}
+
+/// Where issued tokens are kept until used or expired. k: keyed hash of the token
+pub trait TokenStore: Send + Sync {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        purpose: &'a str,
+        uid: u64,
+        expires_at: NaiveDateTime,
+    ) -> BoxFuture<'a, Result<(), DieselErr>>;
+    /// Remove the token, return: its UID if it was there and not expired
+    fn take<'a>(
+        &'a self,
+        key: &'a str,
+        now: NaiveDateTime,
+    ) -> BoxFuture<'a, Result<Option<u64>, DieselErr>>;
+}
+
+/// Tokens in memory, lost on restart and not shared between instances
+pub struct MemoryStore(Cache<String, (u64, NaiveDateTime)>);
+
+impl MemoryStore {
+    /// `max_ttl`: seconds the longest-living tokens last
+    pub fn new(max_ttl: i64) -> Self {
+        MemoryStore(
+            Cache::builder()
+                .max_capacity(100_000)
+                .time_to_live(Duration::from_secs(max_ttl.max(1) as u64))
+                .build(),
+        )
+    }
+}
+
+impl TokenStore for MemoryStore {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        _purpose: &'a str,
+        uid: u64,
+        expires_at: NaiveDateTime,
+    ) -> BoxFuture<'a, Result<(), DieselErr>> {
+        Box::pin(async move {
+            self.0.insert(key.to_string(), (uid, expires_at)).await;
+            Ok(())
+        })
+    }
+
+    fn take<'a>(
+        &'a self,
+        key: &'a str,
+        now: NaiveDateTime,
+    ) -> BoxFuture<'a, Result<Option<u64>, DieselErr>> {
+        Box::pin(async move {
+            Ok(self
+                .0
+                .remove(key)
+                .await
+                .and_then(|(uid, expires_at)| (expires_at > now).then_some(uid)))
+        })
+    }
+}
+
+/// Tokens in the `one_time_token` table, they survive restarts and work on any instance
+pub struct DbStore(pub DBClient);
+
+impl TokenStore for DbStore {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        purpose: &'a str,
+        uid: u64,
+        expires_at: NaiveDateTime,
+    ) -> BoxFuture<'a, Result<(), DieselErr>> {
+        Box::pin(async move {
+            let now = Utc::now().naive_utc();
+            self.0
+                .insert_one_time_token(
+                    &NewOneTimeToken {
+                        token_hash: key,
+                        purpose,
+                        uid,
+                        expires_at: &expires_at,
+                    },
+                    &now,
+                )
+                .await
+        })
+    }
+
+    fn take<'a>(
+        &'a self,
+        key: &'a str,
+        now: NaiveDateTime,
+    ) -> BoxFuture<'a, Result<Option<u64>, DieselErr>> {
+        Box::pin(async move { self.0.take_one_time_token(key, &now).await })
+    }
+}
+
+/// "24h", "1h", "30min": how long a link lasts, for the emails
+pub fn expires_in(secs: i64) -> String {
+    if secs >= 60 * 60 && secs % (60 * 60) == 0 {
+        format!("{}h", secs / (60 * 60))
+    } else {
+        format!("{}min", (secs + 59) / 60)
+    }
+}
+
+#[derive(Clone)]
+pub struct OneTimeTokens {
+    config: OneTimeTokenConfig,
+    secret: Vec<u8>,
+    store: Arc<dyn TokenStore>,
+}
+
+impl OneTimeTokens {
+    /// `secret`: key of the stored hashes
+    pub fn new(config: &OneTimeTokenConfig, secret: &str, db: &DBClient) -> Self {
+        let store: Arc<dyn TokenStore> = match config.store {
+            TokenStoreKind::Memory => Arc::new(MemoryStore::new(
+                [
+                    config.verify_email,
+                    config.login,
+                    config.reset_password,
+                    config.transfer,
+                    config.unlock,
+                ]
+                .into_iter()
+                .max()
+                .unwrap_or_default(),
+            )),
+            TokenStoreKind::Db => Arc::new(DbStore(db.clone())),
+        };
+        OneTimeTokens {
+            config: config.clone(),
+            secret: secret.as_bytes().to_vec(),
+            store,
+        }
+    }
+
+    /// Seconds a token of the purpose lasts
+    pub fn ttl(&self, purpose: TokenPurpose<'_>) -> i64 {
+        match purpose {
+            TokenPurpose::VerifyEmail | TokenPurpose::ChangeEmail(_) => self.config.verify_email,
+            TokenPurpose::Login => self.config.login,
+            TokenPurpose::ResetPassword => self.config.reset_password,
+            TokenPurpose::Transfer(_) => self.config.transfer,
+            TokenPurpose::Unlock => self.config.unlock,
+        }
+    }
+
+    /// Hex HMAC-SHA256 of the token bound to the purpose
+    fn key(&self, purpose: TokenPurpose<'_>, token: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(format!("{}/{}", purpose.binding(), token).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Issue a token of the user for the purpose, return: the token to put in the link
+    pub async fn issue(&self, purpose: TokenPurpose<'_>, uid: u64) -> Result<String, DieselErr> {
+        let token = hex::encode(rand::random::<[u8; TOKEN_LENGTH]>());
+        let expires_at = Utc::now().naive_utc() + ChronoDuration::seconds(self.ttl(purpose));
+        self.store
+            .put(
+                &self.key(purpose, &token),
+                purpose.as_str(),
+                uid,
+                expires_at,
+            )
+            .await?;
+        Ok(token)
+    }
+
+    /// Use up the token, return: the UID it was issued to,
+    /// `None` if it is unknown, expired, already used or of another purpose
+    pub async fn consume(
+        &self,
+        purpose: TokenPurpose<'_>,
+        token: &str,
+    ) -> Result<Option<u64>, DieselErr> {
+        self.store
+            .take(&self.key(purpose, token), Utc::now().naive_utc())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(config: OneTimeTokenConfig) -> OneTimeTokens {
+        OneTimeTokens {
+            store: Arc::new(MemoryStore::new(config.transfer)),
+            config,
+            secret: b"secret".to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn single_use_and_bound() {
+        let tokens = tokens(OneTimeTokenConfig::default());
+        let token = tokens.issue(TokenPurpose::ResetPassword, 1).await.unwrap();
+        assert_eq!(token.len(), TOKEN_LENGTH * 2);
+        // of another purpose
+        assert_eq!(tokens.consume(TokenPurpose::Login, &token).await, Ok(None));
+        assert_eq!(
+            tokens.consume(TokenPurpose::ResetPassword, &token).await,
+            Ok(Some(1))
+        );
+        assert_eq!(
+            tokens.consume(TokenPurpose::ResetPassword, &token).await,
+            Ok(None)
+        );
+
+        // of another transfer
+        let token = tokens.issue(TokenPurpose::Transfer(7), 2).await.unwrap();
+        assert_eq!(
+            tokens.consume(TokenPurpose::Transfer(8), &token).await,
+            Ok(None)
+        );
+        assert_eq!(
+            tokens.consume(TokenPurpose::Transfer(7), &token).await,
+            Ok(Some(2))
+        );
+        // to another address
+        let purpose = TokenPurpose::ChangeEmail("new@example.com");
+        let token = tokens.issue(purpose, 4).await.unwrap();
+        assert_eq!(
+            tokens
+                .consume(TokenPurpose::ChangeEmail("evil@example.com"), &token)
+                .await,
+            Ok(None)
+        );
+        assert_eq!(tokens.consume(purpose, &token).await, Ok(Some(4)));
+        // only the keyed hash is stored
+        let token = tokens.issue(TokenPurpose::Unlock, 3).await.unwrap();
+        let key = tokens.key(TokenPurpose::Unlock, &token);
+        assert_ne!(key, token);
+        assert!(tokens
+            .store
+            .take(&token, Utc::now().naive_utc())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let tokens = tokens(OneTimeTokenConfig {
+            login: 1,
+            ..OneTimeTokenConfig::default()
+        });
+        let token = tokens.issue(TokenPurpose::Login, 1).await.unwrap();
+        let other = tokens.issue(TokenPurpose::VerifyEmail, 1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(tokens.consume(TokenPurpose::Login, &token).await, Ok(None));
+        assert_eq!(
+            tokens.consume(TokenPurpose::VerifyEmail, &other).await,
+            Ok(Some(1))
+        );
+
+        assert_eq!(expires_in(24 * 60 * 60), "24h");
+        assert_eq!(expires_in(60 * 60), "1h");
+        assert_eq!(expires_in(30 * 60), "30min");
+        assert_eq!(expires_in(90), "2min");
+    }
+}
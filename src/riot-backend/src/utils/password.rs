@@ -1,31 +1,58 @@
 use argon2::{self, Config as Argon2cfg, Variant, Version};
-use log::debug;
 
-/// RFC 9106 (OWASP) recommendation algorithm: Argon2id
-pub fn get_pwd_hash(salt: &[u8], password: &[u8]) -> String {
-    let config = Argon2cfg {
-        variant: Variant::Argon2i,
+use crate::config::PasswordConfig;
+
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: u32 = 32;
+
+fn argon2_config(cost: &PasswordConfig) -> Argon2cfg<'static> {
+    Argon2cfg {
+        variant: Variant::Argon2id,
         version: Version::Version13,
-        mem_cost: 4096,
-        time_cost: 1,
-        lanes: 1,
+        mem_cost: cost.mem_cost,
+        time_cost: cost.time_cost,
+        lanes: cost.lanes,
         secret: &[],
         ad: &[],
-        hash_length: 32,
-    };
-    argon2::hash_encoded(password, salt, &config).unwrap()
+        hash_length: HASH_LENGTH,
+    }
+}
+
+/// RFC 9106 (OWASP) recommendation algorithm: Argon2id, with a random salt for each hash
+///
+/// return: the encoded hash, holding the salt and the parameters
+pub fn get_pwd_hash(password: &[u8], cost: &PasswordConfig) -> Result<String, argon2::Error> {
+    let salt = rand::random::<[u8; SALT_LENGTH]>();
+    argon2::hash_encoded(password, &salt, &argon2_config(cost))
+}
+
+/// Check the password against an encoded hash, fails if the hash is malformed
+pub fn verify(hash: &str, password: &[u8]) -> Result<bool, argon2::Error> {
+    argon2::verify_encoded(hash, password)
 }
-pub fn verify(hash: &str, password: &[u8]) -> bool {
-    debug!("hash={}, pwd={:?}", hash, password);
-    argon2::verify_encoded(hash, password).unwrap()
+
+/// Whether the encoded hash was made with other parameters than the configured ones,
+/// and should be replaced with a new hash of the password once it is known
+pub fn needs_rehash(hash: &str, cost: &PasswordConfig) -> bool {
+    // $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>
+    let parts: Vec<&str> = hash.split('$').collect();
+    let [_, variant, version, params, _, _] = parts[..] else {
+        return true;
+    };
+    let expected = format!("m={},t={},p={}", cost.mem_cost, cost.time_cost, cost.lanes);
+    variant != Variant::Argon2id.as_lowercase_str()
+        || version != format!("v={}", Version::Version13.as_u32())
+        || params != expected
 }
+
 #[cfg(test)]
 mod tests {
     use std::thread;
 
     use rand::Rng;
 
-    use crate::utils::password::{get_pwd_hash, verify};
+    use crate::config::PasswordConfig;
+    use crate::utils::password::{get_pwd_hash, needs_rehash, verify};
     fn generate_random_string(length: usize) -> String {
         const CHARSET: &[u8] =
             b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789!@#$%^&*()_+-=`~";
@@ -44,8 +71,8 @@ mod tests {
     #[test]
     /// Performance benchmark to balance with security
     pub fn hash_benchmark() {
-        let hash = get_pwd_hash(b"114514114514", b"Myp4ss!w0rD");
-        println!("{}", hash.to_string());
+        let hash = get_pwd_hash(b"Myp4ss!w0rD", &PasswordConfig::default()).unwrap();
+        println!("{}", hash);
 
         // Create a vector to store the thread handles
         let mut handles = vec![];
@@ -55,8 +82,8 @@ mod tests {
                 for _ in 0..64 {
                     let pwdstr = generate_random_string(8);
                     let pwd = pwdstr.as_bytes();
-                    let hash = get_pwd_hash(b"114514114514", pwd);
-                    assert!(verify(&hash, pwd));
+                    let hash = get_pwd_hash(pwd, &PasswordConfig::default()).unwrap();
+                    assert!(verify(&hash, pwd).unwrap());
                 }
             });
             handles.push(handle);
@@ -65,4 +92,41 @@ mod tests {
             handle.join().unwrap();
         }
     }
+
+    #[test]
+    fn salts_and_upgrades() {
+        let cost = PasswordConfig::default();
+        let hash = get_pwd_hash(b"Myp4ss!w0rD", &cost).unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+        // salted per hash
+        assert_ne!(hash, get_pwd_hash(b"Myp4ss!w0rD", &cost).unwrap());
+        assert!(verify(&hash, b"Myp4ss!w0rD").unwrap());
+        assert!(!verify(&hash, b"myp4ss!w0rD").unwrap());
+        assert!(!needs_rehash(&hash, &cost));
+        let stronger = PasswordConfig {
+            time_cost: 3,
+            ..PasswordConfig::default()
+        };
+        assert!(needs_rehash(&hash, &stronger));
+
+        // hashed by older versions: Argon2i, 4 MiB, one global salt
+        let legacy = argon2::hash_encoded(
+            b"Myp4ss!w0rD",
+            b"argon2_hash_salt",
+            &argon2::Config {
+                variant: argon2::Variant::Argon2i,
+                mem_cost: 4096,
+                time_cost: 1,
+                ..argon2::Config::original()
+            },
+        )
+        .unwrap();
+        assert!(verify(&legacy, b"Myp4ss!w0rD").unwrap());
+        assert!(needs_rehash(&legacy, &cost));
+
+        // malformed hashes are errors, not panics
+        assert!(verify("", b"Myp4ss!w0rD").is_err());
+        assert!(verify("$argon2id$v=19$m=19456", b"Myp4ss!w0rD").is_err());
+        assert!(needs_rehash("not a hash", &cost));
+    }
 }
@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{error, info};
+
+use crate::{db::DBClient, models::WebhookEvent, utils::webhooks::emit_device};
+
+/// Topic suffix of MQTT Last Will messages: a device registered with topic `home/light`
+/// should set its Last Will topic to `<api_key>/home/light/$offline`.
+pub const LAST_WILL_SUFFIX: &str = "/$offline";
+/// Unit: seconds
+const CHECK_INTERVAL: u64 = 10;
+
+/// Heartbeat timeout daemon: periodically mark silent devices as offline
+pub async fn presence_daemon() {
+    let db = DBClient::new(&DBClient::get_database_url());
+    loop {
+        tokio::time::sleep(Duration::from_secs(CHECK_INTERVAL)).await;
+        match db
+            .mark_timed_out_devices_offline(&Utc::now().naive_utc())
+            .await
+        {
+            Ok(dids) if !dids.is_empty() => {
+                info!("Devices went offline: {:?}", dids);
+                for did in dids {
+                    emit_device(&db, WebhookEvent::DeviceOffline, did);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("Presence check failed: {:?}", e),
+        }
+    }
+}
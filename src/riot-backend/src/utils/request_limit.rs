@@ -0,0 +1,259 @@
+//! Token-bucket limits of API requests, per route group and per client (the IP of anonymous
+//! requests, the user of a login session, or the API key), and MQTT ingestion quotas per publisher.
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use moka::future::Cache;
+
+use crate::config::{RateLimitConfig, RateLimitGroup};
+use crate::models::UserPrivilege;
+
+/// Who a request counts against
+#[derive(Debug, Clone, PartialEq)]
+pub enum Client {
+    Ip(String),
+    User {
+        id: u64,
+        privilege: u32,
+    },
+    /// v: hash of the key
+    ApiKey {
+        hash: String,
+        privilege: u32,
+    },
+}
+
+impl Client {
+    fn key(&self) -> String {
+        match self {
+            Client::Ip(ip) => format!("ip:{ip}"),
+            Client::User { id, .. } => format!("user:{id}"),
+            Client::ApiKey { hash, .. } => format!("key:{hash}"),
+        }
+    }
+
+    /// Requests per minute allowed in the group
+    fn limit_in(&self, group: &RateLimitGroup) -> u32 {
+        match self {
+            Client::Ip(_) => group.anonymous,
+            Client::User { privilege, .. } | Client::ApiKey { privilege, .. }
+                if *privilege >= UserPrivilege::Admin as u32 =>
+            {
+                group.admin
+            }
+            _ => group.normal,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// State of the bucket after taking a token, for the `X-RateLimit-*` headers
+#[derive(Debug, PartialEq)]
+pub struct Quota {
+    /// Size of the bucket, i.e. requests per minute
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again
+    pub reset: u64,
+    /// `None` if allowed, otherwise the seconds until the next token
+    pub retry_after: Option<u64>,
+}
+
+/// Whether the path (under `/api`) starts with the pattern, a `*` segment matches any one segment
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut path = path.trim_matches('/').split('/');
+    pattern
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .all(|segment| matches!(path.next(), Some(s) if segment == "*" || segment == s))
+}
+
+#[derive(Clone)]
+pub struct RequestLimiter {
+    config: RateLimitConfig,
+    /// k: group name and client
+    buckets: Cache<String, Arc<Mutex<Bucket>>>,
+    /// Privileges of users and API keys, to pick their limits. k: client
+    privileges: Cache<String, u32>,
+}
+
+impl RequestLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RequestLimiter {
+            config: config.clone(),
+            // An idle bucket is full after a minute at most, dropping it changes nothing
+            buckets: Cache::builder()
+                .max_capacity(1_000_000)
+                .time_to_idle(Duration::from_secs(60))
+                .build(),
+            privileges: Cache::builder()
+                .max_capacity(100_000)
+                .time_to_live(Duration::from_secs(60))
+                .build(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Privilege of a user or API key, from the cache or `init` (the DB)
+    pub async fn privilege_of<E: Send + Sync + 'static>(
+        &self,
+        client: &str,
+        init: impl Future<Output = Result<u32, E>>,
+    ) -> Result<u32, Arc<E>> {
+        self.privileges.try_get_with(client.to_string(), init).await
+    }
+
+    /// Take a token from the bucket of `key`, refilled at `limit` tokens per minute
+    async fn take(&self, key: String, limit: u32) -> Quota {
+        let bucket = self
+            .buckets
+            .get_with(key, async {
+                Arc::new(Mutex::new(Bucket {
+                    tokens: limit as f64,
+                    updated: Instant::now(),
+                }))
+            })
+            .await;
+        let mut bucket = bucket.lock().unwrap();
+        let per_sec = limit as f64 / 60.0;
+        let now = Instant::now();
+        let refilled = bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_sec;
+        bucket.tokens = refilled.min(limit as f64);
+        bucket.updated = now;
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(((1.0 - bucket.tokens) / per_sec).ceil() as u64)
+        };
+        Quota {
+            limit,
+            remaining: bucket.tokens as u32,
+            reset: ((limit as f64 - bucket.tokens) / per_sec).ceil() as u64,
+            retry_after,
+        }
+    }
+
+    /// Count a request to the path (under `/api`), `None` if it is not limited
+    pub async fn check(&self, path: &str, client: &Client) -> Option<Quota> {
+        let group = self.config.groups.iter().find(|group| {
+            group
+                .paths
+                .iter()
+                .any(|pattern| path_matches(pattern, path))
+        })?;
+        let limit = client.limit_in(group);
+        if limit == 0 {
+            return None;
+        }
+        Some(
+            self.take(format!("{}/{}", group.name, client.key()), limit)
+                .await,
+        )
+    }
+
+    /// Count an MQTT message of the publisher (e.g. `user:1`), return: whether it is within the quota
+    pub async fn take_mqtt(&self, publisher: &str) -> bool {
+        let limit = self.config.mqtt_per_minute;
+        if !self.config.enabled || limit == 0 {
+            return true;
+        }
+        self.take(format!("mqtt/{publisher}"), limit)
+            .await
+            .retry_after
+            .is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RequestLimiter {
+        RequestLimiter::new(&RateLimitConfig {
+            enabled: true,
+            groups: vec![
+                RateLimitGroup {
+                    name: "records".into(),
+                    paths: vec!["/devices/*/records".into()],
+                    anonymous: 2,
+                    normal: 3,
+                    admin: 0,
+                },
+                RateLimitGroup {
+                    name: "default".into(),
+                    paths: vec!["/".into()],
+                    anonymous: 60,
+                    normal: 60,
+                    admin: 60,
+                },
+            ],
+            mqtt_per_minute: 1,
+        })
+    }
+
+    #[test]
+    fn paths() {
+        assert!(path_matches("/devices/*/records", "/devices/12/records"));
+        assert!(path_matches("/devices/*/records", "/devices/12/records/"));
+        assert!(path_matches("/devices/*/records", "/devices/12/records/3"));
+        assert!(!path_matches("/devices/*/records", "/devices/12"));
+        assert!(!path_matches("/devices/*/records", "/devices/12/recordsx"));
+        assert!(path_matches("/", "/anything"));
+        assert!(path_matches("/accounts/login", "/accounts/login"));
+    }
+
+    #[tokio::test]
+    async fn buckets() {
+        let limiter = limiter();
+        let ip = Client::Ip("10.0.0.1".into());
+        let first = limiter.check("/devices/1/records", &ip).await.unwrap();
+        assert_eq!(
+            (first.limit, first.remaining, first.retry_after),
+            (2, 1, None)
+        );
+        assert_eq!(first.reset, 30);
+        let second = limiter.check("/devices/2/records", &ip).await.unwrap();
+        assert_eq!((second.remaining, second.retry_after), (0, None));
+        // empty: a token comes every 30s
+        let refused = limiter.check("/devices/1/records", &ip).await.unwrap();
+        assert!(matches!(refused.retry_after, Some(29..=30)));
+        // other groups and clients have their own buckets
+        assert_eq!(
+            limiter.check("/devices/1", &ip).await.unwrap().retry_after,
+            None
+        );
+        let user = Client::User {
+            id: 1,
+            privilege: UserPrivilege::Normal as u32,
+        };
+        assert_eq!(
+            limiter
+                .check("/devices/1/records", &user)
+                .await
+                .unwrap()
+                .remaining,
+            2
+        );
+        // 0: unlimited
+        let admin = Client::ApiKey {
+            hash: "h".into(),
+            privilege: UserPrivilege::Admin as u32,
+        };
+        assert_eq!(limiter.check("/devices/1/records", &admin).await, None);
+
+        assert!(limiter.take_mqtt("user:1").await);
+        assert!(!limiter.take_mqtt("user:1").await);
+        assert!(limiter.take_mqtt("org:1").await);
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::naive::serde::ts_milliseconds_option;
+use chrono::{NaiveDateTime, Utc};
+use log::{error, info, warn};
+use moka::future::Cache;
+use once_cell::sync::Lazy;
+use rdkafka::{
+    error::KafkaResult,
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig,
+};
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{broadcast::error::RecvError, mpsc, RwLock},
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    config::{SinkConfig, SinkKind, CONFIG},
+    db::DBClient,
+    models::Record,
+    utils::{hub::RecordHub, mqtt_instance::mqtt_instancer::MqttDaemon},
+};
+
+/// Max records written at once
+const BATCH_SIZE: usize = 256;
+/// Unit: seconds. Retry delay after a failed write, doubled up to `MAX_RETRY_DELAY`
+const MIN_RETRY_DELAY: u64 = 1;
+/// Unit: seconds
+const MAX_RETRY_DELAY: u64 = 60;
+/// Unit: seconds. Interval to re-resolve the devices under the tags of the filters
+const TAG_REFRESH_INTERVAL: u64 = 60;
+/// Unit: seconds. How long the topic and owner of a device are cached by MQTT sinks
+const DEVICE_CACHE_TTL: u64 = 60;
+/// Unit: milliseconds. How long Kafka sinks try to deliver a record before failing the batch
+const KAFKA_DELIVERY_TIMEOUT: u64 = 30_000;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// Health of a configured sink
+pub struct SinkStatus {
+    pub name: String,
+    /// mqtt / file / kafka
+    pub kind: String,
+    /// Whether the last write (or the connection, for MQTT) succeeded
+    pub healthy: bool,
+    /// Records handed to the sink
+    pub written: u64,
+    /// Records dropped because the buffer was full, or missed by the daemon and not found again
+    pub dropped: u64,
+    pub last_error: Option<String>,
+    /// Precision: milliseconds
+    #[serde(with = "ts_milliseconds_option")]
+    pub last_error_at: Option<NaiveDateTime>,
+}
+
+/// Indexed as `CONFIG.sink`
+pub static SINK_STATUS: Lazy<RwLock<Vec<SinkStatus>>> = Lazy::new(|| {
+    RwLock::new(
+        CONFIG
+            .sink
+            .iter()
+            .map(|config| SinkStatus {
+                name: config.name.clone(),
+                kind: config.kind.as_str().to_string(),
+                healthy: true,
+                written: 0,
+                dropped: 0,
+                last_error: None,
+                last_error_at: None,
+            })
+            .collect(),
+    )
+});
+
+impl SinkKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SinkKind::Mqtt { .. } => "mqtt",
+            SinkKind::File { .. } => "file",
+            SinkKind::Kafka { .. } => "kafka",
+        }
+    }
+}
+
+async fn report_success(index: usize, written: usize) {
+    let mut status = SINK_STATUS.write().await;
+    status[index].healthy = true;
+    status[index].written += written as u64;
+}
+
+async fn report_error(index: usize, reason: String) {
+    warn!("Sink {} failed: {}", CONFIG.sink[index].name, reason);
+    let mut status = SINK_STATUS.write().await;
+    status[index].healthy = false;
+    status[index].last_error = Some(reason);
+    status[index].last_error_at = Some(Utc::now().naive_utc());
+}
+
+/// Destination of records
+trait Sink: Send {
+    /// Write a batch of records, Err: reason
+    fn write(&mut self, records: &[Record]) -> impl Future<Output = Result<(), String>> + Send;
+}
+
+/// Write buffered records to the sink, retrying a failed batch with backoff
+async fn run_sink(index: usize, mut sink: impl Sink, mut rx: mpsc::Receiver<Record>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while rx.recv_many(&mut batch, BATCH_SIZE).await > 0 {
+        let mut delay = MIN_RETRY_DELAY;
+        while let Err(reason) = sink.write(&batch).await {
+            report_error(index, reason).await;
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+        report_success(index, batch.len()).await;
+        batch.clear();
+    }
+}
+
+/// Republish payloads to another MQTT broker
+struct MqttSink {
+    db: DBClient,
+    client: AsyncClient,
+    topic: String,
+    /// k: device id, v: (owner id, device topic)
+    devices: Cache<u64, (u64, String)>,
+}
+
+impl MqttSink {
+    fn new(index: usize, db: DBClient, host: &str, port: u16, topic: &str) -> Self {
+        let (client, mut eventloop) = MqttDaemon::new_daemon(
+            &format!("RIOT_SINK_{}", Uuid::new_v4().simple()),
+            host,
+            port,
+        );
+        // Drive the connection, rumqttc reconnects on the next poll after an error
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                        info!("Sink {} connected", CONFIG.sink[index].name);
+                        report_success(index, 0).await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        report_error(index, e.to_string()).await;
+                        tokio::time::sleep(Duration::from_secs(MIN_RETRY_DELAY)).await;
+                    }
+                }
+            }
+        });
+        MqttSink {
+            db,
+            client,
+            topic: topic.to_string(),
+            devices: Cache::builder()
+                .time_to_live(Duration::from_secs(DEVICE_CACHE_TTL))
+                .build(),
+        }
+    }
+}
+
+/// Fill the placeholders of a topic template
+fn rewrite_topic(template: &str, topic: &str, did: u64, uid: u64) -> String {
+    template
+        .replace("{topic}", topic.trim_start_matches('/'))
+        .replace("{did}", &did.to_string())
+        .replace("{uid}", &uid.to_string())
+}
+
+impl Sink for MqttSink {
+    async fn write(&mut self, records: &[Record]) -> Result<(), String> {
+        for record in records {
+            let (uid, topic) = match self.devices.get(&record.did).await {
+                Some(device) => device,
+                None => {
+                    let device = self
+                        .db
+                        .get_device_by_id(record.did)
+                        .await
+                        .map_err(|e| format!("{:?}", e))?;
+                    let entry = (device.uid, device.topic);
+                    self.devices.insert(record.did, entry.clone()).await;
+                    entry
+                }
+            };
+            self.client
+                .publish(
+                    rewrite_topic(&self.topic, &topic, record.did, uid),
+                    QoS::AtLeastOnce,
+                    false,
+                    record.payload.clone(),
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Append records as JSON lines to a size-rotated file
+struct FileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: Option<File>,
+    size: u64,
+}
+
+impl FileSink {
+    fn new(path: &str, max_bytes: u64, max_files: usize) -> Self {
+        FileSink {
+            path: PathBuf::from(path),
+            max_bytes,
+            max_files,
+            file: None,
+            size: 0,
+        }
+    }
+}
+
+/// `path.{n}`
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{n}"));
+    PathBuf::from(rotated)
+}
+
+/// Shift `path` -> `path.1` -> `path.2`... keeping at most `max_files` rotated files
+async fn rotate_files(path: &Path, max_files: usize) -> std::io::Result<()> {
+    if max_files == 0 {
+        return fs::remove_file(path).await;
+    }
+    let oldest = rotated_path(path, max_files);
+    if fs::try_exists(&oldest).await? {
+        fs::remove_file(&oldest).await?;
+    }
+    for n in (1..max_files).rev() {
+        let from = rotated_path(path, n);
+        if fs::try_exists(&from).await? {
+            fs::rename(&from, rotated_path(path, n + 1)).await?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1)).await
+}
+
+impl Sink for FileSink {
+    async fn write(&mut self, records: &[Record]) -> Result<(), String> {
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record).map_err(|e| e.to_string())?;
+            lines.push(b'\n');
+        }
+        if self.size > 0 && self.size + lines.len() as u64 > self.max_bytes {
+            self.file = None;
+            rotate_files(&self.path, self.max_files)
+                .await
+                .map_err(|e| e.to_string())?;
+            self.size = 0;
+        }
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await
+                .map_err(|e| e.to_string())?;
+            self.size = file.metadata().await.map_err(|e| e.to_string())?.len();
+            self.file = Some(file);
+        }
+        let file = self.file.as_mut().unwrap();
+        let written = async {
+            file.write_all(&lines).await?;
+            file.flush().await
+        };
+        if let Err(e) = written.await {
+            // Reopen (and re-measure) on the next attempt
+            self.file = None;
+            return Err(e.to_string());
+        }
+        self.size += lines.len() as u64;
+        Ok(())
+    }
+}
+
+/// Produce records to Kafka with librdkafka, which follows the partition leaders
+struct KafkaSink {
+    producer: FutureProducer,
+    topic: String,
+    partition: Option<i32>,
+}
+
+impl KafkaSink {
+    fn new(brokers: &str, topic: &str, partition: Option<i32>) -> KafkaResult<Self> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("client.id", "riot")
+            .set("message.timeout.ms", KAFKA_DELIVERY_TIMEOUT.to_string())
+            .create()?;
+        Ok(KafkaSink {
+            producer,
+            topic: topic.to_string(),
+            partition,
+        })
+    }
+}
+
+impl Sink for KafkaSink {
+    async fn write(&mut self, records: &[Record]) -> Result<(), String> {
+        let mut deliveries = Vec::with_capacity(records.len());
+        for record in records {
+            let key = record.did.to_string();
+            let value = serde_json::to_vec(record).map_err(|e| e.to_string())?;
+            let mut message = FutureRecord::to(&self.topic)
+                .key(&key)
+                .payload(&value)
+                .timestamp(record.timestamp.timestamp_millis());
+            if let Some(partition) = self.partition {
+                message = message.partition(partition);
+            }
+            // Queued (and copied) by librdkafka, delivered in the background
+            let delivery = self
+                .producer
+                .send_result(message)
+                .map_err(|(e, _)| e.to_string())?;
+            deliveries.push(delivery);
+        }
+        for delivery in futures::future::join_all(deliveries).await {
+            match delivery {
+                Ok(Ok(_)) => {}
+                Ok(Err((e, _))) => return Err(e.to_string()),
+                Err(_) => return Err("Delivery canceled".to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Buffer of a sink and the records it accepts
+struct Outlet {
+    index: usize,
+    config: &'static SinkConfig,
+    /// Devices matching the filter, tags resolved
+    dids: HashSet<u64>,
+    tx: mpsc::Sender<Record>,
+}
+
+impl Outlet {
+    fn accepts(&self, did: u64) -> bool {
+        (self.config.devices.is_empty() && self.config.tags.is_empty()) || self.dids.contains(&did)
+    }
+
+    /// Hand the record to the sink if it matches the filter, counting it as dropped if the buffer
+    /// is full
+    async fn forward(&self, record: &Record) {
+        if self.accepts(record.did) && self.tx.try_send(record.clone()).is_err() {
+            SINK_STATUS.write().await[self.index].dropped += 1;
+        }
+    }
+
+    async fn refresh(&mut self, db: &DBClient) {
+        let mut dids: HashSet<u64> = self.config.devices.iter().copied().collect();
+        for tid in self.config.tags.iter() {
+            match db.get_dids_under_tag(*tid).await {
+                Ok(tagged) => dids.extend(tagged),
+                Err(e) => {
+                    error!(
+                        "Resolve tag {} of sink {} failed: {:?}",
+                        tid, self.config.name, e
+                    );
+                    return;
+                }
+            }
+        }
+        self.dids = dids;
+    }
+}
+
+/// Sink daemon: forward stored records to the configured sinks
+pub async fn sink_daemon(hub: RecordHub) {
+    if CONFIG.sink.is_empty() {
+        return;
+    }
+    let db = DBClient::new(&DBClient::get_database_url());
+    let mut outlets = Vec::with_capacity(CONFIG.sink.len());
+    for (index, config) in CONFIG.sink.iter().enumerate() {
+        let (tx, rx) = mpsc::channel(config.buffer.max(1));
+        match &config.kind {
+            SinkKind::Mqtt { host, port, topic } => {
+                let sink = MqttSink::new(index, db.clone(), host, *port, topic);
+                tokio::spawn(run_sink(index, sink, rx));
+            }
+            SinkKind::File {
+                path,
+                max_bytes,
+                max_files,
+            } => {
+                let sink = FileSink::new(path, *max_bytes, *max_files);
+                tokio::spawn(run_sink(index, sink, rx));
+            }
+            SinkKind::Kafka {
+                brokers,
+                topic,
+                partition,
+            } => match KafkaSink::new(brokers, topic, *partition) {
+                Ok(sink) => {
+                    tokio::spawn(run_sink(index, sink, rx));
+                }
+                Err(e) => {
+                    report_error(index, e.to_string()).await;
+                    continue;
+                }
+            },
+        }
+        info!("Sink {} ({}) started", config.name, config.kind.as_str());
+        outlets.push(Outlet {
+            index,
+            config,
+            dids: HashSet::new(),
+            tx,
+        });
+    }
+    let mut rx = hub.subscribe();
+    let mut refresh = tokio::time::interval(Duration::from_secs(TAG_REFRESH_INTERVAL));
+    // ID of the last record received from the hub
+    let mut last = 0;
+    // Records up to this ID were read again from the DB after lagging, skipped if still buffered
+    let mut caught_up = 0;
+    loop {
+        tokio::select! {
+            _ = refresh.tick() => {
+                for outlet in outlets.iter_mut() {
+                    outlet.refresh(&db).await;
+                }
+            }
+            record = rx.recv() => match record {
+                Ok(record) if record.id <= caught_up => {}
+                Ok(record) => {
+                    last = last.max(record.id);
+                    for outlet in outlets.iter() {
+                        outlet.forward(&record).await;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    // Only the records after one already seen can be told apart
+                    let missed = match last {
+                        0 => Err("no record received yet".to_string()),
+                        _ => db
+                            .get_records_after(last, skipped as i64)
+                            .await
+                            .map_err(|e| format!("{:?}", e)),
+                    };
+                    match missed {
+                        Ok(missed) => {
+                            warn!(
+                                "Sink daemon lagged, {} of {skipped} records read again",
+                                missed.len()
+                            );
+                            for record in missed.iter() {
+                                for outlet in outlets.iter() {
+                                    outlet.forward(record).await;
+                                }
+                            }
+                            if let Some(record) = missed.last() {
+                                last = record.id;
+                                caught_up = record.id;
+                            }
+                        }
+                        Err(e) => {
+                            error!("Sink daemon lagged, {skipped} records lost: {}", e);
+                            let mut status = SINK_STATUS.write().await;
+                            for outlet in outlets.iter() {
+                                status[outlet.index].dropped += skipped;
+                            }
+                        }
+                    }
+                }
+                Err(RecvError::Closed) => return,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn record(id: u64) -> Record {
+        Record {
+            id,
+            did: 1,
+            payload: b"{\"value\": 1}".to_vec(),
+            timestamp: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn topic_rewriting() {
+        assert_eq!(
+            rewrite_topic("riot/{uid}/{did}/{topic}", "/home/light", 7, 3),
+            "riot/3/7/home/light"
+        );
+        assert_eq!(rewrite_topic("{topic}", "home/light", 7, 3), "home/light");
+    }
+
+    #[tokio::test]
+    async fn file_rotation() {
+        let dir = std::env::temp_dir().join(format!("riot_sink_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("records.ndjson");
+        let line_len = serde_json::to_vec(&record(1)).unwrap().len() as u64 + 1;
+        // Two lines per file, two rotated files kept
+        let mut sink = FileSink::new(path.to_str().unwrap(), line_len * 2, 2);
+        for id in 1..=7 {
+            sink.write(&[record(id)]).await.unwrap();
+        }
+        let ids = |path: PathBuf| -> Vec<u64> {
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<Record>(line).unwrap().id)
+                .collect()
+        };
+        assert_eq!(ids(path.clone()), vec![7]);
+        assert_eq!(ids(rotated_path(&path, 1)), vec![5, 6]);
+        assert_eq!(ids(rotated_path(&path, 2)), vec![3, 4]);
+        assert!(!rotated_path(&path, 3).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
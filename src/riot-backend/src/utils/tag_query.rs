@@ -0,0 +1,238 @@
+//! Boolean tag queries over devices, e.g. `site:hq AND type:sensor AND NOT retired`.
+//!
+//! Grammar (operators are upper case, `AND` binds tighter than `OR`):
+//! ```text
+//! query := and ("OR" and)*
+//! and   := not ("AND" not)*
+//! not   := "NOT" not | "(" query ")" | name | "quoted name"
+//! ```
+//! A name matches devices tagged with any tag of that name or with one of its descendants.
+use std::collections::{HashMap, HashSet};
+
+use crate::models::Tag;
+
+/// Max length of a query string
+pub const MAX_QUERY_LEN: usize = 1024;
+/// Max nesting of `NOT` and parentheses
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TagQuery {
+    Tag(String),
+    Not(Box<TagQuery>),
+    And(Box<TagQuery>, Box<TagQuery>),
+    Or(Box<TagQuery>, Box<TagQuery>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Name(String),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => name.push(c),
+                        None => return Err("Unterminated quoted tag name".into()),
+                    }
+                }
+                tokens.push(Token::Name(name));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Name(word),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn query(&mut self, depth: usize) -> Result<TagQuery, String> {
+        let mut lhs = self.and(depth)?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            lhs = TagQuery::Or(Box::new(lhs), Box::new(self.and(depth)?));
+        }
+        Ok(lhs)
+    }
+    fn and(&mut self, depth: usize) -> Result<TagQuery, String> {
+        let mut lhs = self.not(depth)?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            lhs = TagQuery::And(Box::new(lhs), Box::new(self.not(depth)?));
+        }
+        Ok(lhs)
+    }
+    fn not(&mut self, depth: usize) -> Result<TagQuery, String> {
+        if depth > MAX_DEPTH {
+            return Err("Query is nested too deeply".into());
+        }
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        match token {
+            Some(Token::Not) => Ok(TagQuery::Not(Box::new(self.not(depth + 1)?))),
+            Some(Token::Open) => {
+                let inner = self.query(depth + 1)?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err("Missing `)`".into());
+                }
+                self.pos += 1;
+                Ok(inner)
+            }
+            Some(Token::Name(name)) => Ok(TagQuery::Tag(name.clone())),
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+            None => Err("Unexpected end of query".into()),
+        }
+    }
+}
+
+/// Parse a tag query
+pub fn parse(input: &str) -> Result<TagQuery, String> {
+    if input.len() > MAX_QUERY_LEN {
+        return Err(format!(
+            "Query must not be longer than {MAX_QUERY_LEN} bytes"
+        ));
+    }
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+    };
+    let query = parser.query(0)?;
+    match parser.peek() {
+        None => Ok(query),
+        Some(token) => Err(format!("Unexpected {:?}", token)),
+    }
+}
+
+/// Given tags and all of their descendants
+pub fn descendants(tags: &[Tag], roots: impl IntoIterator<Item = u64>) -> HashSet<u64> {
+    let mut children: HashMap<u64, Vec<u64>> = HashMap::new();
+    for tag in tags {
+        if let Some(parent) = tag.parent {
+            children.entry(parent).or_default().push(tag.id);
+        }
+    }
+    let mut found = HashSet::new();
+    let mut stack: Vec<u64> = roots.into_iter().collect();
+    while let Some(tid) = stack.pop() {
+        if found.insert(tid) {
+            if let Some(next) = children.get(&tid) {
+                stack.extend(next);
+            }
+        }
+    }
+    found
+}
+
+/// Whether making `parent` the parent of `tid` would form a loop
+pub fn creates_cycle(tags: &[Tag], tid: u64, parent: u64) -> bool {
+    descendants(tags, [tid]).contains(&parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{creates_cycle, descendants, parse, TagQuery};
+    use crate::models::Tag;
+
+    fn tag(id: u64, name: &str, parent: Option<u64>) -> Tag {
+        Tag {
+            id,
+            uid: 1,
+            oid: 1,
+            name: name.into(),
+            desc: None,
+            activated: true,
+            parent,
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        let name = |n: &str| Box::new(TagQuery::Tag(n.into()));
+        assert_eq!(
+            parse("site:hq AND type:sensor AND NOT retired").unwrap(),
+            TagQuery::And(
+                Box::new(TagQuery::And(name("site:hq"), name("type:sensor"))),
+                Box::new(TagQuery::Not(name("retired"))),
+            )
+        );
+        assert_eq!(
+            parse("a OR b AND c").unwrap(),
+            TagQuery::Or(name("a"), Box::new(TagQuery::And(name("b"), name("c"))))
+        );
+        assert_eq!(
+            parse("(a OR b) AND \"living room\"").unwrap(),
+            TagQuery::And(
+                Box::new(TagQuery::Or(name("a"), name("b"))),
+                name("living room")
+            )
+        );
+        assert!(parse("").is_err());
+        assert!(parse("a AND").is_err());
+        assert!(parse("(a OR b").is_err());
+        assert!(parse("a b").is_err());
+        assert!(parse(&"NOT ".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn hierarchy() {
+        // building(1) > floor(2) > room(3), sensor(4)
+        let tags = vec![
+            tag(1, "building", None),
+            tag(2, "floor", Some(1)),
+            tag(3, "room", Some(2)),
+            tag(4, "sensor", None),
+        ];
+        let mut found: Vec<u64> = descendants(&tags, [2, 4]).into_iter().collect();
+        found.sort();
+        assert_eq!(found, vec![2, 3, 4]);
+        assert!(creates_cycle(&tags, 1, 3));
+        assert!(creates_cycle(&tags, 2, 2));
+        assert!(!creates_cycle(&tags, 3, 4));
+    }
+}
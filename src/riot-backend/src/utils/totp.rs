@@ -0,0 +1,179 @@
+//! TOTP (RFC 6238, HMAC-SHA1, 6 digits, 30s steps) second factor and its recovery codes.
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::utils::jwt::hash_token;
+
+/// Seconds a code lasts
+pub const PERIOD: u64 = 30;
+/// Steps before and after the current one still accepted, for clock drift
+const SKEW: u64 = 1;
+const DIGITS: u32 = 6;
+/// Recovery codes issued on enrollment
+pub const RECOVERY_CODES: usize = 10;
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Base32 (RFC 4648) without padding, as authenticator apps expect
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+/// Case-insensitive, ignores padding and spaces
+pub fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.chars().filter(|c| !matches!(c, '=' | ' ')) {
+        let value = BASE32
+            .iter()
+            .position(|b| *b as char == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// A new random secret (160 bits, base32)
+pub fn new_secret() -> String {
+    base32_encode(&rand::random::<[u8; 20]>())
+}
+
+/// Code of the time step
+pub fn code_at(key: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Check the code against the secret at unix time `now`, return: the matched time step
+///
+/// The caller must reject steps not after the last accepted one, so that a code works once
+pub fn verify_code(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = base32_decode(secret)?;
+    let current = now / PERIOD;
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|step| code_at(&key, *step) == code)
+}
+
+/// Whether the input looks like a TOTP code rather than a recovery code
+pub fn is_totp_code(code: &str) -> bool {
+    let code = code.trim();
+    code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Percent-encode a URI component
+fn encode_component(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// `otpauth://` URI to be shown as a QR code to authenticator apps
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let issuer = encode_component(issuer);
+    format!(
+        "otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={PERIOD}",
+        encode_component(account)
+    )
+}
+
+/// New recovery codes, `xxxxx-xxxxx` (hex)
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = hex::encode(rand::random::<[u8; 5]>());
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Hash of a recovery code as stored, ignoring case, dashes and spaces
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc6238() {
+        let secret = base32_encode(b"12345678901234567890");
+        assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(
+            base32_decode(&secret.to_lowercase()).unwrap(),
+            b"12345678901234567890"
+        );
+        assert_eq!(base32_decode("not base32!"), None);
+        // the last 6 digits of the RFC test vectors (SHA1)
+        for (time, code) in [(59, 287082), (1111111109, 81804), (1234567890, 5924)] {
+            assert_eq!(code_at(b"12345678901234567890", time / PERIOD), code);
+        }
+        assert_eq!(verify_code(&secret, "005924", 1234567890), Some(41152263));
+        // drift of one step is tolerated
+        assert_eq!(
+            verify_code(&secret, "005924", 1234567890 + 30),
+            Some(41152263)
+        );
+        assert_eq!(verify_code(&secret, "005924", 1234567890 + 90), None);
+        assert_eq!(verify_code(&secret, "5924", 1234567890), None);
+        assert!(is_totp_code(" 081804"));
+        assert!(!is_totp_code("0a1b2-3c4d5"));
+    }
+
+    #[test]
+    fn enrollment() {
+        let secret = new_secret();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(base32_decode(&secret).unwrap().len(), 20);
+        assert_eq!(
+            otpauth_uri("RIoT", "a b@example.com", "ABC"),
+            "otpauth://totp/RIoT:a%20b%40example.com?secret=ABC&issuer=RIoT&algorithm=SHA1&digits=6&period=30"
+        );
+        let codes = new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert_eq!(codes[0].len(), 11);
+        assert_eq!(
+            hash_recovery_code(&codes[0]),
+            hash_recovery_code(&codes[0].to_uppercase().replace('-', " "))
+        );
+    }
+}
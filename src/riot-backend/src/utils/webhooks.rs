@@ -0,0 +1,600 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::result::Error as DieselErr;
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::Name;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::sync::{broadcast::error::RecvError, Notify};
+
+use crate::{
+    config::CONFIG,
+    db::{DBClient, WebhookOwner},
+    models::{
+        DeliveryStatus, LabeledDevice, NewWebhookDelivery, Record, Webhook, WebhookDelivery,
+        WebhookEvent,
+    },
+    utils::hub::RecordHub,
+};
+
+/// Max deliveries attempted concurrently
+const BATCH_SIZE: i64 = 64;
+/// Unit: seconds. Interval to look for due retries when no new event is queued
+const POLL_INTERVAL: u64 = 5;
+/// Unit: seconds. Upper bound of the retry delay
+const MAX_RETRY_DELAY: u64 = 6 * 60 * 60;
+/// Unit: seconds. Interval to purge old delivered entries from the delivery log
+const PURGE_INTERVAL: u64 = 60 * 60;
+/// Max length of the error message kept in the delivery log
+const MAX_ERROR_LEN: usize = 1024;
+
+/// Wakes the delivery loop as soon as new deliveries are queued
+static QUEUED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Deliveries never follow redirects, and only connect to public addresses
+pub static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(CONFIG.webhook.timeout))
+        .redirect(Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .expect("Failed to build HTTP client")
+});
+
+/// Whether webhooks may be delivered to the address: not a loopback, private, link-local
+/// (e.g. cloud metadata), shared, unspecified, broadcast or multicast one
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // 100.64.0.0/10, carrier-grade NAT
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // fc00::/7, unique local
+                    || first & 0xfe00 == 0xfc00
+                    // fe80::/10, link-local
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Refuse resolved addresses of which any is not public
+fn public_addrs(host: &str, addrs: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, String> {
+    if addrs.is_empty() {
+        return Err(format!("{host} does not resolve"));
+    }
+    match addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+        Some(addr) => Err(format!(
+            "{host} resolves to a non-public address {}",
+            addr.ip()
+        )),
+        None => Ok(addrs),
+    }
+}
+
+/// Resolves hosts of the deliveries to public addresses only: checked again on connecting,
+/// a host can not be rebound to an internal address after its webhook was registered
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            let addrs = public_addrs(name.as_str(), addrs)?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Check a webhook URL: http(s) only, to a host resolving to public addresses only.
+/// Err: the reason
+pub async fn check_target(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|e| e.to_string())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("Only http and https URLs are allowed".into());
+    }
+    let Some(host) = url.host_str() else {
+        return Err("The URL has no host".into());
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("{host} does not resolve: {e}"))?
+            .collect(),
+    };
+    public_addrs(host, addrs).map(|_| ())
+}
+
+/// Random secret for a new webhook
+pub fn new_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// `X-RIoT-Signature` header value: `sha256=` + hex HMAC-SHA256 of `{timestamp}.{body}`,
+/// where `timestamp` is the `X-RIoT-Timestamp` header (seconds since epoch)
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Delay before the next attempt after `attempts` failed ones: `base * 2^(attempts - 1)`, capped
+pub fn retry_delay(base: u64, attempts: u32) -> Duration {
+    let factor = 1u64 << attempts.saturating_sub(1).min(32);
+    Duration::from_secs(base.saturating_mul(factor).min(MAX_RETRY_DELAY))
+}
+
+/// Queue an event for the subscribed webhooks in the background
+pub fn emit(db: &DBClient, owner: WebhookOwner, event: WebhookEvent, data: impl Serialize) {
+    let data = match serde_json::to_value(data) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Serialize webhook event failed: {:?}", e);
+            return;
+        }
+    };
+    let db = db.clone();
+    tokio::spawn(async move {
+        let queued = match db.get_subscribed_webhooks(owner, event).await {
+            Ok(hooks) => queue(&db, &hooks, event, &data).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = queued {
+            error!("Queue webhook event failed: {:?}", e);
+        }
+    });
+}
+
+/// Queue a device event with the current device info as data
+pub fn emit_device(db: &DBClient, event: WebhookEvent, did: u64) {
+    let db = db.clone();
+    tokio::spawn(async move {
+        let device = match db.get_device_by_id(did).await {
+            Ok(device) => device,
+            Err(e) => return error!("Queue webhook event failed: {:?}", e),
+        };
+        match db.get_device_labels(&[did]).await {
+            Ok(labels) => {
+                let owner = WebhookOwner::Org(device.oid);
+                let data = LabeledDevice::attach(vec![device], labels).pop();
+                emit(&db, owner, event, data)
+            }
+            Err(e) => error!("Queue webhook event failed: {:?}", e),
+        }
+    });
+}
+
+/// Queue a tag event with the current tag info as data
+pub fn emit_tag(db: &DBClient, event: WebhookEvent, tid: u64) {
+    let db = db.clone();
+    tokio::spawn(async move {
+        match db.get_tag_by_id(tid).await {
+            Ok(tag) => emit(&db, WebhookOwner::Org(tag.oid), event, tag),
+            Err(e) => error!("Queue webhook event failed: {:?}", e),
+        }
+    });
+}
+
+/// Queue a `webhook.ping` delivery to a single webhook
+pub async fn ping(db: &DBClient, hook: &Webhook) -> Result<usize, DieselErr> {
+    let data = json!({"wid": hook.id});
+    queue(db, std::slice::from_ref(hook), WebhookEvent::Ping, &data).await
+}
+
+/// Body of a delivery, `timestamp` in milliseconds
+pub fn envelope(event: WebhookEvent, data: &Value, now: &NaiveDateTime) -> String {
+    json!({
+        "event": event,
+        "timestamp": now.timestamp_millis(),
+        "data": data,
+    })
+    .to_string()
+}
+
+/// Insert a pending delivery of the event for each webhook
+async fn queue(
+    db: &DBClient,
+    hooks: &[Webhook],
+    event: WebhookEvent,
+    data: &Value,
+) -> Result<usize, DieselErr> {
+    if hooks.is_empty() {
+        return Ok(0);
+    }
+    let now = Utc::now().naive_utc();
+    let payload = envelope(event, data, &now);
+    let deliveries: Vec<NewWebhookDelivery> = hooks
+        .iter()
+        .map(|hook| NewWebhookDelivery {
+            wid: hook.id,
+            event,
+            payload: &payload,
+            next_attempt_at: &now,
+            created_at: &now,
+        })
+        .collect();
+    let queued = db.add_webhook_deliveries(&deliveries).await?;
+    QUEUED.notify_one();
+    Ok(queued)
+}
+
+/// POST a signed payload, return the HTTP status if it is a success (2xx).
+/// Err: (HTTP status if responded, reason)
+pub async fn post_signed(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    delivery_id: u64,
+    event: WebhookEvent,
+    payload: &str,
+) -> Result<u16, (Option<u16>, String)> {
+    let timestamp = Utc::now().timestamp();
+    let resp = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-RIoT-Event", event.as_str())
+        .header("X-RIoT-Delivery", delivery_id)
+        .header("X-RIoT-Timestamp", timestamp)
+        .header("X-RIoT-Signature", sign(secret, timestamp, payload))
+        .body(payload.to_string())
+        .send()
+        .await
+        .map_err(|e| (None, e.to_string()))?;
+    let status = resp.status();
+    if status.is_success() {
+        Ok(status.as_u16())
+    } else {
+        Err((Some(status.as_u16()), format!("Responded {status}")))
+    }
+}
+
+/// Attempt a delivery once and schedule the retry (or give up) on failure
+async fn attempt(db: &DBClient, delivery: WebhookDelivery, hook: Webhook) {
+    // Checked again, for the URLs with an IP address, which are not resolved
+    let result = match check_target(&hook.url).await {
+        Ok(()) => {
+            post_signed(
+                &HTTP_CLIENT,
+                &hook.url,
+                &hook.secret,
+                delivery.id,
+                delivery.event,
+                &delivery.payload,
+            )
+            .await
+        }
+        Err(reason) => Err((None, reason)),
+    };
+    let now = Utc::now().naive_utc();
+    let attempts = delivery.attempts + 1;
+    let finished = match result {
+        Ok(code) => {
+            db.finish_webhook_attempt(
+                delivery.id,
+                DeliveryStatus::Delivered,
+                Some(code),
+                None,
+                None,
+                &now,
+            )
+            .await
+        }
+        Err((code, reason)) => {
+            let reason: String = reason.chars().take(MAX_ERROR_LEN).collect();
+            if attempts >= CONFIG.webhook.max_attempts {
+                warn!(
+                    "Webhook delivery {} to {} is dead after {} attempts: {}",
+                    delivery.id, hook.url, attempts, reason
+                );
+                db.finish_webhook_attempt(
+                    delivery.id,
+                    DeliveryStatus::Dead,
+                    code,
+                    Some(&reason),
+                    None,
+                    &now,
+                )
+                .await
+            } else {
+                let delay = retry_delay(CONFIG.webhook.retry_base, attempts);
+                let next = now + chrono::Duration::from_std(delay).unwrap();
+                db.finish_webhook_attempt(
+                    delivery.id,
+                    DeliveryStatus::Pending,
+                    code,
+                    Some(&reason),
+                    Some(&next),
+                    &now,
+                )
+                .await
+            }
+        }
+    };
+    if let Err(e) = finished {
+        error!("Update webhook delivery failed: {:?}", e);
+    }
+}
+
+/// Attempt all due deliveries, a batch at a time
+async fn deliver_due(db: &DBClient) {
+    loop {
+        let now = Utc::now().naive_utc();
+        let due = match db.get_due_webhook_deliveries(&now, BATCH_SIZE).await {
+            Ok(due) => due,
+            Err(e) => {
+                error!("Get due webhook deliveries failed: {:?}", e);
+                return;
+            }
+        };
+        let count = due.len();
+        futures::future::join_all(
+            due.into_iter()
+                .map(|(delivery, hook)| attempt(db, delivery, hook)),
+        )
+        .await;
+        if (count as i64) < BATCH_SIZE {
+            return;
+        }
+    }
+}
+
+/// Queue the `record.created` event of a stored record
+async fn record_event(db: &DBClient, record: &Record) {
+    let event = WebhookEvent::RecordCreated;
+    let queued = match db
+        .get_subscribed_webhooks(WebhookOwner::DeviceOrg(record.did), event)
+        .await
+    {
+        Ok(hooks) => match serde_json::to_value(record) {
+            Ok(data) => queue(db, &hooks, event, &data).await,
+            Err(e) => {
+                error!("Serialize record failed: {:?}", e);
+                return;
+            }
+        },
+        Err(e) => Err(e),
+    };
+    if let Err(e) = queued {
+        error!("Queue webhook event failed: {:?}", e);
+    }
+}
+
+/// Queue `record.created` events of stored records
+async fn record_events(db: DBClient, hub: RecordHub) {
+    let mut rx = hub.subscribe();
+    // ID of the last record received from the hub
+    let mut last = 0;
+    // Records up to this ID were read again from the DB after lagging, skipped if still buffered
+    let mut caught_up = 0;
+    loop {
+        match rx.recv().await {
+            Ok(record) if record.id <= caught_up => {}
+            Ok(record) => {
+                last = last.max(record.id);
+                record_event(&db, &record).await;
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                // Only the records after one already seen can be told apart
+                let missed = match last {
+                    0 => Err("no record received yet".to_string()),
+                    _ => db
+                        .get_records_after(last, skipped as i64)
+                        .await
+                        .map_err(|e| format!("{:?}", e)),
+                };
+                match missed {
+                    Ok(missed) => {
+                        warn!(
+                            "Webhook daemon lagged, {} of {skipped} records read again",
+                            missed.len()
+                        );
+                        for record in missed.iter() {
+                            record_event(&db, record).await;
+                        }
+                        if let Some(record) = missed.last() {
+                            last = record.id;
+                            caught_up = record.id;
+                        }
+                    }
+                    Err(e) => error!(
+                        "Webhook daemon lagged, {skipped} records not delivered: {}",
+                        e
+                    ),
+                }
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Webhook daemon: queue record events and deliver queued events with retries
+pub async fn webhook_daemon(hub: RecordHub) {
+    let db = DBClient::new(&DBClient::get_database_url());
+    tokio::spawn(record_events(db.clone(), hub));
+    let mut last_purge = tokio::time::Instant::now();
+    loop {
+        deliver_due(&db).await;
+        if last_purge.elapsed() >= Duration::from_secs(PURGE_INTERVAL) {
+            last_purge = tokio::time::Instant::now();
+            let before = Utc::now().naive_utc()
+                - chrono::Duration::days(CONFIG.webhook.log_retention as i64);
+            match db.purge_webhook_deliveries(&before).await {
+                Ok(purged) if purged > 0 => info!("Purged {purged} webhook delivery logs"),
+                Ok(_) => {}
+                Err(e) => error!("Purge webhook delivery logs failed: {:?}", e),
+            }
+        }
+        tokio::select! {
+            _ = QUEUED.notified() => {}
+            _ = tokio::time::sleep(Duration::from_secs(POLL_INTERVAL)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn signature() {
+        // RFC 4231 test case 2, with the message split as `{timestamp}.{body}`
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"Jefe").unwrap();
+        mac.update(b"what do ya want for nothing?");
+        assert_eq!(
+            hex::encode(mac.finalize().into_bytes()),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let signature = sign("secret", 1700000000, r#"{"event":"webhook.ping"}"#);
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature.len(), "sha256=".len() + 64);
+        assert_ne!(
+            signature,
+            sign("secret", 1700000001, r#"{"event":"webhook.ping"}"#)
+        );
+        assert_ne!(
+            signature,
+            sign("secreT", 1700000000, r#"{"event":"webhook.ping"}"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn internal_targets() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.1/",
+            "http://172.16.3.4/",
+            "http://192.168.1.1:443/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fe80::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://localhost:8080/",
+            "ftp://example.com/",
+            "file:///etc/passwd",
+            "not a url",
+        ] {
+            assert!(check_target(url).await.is_err(), "{url}");
+        }
+        // public literals are not resolved
+        assert_eq!(check_target("https://93.184.216.34/hook").await, Ok(()));
+        assert_eq!(check_target("http://[2606:4700::1111]:8080/").await, Ok(()));
+
+        // hosts resolved on connecting
+        let resolved = PublicResolver.resolve("localhost".parse().unwrap()).await;
+        assert!(resolved.is_err());
+    }
+
+    #[test]
+    fn backoff() {
+        assert_eq!(retry_delay(30, 1), Duration::from_secs(30));
+        assert_eq!(retry_delay(30, 2), Duration::from_secs(60));
+        assert_eq!(retry_delay(30, 4), Duration::from_secs(240));
+        assert_eq!(retry_delay(30, 100), Duration::from_secs(MAX_RETRY_DELAY));
+    }
+
+    #[actix_web::test]
+    async fn deliver_to_local_stand_in() {
+        // Local receiver: accepts deliveries with the expected event, rejects the others
+        let (tx, mut rx) = mpsc::unbounded_channel::<(String, String, String, String)>();
+        let server = HttpServer::new(move || {
+            let tx = tx.clone();
+            App::new().default_service(web::to(move |req: HttpRequest, body: String| {
+                let tx = tx.clone();
+                async move {
+                    let header = |name: &str| {
+                        req.headers()
+                            .get(name)
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default()
+                            .to_string()
+                    };
+                    let event = header("X-RIoT-Event");
+                    tx.send((
+                        event.clone(),
+                        header("X-RIoT-Timestamp"),
+                        header("X-RIoT-Signature"),
+                        body,
+                    ))
+                    .unwrap();
+                    if event == "webhook.ping" {
+                        HttpResponse::NoContent().finish()
+                    } else {
+                        HttpResponse::ServiceUnavailable().finish()
+                    }
+                }
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/hook", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let client = reqwest::Client::new();
+        let payload = r#"{"event":"webhook.ping","timestamp":0,"data":{}}"#;
+        let delivered = post_signed(&client, &url, "secret", 1, WebhookEvent::Ping, payload).await;
+        assert_eq!(delivered, Ok(204));
+        let (event, timestamp, signature, body) = rx.recv().await.unwrap();
+        assert_eq!(event, "webhook.ping");
+        assert_eq!(body, payload);
+        assert_eq!(signature, sign("secret", timestamp.parse().unwrap(), &body));
+
+        let failed = post_signed(
+            &client,
+            &url,
+            "secret",
+            2,
+            WebhookEvent::RecordCreated,
+            payload,
+        )
+        .await;
+        assert_eq!(failed.map_err(|(code, _)| code), Err(Some(503)));
+        handle.stop(true).await;
+    }
+}
@@ -10,6 +10,9 @@ const PageNotFound = () => import('@/views/httpStatus/PageNotFound.vue')
 const TypeSubView = () => import('@/views/type/TypeSubView.vue')
 const RecordDetailSubView = () => import('@/views/device/RecordDetailSubView.vue')
 const VerifyView = () => import('@/views/VerifyView.vue')
+const TransferView = () => import('@/views/TransferView.vue')
+const ResetPasswordView = () => import('@/views/ResetPasswordView.vue')
+const UnlockView = () => import('@/views/UnlockView.vue')
 import { useUserStore } from '@/stores/user'
 import message from 'ant-design-vue/es/message'
 import { createRouter, createWebHistory } from 'vue-router'
@@ -88,6 +91,24 @@ const router = createRouter({
       component: VerifyView,
       meta: { title: '验证' }
     },
+    {
+      path: '/transfer',
+      name: 'transfer',
+      component: TransferView,
+      meta: { title: '设备转移' }
+    },
+    {
+      path: '/reset_password',
+      name: 'reset',
+      component: ResetPasswordView,
+      meta: { title: '重置密码' }
+    },
+    {
+      path: '/unlock',
+      name: 'unlock',
+      component: UnlockView,
+      meta: { title: '解锁账号' }
+    },
     { path: '/:catchAll(.*)', component: PageNotFound }
   ]
 })
@@ -110,7 +131,9 @@ router.beforeEach(async (to, from) => {
     !userStore.loggedIn() &&
     to.name !== 'login' &&
     to.name !== 'register' &&
-    to.name !== 'verify'
+    to.name !== 'verify' &&
+    to.name !== 'reset' &&
+    to.name !== 'unlock'
   ) {
     return { name: 'login' }
   }
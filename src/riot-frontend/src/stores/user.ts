@@ -7,7 +7,6 @@ export interface User {
   username: string
   email: string
   privilege: number
-  api_key: string
   since: number
 }
 export const useUserStore = defineStore('user', () => {
@@ -20,10 +19,29 @@ export const useUserStore = defineStore('user', () => {
     }
   })
   const data = ref<null | User>(null)
+  // Challenge of a login waiting for the 2FA code
+  const totpToken = ref<null | string>(null)
+  // Why the last password login was refused before checking the password (HTTP 429)
+  const loginRefusal = ref<null | { locked: boolean; retryAfter: number }>(null)
   function set(userinfo: User) {
     data.value = userinfo || null
+    if (loggedIn()) keepAlive()
+  }
+  // The access token is short-lived, renew it with the refresh cookie
+  async function refresh(): Promise<boolean> {
+    try {
+      await api.post('/accounts/refresh')
+      return true
+    } catch (error) {
+      return false
+    }
+  }
+  let refresher: ReturnType<typeof setInterval> | null = null
+  function keepAlive() {
+    if (refresher === null) refresher = setInterval(refresh, 5 * 60 * 1000)
   }
   async function init(): Promise<boolean> {
+    await refresh()
     try {
       const user = (
         await api.get('/accounts/user_info', {
@@ -44,27 +62,50 @@ export const useUserStore = defineStore('user', () => {
       username,
       password
     }
+    loginRefusal.value = null
     try {
-      const user = (
-        await api.post('/accounts/login', loginForm, {
-          headers: {
-            'Content-Type': 'application/json'
-          }
-        })
-      ).data
-      set(user || {})
+      const response = await api.post('/accounts/login', loginForm, {
+        headers: {
+          'Content-Type': 'application/json'
+        }
+      })
+      if (response.status === 202) {
+        totpToken.value = response.data.message
+        return false
+      }
+      set(response.data || {})
       return true
-    } catch (error) {
+    } catch (error: any) {
       console.log(error)
+      const response = error?.response
+      if (response?.status === 429) {
+        loginRefusal.value = {
+          locked: !!response.data?.message?.includes('locked'),
+          retryAfter: Number(response.headers['retry-after']) || 0
+        }
+      }
       return false
     }
     return false
   }
+  async function loginTotp(code: string): Promise<boolean> {
+    try {
+      const user = (await api.post('/accounts/login/totp', { token: totpToken.value, code })).data
+      totpToken.value = null
+      set(user || {})
+      return true
+    } catch (error: any) {
+      console.log(error)
+      // the challenge is gone after too many wrong codes
+      if (error?.response?.data?.message?.includes('token')) totpToken.value = null
+      return false
+    }
+  }
   function inited(): boolean {
     return data.value !== null
   }
   function loggedIn(): boolean {
     return data.value !== null && 'id' in data.value
   }
-  return { data, set, loggedIn, login, init, inited }
+  return { data, totpToken, loginRefusal, set, loggedIn, login, loginTotp, init, inited }
 })